@@ -0,0 +1,335 @@
+//! Benchmarks of the request paths changes like batching or caching target.
+//!
+//! `MyGreeter` stores every greeting in Postgres, so the end-to-end unary
+//! benchmark and those of the queries only run with `BENCH_DATABASE_URL`
+//! pointing at a migrated database. The other benchmarks never touch the store: the server gets a
+//! pool for an address nothing listens on, which connects lazily.
+//!
+//!     cargo bench --bench hot_paths
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use prost::Message as _;
+use tokio::{runtime::Runtime, sync::Notify, task::JoinSet};
+use tonic::transport::{Channel, Server};
+
+use tonic_hello_tls::{
+    codec::Frame,
+    config::{Config, OverflowPolicy},
+    db::{Db, Message},
+    greeter::{
+        hello_world::{
+            greeter_client::GreeterClient, HelloReply, HelloRequest, SayHelloManyRequest,
+        },
+        GreeterServer, MyGreeter,
+    },
+    greeting,
+    listener::{self, ListenerOptions},
+    messages::{Broadcaster, SharedMessage},
+    pool,
+};
+
+/// Nothing listens there, calls reaching the store fail fast.
+const NO_DATABASE: &str = "postgres://bench@127.0.0.1:1/bench";
+
+fn runtime() -> Runtime {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("runtime")
+}
+
+/// Serves a greeter on an ephemeral port and connects a client to it.
+async fn serve(db_url: &str) -> GreeterClient<Channel> {
+    let db = Db::new(db_url).await.expect("database pool");
+    let options = ListenerOptions {
+        nodelay: true,
+        reuseport: false,
+        proxy_protocol: false,
+        ..ListenerOptions::default()
+    };
+    let listener = listener::bind(SocketAddr::from(([127, 0, 0, 1], 0)), &options).expect("bind");
+    let addr = listener.local_addr().expect("local addr");
+    let config = Config {
+        slow_rpc_ms: 0,
+        ..Config::default()
+    };
+    let router = Server::builder().add_service(GreeterServer::new(MyGreeter::new(db, config)));
+    tokio::spawn(router.serve_with_incoming(listener::incoming(vec![listener], options)));
+
+    let channel = Channel::from_shared(format!("http://{}", addr))
+        .unwrap()
+        .connect()
+        .await
+        .expect("connect");
+    GreeterClient::new(channel)
+}
+
+fn hello(name: &str) -> HelloRequest {
+    HelloRequest {
+        name: name.to_string(),
+        ..Default::default()
+    }
+}
+
+fn say_hello(c: &mut Criterion) {
+    let mut group = c.benchmark_group("say_hello");
+    group.throughput(Throughput::Elements(1));
+
+    let request = HelloRequest {
+        locale: "fr-CA".to_string(),
+        ..hello("bench")
+    };
+    group.bench_function("greet", |b| b.iter(|| greeting::greet(&request)));
+    group.bench_function("greet pooled", |b| {
+        b.iter(|| {
+            let mut message = pool::string();
+            greeting::compose_into(
+                &mut message,
+                &request.name,
+                &request.locale,
+                request.salutation(),
+            );
+            message.len()
+        })
+    });
+    group.bench_function("key formatted", |b| {
+        b.iter(|| format!("{}/{}", "tenant", request.name))
+    });
+    group.bench_function("key pooled", |b| {
+        b.iter(|| pool::key(&["tenant", &request.name]).len())
+    });
+
+    match std::env::var("BENCH_DATABASE_URL") {
+        Ok(db_url) => {
+            let rt = runtime();
+            let client = rt.block_on(serve(&db_url));
+            group.bench_function("unary", |b| {
+                b.to_async(&rt).iter(|| {
+                    let mut client = client.clone();
+                    async move { client.say_hello(hello("bench")).await.unwrap() }
+                })
+            });
+        }
+        Err(_) => eprintln!("BENCH_DATABASE_URL not set, skipping say_hello/unary"),
+    }
+    group.finish();
+}
+
+/// The statements `SayHello` and resuming subscribers run, each connection
+/// preparing them once, with `CONCURRENT_QUERIES` in flight at a time.
+fn db_queries(c: &mut Criterion) {
+    const CONCURRENT_QUERIES: usize = 16;
+
+    let Ok(db_url) = std::env::var("BENCH_DATABASE_URL") else {
+        eprintln!("BENCH_DATABASE_URL not set, skipping db_queries");
+        return;
+    };
+    let rt = runtime();
+    let db = rt.block_on(Db::new(&db_url)).expect("database pool");
+    let latest = rt
+        .block_on(db.insert_message("Hello bench!"))
+        .expect("insert")
+        .id;
+    let mut group = c.benchmark_group("db_queries");
+    group.throughput(Throughput::Elements(CONCURRENT_QUERIES as u64));
+
+    group.bench_function("insert_message", |b| {
+        b.to_async(&rt).iter(|| {
+            let mut queries = JoinSet::new();
+            for _ in 0..CONCURRENT_QUERIES {
+                let db = db.clone();
+                queries.spawn(async move { db.insert_message("Hello bench!").await.unwrap() });
+            }
+            async move { while queries.join_next().await.is_some() {} }
+        })
+    });
+    group.bench_function("get_messages_after", |b| {
+        b.to_async(&rt).iter(|| {
+            let mut queries = JoinSet::new();
+            for _ in 0..CONCURRENT_QUERIES {
+                let db = db.clone();
+                queries.spawn(async move { db.get_messages_after(latest).await.unwrap() });
+            }
+            async move { while queries.join_next().await.is_some() {} }
+        })
+    });
+    group.finish();
+}
+
+fn broadcast_fan_out(c: &mut Criterion) {
+    let rt = runtime();
+    let mut group = c.benchmark_group("broadcast_fan_out");
+    let message = Message {
+        id: 1,
+        message: Some("Hello bench!".to_string()),
+        updated: None,
+        tags: serde_json::json!({}),
+        metadata: serde_json::json!({}),
+        priority: 0,
+    };
+
+    for subscribers in [1, 10, 100, 1000, 10_000] {
+        let broadcaster = Broadcaster::new();
+        let mut receivers = (0..subscribers)
+            .map(|_| broadcaster.subscribe("bench"))
+            .collect::<Vec<_>>();
+        group.throughput(Throughput::Elements(subscribers as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(subscribers),
+            &subscribers,
+            |b, _| {
+                b.iter(|| {
+                    rt.block_on(async {
+                        broadcaster.broadcast(message.clone()).await;
+                        for rx in receivers.iter_mut() {
+                            rx.recv().await.unwrap();
+                        }
+                    })
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Subscribers reading concurrently, each in a task of its own as the
+/// streams of `ListMessagesStream` do, one shard against one per core.
+fn sharded_fan_out(c: &mut Criterion) {
+    let rt = runtime();
+    let mut group = c.benchmark_group("sharded_fan_out");
+    let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+    let message = Message {
+        id: 1,
+        message: Some("Hello bench!".to_string()),
+        updated: None,
+        tags: serde_json::json!({}),
+        metadata: serde_json::json!({}),
+        priority: 0,
+    };
+
+    for shards in [1, cores] {
+        for subscribers in [1000, 10_000] {
+            let broadcaster = Broadcaster::with_shards(1024, OverflowPolicy::DropOldest, shards);
+            let received = Arc::new(AtomicUsize::new(0));
+            let all_received = Arc::new(Notify::new());
+            let tasks = (0..subscribers)
+                .map(|_| {
+                    let mut rx = broadcaster.subscribe("bench");
+                    let received = received.clone();
+                    let all_received = all_received.clone();
+                    rt.spawn(async move {
+                        while rx.recv().await.is_ok() {
+                            if received.fetch_add(1, Ordering::AcqRel) + 1 == subscribers {
+                                all_received.notify_one();
+                            }
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            group.throughput(Throughput::Elements(subscribers as u64));
+            group.bench_with_input(
+                BenchmarkId::new(format!("{} shards", shards), subscribers),
+                &subscribers,
+                |b, _| {
+                    b.iter(|| {
+                        rt.block_on(async {
+                            received.store(0, Ordering::Release);
+                            broadcaster.broadcast(message.clone()).await;
+                            all_received.notified().await;
+                        })
+                    })
+                },
+            );
+            for task in tasks {
+                task.abort();
+            }
+        }
+    }
+    group.finish();
+}
+
+/// Encoding the reply of a broadcast message for every subscriber, against
+/// encoding it once and copying the bytes, as `ListMessagesStream` does.
+fn reply_encoding(c: &mut Criterion) {
+    let mut group = c.benchmark_group("reply_encoding");
+    let message = Message {
+        id: 1,
+        message: Some("Hello bench!".to_string()),
+        updated: None,
+        tags: serde_json::json!({"lang": "en"}),
+        metadata: serde_json::json!({"source": "bench", "region": "eu-west-1", "attempt": 1}),
+        priority: 0,
+    };
+    let mut buf = Vec::with_capacity(1024);
+
+    for subscribers in [100, 10_000] {
+        group.throughput(Throughput::Elements(subscribers));
+        group.bench_with_input(
+            BenchmarkId::new("per subscriber", subscribers),
+            &subscribers,
+            |b, _| {
+                b.iter(|| {
+                    for _ in 0..subscribers {
+                        buf.clear();
+                        HelloReply::from(message.clone()).encode(&mut buf).unwrap();
+                    }
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("pre-encoded", subscribers),
+            &subscribers,
+            |b, _| {
+                b.iter(|| {
+                    let shared = SharedMessage::from(message.clone());
+                    for _ in 0..subscribers {
+                        buf.clear();
+                        Frame::Encoded(shared.encoded()).encode(&mut buf).unwrap();
+                    }
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn streaming_echo(c: &mut Criterion) {
+    let rt = runtime();
+    let client = rt.block_on(serve(NO_DATABASE));
+    let mut group = c.benchmark_group("streaming_echo");
+
+    for replies in [1, 100] {
+        group.throughput(Throughput::Elements(replies));
+        group.bench_with_input(BenchmarkId::from_parameter(replies), &replies, |b, _| {
+            b.to_async(&rt).iter(|| {
+                let mut client = client.clone();
+                async move {
+                    let request = SayHelloManyRequest {
+                        names: vec!["bench".to_string()],
+                        count: replies as u32,
+                        delay_ms: 0,
+                    };
+                    let mut stream = client.say_hello_many(request).await.unwrap().into_inner();
+                    while stream.message().await.unwrap().is_some() {}
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().measurement_time(Duration::from_secs(5));
+    targets = say_hello, db_queries, broadcast_fan_out, sharded_fan_out, reply_encoding, streaming_echo
+}
+criterion_main!(benches);
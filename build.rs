@@ -1,9 +1,130 @@
-use std::{env, path::PathBuf};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use prost_build::{Service, ServiceGenerator};
+
+const PROTOS: &[&str] = &[
+    "proto/helloworld.proto",
+    "proto/helloworld/v1/greeter.proto",
+    "proto/helloworld/v2/greeter.proto",
+];
 
 fn main() {
     let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
-    tonic_build::configure()
+    for proto in PROTOS {
+        println!("cargo:rerun-if-changed={}", proto);
+    }
+    prost_build::Config::new()
         .file_descriptor_set_path(out_dir.join("helloworld_descriptor.bin"))
-        .compile(&["proto/helloworld.proto"], &["proto"])
+        .service_generator(Box::new(FramedReplies::new()))
+        .compile_protos(PROTOS, &["proto"])
         .unwrap();
+    // kept out of the descriptor set, reflection would list it as served
+    tonic_build::compile_protos("proto/translation/v1/translation.proto").unwrap();
+
+    write_migrations(&out_dir.join("migrations.rs"));
+    set_build_info();
+}
+
+/// tonic's generators, except that the `ListMessagesStream` server takes
+/// `crate::codec::Frame` replies, some of them encoded beforehand, while its
+/// client still decodes `HelloReply`. Both encode the same on the wire.
+struct FramedReplies {
+    clients: Box<dyn ServiceGenerator>,
+    servers: Box<dyn ServiceGenerator>,
+}
+
+impl FramedReplies {
+    fn new() -> Self {
+        Self {
+            clients: tonic_build::configure()
+                .build_server(false)
+                .service_generator(),
+            servers: tonic_build::configure()
+                .build_client(false)
+                .service_generator(),
+        }
+    }
+}
+
+impl ServiceGenerator for FramedReplies {
+    fn generate(&mut self, service: Service, buf: &mut String) {
+        self.clients.generate(service.clone(), buf);
+        let mut service = service;
+        if service.package == "helloworld" && service.proto_name == "Greeter" {
+            for method in &mut service.methods {
+                if method.proto_name == "ListMessagesStream" {
+                    method.output_type = "crate::codec::Frame".to_string();
+                }
+            }
+        }
+        self.servers.generate(service, buf);
+    }
+
+    fn finalize(&mut self, buf: &mut String) {
+        self.clients.finalize(buf);
+        self.servers.finalize(buf);
+    }
+
+    fn finalize_package(&mut self, package: &str, buf: &mut String) {
+        self.clients.finalize_package(package, buf);
+        self.servers.finalize_package(package, buf);
+    }
+}
+
+/// Sets `GIT_SHA` and `BUILD_TIMESTAMP` for `server_info`. Both can be
+/// given from outside, e.g. where the source isn't a git checkout or for
+/// reproducible builds.
+fn set_build_info() {
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    for path in [".git/HEAD", ".git/refs/heads"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+
+    let git_sha = env::var("GIT_SHA").ok().or_else(|| {
+        let output = Command::new("git")
+            .args(["rev-parse", "HEAD"])
+            .output()
+            .ok()?;
+        output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    });
+    let built_at = env::var("SOURCE_DATE_EPOCH").unwrap_or_else(|_| {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        now.as_secs().to_string()
+    });
+    println!(
+        "cargo:rustc-env=GIT_SHA={}",
+        git_sha.as_deref().unwrap_or("unknown")
+    );
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", built_at);
+}
+
+/// Lists the versions of `migrations/` the way the diesel CLI records them,
+/// `2023-09-12-155328_create_messages_table` as `20230912155328`, for
+/// `compat::check` to find the ones a database lacks.
+fn write_migrations(out: &Path) {
+    println!("cargo:rerun-if-changed=migrations");
+    let mut versions = fs::read_dir("migrations")
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.path().is_dir())
+        .map(|entry| {
+            let name = entry.file_name().into_string().unwrap();
+            let version = name.split('_').next().unwrap_or_default();
+            version.replace('-', "")
+        })
+        .collect::<Vec<_>>();
+    versions.sort();
+    let source = format!("pub const MIGRATIONS: &[&str] = &{:?};\n", versions);
+    fs::write(out, source).unwrap();
 }
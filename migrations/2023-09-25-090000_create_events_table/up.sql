@@ -0,0 +1,15 @@
+-- Your SQL goes here
+CREATE TABLE IF NOT EXISTS events (
+  seq BIGSERIAL PRIMARY KEY,
+  kind TEXT NOT NULL,
+  message_id INTEGER NOT NULL,
+  payload TEXT,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+-- seed the log with the messages stored so far, `messages` is its projection
+-- from here on
+INSERT INTO events (kind, message_id, payload)
+SELECT 'hello', id, json_build_object('message', message)::text
+FROM messages
+ORDER BY id;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS messages_tags_idx;
+ALTER TABLE messages DROP COLUMN IF EXISTS tags;
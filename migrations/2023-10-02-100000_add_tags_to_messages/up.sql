@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS tags JSONB NOT NULL DEFAULT '{}';
+
+CREATE INDEX IF NOT EXISTS messages_tags_idx ON messages USING GIN (tags);
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+CREATE TABLE IF NOT EXISTS pending_deliveries (
+  message_id INTEGER PRIMARY KEY REFERENCES messages (id) ON DELETE CASCADE,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
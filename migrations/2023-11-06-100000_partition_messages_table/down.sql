@@ -0,0 +1,26 @@
+-- This file should undo anything in `up.sql`
+DROP FUNCTION IF EXISTS drop_messages_partitions(TIMESTAMP);
+DROP FUNCTION IF EXISTS create_messages_partition(TIMESTAMP);
+
+ALTER TABLE messages RENAME TO messages_partitioned;
+ALTER TABLE messages_partitioned RENAME CONSTRAINT messages_pkey TO messages_partitioned_pkey;
+ALTER INDEX messages_tags_idx RENAME TO messages_partitioned_tags_idx;
+
+CREATE TABLE messages (
+  id INTEGER PRIMARY KEY DEFAULT nextval('messages_id_seq'),
+  message TEXT,
+  updated INTEGER,
+  tags JSONB NOT NULL DEFAULT '{}'
+);
+ALTER SEQUENCE messages_id_seq OWNED BY messages.id;
+CREATE INDEX messages_tags_idx ON messages USING GIN (tags);
+
+INSERT INTO messages (id, message, updated, tags)
+SELECT id, message, updated, tags
+FROM messages_partitioned;
+DROP TABLE messages_partitioned;
+
+DELETE FROM pending_deliveries WHERE message_id NOT IN (SELECT id FROM messages);
+ALTER TABLE pending_deliveries
+  ADD CONSTRAINT pending_deliveries_message_id_fkey
+  FOREIGN KEY (message_id) REFERENCES messages (id) ON DELETE CASCADE;
@@ -0,0 +1,86 @@
+-- Your SQL goes here
+-- a foreign key to a partitioned table has to cover the partition key,
+-- deleting a message drops its pending delivery itself from here on
+ALTER TABLE pending_deliveries DROP CONSTRAINT IF EXISTS pending_deliveries_message_id_fkey;
+
+ALTER TABLE messages RENAME TO messages_unpartitioned;
+ALTER TABLE messages_unpartitioned RENAME CONSTRAINT messages_pkey TO messages_unpartitioned_pkey;
+ALTER INDEX messages_tags_idx RENAME TO messages_unpartitioned_tags_idx;
+
+CREATE TABLE messages (
+  id INTEGER NOT NULL DEFAULT nextval('messages_id_seq'),
+  message TEXT,
+  updated INTEGER,
+  tags JSONB NOT NULL DEFAULT '{}',
+  created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (id, created_at)
+) PARTITION BY RANGE (created_at);
+ALTER SEQUENCE messages_id_seq OWNED BY messages.id;
+CREATE INDEX messages_tags_idx ON messages USING GIN (tags);
+
+-- months without a partition of their own end up here
+CREATE TABLE messages_default PARTITION OF messages DEFAULT;
+
+-- the messages stored so far count as stored now, they aren't dated
+INSERT INTO messages (id, message, updated, tags)
+SELECT id, message, updated, tags
+FROM messages_unpartitioned;
+DROP TABLE messages_unpartitioned;
+
+-- Creates the partition `messages_YYYY_MM` of the month `in_month` falls in,
+-- moving the messages of that month out of the default partition. Returns
+-- whether the partition is new.
+CREATE OR REPLACE FUNCTION create_messages_partition(in_month TIMESTAMP) RETURNS BOOLEAN AS $$
+DECLARE
+  from_ts TIMESTAMP := date_trunc('month', in_month);
+  to_ts TIMESTAMP := date_trunc('month', in_month) + INTERVAL '1 month';
+  part_name TEXT := 'messages_' || to_char(in_month, 'YYYY_MM');
+BEGIN
+  PERFORM pg_advisory_xact_lock(hashtext('messages_partitions'));
+  IF to_regclass(part_name) IS NOT NULL THEN
+    RETURN FALSE;
+  END IF;
+  -- writes wait until the partition is attached, an insert routed to the
+  -- default partition before would violate its constraint after
+  LOCK TABLE messages IN EXCLUSIVE MODE;
+  EXECUTE format('CREATE TABLE %I (LIKE messages INCLUDING DEFAULTS)', part_name);
+  EXECUTE format(
+    'WITH moved AS (DELETE FROM messages_default WHERE created_at >= %L AND created_at < %L RETURNING *) '
+    'INSERT INTO %I SELECT * FROM moved',
+    from_ts, to_ts, part_name);
+  EXECUTE format(
+    'ALTER TABLE messages ATTACH PARTITION %I FOR VALUES FROM (%L) TO (%L)',
+    part_name, from_ts, to_ts);
+  RETURN TRUE;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Drops the monthly partitions of the months ending by `before`, logging a
+-- delete event for each of their messages. Returns the partitions dropped.
+CREATE OR REPLACE FUNCTION drop_messages_partitions(before TIMESTAMP) RETURNS INTEGER AS $$
+DECLARE
+  part_name TEXT;
+  dropped INTEGER := 0;
+BEGIN
+  PERFORM pg_advisory_xact_lock(hashtext('messages_partitions'));
+  FOR part_name IN
+    SELECT child.relname
+    FROM pg_inherits
+    JOIN pg_class child ON child.oid = pg_inherits.inhrelid
+    WHERE pg_inherits.inhparent = 'messages'::regclass
+      AND child.relname ~ '^messages_\d{4}_\d{2}$'
+      AND to_timestamp(substring(child.relname FROM 10), 'YYYY_MM') + INTERVAL '1 month' <= before
+    ORDER BY child.relname
+  LOOP
+    EXECUTE format(
+      'INSERT INTO events (kind, message_id) SELECT ''delete'', id FROM %I ORDER BY id',
+      part_name);
+    EXECUTE format(
+      'DELETE FROM pending_deliveries WHERE message_id IN (SELECT id FROM %I)',
+      part_name);
+    EXECUTE format('DROP TABLE %I', part_name);
+    dropped := dropped + 1;
+  END LOOP;
+  RETURN dropped;
+END;
+$$ LANGUAGE plpgsql;
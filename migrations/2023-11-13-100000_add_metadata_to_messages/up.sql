@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS metadata JSONB NOT NULL DEFAULT '{}';
+
+CREATE INDEX IF NOT EXISTS messages_metadata_idx ON messages USING GIN (metadata);
@@ -0,0 +1,16 @@
+-- Your SQL goes here
+-- embeddings take the pgvector extension, databases without it go without
+-- similarity search (`pgvector` feature)
+DO $$
+BEGIN
+  IF EXISTS (SELECT FROM pg_available_extensions WHERE name = 'vector') THEN
+    CREATE EXTENSION IF NOT EXISTS vector;
+    -- no foreign key, those can't reference the partitioned `messages`;
+    -- searches join the messages, which skips embeddings of deleted ones
+    CREATE TABLE IF NOT EXISTS message_embeddings (
+      message_id INTEGER PRIMARY KEY,
+      embedding vector NOT NULL
+    );
+  END IF;
+END
+$$;
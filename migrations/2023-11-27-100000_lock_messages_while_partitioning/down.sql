@@ -0,0 +1,28 @@
+-- This file should undo anything in `up.sql`
+-- Creates the partition `messages_YYYY_MM` of the month `in_month` falls in,
+-- moving the messages of that month out of the default partition. Returns
+-- whether the partition is new.
+CREATE OR REPLACE FUNCTION create_messages_partition(in_month TIMESTAMP) RETURNS BOOLEAN AS $$
+DECLARE
+  from_ts TIMESTAMP := date_trunc('month', in_month);
+  to_ts TIMESTAMP := date_trunc('month', in_month) + INTERVAL '1 month';
+  part_name TEXT := 'messages_' || to_char(in_month, 'YYYY_MM');
+BEGIN
+  PERFORM pg_advisory_xact_lock(hashtext('messages_partitions'));
+  IF to_regclass(part_name) IS NOT NULL THEN
+    RETURN FALSE;
+  END IF;
+  -- writes wait until the partition is attached, an insert routed to the
+  -- default partition before would violate its constraint after
+  LOCK TABLE messages IN EXCLUSIVE MODE;
+  EXECUTE format('CREATE TABLE %I (LIKE messages INCLUDING DEFAULTS)', part_name);
+  EXECUTE format(
+    'WITH moved AS (DELETE FROM messages_default WHERE created_at >= %L AND created_at < %L RETURNING *) '
+    'INSERT INTO %I SELECT * FROM moved',
+    from_ts, to_ts, part_name);
+  EXECUTE format(
+    'ALTER TABLE messages ATTACH PARTITION %I FOR VALUES FROM (%L) TO (%L)',
+    part_name, from_ts, to_ts);
+  RETURN TRUE;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+-- the row of a flag for a tenant wins over its '*' row, which applies to the
+-- tenants without one
+CREATE TABLE IF NOT EXISTS feature_flags (
+  name TEXT NOT NULL,
+  tenant TEXT NOT NULL DEFAULT '*',
+  enabled BOOLEAN NOT NULL,
+  PRIMARY KEY (name, tenant)
+);
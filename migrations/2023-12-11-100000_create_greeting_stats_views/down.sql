@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP MATERIALIZED VIEW IF EXISTS greeting_stats_names;
+DROP MATERIALIZED VIEW IF EXISTS greeting_stats_daily;
@@ -0,0 +1,17 @@
+-- Your SQL goes here
+-- aggregates for `GetStats`, counted by the stats job instead of on every
+-- call; the unique indexes let it refresh them without blocking readers
+CREATE MATERIALIZED VIEW IF NOT EXISTS greeting_stats_daily AS
+SELECT created_at::DATE AS day, count(*) AS greetings
+FROM messages
+GROUP BY 1;
+CREATE UNIQUE INDEX IF NOT EXISTS greeting_stats_daily_day_idx ON greeting_stats_daily (day);
+
+-- the name is taken from the `<salutation> <name>!` greetings are composed
+-- as, messages stored as is that don't read like one are left out
+CREATE MATERIALIZED VIEW IF NOT EXISTS greeting_stats_names AS
+SELECT substring(message FROM '^\S+ (.+)!$') AS name, count(*) AS greetings
+FROM messages
+WHERE message ~ '^\S+ .+!$'
+GROUP BY 1;
+CREATE UNIQUE INDEX IF NOT EXISTS greeting_stats_names_name_idx ON greeting_stats_names (name);
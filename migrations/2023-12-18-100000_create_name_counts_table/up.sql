@@ -0,0 +1,14 @@
+-- Your SQL goes here
+-- greetings per name behind `StreamLeaderboard`, counted in memory by every
+-- server and added here periodically; seeded like `greeting_stats_names`
+CREATE TABLE IF NOT EXISTS name_counts (
+  name TEXT PRIMARY KEY,
+  greetings BIGINT NOT NULL
+);
+
+INSERT INTO name_counts (name, greetings)
+SELECT substring(message FROM '^\S+ (.+)!$'), count(*)
+FROM messages
+WHERE message ~ '^\S+ .+!$'
+GROUP BY 1
+ON CONFLICT (name) DO NOTHING;
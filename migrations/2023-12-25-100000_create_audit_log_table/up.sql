@@ -0,0 +1,11 @@
+-- Your SQL goes here
+-- Decisions worth keeping track of, e.g. names masked or rejected by the
+-- moderation, newest last.
+CREATE TABLE audit_log (
+  id BIGSERIAL PRIMARY KEY,
+  tenant TEXT NOT NULL,
+  action TEXT NOT NULL,
+  subject TEXT NOT NULL,
+  detail TEXT NOT NULL DEFAULT '',
+  created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
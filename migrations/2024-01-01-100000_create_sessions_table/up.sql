@@ -0,0 +1,10 @@
+-- Your SQL goes here
+-- Transcripts of `SayHelloStream` calls: one row per call, its exchanges
+-- (name received, greeting replied) appended in order as they happen.
+CREATE TABLE sessions (
+  id BIGSERIAL PRIMARY KEY,
+  tenant TEXT NOT NULL,
+  started_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  ended_at TIMESTAMP,
+  exchanges JSONB NOT NULL DEFAULT '[]'
+);
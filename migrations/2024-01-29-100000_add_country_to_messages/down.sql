@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP MATERIALIZED VIEW IF EXISTS greeting_stats_countries;
+ALTER TABLE messages DROP COLUMN IF EXISTS country;
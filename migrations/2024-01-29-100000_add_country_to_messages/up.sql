@@ -0,0 +1,11 @@
+-- Your SQL goes here
+-- ISO country code of the client address a greeting came from, when the
+-- server looks addresses up
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS country TEXT;
+
+CREATE MATERIALIZED VIEW IF NOT EXISTS greeting_stats_countries AS
+SELECT country, count(*) AS greetings
+FROM messages
+WHERE country IS NOT NULL
+GROUP BY 1;
+CREATE UNIQUE INDEX IF NOT EXISTS greeting_stats_countries_country_idx ON greeting_stats_countries (country);
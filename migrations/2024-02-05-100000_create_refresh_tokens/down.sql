@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE IF EXISTS revoked_sessions;
+DROP TABLE IF EXISTS refresh_tokens;
@@ -0,0 +1,18 @@
+-- Your SQL goes here
+-- Refresh tokens issued by `Tokens`, kept hashed. A token is revoked once
+-- used, its replacement taking over.
+CREATE TABLE refresh_tokens (
+  token_sha256 TEXT PRIMARY KEY,
+  subject TEXT NOT NULL,
+  groups JSONB NOT NULL DEFAULT '[]',
+  expires_at TIMESTAMP NOT NULL,
+  revoked_at TIMESTAMP
+);
+CREATE INDEX refresh_tokens_subject_idx ON refresh_tokens (subject);
+
+-- When the sessions of a subject were last revoked: the tokens issued to it
+-- until then are refused.
+CREATE TABLE revoked_sessions (
+  subject TEXT PRIMARY KEY,
+  revoked_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
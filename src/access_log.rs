@@ -0,0 +1,254 @@
+use std::{
+    fmt::Write,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use http::{HeaderMap, HeaderValue};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::{config::AccessLogSampling, peer_info::PeerInfo, reload::Reloadable};
+
+/// Metadata never logged as is, next to every binary (`-bin`) entry.
+const REDACTED_METADATA: &[&str] = &["authorization", "cookie", "x-api-key"];
+/// Transport headers present on every call, left out of the summary.
+const SKIPPED_METADATA: &[&str] = &["content-type", "te", "grpc-accept-encoding"];
+
+/// Logs one line per call once it completes: method, peer, gRPC status,
+/// latency and a summary of the request metadata with secrets redacted.
+///
+/// Successful calls are sampled with the per-method rates of the config,
+/// failed ones are always logged.
+#[derive(Clone)]
+pub struct AccessLogLayer {
+    sampler: Reloadable<Arc<Sampler>>,
+}
+
+impl AccessLogLayer {
+    pub fn new(sampling: AccessLogSampling) -> Self {
+        Self {
+            sampler: Reloadable::new(Arc::new(Sampler::new(sampling))),
+        }
+    }
+
+    /// Samples the calls from now on with `sampling`, for the services this
+    /// layer was applied to as well.
+    pub fn set_sampling(&self, sampling: AccessLogSampling) {
+        self.sampler.set(Arc::new(Sampler::new(sampling)));
+    }
+}
+
+impl<S> Layer<S> for AccessLogLayer {
+    type Service = AccessLog<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AccessLog {
+            inner,
+            sampler: self.sampler.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AccessLog<S> {
+    inner: S,
+    sampler: Reloadable<Arc<Sampler>>,
+}
+
+impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for AccessLog<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = http::Response<LoggedBody<ResBody>>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<ReqBody>) -> Self::Future {
+        let mut entry = Entry {
+            sampler: self.sampler.get(),
+            method: req.uri().path().to_string(),
+            peer: PeerInfo::from_http(&req)
+                .remote_addr
+                .map_or_else(|| "-".to_string(), |addr| addr.to_string()),
+            location: location(&req),
+            metadata: summarize(req.headers()),
+            start: Instant::now(),
+            status: None,
+        };
+        let fut = self.inner.call(req);
+
+        Box::pin(async move {
+            let res = fut.await?;
+            // errors returned by a handler come back as trailers-only responses
+            entry.status = grpc_status(res.headers());
+            Ok(res.map(|inner| LoggedBody { inner, entry }))
+        })
+    }
+}
+
+/// Response body logging its call once dropped, after the trailers carrying
+/// the final status went out or the client went away.
+pub struct LoggedBody<B> {
+    inner: B,
+    entry: Entry,
+}
+
+impl<B> http_body::Body for LoggedBody<B>
+where
+    B: http_body::Body + Unpin,
+{
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Pin::new(&mut self.inner).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        let poll = Pin::new(&mut self.inner).poll_trailers(cx);
+        if let Poll::Ready(Ok(Some(trailers))) = &poll {
+            if let Some(status) = grpc_status(trailers) {
+                self.entry.status = Some(status);
+            }
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+struct Entry {
+    sampler: Arc<Sampler>,
+    method: String,
+    peer: String,
+    /// ` country=.. asn=..` of what `geoip` knows of the peer, if anything.
+    location: String,
+    metadata: String,
+    start: Instant,
+    status: Option<i32>,
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        let failed = self.status != Some(tonic::Code::Ok as i32);
+        if !failed && !self.sampler.sample(&self.method) {
+            return;
+        }
+        let status = match self.status {
+            Some(code) => format!("{:?}", tonic::Code::from_i32(code)),
+            None => "Unfinished".to_string(),
+        };
+        println!(
+            "access method={} peer={}{} status={} latency_ms={:.3} metadata={{{}}}",
+            self.method,
+            self.peer,
+            self.location,
+            status,
+            self.start.elapsed().as_secs_f64() * 1000.0,
+            self.metadata,
+        );
+    }
+}
+
+struct Sampler {
+    rules: Vec<(String, f64, AtomicU64)>,
+    default: (f64, AtomicU64),
+}
+
+impl Sampler {
+    fn new(sampling: AccessLogSampling) -> Self {
+        Self {
+            rules: sampling
+                .rules
+                .into_iter()
+                .map(|(method, rate)| (method, rate, AtomicU64::new(0)))
+                .collect(),
+            default: (sampling.default, AtomicU64::new(0)),
+        }
+    }
+
+    /// Spreads the logged calls evenly: with a rate of 0.01 every hundredth
+    /// call of the method is logged.
+    fn sample(&self, path: &str) -> bool {
+        let method = path.rsplit('/').next().unwrap_or(path);
+        let (rate, counter) = self
+            .rules
+            .iter()
+            .find(|(name, _, _)| name == method)
+            .map(|(_, rate, counter)| (*rate, counter))
+            .unwrap_or((self.default.0, &self.default.1));
+
+        let n = counter.fetch_add(1, Ordering::Relaxed) as f64;
+        ((n + 1.0) * rate).floor() > (n * rate).floor()
+    }
+}
+
+#[cfg(not(feature = "geoip"))]
+fn location<B>(_req: &http::Request<B>) -> String {
+    String::new()
+}
+
+#[cfg(feature = "geoip")]
+fn location<B>(req: &http::Request<B>) -> String {
+    let mut location = String::new();
+    if let Some(found) = crate::geoip::Location::of(req.extensions()) {
+        if let Some(country) = &found.country {
+            let _ = write!(location, " country={}", country);
+        }
+        if let Some(asn) = found.asn {
+            let _ = write!(location, " asn={}", asn);
+        }
+    }
+    location
+}
+
+fn summarize(headers: &HeaderMap) -> String {
+    let mut summary = String::new();
+    for (key, value) in headers {
+        let key = key.as_str();
+        if SKIPPED_METADATA.contains(&key) {
+            continue;
+        }
+        if !summary.is_empty() {
+            summary.push(' ');
+        }
+        if REDACTED_METADATA.contains(&key) || key.ends_with("-bin") {
+            let _ = write!(summary, "{}=<redacted>", key);
+        } else {
+            let _ = write!(summary, "{}={:?}", key, value);
+        }
+    }
+    summary
+}
+
+fn grpc_status(headers: &HeaderMap) -> Option<i32> {
+    headers
+        .get("grpc-status")
+        .map(HeaderValue::to_str)
+        .and_then(Result::ok)
+        .and_then(|status| status.parse().ok())
+}
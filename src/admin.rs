@@ -0,0 +1,408 @@
+use std::{collections::HashSet, pin::Pin, sync::Arc};
+
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+use tokio_stream::{wrappers::ReceiverStream, Stream};
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::backup;
+use crate::certs::Certificate;
+use crate::db::Db;
+pub use crate::greeter::hello_world::admin_server::AdminServer;
+use crate::greeter::hello_world::{
+    admin_server::Admin, BackupChunk, CertificateExpiry, CertificatesReply, CreateBackupRequest,
+    GetCertificatesRequest, GetIpRulesRequest, GetReadOnlyRequest, GetServerInfoRequest,
+    IpRulesReply, KillSessionReply, KillSessionRequest, ListSessionsReply, ListSessionsRequest,
+    LiveSession, ReadOnlyReply, RestoreBackupReply, RevokeSessionsReply, RevokeSessionsRequest,
+    ServerEvent, ServerEventKind, ServerInfoReply, SetIpRulesRequest, SetReadOnlyRequest,
+    StreamServerEventsRequest,
+};
+use crate::greeter::unix_ms;
+use crate::ip_filter::{Cidr, IpRules};
+use crate::peer_info::PeerInfo;
+use crate::read_only::ReadOnly;
+use crate::reload::Reloadable;
+use crate::server_events::{self, EventKind};
+use crate::server_info;
+use crate::sessions::LiveSessions;
+use crate::stream::spawn_feeder;
+use crate::tenant;
+use crate::tokens::SessionRevocations;
+
+type AdminResponseStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+/// Server events buffered per `StreamServerEvents` call, past the ones the
+/// event channel keeps.
+const SERVER_EVENTS_DEPTH: usize = 64;
+
+/// Chunks of a `CreateBackup` archive waiting for the client.
+const BACKUP_CHUNKS_DEPTH: usize = 4;
+
+/// The `Admin` service, operations on the running server.
+pub struct MyAdmin {
+    db: Db,
+    read_only: ReadOnly,
+    certificates: Arc<[Certificate]>,
+    restore_max_bytes: usize,
+    sessions: LiveSessions,
+    ip_rules: Reloadable<IpRules>,
+    revocations: SessionRevocations,
+}
+
+impl MyAdmin {
+    pub fn new(
+        db: Db,
+        read_only: ReadOnly,
+        certificates: Arc<[Certificate]>,
+        restore_max_bytes: usize,
+        sessions: LiveSessions,
+        ip_rules: Reloadable<IpRules>,
+        revocations: SessionRevocations,
+    ) -> Self {
+        Self {
+            db,
+            read_only,
+            certificates,
+            restore_max_bytes,
+            sessions,
+            ip_rules,
+            revocations,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Admin for MyAdmin {
+    async fn set_read_only(
+        &self,
+        request: Request<SetReadOnlyRequest>,
+    ) -> Result<Response<ReadOnlyReply>, Status> {
+        println!(
+            "Got a read-only request from '{}'",
+            PeerInfo::from_request(&request)
+        );
+        let read_only = request.into_inner().read_only;
+        self.read_only.set(read_only);
+        Ok(Response::new(ReadOnlyReply { read_only }))
+    }
+
+    async fn get_read_only(
+        &self,
+        _request: Request<GetReadOnlyRequest>,
+    ) -> Result<Response<ReadOnlyReply>, Status> {
+        Ok(Response::new(ReadOnlyReply {
+            read_only: self.read_only.is_enabled(),
+        }))
+    }
+
+    async fn get_certificates(
+        &self,
+        _request: Request<GetCertificatesRequest>,
+    ) -> Result<Response<CertificatesReply>, Status> {
+        let certificates = self
+            .certificates
+            .iter()
+            .map(|cert| CertificateExpiry {
+                name: cert.name.clone(),
+                subject: cert.subject.clone(),
+                not_after: cert.not_after,
+                days_left: cert.days_left(),
+            })
+            .collect();
+        Ok(Response::new(CertificatesReply { certificates }))
+    }
+
+    type CreateBackupStream = AdminResponseStream<BackupChunk>;
+
+    async fn create_backup(
+        &self,
+        request: Request<CreateBackupRequest>,
+    ) -> Result<Response<Self::CreateBackupStream>, Status> {
+        println!(
+            "Got a backup request from '{}'",
+            PeerInfo::from_request(&request)
+        );
+        // pages of messages are compressed and sent as they are read, the
+        // archive is never held whole
+        let (tx, rx) = mpsc::channel(BACKUP_CHUNKS_DEPTH);
+        let db = self.db.clone();
+        spawn_feeder(tx.clone(), async move {
+            let (pages_tx, mut pages_rx) = mpsc::channel::<Vec<_>>(1);
+            let encode = async {
+                let mut writer = backup::Writer::new()?;
+                while let Some(page) = pages_rx.recv().await {
+                    for data in writer.write(&page)? {
+                        if tx.send(Ok(BackupChunk { data })).await.is_err() {
+                            return Ok(None);
+                        }
+                    }
+                }
+                Ok::<_, backup::BackupError>(Some(writer))
+            };
+            let (read, encoded) = tokio::join!(db.backup_messages(pages_tx), encode);
+            let rest = match (read, encoded) {
+                (Err(err), _) => Err(internal(err)),
+                (_, Err(err)) => Err(internal(err)),
+                // the client went away
+                (Ok(()), Ok(None)) => return,
+                (Ok(()), Ok(Some(writer))) => writer.finish().map_err(internal),
+            };
+            match rest {
+                Ok(chunks) => {
+                    for data in chunks {
+                        if tx.send(Ok(BackupChunk { data })).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+                Err(status) => {
+                    let _ = tx.send(Err(status)).await;
+                }
+            }
+        });
+        Ok(Response::new(
+            Box::pin(ReceiverStream::new(rx)) as Self::CreateBackupStream
+        ))
+    }
+
+    async fn restore_backup(
+        &self,
+        request: Request<Streaming<BackupChunk>>,
+    ) -> Result<Response<RestoreBackupReply>, Status> {
+        println!(
+            "Got a restore request from '{}'",
+            PeerInfo::from_request(&request)
+        );
+        let tenant = tenant::from_request(&request);
+        if self.read_only.is_enabled() {
+            return Err(Status::failed_precondition(
+                "the server is read-only for maintenance, restores are refused until it is over",
+            ));
+        }
+
+        // chunks are decompressed and parsed as they come
+        let invalid = |err| Status::invalid_argument(format!("invalid backup: {}", err));
+        let mut in_stream = request.into_inner();
+        let mut archive = backup::Reader::new(self.restore_max_bytes);
+        while let Some(chunk) = in_stream.message().await? {
+            archive.write(&chunk.data).map_err(invalid)?;
+        }
+        let messages = archive.finish().map_err(invalid)?;
+
+        let deleted = self
+            .db
+            .restore_messages(&messages)
+            .await
+            .map_err(internal)?;
+        let detail = format!("{} messages deleted, {} restored", deleted, messages.len());
+        if let Err(err) = self
+            .db
+            .add_audit_entry(&tenant, "restore_backup", "messages", &detail)
+            .await
+        {
+            eprintln!("failed to audit restore: {}", err);
+        }
+        Ok(Response::new(RestoreBackupReply {
+            deleted: deleted as u64,
+            restored: messages.len() as u64,
+        }))
+    }
+
+    async fn get_server_info(
+        &self,
+        _request: Request<GetServerInfoRequest>,
+    ) -> Result<Response<ServerInfoReply>, Status> {
+        Ok(Response::new(ServerInfoReply {
+            version: server_info::VERSION.to_string(),
+            git_sha: server_info::GIT_SHA.to_string(),
+            built_at: server_info::built_at(),
+            features: server_info::features()
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            descriptor_sha256: server_info::descriptor_sha256().to_string(),
+        }))
+    }
+
+    async fn list_sessions(
+        &self,
+        _request: Request<ListSessionsRequest>,
+    ) -> Result<Response<ListSessionsReply>, Status> {
+        let sessions = self
+            .sessions
+            .list()
+            .into_iter()
+            .map(|session| LiveSession {
+                id: session.id,
+                method: session.method.to_string(),
+                tenant: session.tenant,
+                peer: session.peer,
+                created_at_ms: unix_ms(session.created_at),
+                received: session.received,
+                sent: session.sent,
+            })
+            .collect();
+        Ok(Response::new(ListSessionsReply { sessions }))
+    }
+
+    async fn kill_session(
+        &self,
+        request: Request<KillSessionRequest>,
+    ) -> Result<Response<KillSessionReply>, Status> {
+        println!(
+            "Got a kill session request from '{}'",
+            PeerInfo::from_request(&request)
+        );
+        let tenant = tenant::from_request(&request);
+        let id = request.into_inner().id;
+        if !self.sessions.kill(id) {
+            return Err(Status::not_found(format!("no open session {}", id)));
+        }
+        if let Err(err) = self
+            .db
+            .add_audit_entry(&tenant, "kill_session", &id.to_string(), "")
+            .await
+        {
+            eprintln!("failed to audit session kill: {}", err);
+        }
+        Ok(Response::new(KillSessionReply {}))
+    }
+
+    async fn get_ip_rules(
+        &self,
+        _request: Request<GetIpRulesRequest>,
+    ) -> Result<Response<IpRulesReply>, Status> {
+        Ok(Response::new(self.ip_rules.read(ip_rules_reply)))
+    }
+
+    async fn set_ip_rules(
+        &self,
+        request: Request<SetIpRulesRequest>,
+    ) -> Result<Response<IpRulesReply>, Status> {
+        println!(
+            "Got an IP rules request from '{}'",
+            PeerInfo::from_request(&request)
+        );
+        let request = request.into_inner();
+        let parse = |blocks: Vec<String>| {
+            blocks
+                .iter()
+                .map(|block| block.parse::<Cidr>())
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let rules = IpRules {
+            allow: parse(request.allow).map_err(Status::invalid_argument)?,
+            deny: parse(request.deny).map_err(Status::invalid_argument)?,
+        };
+        let reply = ip_rules_reply(&rules);
+        self.ip_rules.set(rules);
+        Ok(Response::new(reply))
+    }
+
+    async fn revoke_sessions(
+        &self,
+        request: Request<RevokeSessionsRequest>,
+    ) -> Result<Response<RevokeSessionsReply>, Status> {
+        println!(
+            "Got a revoke sessions request from '{}'",
+            PeerInfo::from_request(&request)
+        );
+        let tenant = tenant::from_request(&request);
+        let subject = request.into_inner().subject;
+        if subject.is_empty() {
+            return Err(Status::invalid_argument("subject required"));
+        }
+        let (revoked_at, revoked) = self.revocations.revoke(&subject).await.map_err(internal)?;
+        let detail = format!("{} refresh tokens revoked", revoked);
+        if let Err(err) = self
+            .db
+            .add_audit_entry(&tenant, "revoke_sessions", &subject, &detail)
+            .await
+        {
+            eprintln!("failed to audit session revocation: {}", err);
+        }
+        Ok(Response::new(RevokeSessionsReply {
+            refresh_tokens_revoked: revoked as u64,
+            revoked_at_ms: unix_ms(revoked_at),
+        }))
+    }
+
+    type StreamServerEventsStream = AdminResponseStream<ServerEvent>;
+
+    async fn stream_server_events(
+        &self,
+        request: Request<StreamServerEventsRequest>,
+    ) -> Result<Response<Self::StreamServerEventsStream>, Status> {
+        println!(
+            "Got a server events request from '{}'",
+            PeerInfo::from_request(&request)
+        );
+        let kinds = request.into_inner().kinds().collect::<HashSet<_>>();
+        let mut events = server_events::subscribe();
+
+        let (tx, rx) = mpsc::channel(SERVER_EVENTS_DEPTH);
+        spawn_feeder(tx.clone(), async move {
+            let mut missed = 0;
+            loop {
+                let event = tokio::select! {
+                    _ = tx.closed() => return,
+                    event = events.recv() => event,
+                };
+                let mut event = match event {
+                    Ok(event) => ServerEvent::from(event),
+                    Err(RecvError::Lagged(skipped)) => {
+                        missed += skipped;
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+                if !kinds.is_empty() && !kinds.contains(&event.kind()) {
+                    continue;
+                }
+                event.missed = std::mem::take(&mut missed);
+                if tx.send(Ok(event)).await.is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(Response::new(
+            Box::pin(ReceiverStream::new(rx)) as Self::StreamServerEventsStream
+        ))
+    }
+}
+
+impl From<server_events::ServerEvent> for ServerEvent {
+    fn from(event: server_events::ServerEvent) -> Self {
+        let kind = match event.kind {
+            EventKind::Connected => ServerEventKind::Connected,
+            EventKind::Disconnected => ServerEventKind::Disconnected,
+            EventKind::DbError => ServerEventKind::DbError,
+            EventKind::ConfigReloaded => ServerEventKind::ConfigReloaded,
+            EventKind::ConfigReloadFailed => ServerEventKind::ConfigReloadFailed,
+            EventKind::Certificate => ServerEventKind::Certificate,
+            EventKind::ClientBanned => ServerEventKind::ClientBanned,
+        };
+        Self {
+            kind: kind.into(),
+            at_ms: unix_ms(event.at),
+            message: event.message,
+            fields: event
+                .fields
+                .into_iter()
+                .map(|(field, value)| (field.to_string(), value))
+                .collect(),
+            missed: 0,
+        }
+    }
+}
+
+fn ip_rules_reply(rules: &IpRules) -> IpRulesReply {
+    let blocks = |cidrs: &[Cidr]| cidrs.iter().map(Cidr::to_string).collect();
+    IpRulesReply {
+        allow: blocks(&rules.allow),
+        deny: blocks(&rules.deny),
+    }
+}
+
+fn internal(err: impl std::fmt::Display) -> Status {
+    Status::internal(err.to_string())
+}
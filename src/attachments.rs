@@ -0,0 +1,162 @@
+//! Attachments: files too big for the database, stored in an S3-compatible
+//! object store such as AWS S3 or MinIO (`attachments` feature). A message
+//! only keeps the key of its attachment's object.
+//!
+//! Uploads are streamed to the store as multipart uploads, an attachment is
+//! never held in memory whole. Downloads are streamed back the same way or
+//! left to the client with a presigned URL.
+
+use std::{pin::Pin, sync::Arc, time::Duration};
+
+use object_store::{
+    aws::{AmazonS3, AmazonS3Builder},
+    path::Path,
+    signer::Signer,
+    MultipartId, ObjectStore,
+};
+use thiserror::Error;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio_stream::{Stream, StreamExt};
+
+use crate::config::Config;
+
+#[derive(Error, Debug)]
+pub enum AttachmentError {
+    #[error("Object store: {0}")]
+    Store(#[from] object_store::Error),
+    #[error("Object store upload: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl AttachmentError {
+    /// Whether the object asked for isn't in the store.
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, Self::Store(object_store::Error::NotFound { .. }))
+    }
+}
+
+type AttachmentResult<T> = Result<T, AttachmentError>;
+
+/// The chunks of a downloaded attachment, as the store sends them.
+pub type Download = Pin<Box<dyn Stream<Item = AttachmentResult<Vec<u8>>> + Send>>;
+
+/// The bucket attachments are stored in, cheap to clone.
+#[derive(Clone)]
+pub struct Attachments {
+    store: Arc<AmazonS3>,
+    url_ttl: Duration,
+}
+
+impl Attachments {
+    /// The attachments of `bucket`, on the endpoint and region of `config`.
+    pub fn new(bucket: &str, config: &Config) -> AttachmentResult<Self> {
+        let mut builder = AmazonS3Builder::from_env()
+            .with_bucket_name(bucket)
+            .with_region(&config.attachments_region);
+        if let Some(endpoint) = &config.attachments_endpoint {
+            // MinIO and the like serve buckets by path, often without TLS
+            builder = builder
+                .with_endpoint(endpoint)
+                .with_virtual_hosted_style_request(false)
+                .with_allow_http(true);
+        }
+        Ok(Self {
+            store: Arc::new(builder.build()?),
+            url_ttl: Duration::from_secs(config.attachment_url_ttl_secs),
+        })
+    }
+
+    /// A key for a new attachment of message `id`. Every upload gets its
+    /// own, the object of the previous one stays intact until the message
+    /// points to the new one.
+    pub fn new_key(id: i32) -> String {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        format!("messages/{}/{}", id, nanos)
+    }
+
+    /// Starts uploading the object `key`, which only appears in the store
+    /// once the upload is finished.
+    pub async fn upload(&self, key: &str) -> AttachmentResult<Upload> {
+        let path = Path::from(key);
+        let (id, writer) = self.store.put_multipart(&path).await?;
+        Ok(Upload {
+            store: self.store.clone(),
+            path,
+            id,
+            writer,
+            size: 0,
+        })
+    }
+
+    /// Streams the object `key`.
+    pub async fn download(&self, key: &str) -> AttachmentResult<Download> {
+        let object = self.store.get(&Path::from(key)).await?;
+        let chunks = object.into_stream().map(|chunk| {
+            chunk
+                .map(|data| data.to_vec())
+                .map_err(AttachmentError::from)
+        });
+        Ok(Box::pin(chunks))
+    }
+
+    /// A URL to download the object `key` with, valid for `url_ttl`.
+    pub async fn presigned_url(&self, key: &str) -> AttachmentResult<String> {
+        let url = self
+            .store
+            .signed_url(http::Method::GET, &Path::from(key), self.url_ttl)
+            .await?;
+        Ok(url.to_string())
+    }
+
+    /// How long the URLs of `presigned_url` stay valid.
+    pub fn url_ttl(&self) -> Duration {
+        self.url_ttl
+    }
+
+    /// Deletes the object `key`, logging failures: a leftover object only
+    /// costs storage.
+    pub async fn delete(&self, key: &str) {
+        if let Err(err) = self.store.delete(&Path::from(key)).await {
+            eprintln!("failed to delete attachment {}: {}", key, err);
+        }
+    }
+}
+
+/// An upload in progress, see `Attachments::upload`. Dropped unfinished,
+/// the parts uploaded are left to the bucket's lifecycle rules, `abort`
+/// cleans them up.
+pub struct Upload {
+    store: Arc<AmazonS3>,
+    path: Path,
+    id: MultipartId,
+    writer: Box<dyn AsyncWrite + Unpin + Send>,
+    size: u64,
+}
+
+impl Upload {
+    pub async fn write(&mut self, data: &[u8]) -> AttachmentResult<()> {
+        self.writer.write_all(data).await?;
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    /// Bytes written so far.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Completes the upload, returns the size of the object.
+    pub async fn finish(mut self) -> AttachmentResult<u64> {
+        self.writer.shutdown().await?;
+        Ok(self.size)
+    }
+
+    pub async fn abort(self) {
+        if let Err(err) = self.store.abort_multipart(&self.path, &self.id).await {
+            eprintln!("failed to abort upload of {}: {}", self.path, err);
+        }
+    }
+}
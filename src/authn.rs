@@ -0,0 +1,445 @@
+//! Authentication of callers by an external identity provider, ahead of the
+//! policy of `authz`: the bearer token of a call introspected at an OIDC
+//! provider (`oidc` feature), or its basic credentials bound with at an
+//! LDAP directory (`ldap` feature). The groups the provider puts the caller
+//! in are mapped to the roles of the service, which the policy sees along
+//! with the user and the handlers find in the request extensions as the
+//! `Caller`. Set up with the `identity_provider` of the `[authz]` table,
+//! the tokens of `tokens` are taken as well once enabled.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use base64::Engine;
+use http::HeaderMap;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    authz::AuthzError,
+    config::{AuthzConfig, IdentityProviderConfig},
+    cooldown::TtlMap,
+    tokens::SessionRevocations,
+};
+
+/// What a call authenticates with, from its `authorization` metadata.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Credentials {
+    Bearer(String),
+    Basic { username: String, password: String },
+}
+
+impl Credentials {
+    /// The credentials in `headers`, `None` without any or with a scheme
+    /// other than `Bearer` and `Basic`.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let value = headers.get(http::header::AUTHORIZATION)?.to_str().ok()?;
+        let (scheme, rest) = value.trim().split_once(' ')?;
+        if scheme.eq_ignore_ascii_case("bearer") {
+            return Some(Self::Bearer(rest.trim().to_string()));
+        }
+        if scheme.eq_ignore_ascii_case("basic") {
+            let decoded = base64::engine::general_purpose::STANDARD
+                .decode(rest.trim())
+                .ok()?;
+            let decoded = String::from_utf8(decoded).ok()?;
+            let (username, password) = decoded.split_once(':')?;
+            return Some(Self::Basic {
+                username: username.to_string(),
+                password: password.to_string(),
+            });
+        }
+        None
+    }
+
+    /// Cache key of the credentials, which aren't kept as they are.
+    fn key(&self) -> String {
+        let mut hasher = Sha256::new();
+        match self {
+            Self::Bearer(token) => hasher.update(format!("bearer\0{}", token)),
+            Self::Basic { username, password } => {
+                hasher.update(format!("basic\0{}\0{}", username, password))
+            }
+        }
+        format!("{:x}", hasher.finalize())
+    }
+}
+
+// the credentials stay out of logs
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bearer(_) => f.write_str("Bearer(<redacted>)"),
+            Self::Basic { username, .. } => write!(f, "Basic({}, <redacted>)", username),
+        }
+    }
+}
+
+/// Who a provider says a caller is.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExternalIdentity {
+    pub user: String,
+    pub groups: Vec<String>,
+    /// When the credentials were issued, those issued before a revocation
+    /// of the user's sessions are refused. Unknown, they aren't.
+    pub issued_at: Option<SystemTime>,
+}
+
+/// Tells who presents credentials.
+#[tonic::async_trait]
+pub trait IdentityProvider: Send + Sync + 'static {
+    /// Who presents `credentials`, `None` when they are invalid, expired or
+    /// of a kind the provider doesn't take.
+    async fn authenticate(
+        &self,
+        credentials: &Credentials,
+    ) -> Result<Option<ExternalIdentity>, Box<dyn Error + Send + Sync>>;
+}
+
+/// The authenticated caller of a call, in its request extensions.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Caller {
+    pub user: String,
+    pub groups: Vec<String>,
+    /// Roles of the service the groups map to, in the order of the groups.
+    pub roles: Vec<String>,
+    pub issued_at: Option<SystemTime>,
+}
+
+/// Authenticates calls with a provider, caching its answers.
+#[derive(Clone)]
+pub struct Authenticator {
+    provider: Arc<dyn IdentityProvider>,
+    roles: Arc<HashMap<String, Vec<String>>>,
+    anonymous: Arc<Vec<String>>,
+    callers: TtlMap<Option<Caller>>,
+    revocations: Option<SessionRevocations>,
+}
+
+impl Authenticator {
+    /// Authenticates with `provider`, the groups mapped to roles by
+    /// `roles`. Answers are reused for `cache_ttl`, zero asks every time.
+    pub fn new(
+        provider: Arc<dyn IdentityProvider>,
+        roles: HashMap<String, Vec<String>>,
+        anonymous: Vec<String>,
+        cache_ttl: Duration,
+    ) -> Self {
+        Self {
+            provider,
+            roles: Arc::new(roles),
+            anonymous: Arc::new(anonymous),
+            callers: TtlMap::new(cache_ttl),
+            revocations: None,
+        }
+    }
+
+    /// Refuses the credentials issued before the sessions of their user were
+    /// revoked, checked on every call, the cached ones included.
+    pub fn with_revocations(mut self, revocations: SessionRevocations) -> Self {
+        self.revocations = Some(revocations);
+        self
+    }
+
+    /// Whether calls to `path` are taken without credentials.
+    pub fn is_anonymous(&self, path: &str) -> bool {
+        let method = path.trim_start_matches('/');
+        let service = method
+            .split_once('/')
+            .map_or(method, |(service, _)| service);
+        self.anonymous
+            .iter()
+            .any(|route| route == method || route == service)
+    }
+
+    /// The caller presenting `credentials`, `None` when the provider doesn't
+    /// know them.
+    pub async fn authenticate(
+        &self,
+        credentials: &Credentials,
+    ) -> Result<Option<Caller>, Box<dyn Error + Send + Sync>> {
+        let key = credentials.key();
+        let caller = match self.callers.get(&key) {
+            Some(caller) => caller,
+            None => {
+                let caller = self
+                    .provider
+                    .authenticate(credentials)
+                    .await?
+                    .map(|identity| self.caller(identity));
+                self.callers.insert(&key, caller.clone());
+                caller
+            }
+        };
+        if let (Some(revocations), Some(caller)) = (&self.revocations, &caller) {
+            if let Some(issued_at) = caller.issued_at {
+                if revocations.is_revoked(&caller.user, issued_at).await? {
+                    return Ok(None);
+                }
+            }
+        }
+        Ok(caller)
+    }
+
+    fn caller(&self, identity: ExternalIdentity) -> Caller {
+        let mut roles = Vec::new();
+        for group in &identity.groups {
+            for role in self.roles.get(group).into_iter().flatten() {
+                if !roles.contains(role) {
+                    roles.push(role.clone());
+                }
+            }
+        }
+        Caller {
+            user: identity.user,
+            groups: identity.groups,
+            roles,
+            issued_at: identity.issued_at,
+        }
+    }
+}
+
+/// The identity provider of `config`, `None` when it sets none. Discovers
+/// the OIDC provider on the way.
+pub async fn from_config(
+    config: &AuthzConfig,
+) -> Result<Option<Arc<dyn IdentityProvider>>, AuthzError> {
+    match &config.identity_provider {
+        Some(provider) => Ok(Some(
+            connect(provider, Duration::from_millis(config.timeout_ms)).await?,
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Asks providers in turn, the first knowing the credentials answers.
+pub struct FirstOf(pub Vec<Arc<dyn IdentityProvider>>);
+
+#[tonic::async_trait]
+impl IdentityProvider for FirstOf {
+    async fn authenticate(
+        &self,
+        credentials: &Credentials,
+    ) -> Result<Option<ExternalIdentity>, Box<dyn Error + Send + Sync>> {
+        for provider in &self.0 {
+            if let Some(identity) = provider.authenticate(credentials).await? {
+                return Ok(Some(identity));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg_attr(not(any(feature = "oidc", feature = "ldap")), allow(unused_variables))]
+async fn connect(
+    provider: &IdentityProviderConfig,
+    timeout: Duration,
+) -> Result<Arc<dyn IdentityProvider>, AuthzError> {
+    match provider {
+        #[cfg(feature = "oidc")]
+        IdentityProviderConfig::Oidc {
+            issuer,
+            client_id,
+            client_secret,
+            groups_claim,
+        } => Ok(Arc::new(
+            OidcProvider::discover(issuer, client_id, client_secret, groups_claim, timeout).await?,
+        )),
+        #[cfg(not(feature = "oidc"))]
+        IdentityProviderConfig::Oidc { .. } => Err(AuthzError::OidcDisabled),
+        #[cfg(feature = "ldap")]
+        IdentityProviderConfig::Ldap {
+            url,
+            user_dn,
+            group_base,
+            group_filter,
+            group_attribute,
+        } => Ok(Arc::new(LdapProvider {
+            url: url.clone(),
+            user_dn: user_dn.clone(),
+            group_base: group_base.clone(),
+            group_filter: group_filter.clone(),
+            group_attribute: group_attribute.clone(),
+            timeout,
+        })),
+        #[cfg(not(feature = "ldap"))]
+        IdentityProviderConfig::Ldap { .. } => Err(AuthzError::LdapDisabled),
+    }
+}
+
+/// Introspects bearer tokens at an OIDC provider (RFC 7662), the endpoint
+/// found through the discovery document of its issuer. The user is the
+/// `sub` of an active token, its groups the `groups_claim`.
+#[cfg(feature = "oidc")]
+pub struct OidcProvider {
+    client: reqwest::Client,
+    introspection_endpoint: String,
+    client_id: String,
+    client_secret: String,
+    groups_claim: String,
+}
+
+#[cfg(feature = "oidc")]
+#[derive(serde::Deserialize)]
+struct Discovery {
+    introspection_endpoint: Option<String>,
+}
+
+#[cfg(feature = "oidc")]
+impl OidcProvider {
+    pub async fn discover(
+        issuer: &str,
+        client_id: &str,
+        client_secret: &str,
+        groups_claim: &str,
+        timeout: Duration,
+    ) -> Result<Self, AuthzError> {
+        let client = reqwest::Client::builder().timeout(timeout).build()?;
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            issuer.trim_end_matches('/')
+        );
+        let discovery = client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Discovery>()
+            .await?;
+        let introspection_endpoint = discovery.introspection_endpoint.ok_or_else(|| {
+            AuthzError::Invalid(format!("{} has no introspection endpoint", issuer))
+        })?;
+        Ok(Self {
+            client,
+            introspection_endpoint,
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            groups_claim: groups_claim.to_string(),
+        })
+    }
+}
+
+#[cfg(feature = "oidc")]
+#[tonic::async_trait]
+impl IdentityProvider for OidcProvider {
+    async fn authenticate(
+        &self,
+        credentials: &Credentials,
+    ) -> Result<Option<ExternalIdentity>, Box<dyn Error + Send + Sync>> {
+        let Credentials::Bearer(token) = credentials else {
+            return Ok(None);
+        };
+        let claims = self
+            .client
+            .post(&self.introspection_endpoint)
+            .basic_auth(&self.client_id, Some(&self.client_secret))
+            .form(&[("token", token.as_str())])
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<serde_json::Value>()
+            .await?;
+        if claims["active"] != true {
+            return Ok(None);
+        }
+        let user = claims["sub"]
+            .as_str()
+            .or_else(|| claims["username"].as_str())
+            .unwrap_or_default()
+            .to_string();
+        // a single group may come as a string
+        let groups = match &claims[self.groups_claim.as_str()] {
+            serde_json::Value::Array(groups) => groups
+                .iter()
+                .filter_map(|group| group.as_str().map(str::to_string))
+                .collect(),
+            serde_json::Value::String(group) => vec![group.clone()],
+            _ => Vec::new(),
+        };
+        let issued_at = claims["iat"]
+            .as_u64()
+            .map(|iat| SystemTime::UNIX_EPOCH + Duration::from_secs(iat));
+        Ok(Some(ExternalIdentity {
+            user,
+            groups,
+            issued_at,
+        }))
+    }
+}
+
+/// Binds with basic credentials at an LDAP directory, as the DN of
+/// `user_dn` with `{username}` replaced, then looks up the groups the DN
+/// is a member of.
+#[cfg(feature = "ldap")]
+pub struct LdapProvider {
+    url: String,
+    /// e.g. `uid={username},ou=people,dc=example,dc=org`.
+    user_dn: String,
+    group_base: String,
+    /// Search filter of the groups with `{dn}` replaced, e.g.
+    /// `(member={dn})`.
+    group_filter: String,
+    /// Attribute naming a group, e.g. `cn`.
+    group_attribute: String,
+    timeout: Duration,
+}
+
+#[cfg(feature = "ldap")]
+#[tonic::async_trait]
+impl IdentityProvider for LdapProvider {
+    async fn authenticate(
+        &self,
+        credentials: &Credentials,
+    ) -> Result<Option<ExternalIdentity>, Box<dyn Error + Send + Sync>> {
+        let Credentials::Basic { username, password } = credentials else {
+            return Ok(None);
+        };
+        // an empty password would be an unauthenticated bind, which succeeds
+        if password.is_empty() {
+            return Ok(None);
+        }
+        let settings = ldap3::LdapConnSettings::new().set_conn_timeout(self.timeout);
+        let (conn, mut ldap) = ldap3::LdapConnAsync::with_settings(settings, &self.url).await?;
+        ldap3::drive!(conn);
+        ldap.with_timeout(self.timeout);
+
+        let dn = self
+            .user_dn
+            .replace("{username}", &ldap3::dn_escape(username.as_str()));
+        let bind = ldap.simple_bind(&dn, password).await?;
+        // invalidCredentials
+        if bind.rc == 49 {
+            return Ok(None);
+        }
+        bind.success()?;
+
+        let filter = self
+            .group_filter
+            .replace("{dn}", &ldap3::ldap_escape(dn.as_str()));
+        ldap.with_timeout(self.timeout);
+        let (entries, _) = ldap
+            .search(
+                &self.group_base,
+                ldap3::Scope::Subtree,
+                &filter,
+                vec![self.group_attribute.as_str()],
+            )
+            .await?
+            .success()?;
+        let groups = entries
+            .into_iter()
+            .map(ldap3::SearchEntry::construct)
+            .filter_map(|mut entry| entry.attrs.remove(&self.group_attribute))
+            .flatten()
+            .collect();
+        let _ = ldap.unbind().await;
+        Ok(Some(ExternalIdentity {
+            user: username.clone(),
+            groups,
+            issued_at: None,
+        }))
+    }
+}
@@ -0,0 +1,385 @@
+//! Authorization of calls by policy, the decisions delegated to Open Policy
+//! Agent: a sidecar asked over HTTP (`opa` feature) or a Rego policy
+//! evaluated in process (`rego` feature). Every call is described to the
+//! policy by its method, the identity of the caller and the metadata it was
+//! made with, and refused with `PERMISSION_DENIED` unless the policy allows
+//! it. Set up with the `[authz]` table of the config file, more policies are
+//! plugged in with `ServerBuilder::with_policy`. Callers are authenticated
+//! first when an identity provider is configured, see `authn`.
+//!
+//! The policy sees the metadata, `authorization` included so it can check
+//! bearer tokens itself, but not the messages: calls are decided on before
+//! their body is read.
+
+use std::{
+    error::Error,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use http::HeaderMap;
+use serde_json::{json, Map, Value};
+use thiserror::Error;
+use tonic::{body::BoxBody, Status};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::{
+    authn::{Authenticator, Caller, Credentials},
+    config::AuthzConfig,
+    peer_info::PeerInfo,
+    tenant,
+};
+
+#[derive(Error, Debug)]
+pub enum AuthzError {
+    #[error("Authorization opa_url needs the `opa` feature")]
+    OpaDisabled,
+    #[error("Authorization policy_file needs the `rego` feature")]
+    RegoDisabled,
+    #[error("OIDC identity provider needs the `oidc` feature")]
+    OidcDisabled,
+    #[error("LDAP identity provider needs the `ldap` feature")]
+    LdapDisabled,
+    #[error("Authorization takes one of opa_url and policy_file")]
+    Ambiguous,
+    #[error("Policy file error: {0}")]
+    Io(#[from] io::Error),
+    #[cfg(any(feature = "opa", feature = "oidc"))]
+    #[error("HTTP client error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("Invalid policy: {0}")]
+    Invalid(String),
+}
+
+/// Decides whether a call is allowed.
+#[tonic::async_trait]
+pub trait Policy: Send + Sync + 'static {
+    /// Whether the call `input` describes is allowed, see `input`.
+    async fn allows(&self, input: &Value) -> Result<bool, Box<dyn Error + Send + Sync>>;
+}
+
+/// Asks an OPA sidecar through its data API: posts `{"input": ...}` to the
+/// URL of a rule, e.g. `http://localhost:8181/v1/data/helloworld/allow`,
+/// and allows the call when the `result` is `true`. An undefined rule
+/// denies it.
+#[cfg(feature = "opa")]
+#[derive(Clone, Debug)]
+pub struct OpaPolicy {
+    client: reqwest::Client,
+    url: String,
+}
+
+#[cfg(feature = "opa")]
+impl OpaPolicy {
+    pub fn new(url: &str, timeout: std::time::Duration) -> Result<Self, reqwest::Error> {
+        Ok(Self {
+            client: reqwest::Client::builder().timeout(timeout).build()?,
+            url: url.to_string(),
+        })
+    }
+}
+
+#[cfg(feature = "opa")]
+#[derive(serde::Deserialize)]
+struct OpaDecision {
+    #[serde(default)]
+    result: Option<Value>,
+}
+
+#[cfg(feature = "opa")]
+#[tonic::async_trait]
+impl Policy for OpaPolicy {
+    async fn allows(&self, input: &Value) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let decision = self
+            .client
+            .post(&self.url)
+            .json(&json!({ "input": input }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<OpaDecision>()
+            .await?;
+        Ok(decision.result == Some(Value::Bool(true)))
+    }
+}
+
+/// Evaluates a Rego policy in process. The call is allowed when `query`,
+/// e.g. `data.helloworld.allow`, is `true`.
+#[cfg(feature = "rego")]
+pub struct RegoPolicy {
+    engine: std::sync::Mutex<regorus::Engine>,
+    query: String,
+}
+
+#[cfg(feature = "rego")]
+impl RegoPolicy {
+    pub fn new(path: &std::path::Path, query: &str) -> Result<Self, AuthzError> {
+        let rego = std::fs::read_to_string(path)?;
+        let mut engine = regorus::Engine::new();
+        engine
+            .add_policy(path.display().to_string(), rego)
+            .map_err(|err| AuthzError::Invalid(err.to_string()))?;
+        Ok(Self {
+            engine: std::sync::Mutex::new(engine),
+            query: query.to_string(),
+        })
+    }
+}
+
+#[cfg(feature = "rego")]
+#[tonic::async_trait]
+impl Policy for RegoPolicy {
+    async fn allows(&self, input: &Value) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let input =
+            regorus::Value::from_json_str(&input.to_string()).map_err(|err| err.to_string())?;
+        let mut engine = self.engine.lock().unwrap();
+        engine.set_input(input);
+        let results = engine
+            .eval_query(self.query.clone(), false)
+            .map_err(|err| err.to_string())?;
+        // an undefined rule has no result
+        Ok(results
+            .result
+            .first()
+            .and_then(|result| result.expressions.first())
+            .is_some_and(|expression| expression.value == regorus::Value::from(true)))
+    }
+}
+
+/// What the policy of `config` is, `None` when it sets none.
+pub fn from_config(config: &AuthzConfig) -> Result<Option<Arc<dyn Policy>>, AuthzError> {
+    match (&config.opa_url, &config.policy_file) {
+        (Some(_), Some(_)) => Err(AuthzError::Ambiguous),
+        (Some(url), None) => {
+            #[cfg(feature = "opa")]
+            {
+                let timeout = std::time::Duration::from_millis(config.timeout_ms);
+                Ok(Some(Arc::new(OpaPolicy::new(url, timeout)?)))
+            }
+            #[cfg(not(feature = "opa"))]
+            {
+                let _ = url;
+                Err(AuthzError::OpaDisabled)
+            }
+        }
+        (None, Some(path)) => {
+            #[cfg(feature = "rego")]
+            {
+                Ok(Some(Arc::new(RegoPolicy::new(path, &config.query)?)))
+            }
+            #[cfg(not(feature = "rego"))]
+            {
+                let _ = path;
+                Err(AuthzError::RegoDisabled)
+            }
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+/// What the policy is asked about a call to `path`, e.g.
+///
+/// ```json
+/// {
+///   "method": "/helloworld.Greeter/SayHello",
+///   "service": "helloworld.Greeter",
+///   "rpc": "SayHello",
+///   "identity": {
+///     "tenant": "acme",
+///     "peer": "10.0.0.7:50312",
+///     "subject": "CN=client",
+///     "user": "alice",
+///     "groups": ["staff"],
+///     "roles": ["greeter"]
+///   },
+///   "metadata": { "authorization": "Bearer ..." }
+/// }
+/// ```
+///
+/// `subject` is the one of the client certificate, with mutual TLS, `user`,
+/// `groups` and `roles` those of the caller authenticated by `authn`, added
+/// once known. Binary metadata is left out.
+pub fn input(path: &str, headers: &HeaderMap, peer: &PeerInfo) -> Value {
+    let (service, rpc) = path
+        .trim_start_matches('/')
+        .split_once('/')
+        .unwrap_or(("", ""));
+    let mut identity = Map::new();
+    let tenant = headers
+        .get(tenant::TENANT_METADATA)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|tenant| !tenant.is_empty())
+        .unwrap_or(tenant::DEFAULT_TENANT);
+    identity.insert("tenant".to_string(), tenant.into());
+    if let Some(addr) = peer.remote_addr {
+        identity.insert("peer".to_string(), addr.to_string().into());
+    }
+    #[cfg(feature = "tls")]
+    if let Some(subject) = peer.client_subject() {
+        identity.insert("subject".to_string(), subject.into());
+    }
+    let mut metadata = Map::new();
+    for (key, value) in headers {
+        if key.as_str().ends_with("-bin") {
+            continue;
+        }
+        if let Ok(value) = value.to_str() {
+            metadata.insert(key.to_string(), value.into());
+        }
+    }
+    json!({
+        "method": path,
+        "service": service,
+        "rpc": rpc,
+        "identity": identity,
+        "metadata": metadata,
+    })
+}
+
+/// Adds `user`, `groups` and `roles` of the authenticated `caller` to the
+/// identity of `input`.
+fn add_caller(input: &mut Value, caller: &Caller) {
+    let identity = &mut input["identity"];
+    identity["user"] = caller.user.clone().into();
+    identity["groups"] = caller.groups.clone().into();
+    identity["roles"] = caller.roles.clone().into();
+}
+
+/// Refuses the calls the policy doesn't allow with `PERMISSION_DENIED`.
+/// Calls the policy fails to decide on fail with `UNAVAILABLE`, or go
+/// through with `fail_open`. With an `Authenticator` the callers are
+/// authenticated first, calls without valid credentials fail with
+/// `UNAUTHENTICATED` unless their route is anonymous.
+#[derive(Clone)]
+pub struct AuthzLayer {
+    policy: Option<Arc<dyn Policy>>,
+    authenticator: Option<Authenticator>,
+    fail_open: bool,
+}
+
+impl AuthzLayer {
+    pub fn new(policy: Arc<dyn Policy>, fail_open: bool) -> Self {
+        Self {
+            policy: Some(policy),
+            authenticator: None,
+            fail_open,
+        }
+    }
+
+    /// Lets every call through, for servers without a policy.
+    pub fn disabled() -> Self {
+        Self {
+            policy: None,
+            authenticator: None,
+            fail_open: false,
+        }
+    }
+
+    /// Authenticates the callers with `authenticator` before the policy
+    /// decides, if there is one.
+    pub fn with_authenticator(mut self, authenticator: Authenticator) -> Self {
+        self.authenticator = Some(authenticator);
+        self
+    }
+}
+
+impl<S> Layer<S> for AuthzLayer {
+    type Service = Authz<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Authz {
+            inner,
+            policy: self.policy.clone(),
+            authenticator: self.authenticator.clone(),
+            fail_open: self.fail_open,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Authz<S> {
+    inner: S,
+    policy: Option<Arc<dyn Policy>>,
+    authenticator: Option<Authenticator>,
+    fail_open: bool,
+}
+
+impl<S, B> Service<http::Request<B>> for Authz<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    B: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: http::Request<B>) -> Self::Future {
+        if self.policy.is_none() && self.authenticator.is_none() {
+            return Box::pin(self.inner.call(req));
+        }
+        let path = req.uri().path();
+        let authenticate = match &self.authenticator {
+            Some(authenticator) => match Credentials::from_headers(req.headers()) {
+                Some(credentials) => Some((authenticator.clone(), credentials)),
+                None if authenticator.is_anonymous(path) => None,
+                None => {
+                    return Box::pin(async {
+                        Ok(Status::unauthenticated("credentials required").to_http())
+                    });
+                }
+            },
+            None => None,
+        };
+        let mut input = input(path, req.headers(), &PeerInfo::from_http(&req));
+        let policy = self.policy.clone();
+        let fail_open = self.fail_open;
+        // the clone isn't ready, keep the service `poll_ready` was called on
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            if let Some((authenticator, credentials)) = authenticate {
+                match authenticator.authenticate(&credentials).await {
+                    Ok(Some(caller)) => {
+                        add_caller(&mut input, &caller);
+                        req.extensions_mut().insert(caller);
+                    }
+                    Ok(None) => {
+                        return Ok(Status::unauthenticated("invalid credentials").to_http());
+                    }
+                    Err(err) => {
+                        eprintln!("authentication failed: {}", err);
+                        return Ok(Status::unavailable("authentication unavailable").to_http());
+                    }
+                }
+            }
+            let Some(policy) = policy else {
+                return inner.call(req).await;
+            };
+            match policy.allows(&input).await {
+                Ok(true) => (),
+                Ok(false) => {
+                    return Ok(Status::permission_denied("denied by policy").to_http());
+                }
+                Err(err) if fail_open => {
+                    eprintln!("authorization failed, letting the call through: {}", err);
+                }
+                Err(err) => {
+                    eprintln!("authorization failed: {}", err);
+                    return Ok(Status::unavailable("authorization unavailable").to_http());
+                }
+            }
+            inner.call(req).await
+        })
+    }
+}
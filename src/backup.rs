@@ -0,0 +1,171 @@
+//! Archives of the stored messages made by `CreateBackup` and read back by
+//! `RestoreBackup`.
+//!
+//! An archive is gzip compressed JSON lines: a header naming the format and
+//! its version, then one `StoredMessage` per line in id order. Texts are
+//! kept as stored, encrypted ones stay encrypted. Both ends work a piece at
+//! a time, neither holds a whole archive.
+
+use std::{
+    io::{self, Write},
+    mem,
+};
+
+use flate2::{write::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::db::StoredMessage;
+
+/// Bytes of archive per `BackupChunk`, well below the default message size
+/// limit of tonic.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+const FORMAT: &str = "tonic-hello-tls/messages";
+const VERSION: u32 = 1;
+
+#[derive(Error, Debug)]
+pub enum BackupError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("line {0}: {1}")]
+    Malformed(usize, serde_json::Error),
+    #[error("not a backup of the messages")]
+    Format,
+    #[error("backup version {0} isn't supported")]
+    Version(u32),
+    #[error("backup larger than {0} bytes")]
+    TooLarge(usize),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    format: String,
+    version: u32,
+}
+
+/// Compresses messages into an archive as they come, handing it out in
+/// chunks of `CHUNK_SIZE`.
+pub struct Writer {
+    out: GzEncoder<Vec<u8>>,
+}
+
+impl Writer {
+    pub fn new() -> Result<Self, BackupError> {
+        let mut out = GzEncoder::new(Vec::new(), Compression::default());
+        let header = Header {
+            format: FORMAT.to_string(),
+            version: VERSION,
+        };
+        write_line(&mut out, &header)?;
+        Ok(Self { out })
+    }
+
+    /// Appends `messages`, returns the chunks of archive completed so far.
+    pub fn write(&mut self, messages: &[StoredMessage]) -> Result<Vec<Vec<u8>>, BackupError> {
+        for msg in messages {
+            write_line(&mut self.out, msg)?;
+        }
+        let compressed = self.out.get_mut();
+        let full = compressed.len() - compressed.len() % CHUNK_SIZE;
+        Ok(chunks(compressed.drain(..full).as_slice()))
+    }
+
+    /// The rest of the archive, in chunks.
+    pub fn finish(self) -> Result<Vec<Vec<u8>>, BackupError> {
+        Ok(chunks(&self.out.finish()?))
+    }
+}
+
+fn chunks(data: &[u8]) -> Vec<Vec<u8>> {
+    data.chunks(CHUNK_SIZE).map(<[u8]>::to_vec).collect()
+}
+
+/// Decompresses an archive as its chunks come, parsing every line that is
+/// complete. Refuses archives decompressing past `max_bytes`.
+pub struct Reader {
+    decoder: GzDecoder<Vec<u8>>,
+    max_bytes: usize,
+    /// Bytes decompressed and parsed, the partial line left in `decoder`
+    /// not included.
+    parsed_bytes: usize,
+    /// Number of the next line, from 1.
+    line: usize,
+    messages: Vec<StoredMessage>,
+}
+
+impl Reader {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            decoder: GzDecoder::new(Vec::new()),
+            max_bytes,
+            parsed_bytes: 0,
+            line: 1,
+            messages: Vec::new(),
+        }
+    }
+
+    /// Decompresses the next piece of the archive.
+    pub fn write(&mut self, data: &[u8]) -> Result<(), BackupError> {
+        self.decoder.write_all(data)?;
+        // hands over what was decompressed so far
+        self.decoder.flush()?;
+        self.parse_lines()
+    }
+
+    /// The messages of the archive, once all of it was written.
+    pub fn finish(mut self) -> Result<Vec<StoredMessage>, BackupError> {
+        self.decoder.try_finish()?;
+        self.parse_lines()?;
+        let rest = mem::take(self.decoder.get_mut());
+        if !rest.is_empty() {
+            self.parse_line(&rest)?;
+        }
+        if self.line == 1 {
+            return Err(BackupError::Format);
+        }
+        Ok(self.messages)
+    }
+
+    fn parse_lines(&mut self) -> Result<(), BackupError> {
+        let decompressed = self.decoder.get_mut();
+        if self.parsed_bytes + decompressed.len() > self.max_bytes {
+            return Err(BackupError::TooLarge(self.max_bytes));
+        }
+        let Some(end) = decompressed.iter().rposition(|&byte| byte == b'\n') else {
+            return Ok(());
+        };
+        let complete = decompressed.drain(..=end).collect::<Vec<_>>();
+        self.parsed_bytes += complete.len();
+        for line in complete[..end].split(|&byte| byte == b'\n') {
+            self.parse_line(line)?;
+        }
+        Ok(())
+    }
+
+    fn parse_line(&mut self, line: &[u8]) -> Result<(), BackupError> {
+        let idx = self.line;
+        self.line += 1;
+        if idx == 1 {
+            let header: Header = serde_json::from_slice(line).map_err(|_| BackupError::Format)?;
+            if header.format != FORMAT {
+                return Err(BackupError::Format);
+            }
+            if header.version != VERSION {
+                return Err(BackupError::Version(header.version));
+            }
+            return Ok(());
+        }
+        if line.is_empty() {
+            return Ok(());
+        }
+        let msg = serde_json::from_slice(line).map_err(|err| BackupError::Malformed(idx, err))?;
+        self.messages.push(msg);
+        Ok(())
+    }
+}
+
+fn write_line<T: Serialize>(out: &mut impl Write, value: &T) -> io::Result<()> {
+    serde_json::to_writer(&mut *out, value)?;
+    out.write_all(b"\n")
+}
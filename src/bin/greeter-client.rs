@@ -0,0 +1,333 @@
+//! Command line client for the greeter, doubling as a load generator.
+//!
+//!     greeter-client [--addr URL] [--ca PEM] [--domain NAME] hello NAME
+//!     greeter-client [--addr URL] [--ca PEM] [--domain NAME] bench
+//!         [--duration SECS] [--unary CALLS] [--streaming CALLS]
+//!         [--names N] [--connections N]
+//!
+//! `bench` keeps `--unary` SayHello and `--streaming` SayHelloStream calls
+//! (of `--names` names each) in flight for `--duration` seconds, spread over
+//! `--connections` HTTP/2 connections, then reports latency percentiles and
+//! error rates per kind of call. `--ca` trusts a server certificate over
+//! `https://` and needs the `tls` feature.
+
+use std::{collections::BTreeMap, error::Error, path::PathBuf, time::Duration};
+
+use tokio::time::Instant;
+use tonic::transport::Channel;
+
+use tonic_hello_tls::greeter::hello_world::{greeter_client::GreeterClient, HelloRequest};
+
+const USAGE: &str = "usage: greeter-client [--addr URL] [--ca PEM] [--domain NAME] \
+    (hello NAME | bench [--duration SECS] [--unary CALLS] [--streaming CALLS] \
+    [--names N] [--connections N])";
+
+struct Args {
+    addr: String,
+    ca: Option<PathBuf>,
+    domain: Option<String>,
+    command: Command,
+}
+
+enum Command {
+    Hello(String),
+    Bench(BenchOptions),
+}
+
+struct BenchOptions {
+    duration: Duration,
+    unary: usize,
+    streaming: usize,
+    names: usize,
+    connections: usize,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        Self {
+            duration: Duration::from_secs(10),
+            unary: 16,
+            streaming: 0,
+            names: 10,
+            connections: 1,
+        }
+    }
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut addr = std::env::var("GREETER_ADDR").unwrap_or("http://[::1]:50051".to_string());
+        let mut ca = None;
+        let mut domain = None;
+        let command = loop {
+            match args.next().as_deref() {
+                Some("--addr") => addr = value(&mut args, "--addr")?,
+                Some("--ca") => ca = Some(value(&mut args, "--ca")?.into()),
+                Some("--domain") => domain = Some(value(&mut args, "--domain")?),
+                Some("hello") => break Command::Hello(value(&mut args, "hello")?),
+                Some("bench") => break Command::Bench(BenchOptions::parse(&mut args)?),
+                Some(other) => return Err(format!("unexpected argument {:?}", other)),
+                None => return Err("missing command".to_string()),
+            }
+        };
+        Ok(Self {
+            addr,
+            ca,
+            domain,
+            command,
+        })
+    }
+}
+
+impl BenchOptions {
+    fn parse(args: &mut impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut options = Self::default();
+        while let Some(arg) = args.next() {
+            let field = match arg.as_str() {
+                "--duration" => {
+                    options.duration = Duration::from_secs(number(args, &arg)? as u64);
+                    continue;
+                }
+                "--unary" => &mut options.unary,
+                "--streaming" => &mut options.streaming,
+                "--names" => &mut options.names,
+                "--connections" => &mut options.connections,
+                _ => return Err(format!("unexpected argument {:?}", arg)),
+            };
+            *field = number(args, &arg)?;
+        }
+        if options.names == 0 || options.connections == 0 {
+            return Err("--names and --connections must be at least 1".to_string());
+        }
+        if options.unary + options.streaming == 0 {
+            return Err("--unary and --streaming are both 0".to_string());
+        }
+        Ok(options)
+    }
+}
+
+fn value(args: &mut impl Iterator<Item = String>, flag: &str) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("{} expects a value", flag))
+}
+
+fn number(args: &mut impl Iterator<Item = String>, flag: &str) -> Result<usize, String> {
+    value(args, flag)?
+        .parse()
+        .map_err(|err| format!("{}: {}", flag, err))
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{}\n{}", err, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(args))
+}
+
+async fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    match &args.command {
+        Command::Hello(name) => {
+            let mut client = GreeterClient::new(connect(&args).await?);
+            let reply = client.say_hello(hello(name)).await?.into_inner();
+            println!("{}", reply.message);
+        }
+        Command::Bench(options) => bench(&args, options).await?,
+    }
+    Ok(())
+}
+
+async fn connect(args: &Args) -> Result<Channel, Box<dyn Error>> {
+    let endpoint = Channel::from_shared(args.addr.clone())?;
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "tls")] {
+            let endpoint = match &args.ca {
+                Some(ca) => {
+                    let pem = std::fs::read_to_string(ca)?;
+                    let mut tls = tonic::transport::ClientTlsConfig::new()
+                        .ca_certificate(tonic::transport::Certificate::from_pem(pem));
+                    if let Some(domain) = &args.domain {
+                        tls = tls.domain_name(domain);
+                    }
+                    endpoint.tls_config(tls)?
+                }
+                None => endpoint,
+            };
+        } else {
+            if args.ca.is_some() || args.domain.is_some() {
+                return Err("--ca and --domain need the tls feature".into());
+            }
+        }
+    }
+    Ok(endpoint.connect().await?)
+}
+
+fn hello(name: &str) -> HelloRequest {
+    HelloRequest {
+        name: name.to_string(),
+        client_version: env!("CARGO_PKG_VERSION").to_string(),
+        ..Default::default()
+    }
+}
+
+/// Latencies of the successful calls of one kind and the failed ones by
+/// status code.
+#[derive(Default)]
+struct Recorder {
+    latencies: Vec<Duration>,
+    errors: BTreeMap<String, u64>,
+}
+
+impl Recorder {
+    fn record<T>(&mut self, start: Instant, result: Result<T, tonic::Status>) {
+        match result {
+            Ok(_) => self.latencies.push(start.elapsed()),
+            Err(status) => {
+                *self
+                    .errors
+                    .entry(format!("{:?}", status.code()))
+                    .or_default() += 1
+            }
+        }
+    }
+
+    fn merge(&mut self, other: Recorder) {
+        self.latencies.extend(other.latencies);
+        for (code, count) in other.errors {
+            *self.errors.entry(code).or_default() += count;
+        }
+    }
+
+    fn report(mut self, kind: &str, elapsed: Duration) {
+        let errors = self.errors.values().sum::<u64>();
+        let calls = self.latencies.len() as u64 + errors;
+        if calls == 0 {
+            return;
+        }
+        println!(
+            "{}: {} calls, {:.1} calls/s, {} errors ({:.2}%)",
+            kind,
+            calls,
+            calls as f64 / elapsed.as_secs_f64(),
+            errors,
+            errors as f64 * 100.0 / calls as f64
+        );
+        for (code, count) in &self.errors {
+            println!("  {}: {}", code, count);
+        }
+
+        self.latencies.sort();
+        if let Some(max) = self.latencies.last() {
+            let percentile = |p: usize| self.latencies[(self.latencies.len() - 1) * p / 100];
+            println!(
+                "  latency p50 {:?} p90 {:?} p99 {:?} max {:?}",
+                percentile(50),
+                percentile(90),
+                percentile(99),
+                max
+            );
+        }
+    }
+}
+
+async fn bench(args: &Args, options: &BenchOptions) -> Result<(), Box<dyn Error>> {
+    let mut channels = Vec::with_capacity(options.connections);
+    for _ in 0..options.connections {
+        channels.push(connect(args).await?);
+    }
+    println!(
+        "benchmarking {} for {:?}: {} unary, {} streaming calls of {} names, {} connections",
+        args.addr,
+        options.duration,
+        options.unary,
+        options.streaming,
+        options.names,
+        options.connections
+    );
+
+    let names = (0..options.names)
+        .map(|i| format!("bench-{}", i))
+        .collect::<Vec<_>>();
+    let start = Instant::now();
+    let deadline = start + options.duration;
+    let client = |i: usize| GreeterClient::new(channels[i % channels.len()].clone());
+
+    let unary = (0..options.unary)
+        .map(|i| tokio::spawn(unary_calls(client(i), deadline, names.clone())))
+        .collect::<Vec<_>>();
+    let streaming = (0..options.streaming)
+        .map(|i| {
+            tokio::spawn(streaming_calls(
+                client(options.unary + i),
+                deadline,
+                names.clone(),
+            ))
+        })
+        .collect::<Vec<_>>();
+
+    for (kind, workers) in [("unary", unary), ("streaming", streaming)] {
+        let mut recorder = Recorder::default();
+        for worker in workers {
+            recorder.merge(worker.await?);
+        }
+        recorder.report(kind, start.elapsed());
+    }
+    Ok(())
+}
+
+/// Calls SayHello back to back until `deadline`, cycling through `names`.
+async fn unary_calls(
+    mut client: GreeterClient<Channel>,
+    deadline: Instant,
+    names: Vec<String>,
+) -> Recorder {
+    let mut recorder = Recorder::default();
+    for name in names.iter().cycle() {
+        if Instant::now() >= deadline {
+            break;
+        }
+        let start = Instant::now();
+        let result = client.say_hello(hello(name)).await;
+        recorder.record(start, result);
+    }
+    recorder
+}
+
+/// Makes SayHelloStream calls greeting all of `names` until `deadline`, a
+/// call only counts as successful once every reply came back.
+async fn streaming_calls(
+    mut client: GreeterClient<Channel>,
+    deadline: Instant,
+    names: Vec<String>,
+) -> Recorder {
+    let mut recorder = Recorder::default();
+    while Instant::now() < deadline {
+        let start = Instant::now();
+        let requests = names.iter().map(|name| hello(name)).collect::<Vec<_>>();
+        let result = async {
+            let mut replies = client
+                .say_hello_stream(tokio_stream::iter(requests))
+                .await?
+                .into_inner();
+            let mut count = 0;
+            while replies.message().await?.is_some() {
+                count += 1;
+            }
+            if count != names.len() {
+                let message = format!("{} replies to {} names", count, names.len());
+                return Err(tonic::Status::data_loss(message));
+            }
+            Ok(())
+        }
+        .await;
+        recorder.record(start, result);
+    }
+    recorder
+}
@@ -0,0 +1,161 @@
+//! In-process canarying: calls carrying `x-canary: true` are answered by an
+//! alternate implementation of a service, e.g. one with new greeting logic,
+//! the others by the stable one. Calls and failures are counted per variant
+//! to compare the two.
+
+use std::{
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+
+use http::HeaderMap;
+use serde::Serialize;
+use tonic::{
+    body::BoxBody,
+    server::NamedService,
+    transport::{server::Routes, Body},
+    Status,
+};
+use tower_service::Service;
+
+/// Request metadata routing a call to the canary when set to `true`.
+pub const CANARY_METADATA: &str = "x-canary";
+
+static CALLS: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+static FAILURES: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Variant {
+    Stable,
+    Canary,
+}
+
+impl Variant {
+    /// The variant `headers` ask for, `Canary` only with `x-canary: true`.
+    pub fn of(headers: &HeaderMap) -> Self {
+        let canary = headers
+            .get(CANARY_METADATA)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.trim().eq_ignore_ascii_case("true"));
+        if canary {
+            Self::Canary
+        } else {
+            Self::Stable
+        }
+    }
+
+    fn index(self) -> usize {
+        match self {
+            Self::Stable => 0,
+            Self::Canary => 1,
+        }
+    }
+}
+
+/// Calls a variant answered since startup, and how many of them failed
+/// with a status other than `OK` ahead of their response body.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct VariantStats {
+    pub calls: u64,
+    pub failures: u64,
+}
+
+/// Counts of both variants, see `stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct CanaryStats {
+    pub stable: VariantStats,
+    pub canary: VariantStats,
+}
+
+pub fn stats() -> CanaryStats {
+    let variant = |variant: Variant| VariantStats {
+        calls: CALLS[variant.index()].load(Ordering::Relaxed),
+        failures: FAILURES[variant.index()].load(Ordering::Relaxed),
+    };
+    CanaryStats {
+        stable: variant(Variant::Stable),
+        canary: variant(Variant::Canary),
+    }
+}
+
+/// Serves the stable service `S` under its name, handing the calls asking
+/// for the canary to the canary service when there is one. Without a canary
+/// every call goes to the stable service.
+#[derive(Clone)]
+pub struct CanaryRouter<S> {
+    stable: S,
+    canary: Option<Routes>,
+}
+
+impl<S> CanaryRouter<S> {
+    pub fn new(stable: S, canary: Option<Routes>) -> Self {
+        Self { stable, canary }
+    }
+
+    /// Answers the calls asking for the canary with `canary`, which has to
+    /// serve the same service as `S`.
+    pub fn with_canary<C>(mut self, canary: C) -> Self
+    where
+        C: Service<http::Request<Body>, Response = http::Response<BoxBody>, Error = Infallible>
+            + NamedService
+            + Clone
+            + Send
+            + 'static,
+        C::Future: Send + 'static,
+    {
+        self.canary = Some(Routes::new(canary));
+        self
+    }
+}
+
+impl<S: NamedService> NamedService for CanaryRouter<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S> Service<http::Request<Body>> for CanaryRouter<S>
+where
+    S: Service<http::Request<Body>, Response = http::Response<BoxBody>, Error = Infallible>,
+    S::Future: Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // `Routes` is always ready
+        self.stable.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<Body>) -> Self::Future {
+        let variant = match &self.canary {
+            Some(_) => Variant::of(req.headers()),
+            None => Variant::Stable,
+        };
+        CALLS[variant.index()].fetch_add(1, Ordering::Relaxed);
+        let response: Self::Future = match (&mut self.canary, variant) {
+            (Some(canary), Variant::Canary) => {
+                let response = canary.call(req);
+                Box::pin(async move {
+                    Ok(response
+                        .await
+                        .unwrap_or_else(|err| Status::internal(err.to_string()).to_http()))
+                })
+            }
+            _ => Box::pin(self.stable.call(req)),
+        };
+        Box::pin(async move {
+            let response = response.await?;
+            let failed = response
+                .headers()
+                .get("grpc-status")
+                .is_some_and(|status| status.as_bytes() != b"0");
+            if failed {
+                FAILURES[variant.index()].fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(response)
+        })
+    }
+}
@@ -0,0 +1,112 @@
+//! Expiry of the certificates the server was started with, reported by the
+//! `Admin` service and the dashboard and warned about as it approaches, so
+//! they are renewed before clients start failing the handshake.
+
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use thiserror::Error;
+
+use crate::server_events::{self, EventKind, ServerEvent};
+
+/// Days left at which the warnings escalate, logged once each.
+const WARN_DAYS: [i64; 4] = [30, 14, 7, 1];
+
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+#[derive(Error, Debug)]
+pub enum CertError {
+    #[error("No certificate in {0}")]
+    Missing(String),
+    #[error("Invalid certificate in {name}: {reason}")]
+    Invalid { name: String, reason: String },
+}
+
+/// A loaded certificate, named after what it is loaded as, e.g. `server`.
+#[derive(Clone, Debug)]
+pub struct Certificate {
+    pub name: String,
+    pub subject: String,
+    /// End of the validity period, in seconds since the Unix epoch.
+    pub not_after: i64,
+}
+
+impl Certificate {
+    /// Whole days until the certificate expires, negative once it has.
+    pub fn days_left(&self) -> i64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs() as i64);
+        (self.not_after - now).div_euclid(SECS_PER_DAY)
+    }
+}
+
+/// The certificates of a PEM bundle, a chain or a CA bundle, each named
+/// `name`.
+#[cfg(feature = "tls")]
+pub fn parse(name: &str, pem: &[u8]) -> Result<Vec<Certificate>, CertError> {
+    let invalid = |reason: String| CertError::Invalid {
+        name: name.to_string(),
+        reason,
+    };
+    let mut certs = Vec::new();
+    for pem in x509_parser::pem::Pem::iter_from_buffer(pem) {
+        let pem = pem.map_err(|err| invalid(err.to_string()))?;
+        let cert = pem.parse_x509().map_err(|err| invalid(err.to_string()))?;
+        certs.push(Certificate {
+            name: name.to_string(),
+            subject: cert.subject().to_string(),
+            not_after: cert.validity().not_after.timestamp(),
+        });
+    }
+    if certs.is_empty() {
+        return Err(CertError::Missing(name.to_string()));
+    }
+    Ok(certs)
+}
+
+/// Checks `certs` every `interval` until the task is dropped. A warning is
+/// logged as each of `WARN_DAYS` is reached and an error on every check once
+/// a certificate has expired.
+pub async fn monitor(certs: Arc<[Certificate]>, interval: Duration) {
+    // the smallest of `WARN_DAYS` warned about, per certificate
+    let mut warned = vec![None; certs.len()];
+    loop {
+        for (cert, warned) in certs.iter().zip(&mut warned) {
+            check(cert, warned);
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+fn check(cert: &Certificate, warned: &mut Option<i64>) {
+    let days_left = cert.days_left();
+    if days_left < 0 {
+        eprintln!(
+            "error: {} certificate '{}' expired {} days ago",
+            cert.name, cert.subject, -days_left
+        );
+        server_events::emit(|| certificate_event(cert, "certificate expired", days_left));
+        return;
+    }
+    let Some(&reached) = WARN_DAYS.iter().rev().find(|&&days| days_left <= days) else {
+        return;
+    };
+    if warned.is_none_or(|warned| reached < warned) {
+        eprintln!(
+            "warning: {} certificate '{}' expires in {} days",
+            cert.name, cert.subject, days_left
+        );
+        server_events::emit(|| certificate_event(cert, "certificate expires soon", days_left));
+        *warned = Some(reached);
+    }
+}
+
+fn certificate_event(cert: &Certificate, message: &str, days_left: i64) -> ServerEvent {
+    ServerEvent::new(EventKind::Certificate, message)
+        .with("name", &cert.name)
+        .with("subject", &cert.subject)
+        .with("days_left", days_left)
+}
@@ -0,0 +1,260 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{ready, Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use http::HeaderMap;
+use http_body::Body;
+use tokio::time::Sleep;
+use tonic::{body::BoxBody, Status};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::config::Chaos;
+
+/// Request metadata overriding the configured faults for one call, so a
+/// client test can ask for exactly the failure it exercises.
+const LATENCY_HEADER: &str = "x-chaos-latency-ms";
+const UNAVAILABLE_HEADER: &str = "x-chaos-unavailable";
+const DISCONNECT_AFTER_HEADER: &str = "x-chaos-disconnect-after";
+
+/// Compressed flag and length ahead of every gRPC message.
+const GRPC_PREFIX_LEN: usize = 5;
+const RESET_DELAY: Duration = Duration::from_millis(50);
+
+static RNG: AtomicU64 = AtomicU64::new(0);
+
+/// Injects latency, `UNAVAILABLE` errors and responses cut off mid-stream,
+/// to test client retry logic against this server. Faults come from the
+/// `CHAOS` config and from request metadata:
+///
+/// - `x-chaos-latency-ms: 250` delays the call.
+/// - `x-chaos-unavailable: 1` fails it, `0.5` fails every other call or so.
+/// - `x-chaos-disconnect-after: 3` resets the stream after three messages.
+///
+/// Anyone can trigger these, never build the `chaos` feature into a
+/// production server.
+#[derive(Clone, Copy)]
+pub struct ChaosLayer {
+    chaos: Chaos,
+}
+
+impl ChaosLayer {
+    pub fn new(chaos: Chaos) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        let _ = RNG.compare_exchange(0, seed, Ordering::Relaxed, Ordering::Relaxed);
+        Self { chaos }
+    }
+}
+
+impl<S> Layer<S> for ChaosLayer {
+    type Service = ChaosService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ChaosService {
+            inner,
+            chaos: self.chaos,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ChaosService<S> {
+    inner: S,
+    chaos: Chaos,
+}
+
+impl<S, B> Service<http::Request<B>> for ChaosService<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    B: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let faults = Faults::pick(&self.chaos, req.headers());
+        // the clone isn't ready, keep the service `poll_ready` was called on
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            if !faults.latency.is_zero() {
+                tokio::time::sleep(faults.latency).await;
+            }
+            if faults.unavailable {
+                return Ok(Status::unavailable("injected fault").to_http());
+            }
+            let res = inner.call(req).await?;
+            Ok(match faults.disconnect_after {
+                Some(messages) => {
+                    res.map(|inner| DisconnectingBody::new(inner, messages).boxed_unsync())
+                }
+                None => res,
+            })
+        })
+    }
+}
+
+/// The faults drawn for one call.
+struct Faults {
+    latency: Duration,
+    unavailable: bool,
+    disconnect_after: Option<u64>,
+}
+
+impl Faults {
+    fn pick(chaos: &Chaos, headers: &HeaderMap) -> Self {
+        let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+
+        let latency_ms = match header(LATENCY_HEADER).and_then(|v| v.parse().ok()) {
+            Some(ms) => ms,
+            None if chaos.jitter_ms > 0 => chaos.latency_ms + next() % (chaos.jitter_ms + 1),
+            None => chaos.latency_ms,
+        };
+        let unavailable = header(UNAVAILABLE_HEADER)
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(chaos.unavailable);
+        let disconnect_after = match header(DISCONNECT_AFTER_HEADER).and_then(|v| v.parse().ok()) {
+            Some(messages) => Some(messages),
+            None => roll(chaos.disconnect).then_some(chaos.disconnect_after),
+        };
+
+        Self {
+            latency: Duration::from_millis(latency_ms),
+            unavailable: roll(unavailable),
+            disconnect_after,
+        }
+    }
+}
+
+/// Response body failing after `remaining` gRPC messages, which makes hyper
+/// reset the HTTP/2 stream before the trailers go out. Messages are counted
+/// by their length prefix as tonic packs several into one data frame.
+struct DisconnectingBody {
+    inner: BoxBody,
+    remaining: u64,
+    /// Length prefix of the current message, while it is split over frames.
+    prefix: Vec<u8>,
+    /// Bytes of the current message still to come after its prefix.
+    left: usize,
+    /// Holds the reset back once the last message is out, a reset sent along
+    /// with it would make the client discard it.
+    reset: Option<Pin<Box<Sleep>>>,
+}
+
+impl DisconnectingBody {
+    fn new(inner: BoxBody, remaining: u64) -> Self {
+        Self {
+            inner,
+            remaining,
+            prefix: Vec::with_capacity(GRPC_PREFIX_LEN),
+            left: 0,
+            reset: None,
+        }
+    }
+
+    fn poll_reset(&mut self, cx: &mut Context<'_>) -> Poll<Status> {
+        let reset = self
+            .reset
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(RESET_DELAY)));
+        ready!(reset.as_mut().poll(cx));
+        Poll::Ready(Status::unavailable("injected disconnect"))
+    }
+
+    /// Walks the messages of `data` and returns the length of the part to
+    /// send, short of all of it once the last allowed message ends.
+    fn advance(&mut self, data: &[u8]) -> usize {
+        let mut pos = 0;
+        while pos < data.len() && self.remaining > 0 {
+            if self.left == 0 {
+                let take = (GRPC_PREFIX_LEN - self.prefix.len()).min(data.len() - pos);
+                self.prefix.extend_from_slice(&data[pos..pos + take]);
+                pos += take;
+                if self.prefix.len() < GRPC_PREFIX_LEN {
+                    continue;
+                }
+                let len = [
+                    self.prefix[1],
+                    self.prefix[2],
+                    self.prefix[3],
+                    self.prefix[4],
+                ];
+                self.left = u32::from_be_bytes(len) as usize;
+                self.prefix.clear();
+            } else {
+                let take = self.left.min(data.len() - pos);
+                pos += take;
+                self.left -= take;
+            }
+            if self.left == 0 && self.prefix.is_empty() {
+                self.remaining -= 1;
+            }
+        }
+        pos
+    }
+}
+
+impl Body for DisconnectingBody {
+    type Data = <BoxBody as Body>::Data;
+    type Error = Status;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        if self.remaining == 0 {
+            return self.poll_reset(cx).map(|status| Some(Err(status)));
+        }
+        match Pin::new(&mut self.inner).poll_data(cx) {
+            Poll::Ready(Some(Ok(mut data))) => {
+                let len = self.advance(&data);
+                data.truncate(len);
+                Poll::Ready(Some(Ok(data)))
+            }
+            poll => poll,
+        }
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        if self.remaining == 0 {
+            return self.poll_reset(cx).map(Err);
+        }
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.remaining > 0 && self.inner.is_end_stream()
+    }
+}
+
+/// Whether an event of probability `rate` happens this time.
+fn roll(rate: f64) -> bool {
+    let sample = (next() >> 11) as f64 / (1u64 << 53) as f64;
+    sample < rate
+}
+
+/// splitmix64 over a shared counter, good enough to spread faults around.
+fn next() -> u64 {
+    let mut z = RNG
+        .fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed)
+        .wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
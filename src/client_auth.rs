@@ -0,0 +1,92 @@
+//! Client certificates required by route (`tls` feature). With a
+//! `client_auth` policy the handshake lets clients without a certificate
+//! in, and this layer refuses their calls to the routes requiring one, so
+//! e.g. health checks and reflection stay open while `Greeter` needs
+//! mutual TLS.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use tonic::{body::BoxBody, Status};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::{
+    config::{ClientAuth, ClientAuthPolicy},
+    peer_info::PeerInfo,
+};
+
+/// Fails the calls without a client certificate to the routes `policy`
+/// requires one for with `UNAUTHENTICATED`.
+#[derive(Clone)]
+pub struct ClientAuthLayer {
+    policy: Option<Arc<ClientAuthPolicy>>,
+}
+
+impl ClientAuthLayer {
+    pub fn new(policy: ClientAuthPolicy) -> Self {
+        Self {
+            policy: Some(Arc::new(policy)),
+        }
+    }
+
+    /// Lets every call through, for servers leaving client authentication
+    /// to the handshake.
+    pub fn disabled() -> Self {
+        Self { policy: None }
+    }
+}
+
+impl<S> Layer<S> for ClientAuthLayer {
+    type Service = ClientAuthService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ClientAuthService {
+            inner,
+            policy: self.policy.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ClientAuthService<S> {
+    inner: S,
+    policy: Option<Arc<ClientAuthPolicy>>,
+}
+
+impl<S, B> Service<http::Request<B>> for ClientAuthService<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<BoxBody>>,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        if let Some(policy) = &self.policy {
+            let required = policy.for_path(req.uri().path()) == ClientAuth::Required;
+            if required && !has_client_cert(&PeerInfo::from_http(&req)) {
+                return Box::pin(async {
+                    Ok(Status::unauthenticated("client certificate required").to_http())
+                });
+            }
+        }
+        Box::pin(self.inner.call(req))
+    }
+}
+
+fn has_client_cert(peer: &PeerInfo) -> bool {
+    peer.peer_certs
+        .as_ref()
+        .is_some_and(|certs| !certs.is_empty())
+}
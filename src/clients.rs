@@ -0,0 +1,228 @@
+//! Channels to the upstream gRPC services handlers call, e.g. a translation
+//! service, configured by the `[upstreams.<name>]` tables of the config file.
+//!
+//! Every upstream gets a small pool of channels, each one HTTP/2
+//! connection, handed out in turn so one busy connection doesn't hold up
+//! every call. Calls go through `Upstream::call`, which applies the deadline,
+//! retries the `UNAVAILABLE` ones and counts them for the dashboard.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use thiserror::Error;
+use tonic::{
+    transport::{Channel, Endpoint},
+    Code, Status,
+};
+
+use crate::config::UpstreamConfig;
+
+static STATS: Mutex<BTreeMap<String, Arc<Counters>>> = Mutex::new(BTreeMap::new());
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("Upstream {0}: {1}")]
+    Transport(String, tonic::transport::Error),
+    #[error("Upstream {0}: CA certificate: {1}")]
+    Io(String, std::io::Error),
+    #[error("Upstream {0}: TLS settings need the `tls` feature")]
+    TlsDisabled(String),
+    #[error("No upstream {0} configured")]
+    Missing(String),
+}
+
+/// The configured upstreams by name, cheap to clone.
+#[derive(Clone, Default)]
+pub struct Clients {
+    upstreams: Arc<HashMap<String, Upstream>>,
+}
+
+impl Clients {
+    /// Sets up the channels of `upstreams`. Connections are made on the
+    /// first call, an upstream down at startup doesn't keep the server from
+    /// starting.
+    pub fn new(upstreams: &HashMap<String, UpstreamConfig>) -> Result<Self, ClientError> {
+        let upstreams = upstreams
+            .iter()
+            .map(|(name, config)| Ok((name.clone(), Upstream::new(name, config)?)))
+            .collect::<Result<_, ClientError>>()?;
+        Ok(Self {
+            upstreams: Arc::new(upstreams),
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Upstream> {
+        self.upstreams.get(name)
+    }
+
+    /// The upstream `name`, which the config has to set up.
+    pub fn require(&self, name: &str) -> Result<&Upstream, ClientError> {
+        self.get(name)
+            .ok_or_else(|| ClientError::Missing(name.to_string()))
+    }
+}
+
+/// A pool of channels to one upstream.
+#[derive(Clone)]
+pub struct Upstream {
+    name: String,
+    channels: Arc<[Channel]>,
+    next: Arc<AtomicUsize>,
+    retries: u32,
+    retry_backoff: Duration,
+    counters: Arc<Counters>,
+}
+
+impl Upstream {
+    pub fn new(name: &str, config: &UpstreamConfig) -> Result<Self, ClientError> {
+        let transport = |err| ClientError::Transport(name.to_string(), err);
+        let mut endpoint = Endpoint::from_shared(config.url.clone())
+            .map_err(transport)?
+            .timeout(Duration::from_millis(config.timeout_ms))
+            .connect_timeout(Duration::from_millis(config.connect_timeout_ms));
+        if config.ca_cert.is_some() || config.tls_domain.is_some() {
+            endpoint = tls(name, endpoint, config)?;
+        }
+        let channels = (0..config.connections.max(1))
+            .map(|_| endpoint.connect_lazy())
+            .collect();
+
+        let counters = Arc::new(Counters::default());
+        STATS
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), counters.clone());
+        Ok(Self {
+            name: name.to_string(),
+            channels,
+            next: Arc::new(AtomicUsize::new(0)),
+            retries: config.retries,
+            retry_backoff: Duration::from_millis(config.retry_backoff_ms),
+            counters,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The next channel of the pool, for calls `call` doesn't fit.
+    pub fn channel(&self) -> Channel {
+        let idx = self.next.fetch_add(1, Ordering::Relaxed) % self.channels.len();
+        self.channels[idx].clone()
+    }
+
+    /// Makes a call with `attempt`, on a client built around the channel it
+    /// gets, e.g. `|channel| async { Client::new(channel).get(req).await }`.
+    /// `UNAVAILABLE` calls are attempted again up to `retries` times, after
+    /// a backoff doubled every time. Calls past the deadline fail with
+    /// `DEADLINE_EXCEEDED`, they may have been done upstream and aren't
+    /// retried.
+    pub async fn call<T, F, Fut>(&self, mut attempt: F) -> Result<T, Status>
+    where
+        F: FnMut(Channel) -> Fut,
+        Fut: Future<Output = Result<tonic::Response<T>, Status>>,
+    {
+        let start = Instant::now();
+        let mut backoff = self.retry_backoff;
+        let mut retries = 0;
+        let result = loop {
+            match attempt(self.channel()).await {
+                Err(status) if status.code() == Code::Unavailable && retries < self.retries => {
+                    retries += 1;
+                    self.counters.retries.fetch_add(1, Ordering::Relaxed);
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                // the channel's deadline fails calls as cancelled
+                Err(status)
+                    if status.code() == Code::Cancelled
+                        && status.message() == "Timeout expired" =>
+                {
+                    break Err(Status::deadline_exceeded(format!(
+                        "upstream {} timed out",
+                        self.name
+                    )))
+                }
+                result => break result.map(tonic::Response::into_inner),
+            }
+        };
+
+        self.counters.calls.fetch_add(1, Ordering::Relaxed);
+        if result.is_err() {
+            self.counters.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        self.counters
+            .latency_us
+            .fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+        result
+    }
+}
+
+#[cfg(feature = "tls")]
+fn tls(name: &str, endpoint: Endpoint, config: &UpstreamConfig) -> Result<Endpoint, ClientError> {
+    use tonic::transport::{Certificate, ClientTlsConfig};
+
+    let mut tls = ClientTlsConfig::new();
+    if let Some(path) = &config.ca_cert {
+        let pem = std::fs::read(path).map_err(|err| ClientError::Io(name.to_string(), err))?;
+        tls = tls.ca_certificate(Certificate::from_pem(pem));
+    }
+    if let Some(domain) = &config.tls_domain {
+        tls = tls.domain_name(domain);
+    }
+    endpoint
+        .tls_config(tls)
+        .map_err(|err| ClientError::Transport(name.to_string(), err))
+}
+
+#[cfg(not(feature = "tls"))]
+fn tls(name: &str, _: Endpoint, _: &UpstreamConfig) -> Result<Endpoint, ClientError> {
+    Err(ClientError::TlsDisabled(name.to_string()))
+}
+
+#[derive(Default)]
+struct Counters {
+    calls: AtomicU64,
+    failures: AtomicU64,
+    retries: AtomicU64,
+    latency_us: AtomicU64,
+}
+
+/// Calls made to an upstream since startup, retries not counted apart.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct UpstreamStats {
+    pub calls: u64,
+    pub failures: u64,
+    pub retries: u64,
+    /// Mean time a call took, retries included.
+    pub mean_latency_ms: u64,
+}
+
+/// Counts of every upstream set up, by name.
+pub fn stats() -> BTreeMap<String, UpstreamStats> {
+    STATS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, counters)| {
+            let calls = counters.calls.load(Ordering::Relaxed);
+            let latency_us = counters.latency_us.load(Ordering::Relaxed);
+            let stats = UpstreamStats {
+                calls,
+                failures: counters.failures.load(Ordering::Relaxed),
+                retries: counters.retries.load(Ordering::Relaxed),
+                mean_latency_ms: latency_us.checked_div(calls).unwrap_or(0) / 1000,
+            };
+            (name.clone(), stats)
+        })
+        .collect()
+}
@@ -0,0 +1,75 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex, Weak},
+};
+
+use tokio::sync::OnceCell;
+use tonic::metadata::MetadataMap;
+
+/// Request metadata naming the logical call a request is a copy of. Clients
+/// hedging `SayHello` send the same key with every copy.
+pub const IDEMPOTENCY_KEY_METADATA: &str = "idempotency-key";
+
+/// The idempotency key of a request, if it has one.
+pub fn idempotency_key(metadata: &MetadataMap) -> Option<String> {
+    metadata
+        .get(IDEMPOTENCY_KEY_METADATA)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(str::to_string)
+}
+
+/// Coalesces concurrent calls under the same key: the first one runs, the
+/// others wait for it and share its result. Calls coming in after it finished
+/// run again, nothing is cached.
+pub struct InFlight<T> {
+    calls: Arc<Mutex<HashMap<String, Weak<OnceCell<T>>>>>,
+}
+
+impl<T> Clone for InFlight<T> {
+    fn clone(&self) -> Self {
+        Self {
+            calls: self.calls.clone(),
+        }
+    }
+}
+
+impl<T> Default for InFlight<T> {
+    fn default() -> Self {
+        Self {
+            calls: Arc::default(),
+        }
+    }
+}
+
+impl<T: Clone> InFlight<T> {
+    /// Runs `call` unless a call under `key` is in flight already, returns
+    /// that one's result then. If the running call is cancelled one of the
+    /// waiting ones runs instead.
+    pub async fn run<F: Future<Output = T>>(&self, key: &str, call: F) -> T {
+        let cell = {
+            let mut calls = self.calls.lock().unwrap();
+            match calls.get(key).and_then(Weak::upgrade) {
+                Some(cell) => cell,
+                None => {
+                    calls.retain(|_, cell| cell.strong_count() > 0);
+                    let cell = Arc::new(OnceCell::new());
+                    calls.insert(key.to_string(), Arc::downgrade(&cell));
+                    cell
+                }
+            }
+        };
+        let result = cell.get_or_init(|| call).await.clone();
+
+        let mut calls = self.calls.lock().unwrap();
+        if calls
+            .get(key)
+            .is_some_and(|running| running.as_ptr() == Arc::as_ptr(&cell))
+        {
+            calls.remove(key);
+        }
+        result
+    }
+}
@@ -0,0 +1,110 @@
+//! Replies encoded beforehand. Fanning a message out to thousands of
+//! `ListMessagesStream` subscribers, or keeping idle streams alive, the same
+//! reply is encoded once as an `EncodedReply` and its bytes copied into
+//! every stream. The generated `ListMessagesStream` service takes `Frame`
+//! replies for that, see `build.rs`, its clients decode `HelloReply`.
+
+use std::sync::OnceLock;
+
+use prost::{
+    bytes::{Buf, BufMut, Bytes},
+    encoding::{DecodeContext, WireType},
+    DecodeError, Message,
+};
+
+use crate::greeter::hello_world::HelloReply;
+
+/// A reply encoded once, to send to any number of streams. Cheap to clone.
+#[derive(Clone, Debug)]
+pub struct EncodedReply {
+    bytes: Bytes,
+}
+
+impl EncodedReply {
+    pub fn new(reply: &HelloReply) -> Self {
+        Self {
+            bytes: reply.encode_to_vec().into(),
+        }
+    }
+
+    /// The keepalive reply of idle streams.
+    pub fn heartbeat() -> Self {
+        static HEARTBEAT: OnceLock<EncodedReply> = OnceLock::new();
+        HEARTBEAT
+            .get_or_init(|| {
+                EncodedReply::new(&HelloReply {
+                    heartbeat: true,
+                    ..Default::default()
+                })
+            })
+            .clone()
+    }
+}
+
+/// A reply of a stream, encoded beforehand or not.
+#[derive(Clone, Debug)]
+pub enum Frame {
+    Reply(HelloReply),
+    Encoded(EncodedReply),
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Frame::Reply(HelloReply::default())
+    }
+}
+
+impl From<HelloReply> for Frame {
+    fn from(reply: HelloReply) -> Self {
+        Frame::Reply(reply)
+    }
+}
+
+impl From<EncodedReply> for Frame {
+    fn from(encoded: EncodedReply) -> Self {
+        Frame::Encoded(encoded)
+    }
+}
+
+/// Encodes as the reply it holds, which `SendBuffer` counts.
+impl Message for Frame {
+    fn encode_raw<B>(&self, buf: &mut B)
+    where
+        B: BufMut,
+    {
+        match self {
+            Frame::Reply(reply) => reply.encode_raw(buf),
+            Frame::Encoded(encoded) => buf.put_slice(&encoded.bytes),
+        }
+    }
+
+    fn merge_field<B>(
+        &mut self,
+        tag: u32,
+        wire_type: WireType,
+        buf: &mut B,
+        ctx: DecodeContext,
+    ) -> Result<(), DecodeError>
+    where
+        B: Buf,
+    {
+        if let Frame::Encoded(encoded) = self {
+            *self = Frame::Reply(HelloReply::decode(encoded.bytes.clone())?);
+        }
+        match self {
+            Frame::Reply(reply) => reply.merge_field(tag, wire_type, buf, ctx),
+            Frame::Encoded(_) => unreachable!("decoded above"),
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        match self {
+            Frame::Reply(reply) => reply.encoded_len(),
+            Frame::Encoded(encoded) => encoded.bytes.len(),
+        }
+    }
+
+    fn clear(&mut self) {
+        *self = Frame::default();
+    }
+}
@@ -0,0 +1,202 @@
+//! Checks at startup that the database has the schema this build expects.
+//!
+//! During a blue/green deployment the database may be migrated ahead of
+//! the binary, so tables, columns and migrations the build doesn't know
+//! about are fine. What it expects and doesn't find would make its queries
+//! fail at runtime: a missing table or column, a column of another type or
+//! nullability, a migration not applied yet.
+
+use std::{fmt, str::FromStr};
+
+use crate::db::{Db, DbError, SchemaColumn};
+
+mod migrations {
+    include!(concat!(env!("OUT_DIR"), "/migrations.rs"));
+}
+
+/// Versions of the migrations the build comes with, oldest first.
+pub use migrations::MIGRATIONS;
+
+/// The columns of `schema.rs` as `information_schema` names them: table,
+/// column, `udt_name` and whether it is nullable. Keep it in sync with
+/// `schema.rs`.
+const EXPECTED_COLUMNS: &[(&str, &str, &str, bool)] = &[
+    ("audit_log", "id", "int8", false),
+    ("audit_log", "tenant", "text", false),
+    ("audit_log", "action", "text", false),
+    ("audit_log", "subject", "text", false),
+    ("audit_log", "detail", "text", false),
+    ("audit_log", "created_at", "timestamp", false),
+    ("events", "seq", "int8", false),
+    ("events", "kind", "text", false),
+    ("events", "message_id", "int4", false),
+    ("events", "payload", "text", true),
+    ("events", "created_at", "timestamp", false),
+    ("feature_flags", "name", "text", false),
+    ("feature_flags", "tenant", "text", false),
+    ("feature_flags", "enabled", "bool", false),
+    ("messages", "id", "int4", false),
+    ("messages", "message", "text", true),
+    ("messages", "updated", "int4", true),
+    ("messages", "tags", "jsonb", false),
+    ("messages", "created_at", "timestamp", false),
+    ("messages", "metadata", "jsonb", false),
+    ("messages", "priority", "int2", false),
+    ("messages", "payload", "bytea", true),
+    ("messages", "attachment_key", "text", true),
+    ("messages", "country", "text", true),
+    ("name_counts", "name", "text", false),
+    ("name_counts", "greetings", "int8", false),
+    ("outbox", "id", "int8", false),
+    ("outbox", "topic", "text", false),
+    ("outbox", "payload", "text", false),
+    ("outbox", "created_at", "timestamp", false),
+    ("outbox", "published_at", "timestamp", true),
+    ("pending_deliveries", "message_id", "int4", false),
+    ("pending_deliveries", "created_at", "timestamp", false),
+    ("refresh_tokens", "token_sha256", "text", false),
+    ("refresh_tokens", "subject", "text", false),
+    ("refresh_tokens", "groups", "jsonb", false),
+    ("refresh_tokens", "expires_at", "timestamp", false),
+    ("refresh_tokens", "revoked_at", "timestamp", true),
+    ("revoked_sessions", "subject", "text", false),
+    ("revoked_sessions", "revoked_at", "timestamp", false),
+    ("sessions", "id", "int8", false),
+    ("sessions", "tenant", "text", false),
+    ("sessions", "started_at", "timestamp", false),
+    ("sessions", "ended_at", "timestamp", true),
+    ("sessions", "exchanges", "jsonb", false),
+    ("subscriber_acks", "subscriber", "text", false),
+    ("subscriber_acks", "acked_until", "int4", false),
+    ("subscriber_acks", "updated_at", "timestamp", false),
+    ("subscriptions", "name", "text", false),
+    ("subscriptions", "delivered_until", "int4", false),
+    ("subscriptions", "updated_at", "timestamp", false),
+    ("tenant_usage", "tenant", "text", false),
+    ("tenant_usage", "messages", "int8", false),
+    ("tenant_usage", "bytes", "int8", false),
+];
+
+/// A difference between the schema the build expects and the database's
+/// that breaks the build's queries.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mismatch {
+    MissingColumn {
+        table: String,
+        column: String,
+    },
+    ColumnType {
+        table: String,
+        column: String,
+        expected: String,
+        found: String,
+    },
+    /// A column nullable where the build expects `NOT NULL` or the other
+    /// way round.
+    Nullability {
+        table: String,
+        column: String,
+        nullable: bool,
+    },
+    /// A migration of the build the database hasn't run.
+    PendingMigration(String),
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingColumn { table, column } => write!(f, "{}.{} is missing", table, column),
+            Self::ColumnType {
+                table,
+                column,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{}.{} is {}, expected {}",
+                table, column, found, expected
+            ),
+            Self::Nullability {
+                table,
+                column,
+                nullable,
+            } => {
+                let found = if *nullable { "nullable" } else { "NOT NULL" };
+                write!(f, "{}.{} is {}", table, column, found)
+            }
+            Self::PendingMigration(version) => write!(f, "migration {} isn't applied", version),
+        }
+    }
+}
+
+/// What mismatches do to the startup, parsed from `refuse`, `read-only` or
+/// `ignore`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MismatchPolicy {
+    /// The server doesn't start.
+    #[default]
+    Refuse,
+    /// The server starts read-only, see `read_only::ReadOnly`.
+    ReadOnly,
+    /// The mismatches are only logged.
+    Ignore,
+}
+
+impl FromStr for MismatchPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "refuse" => Ok(Self::Refuse),
+            "read-only" => Ok(Self::ReadOnly),
+            "ignore" => Ok(Self::Ignore),
+            other => Err(format!("unknown schema mismatch policy {}", other)),
+        }
+    }
+}
+
+/// What `db` lacks of the schema this build expects, nothing when it is
+/// compatible.
+pub async fn check(db: &Db) -> Result<Vec<Mismatch>, DbError> {
+    let columns = db.schema_columns().await?;
+    let applied = db.applied_migrations().await?;
+    Ok(compare(&columns, &applied))
+}
+
+/// Compares the columns and applied migration versions of a database with
+/// the ones the build expects.
+pub fn compare(columns: &[SchemaColumn], applied: &[String]) -> Vec<Mismatch> {
+    let mut mismatches = Vec::new();
+    for &(table, column, udt, nullable) in EXPECTED_COLUMNS {
+        let found = columns
+            .iter()
+            .find(|found| found.table == table && found.column == column);
+        let Some(found) = found else {
+            mismatches.push(Mismatch::MissingColumn {
+                table: table.to_string(),
+                column: column.to_string(),
+            });
+            continue;
+        };
+        if found.udt != udt {
+            mismatches.push(Mismatch::ColumnType {
+                table: table.to_string(),
+                column: column.to_string(),
+                expected: udt.to_string(),
+                found: found.udt.clone(),
+            });
+        } else if found.nullable != nullable {
+            mismatches.push(Mismatch::Nullability {
+                table: table.to_string(),
+                column: column.to_string(),
+                nullable: found.nullable,
+            });
+        }
+    }
+    for version in MIGRATIONS {
+        if !applied.iter().any(|applied| applied == version) {
+            mismatches.push(Mismatch::PendingMigration(version.to_string()));
+        }
+    }
+    mismatches
+}
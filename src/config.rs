@@ -0,0 +1,1489 @@
+use std::{
+    collections::HashMap, env, fs, net::SocketAddr, path::PathBuf, str::FromStr, time::Duration,
+};
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::compat::MismatchPolicy;
+use crate::crypt::EncryptionKey;
+use crate::ip_filter::Cidr;
+use crate::startup::Retry;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Invalid value for {key}: {value:?}")]
+    Invalid { key: &'static str, value: String },
+    #[error("Config file error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Config file parse error: {0}")]
+    Toml(#[from] toml::de::Error),
+}
+
+type ConfigResult<T> = Result<T, ConfigError>;
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Runtime worker threads, one per core when unset.
+    pub worker_threads: Option<usize>,
+    /// Upper bound of the runtime's blocking thread pool.
+    pub max_blocking_threads: usize,
+    /// Name of the runtime's threads.
+    pub thread_name: String,
+    /// Depth of the per-stream reply and DB write channels.
+    pub stream_channel_depth: usize,
+    /// Greetings buffered for live subscribers, per the slowest one.
+    pub broadcast_capacity: usize,
+    /// What happens to greetings broadcast while the buffer is full.
+    pub broadcast_overflow: OverflowPolicy,
+    /// Channels live subscribers are spread over, each fed by a worker of
+    /// its own, one per core when unset.
+    pub broadcast_shards: Option<usize>,
+    /// Bytes of replies a `ListMessagesStream` subscriber may leave unread
+    /// before `stream_buffer_policy` applies, 0 for no limit.
+    pub stream_buffer_bytes: usize,
+    pub stream_buffer_policy: SlowSubscriberPolicy,
+    /// Milliseconds a `ListMessagesStream` holds a burst of replies back to
+    /// send them together, 0 sends them as they come.
+    pub stream_coalesce_ms: u64,
+    /// Replies held back at most, sent right away once that many wait.
+    pub stream_coalesce_max: usize,
+    /// Names greeted by `SayHelloStream` calls waiting to be stored, all
+    /// calls together.
+    pub stream_write_queue: usize,
+    /// Names stored at once, per tenant.
+    pub stream_write_batch: usize,
+    /// What a `SayHelloStream` does once `stream_write_queue` is full.
+    pub stream_write_overflow: WriteOverflowPolicy,
+    /// Keep greetings no live subscriber got, overflow and shutdown
+    /// included, and replay them to the next `ListMessagesStream` call.
+    pub durable_delivery: bool,
+    /// Default heartbeat interval on `ListMessagesStream`, 0 disables it.
+    pub heartbeat_interval_secs: u64,
+    /// How long a `ListMessagesStream` reply waits for its ack before it is
+    /// sent again, for streams of a named subscriber.
+    pub ack_timeout_ms: u64,
+    /// Replies a named subscriber may leave unacked before its stream fails
+    /// with RESOURCE_EXHAUSTED.
+    pub max_unacked_replies: usize,
+    /// Unacknowledged replies allowed on `SayHelloStream`, 0 disables acks.
+    /// Names past the window wait for acks, up to another window of them.
+    pub stream_ack_window: u64,
+    /// Default delay between `SayHelloMany` replies.
+    pub say_hello_many_delay_ms: u64,
+    /// Seconds within which `SayHello` greets a name only once, calls for the
+    /// same name get the first reply back marked `cached`. 0 disables it.
+    pub greeting_cooldown_secs: u64,
+    /// Listen address of the WebSocket feed (`websocket` feature).
+    pub ws_addr: SocketAddr,
+    /// Listen address of the web dashboard (`dashboard` feature).
+    pub dashboard_addr: SocketAddr,
+    /// Listen address of the HTTP/JSON gateway (`transcoding` feature).
+    pub http_addr: SocketAddr,
+    /// Listen address of the GraphQL endpoint (`graphql` feature).
+    pub graphql_addr: SocketAddr,
+    /// Default rows per `ExportMessages` chunk.
+    pub export_batch_size: u32,
+    /// Largest `ListMessages` reply in bytes, bigger results have to be
+    /// fetched with `ListMessagesChunked` in chunks of this size. 0 disables
+    /// the limit.
+    pub list_reply_max_bytes: usize,
+    /// Longest message accepted by `ImportMessages`, in characters.
+    pub import_max_message_len: usize,
+    /// Largest backup `RestoreBackup` accepts, in bytes once decompressed.
+    pub restore_max_bytes: usize,
+    /// Largest payload a message may carry, in bytes. Payloads bigger than
+    /// the gRPC message limit have to be sent with `UploadPayload`.
+    pub payload_max_bytes: usize,
+    /// S3 bucket message attachments are stored in (`attachments` feature),
+    /// see `attachments::Attachments`. Attachments are refused when unset.
+    pub attachments_bucket: Option<String>,
+    /// Endpoint of an S3-compatible object store, e.g. `http://minio:9000`,
+    /// AWS S3 when unset. Credentials are read from the `AWS_*` env vars.
+    pub attachments_endpoint: Option<String>,
+    pub attachments_region: String,
+    /// Largest attachment `UploadAttachment` accepts, in bytes.
+    pub attachment_max_bytes: u64,
+    /// How long the presigned URLs of `GetAttachment` stay valid.
+    pub attachment_url_ttl_secs: u64,
+    /// Kafka bootstrap servers, greetings are only published when set
+    /// (`kafka` feature).
+    pub kafka_brokers: Option<String>,
+    /// Kafka topic greeting events are published to.
+    pub kafka_topic: String,
+    /// Delay between outbox relay rounds once the outbox is drained.
+    pub outbox_poll_interval_ms: u64,
+    /// Delay between event log polls of a following `StreamEvents` call.
+    pub events_poll_interval_ms: u64,
+    /// Delay between maintenance rounds of the monthly `messages`
+    /// partitions, 0 disables the maintenance.
+    pub partition_maintenance_secs: u64,
+    /// Months after the current one partitions are created ahead for.
+    pub partition_months_ahead: u16,
+    /// Months before the current one whose partitions are kept, older ones
+    /// are dropped with their messages. 0 keeps every partition.
+    pub message_retention_months: u16,
+    /// Delay between database health probes, 0 disables them.
+    pub db_health_interval_ms: u64,
+    /// Delay between recounts of the greeting statistics, 0 disables them.
+    pub stats_refresh_secs: u64,
+    /// Delay between syncs of the leaderboard counts with the store.
+    pub leaderboard_sync_ms: u64,
+    /// Delay between expiry checks of the loaded certificates, 0 disables
+    /// the expiry warnings.
+    pub cert_check_interval_secs: u64,
+    /// Delay between leader election rounds of the background tasks that
+    /// run on one replica only, see `leader::lead`. 0 runs them on every
+    /// replica.
+    pub leader_check_interval_ms: u64,
+    /// Delay before the first retry of a dependency not ready at startup,
+    /// doubled after every other retry.
+    pub startup_retry_initial_ms: u64,
+    /// Longest delay between two retries of a dependency at startup.
+    pub startup_retry_max_ms: u64,
+    /// How long startup waits for a dependency before the server exits, 0
+    /// gives up on the first failure.
+    pub startup_max_wait_secs: u64,
+    /// Postgres `statement_timeout` of the pooled connections, 0 keeps the
+    /// server's.
+    pub statement_timeout_ms: u64,
+    /// How long feature flags are cached before they are read again.
+    pub feature_flag_ttl_ms: u64,
+    /// Dimension of the embeddings of the default `HashingEmbedder`
+    /// (`pgvector` feature).
+    pub embedding_dimensions: usize,
+    /// Reflection protocol versions served.
+    pub reflection_versions: ReflectionVersions,
+    /// Set TCP_NODELAY on accepted gRPC connections.
+    pub tcp_nodelay: bool,
+    /// Bind the gRPC listener with SO_REUSEPORT.
+    pub so_reuseport: bool,
+    /// gRPC listeners bound to the same address, each with its own accept
+    /// loop. More than one implies `so_reuseport`.
+    pub listeners: usize,
+    /// Expect a PROXY protocol header on gRPC connections from
+    /// `trusted_proxies`, for deployments behind a load balancer.
+    pub proxy_protocol: bool,
+    /// Address blocks of the load balancers whose PROXY header is trusted,
+    /// required with `proxy_protocol`.
+    pub trusted_proxies: Vec<Cidr>,
+    /// TLS handshakes in progress at once on the gRPC listeners, 0 for no
+    /// limit.
+    pub max_concurrent_handshakes: usize,
+    /// Time a TLS handshake may take before the connection is dropped, 0
+    /// for no limit.
+    pub handshake_timeout_ms: u64,
+    /// Failed TLS handshakes within `handshake_ban_window_secs` that get a
+    /// client address banned for `handshake_ban_secs`, 0 never bans.
+    pub handshake_ban_failures: u32,
+    pub handshake_ban_window_secs: u64,
+    pub handshake_ban_secs: u64,
+    /// Address blocks gRPC calls are only taken from, any when empty, see
+    /// `ip_filter`.
+    pub ip_allow: Vec<Cidr>,
+    /// Address blocks gRPC calls are refused from.
+    pub ip_deny: Vec<Cidr>,
+    /// Address blocks of the frontends allowed to name the tenant of a call,
+    /// see `tenant::TENANT_METADATA`. Calls from anywhere else are made for
+    /// the default tenant.
+    pub tenant_frontends: Vec<Cidr>,
+    /// MaxMind GeoIP2 or GeoLite2 Country (or City) database the country of
+    /// client addresses is looked up in, see `geoip`. Needs the `geoip`
+    /// feature.
+    pub geoip_country_db: Option<PathBuf>,
+    /// MaxMind ASN database the autonomous system of client addresses is
+    /// looked up in.
+    pub geoip_asn_db: Option<PathBuf>,
+    /// Master key the stored message texts are encrypted with, see
+    /// `Db::with_encryption`. The name statistics of `GetStats` only see
+    /// messages stored unencrypted.
+    pub message_encryption_key: Option<EncryptionKey>,
+    /// Keep the names greeted out of the logs, the bind values of slow
+    /// queries included.
+    pub redact_logs: bool,
+    /// Queries slower than this are logged with their SQL, 0 disables it.
+    pub slow_query_ms: u64,
+    /// RPCs slower than this to respond are logged, 0 disables it.
+    pub slow_rpc_ms: u64,
+    /// Share of successful calls written to the access log, per method.
+    /// `access_log_sampling` of the config file overrides it, also on reload.
+    pub access_log_sampling: AccessLogSampling,
+    /// Faults injected into calls (`chaos` feature).
+    pub chaos: Chaos,
+    /// Secondary backend unary calls are mirrored to, e.g.
+    /// `http://canary:50051`, see `mirror::MirrorLayer`. Nothing is mirrored
+    /// when unset.
+    pub mirror_addr: Option<String>,
+    /// Percentage of the unary calls mirrored to `mirror_addr`.
+    pub mirror_percent: f64,
+    /// Messages and bytes each tenant may store. `tenant_quotas` of the
+    /// config file overrides it, also on reload.
+    pub tenant_quotas: TenantQuotas,
+    /// Sinks notified about broadcast messages (`notifications` feature).
+    pub notifications: Vec<NotificationSink>,
+    /// Checks names go through before they are greeted, the `[moderation]`
+    /// table of the config file. Nothing is moderated by default.
+    pub moderation: ModerationConfig,
+    /// Policy calls are authorized by, the `[authz]` table of the config
+    /// file. Every call is allowed by default.
+    pub authz: AuthzConfig,
+    /// Short-lived tokens exchanged for client certificates and API keys,
+    /// the `[tokens]` table of the config file, see `tokens`.
+    pub tokens: TokensConfig,
+    /// gRPC services handlers call, by name, the `[upstreams.<name>]` tables
+    /// of the config file. See `clients::Clients`.
+    pub upstreams: HashMap<String, UpstreamConfig>,
+    /// Concurrency limits by method or service, the
+    /// `[method_limits."<route>"]` tables of the config file. See `limits`.
+    pub method_limits: HashMap<String, MethodLimit>,
+    /// What makes a call `batch` rather than interactive, the
+    /// `[caller_classes]` table of the config file.
+    pub caller_classes: CallerClassesConfig,
+    /// Concurrency budgets by caller class, the `[class_limits.<class>]`
+    /// tables of the config file. Classes without one aren't limited.
+    pub class_limits: HashMap<CallerClass, MethodLimit>,
+    /// Upstream among `upstreams` greetings are translated by, see
+    /// `translate::Translator`. Greetings aren't translated when unset.
+    pub translation_upstream: Option<String>,
+    /// How long a translated salutation is reused.
+    pub translation_cache_secs: u64,
+    /// Starts the server refusing greetings, see `read_only::ReadOnly`.
+    /// `read_only` of the config file overrides it, also on reload.
+    pub read_only: bool,
+    /// What a database lacking the schema this build expects does to the
+    /// startup, see `compat::check`.
+    pub schema_mismatch: MismatchPolicy,
+    /// Format of the TLS identity in `tls/` (`tls` feature), see
+    /// `identity::load`.
+    pub tls_format: IdentityFormat,
+    /// Password of a PKCS#12 bundle, empty when it has none.
+    pub tls_pkcs12_password: String,
+    /// Passphrase of an encrypted `server.key`.
+    pub tls_key_passphrase: Option<String>,
+    /// File holding the passphrase of an encrypted `server.key`, such as a
+    /// mounted secret, read when the identity is. `tls_key_passphrase` wins.
+    pub tls_key_passphrase_file: Option<PathBuf>,
+    /// CRL file of the client CA, whose revoked client certificates are
+    /// refused with mutual TLS (`tls` feature), see `revocation`.
+    pub tls_crl_file: Option<PathBuf>,
+    /// Delay between checks of `tls_crl_file` for changes, 0 never reads it
+    /// again.
+    pub crl_reload_secs: u64,
+    /// Which routes take calls without a client certificate when a client
+    /// CA is set (`tls` feature). Unset, every route requires one.
+    pub client_auth: Option<ClientAuthPolicy>,
+}
+
+/// How the files of the TLS identity are read: `pem` and `der` for
+/// `server.pem` and `server.key`, `pkcs12` for a `server.p12` bundle.
+/// `auto` tells PEM from DER by the content and reads the bundle when
+/// there is no `server.pem`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IdentityFormat {
+    #[default]
+    Auto,
+    Pem,
+    Der,
+    Pkcs12,
+}
+
+impl FromStr for IdentityFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "auto" => Ok(Self::Auto),
+            "pem" => Ok(Self::Pem),
+            "der" => Ok(Self::Der),
+            "pkcs12" | "p12" => Ok(Self::Pkcs12),
+            other => Err(format!("unknown identity format {}", other)),
+        }
+    }
+}
+
+/// What a broadcast does once the slowest subscriber is `broadcast_capacity`
+/// greetings behind: `drop-oldest` makes that subscriber skip the oldest
+/// ones, `drop-newest` drops the new greeting for every subscriber, `block`
+/// waits for the subscriber to catch up, holding up the call broadcasting.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    #[default]
+    DropOldest,
+    DropNewest,
+    Block,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "drop-oldest" => Ok(Self::DropOldest),
+            "drop-newest" => Ok(Self::DropNewest),
+            "block" => Ok(Self::Block),
+            other => Err(format!("unknown overflow policy {}", other)),
+        }
+    }
+}
+
+/// What happens to a `ListMessagesStream` subscriber leaving more than
+/// `stream_buffer_bytes` of replies unread: `disconnect` ends its stream
+/// with `RESOURCE_EXHAUSTED`, `pause` stops sending it greetings until it
+/// read half of them, then sends what it missed from the store.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SlowSubscriberPolicy {
+    #[default]
+    Disconnect,
+    Pause,
+}
+
+impl FromStr for SlowSubscriberPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "disconnect" => Ok(Self::Disconnect),
+            "pause" => Ok(Self::Pause),
+            other => Err(format!("unknown slow subscriber policy {}", other)),
+        }
+    }
+}
+
+/// What a `SayHelloStream` call does with a name once `stream_write_queue`
+/// names wait to be stored: `block` waits for room, holding up the replies
+/// that follow, `reject` ends the call with `UNAVAILABLE` so that replies
+/// keep their pace while the database is slow.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WriteOverflowPolicy {
+    #[default]
+    Block,
+    Reject,
+}
+
+impl FromStr for WriteOverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "block" => Ok(Self::Block),
+            "reject" => Ok(Self::Reject),
+            other => Err(format!("unknown write overflow policy {}", other)),
+        }
+    }
+}
+
+/// gRPC reflection versions to serve, parsed from a comma separated list such
+/// as `v1,v1alpha`. Some clients (older grpcurl among others) only speak one.
+#[derive(Clone, Copy, Debug)]
+pub struct ReflectionVersions {
+    pub v1: bool,
+    pub v1alpha: bool,
+}
+
+impl FromStr for ReflectionVersions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut versions = Self {
+            v1: false,
+            v1alpha: false,
+        };
+        for version in s.split(',').map(str::trim).filter(|v| !v.is_empty()) {
+            match version {
+                "v1" => versions.v1 = true,
+                "v1alpha" => versions.v1alpha = true,
+                other => return Err(format!("unknown reflection version {}", other)),
+            }
+        }
+        Ok(versions)
+    }
+}
+
+/// Access log sample rates between 0 and 1, parsed from a comma separated
+/// list of `Method=rate` pairs such as `SayHello=0.01,*=1`, where `*` sets
+/// the rate of the methods not listed.
+#[derive(Clone, Debug)]
+pub struct AccessLogSampling {
+    pub rules: Vec<(String, f64)>,
+    pub default: f64,
+}
+
+impl FromStr for AccessLogSampling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut sampling = Self {
+            rules: Vec::new(),
+            default: 1.0,
+        };
+        for rule in s.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+            let (method, rate) = rule
+                .split_once('=')
+                .ok_or_else(|| format!("missing rate in {}", rule))?;
+            let rate: f64 = rate
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid rate in {}", rule))?;
+            if !(0.0..=1.0).contains(&rate) {
+                return Err(format!("rate out of range in {}", rule));
+            }
+            match method.trim() {
+                "*" => sampling.default = rate,
+                method => sampling.rules.push((method.to_string(), rate)),
+            }
+        }
+        Ok(sampling)
+    }
+}
+
+/// Whether a route needs a client certificate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClientAuth {
+    Required,
+    Optional,
+}
+
+/// Client certificate requirements by route, parsed from a comma separated
+/// list of `route=required` or `route=optional` pairs such as
+/// `grpc.health.v1.Health=optional,helloworld.Greeter=required`. A route is
+/// a service or one of its methods, `helloworld.Greeter/SayHello`, the
+/// method's pair winning. `*` sets the requirement of the routes not
+/// listed, `required` by default.
+#[derive(Clone, Debug)]
+pub struct ClientAuthPolicy {
+    pub rules: Vec<(String, ClientAuth)>,
+    pub default: ClientAuth,
+}
+
+impl ClientAuthPolicy {
+    /// The requirement of the call to `path`, `/package.Service/Method`.
+    pub fn for_path(&self, path: &str) -> ClientAuth {
+        let method = path.trim_start_matches('/');
+        let service = method
+            .split_once('/')
+            .map_or(method, |(service, _)| service);
+        let rule = |route: &str| {
+            self.rules
+                .iter()
+                .find(|(name, _)| name == route)
+                .map(|(_, auth)| *auth)
+        };
+        rule(method)
+            .or_else(|| rule(service))
+            .unwrap_or(self.default)
+    }
+}
+
+impl FromStr for ClientAuthPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut policy = Self {
+            rules: Vec::new(),
+            default: ClientAuth::Required,
+        };
+        for rule in s.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+            let (route, auth) = rule
+                .split_once('=')
+                .ok_or_else(|| format!("missing requirement in {}", rule))?;
+            let auth = match auth.trim() {
+                "required" => ClientAuth::Required,
+                "optional" => ClientAuth::Optional,
+                other => return Err(format!("unknown requirement {} in {}", other, rule)),
+            };
+            match route.trim().trim_start_matches('/') {
+                "*" => policy.default = auth,
+                route => policy.rules.push((route.to_string(), auth)),
+            }
+        }
+        Ok(policy)
+    }
+}
+
+/// Faults to inject into calls, parsed from a comma separated list of
+/// `key=value` pairs such as `latency_ms=100,unavailable=0.1`:
+///
+/// - `latency_ms`, `jitter_ms`: delay added before every call, plus a random
+///   share of the jitter.
+/// - `unavailable`: share of calls failing with `UNAVAILABLE` up front.
+/// - `disconnect`: share of calls whose response is cut off after
+///   `disconnect_after` messages (1 by default).
+#[derive(Clone, Copy, Debug)]
+pub struct Chaos {
+    pub latency_ms: u64,
+    pub jitter_ms: u64,
+    pub unavailable: f64,
+    pub disconnect: f64,
+    pub disconnect_after: u64,
+}
+
+impl Default for Chaos {
+    fn default() -> Self {
+        Self {
+            latency_ms: 0,
+            jitter_ms: 0,
+            unavailable: 0.0,
+            disconnect: 0.0,
+            disconnect_after: 1,
+        }
+    }
+}
+
+impl FromStr for Chaos {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chaos = Self::default();
+        for fault in s.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            let (key, value) = fault
+                .split_once('=')
+                .ok_or_else(|| format!("missing value in {}", fault))?;
+            let invalid = || format!("invalid value in {}", fault);
+            let value = value.trim();
+            match key.trim() {
+                "latency_ms" => chaos.latency_ms = value.parse().map_err(|_| invalid())?,
+                "jitter_ms" => chaos.jitter_ms = value.parse().map_err(|_| invalid())?,
+                "disconnect_after" => {
+                    chaos.disconnect_after = value.parse().map_err(|_| invalid())?
+                }
+                "unavailable" | "disconnect" => {
+                    let rate: f64 = value.parse().map_err(|_| invalid())?;
+                    if !(0.0..=1.0).contains(&rate) {
+                        return Err(format!("rate out of range in {}", fault));
+                    }
+                    if key.trim() == "unavailable" {
+                        chaos.unavailable = rate;
+                    } else {
+                        chaos.disconnect = rate;
+                    }
+                }
+                other => return Err(format!("unknown fault {}", other)),
+            }
+        }
+        Ok(chaos)
+    }
+}
+
+/// Storage quotas per tenant, parsed from a comma separated list of
+/// `tenant=messages:bytes` pairs such as `acme=10000:1048576,*=100:`, where
+/// `*` sets the quota of the tenants not listed and an empty side is
+/// unlimited.
+#[derive(Clone, Debug, Default)]
+pub struct TenantQuotas {
+    pub rules: Vec<(String, Quota)>,
+    pub default: Quota,
+}
+
+impl TenantQuotas {
+    pub fn quota(&self, tenant: &str) -> Quota {
+        self.rules
+            .iter()
+            .find(|(name, _)| name == tenant)
+            .map_or(self.default, |(_, quota)| *quota)
+    }
+}
+
+impl FromStr for TenantQuotas {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut quotas = Self::default();
+        for rule in s.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+            let (tenant, quota) = rule
+                .split_once('=')
+                .ok_or_else(|| format!("missing quota in {}", rule))?;
+            let (messages, bytes) = quota
+                .split_once(':')
+                .ok_or_else(|| format!("missing bytes in {}", rule))?;
+            let limit = |value: &str| match value.trim() {
+                "" => Ok(None),
+                value => value
+                    .parse()
+                    .map(Some)
+                    .map_err(|_| format!("invalid quota in {}", rule)),
+            };
+            let quota = Quota {
+                max_messages: limit(messages)?,
+                max_bytes: limit(bytes)?,
+            };
+            match tenant.trim() {
+                "*" => quotas.default = quota,
+                tenant => quotas.rules.push((tenant.to_string(), quota)),
+            }
+        }
+        Ok(quotas)
+    }
+}
+
+/// Limits on what a tenant stores, `None` is unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Quota {
+    pub max_messages: Option<u64>,
+    pub max_bytes: Option<u64>,
+}
+
+/// Settings that don't fit in env vars, or change without a restart (see
+/// `reload`), read from the TOML file named by `CONFIG_FILE`.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct FileConfig {
+    notifications: Vec<NotificationSink>,
+    moderation: Option<ModerationConfig>,
+    authz: Option<AuthzConfig>,
+    tokens: Option<TokensConfig>,
+    read_only: Option<bool>,
+    tenant_quotas: Option<String>,
+    access_log_sampling: Option<String>,
+    upstreams: HashMap<String, UpstreamConfig>,
+    method_limits: HashMap<String, MethodLimit>,
+    caller_classes: Option<CallerClassesConfig>,
+    class_limits: HashMap<CallerClass, MethodLimit>,
+}
+
+/// A `[[notifications]]` table of the config file.
+#[derive(Deserialize, Clone, Debug)]
+pub struct NotificationSink {
+    #[serde(flatten)]
+    pub target: NotificationTarget,
+    /// Regex a message has to match to be sent, every message when unset.
+    pub filter: Option<String>,
+    /// Minimum delay between two notifications, messages in between are
+    /// dropped.
+    #[serde(default)]
+    pub min_interval_secs: u64,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum NotificationTarget {
+    Slack {
+        webhook_url: String,
+    },
+    Smtp {
+        relay: String,
+        from: String,
+        to: String,
+        username: Option<String>,
+        password: Option<String>,
+    },
+}
+
+/// The `[moderation]` table of the config file, see `moderation::Moderation`.
+/// The checks run in the order of the fields.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ModerationConfig {
+    /// Longest name accepted, in characters, 0 accepts any length.
+    pub max_name_chars: usize,
+    /// Longest run of one character repeated a name may contain, 0 allows
+    /// any.
+    pub max_repeated_chars: usize,
+    /// Regexes of what names must not contain.
+    pub deny: Vec<String>,
+    /// What happens to names matching `deny`.
+    pub deny_action: DenyAction,
+    /// Endpoint names are posted to for a verdict (`moderation-api`
+    /// feature).
+    pub api_url: Option<String>,
+    /// How long the endpoint has to answer before the name is let through.
+    pub api_timeout_ms: u64,
+}
+
+impl Default for ModerationConfig {
+    fn default() -> Self {
+        Self {
+            max_name_chars: 0,
+            max_repeated_chars: 0,
+            deny: Vec::new(),
+            deny_action: DenyAction::Reject,
+            api_url: None,
+            api_timeout_ms: 2000,
+        }
+    }
+}
+
+/// The `[authz]` table of the config file, see `authz`. Takes one of
+/// `opa_url` and `policy_file`, and optionally an `identity_provider`.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct AuthzConfig {
+    /// Data API URL of the rule of an OPA sidecar deciding on calls, e.g.
+    /// `http://localhost:8181/v1/data/helloworld/allow` (`opa` feature).
+    pub opa_url: Option<String>,
+    /// Rego policy evaluated in process (`rego` feature).
+    pub policy_file: Option<PathBuf>,
+    /// Rule of `policy_file` deciding on calls.
+    pub query: String,
+    /// How long the sidecar and the identity provider have to answer.
+    pub timeout_ms: u64,
+    /// Lets calls through when the policy can't be evaluated, instead of
+    /// failing them with `UNAVAILABLE`.
+    pub fail_open: bool,
+    /// Who authenticates the callers, see `authn`. Calls aren't
+    /// authenticated when unset.
+    pub identity_provider: Option<IdentityProviderConfig>,
+    /// Roles of the service by group of the identity provider, the
+    /// `[authz.roles]` table, e.g. `admins = ["admin", "greeter"]`.
+    pub roles: HashMap<String, Vec<String>>,
+    /// Routes taking calls without credentials, services or methods such
+    /// as `helloworld.Greeter/GetStats`.
+    pub anonymous: Vec<String>,
+    /// How long the identity of a caller is reused before the provider is
+    /// asked again.
+    pub identity_cache_secs: u64,
+}
+
+impl Default for AuthzConfig {
+    fn default() -> Self {
+        Self {
+            opa_url: None,
+            policy_file: None,
+            query: "data.helloworld.allow".to_string(),
+            timeout_ms: 500,
+            fail_open: false,
+            identity_provider: None,
+            roles: HashMap::new(),
+            anonymous: vec![
+                "grpc.health.v1.Health".to_string(),
+                "grpc.reflection.v1.ServerReflection".to_string(),
+                "grpc.reflection.v1alpha.ServerReflection".to_string(),
+                "helloworld.Tokens".to_string(),
+            ],
+            identity_cache_secs: 60,
+        }
+    }
+}
+
+/// The `[authz.identity_provider]` table of the config file.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum IdentityProviderConfig {
+    /// Bearer tokens introspected at the provider of `issuer` (`oidc`
+    /// feature).
+    Oidc {
+        issuer: String,
+        client_id: String,
+        client_secret: String,
+        /// Claim of the introspection response listing the groups.
+        #[serde(default = "IdentityProviderConfig::default_groups_claim")]
+        groups_claim: String,
+    },
+    /// Basic credentials bound with at a directory (`ldap` feature).
+    Ldap {
+        /// e.g. `ldaps://ldap.example.org`.
+        url: String,
+        /// DN bound as, `{username}` replaced, e.g.
+        /// `uid={username},ou=people,dc=example,dc=org`.
+        user_dn: String,
+        /// Where groups are searched.
+        group_base: String,
+        /// Filter of the groups of the user, `{dn}` replaced.
+        #[serde(default = "IdentityProviderConfig::default_group_filter")]
+        group_filter: String,
+        /// Attribute naming a group.
+        #[serde(default = "IdentityProviderConfig::default_group_attribute")]
+        group_attribute: String,
+    },
+}
+
+impl IdentityProviderConfig {
+    fn default_groups_claim() -> String {
+        "groups".to_string()
+    }
+
+    fn default_group_filter() -> String {
+        "(member={dn})".to_string()
+    }
+
+    fn default_group_attribute() -> String {
+        "cn".to_string()
+    }
+}
+
+/// The `[tokens]` table of the config file, see `tokens`.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TokensConfig {
+    /// Serves `Tokens` and takes its tokens as bearer tokens.
+    pub enabled: bool,
+    /// How long a token is valid.
+    pub ttl_secs: u64,
+    /// How long a refresh token is valid, 0 issues none.
+    pub refresh_ttl_secs: u64,
+    /// How long the revocation of a subject's sessions through another
+    /// replica may go unnoticed.
+    pub revocation_cache_secs: u64,
+    /// How often the signing key changes.
+    pub rotation_secs: u64,
+    /// What the signing keys are derived from, shared by the replicas. A
+    /// random one per process when unset.
+    pub secret: Option<String>,
+    /// `iss` of the tokens.
+    pub issuer: String,
+    /// API keys exchanged for tokens by name, the `[tokens.api_keys.<name>]`
+    /// tables. The name is the subject of their tokens.
+    pub api_keys: HashMap<String, ApiKey>,
+}
+
+impl Default for TokensConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ttl_secs: 300,
+            refresh_ttl_secs: 86_400,
+            revocation_cache_secs: 5,
+            rotation_secs: 3600,
+            secret: None,
+            issuer: "tonic-hello-tls".to_string(),
+            api_keys: HashMap::new(),
+        }
+    }
+}
+
+/// An `[tokens.api_keys.<name>]` table of the config file.
+#[derive(Deserialize, Clone, Debug)]
+pub struct ApiKey {
+    /// Hex SHA-256 of the key, which isn't kept as it is.
+    pub sha256: String,
+    /// Groups of the holder, mapped to roles like those of an identity
+    /// provider.
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+/// An `[upstreams.<name>]` table of the config file.
+#[derive(Deserialize, Clone, Debug)]
+pub struct UpstreamConfig {
+    /// Where the service is, e.g. `https://translate:50051`.
+    pub url: String,
+    /// Connections calls are spread over.
+    #[serde(default = "UpstreamConfig::default_connections")]
+    pub connections: usize,
+    /// Deadline of every attempt of a call.
+    #[serde(default = "UpstreamConfig::default_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default = "UpstreamConfig::default_connect_timeout_ms")]
+    pub connect_timeout_ms: u64,
+    /// Times an `UNAVAILABLE` call is attempted again.
+    #[serde(default = "UpstreamConfig::default_retries")]
+    pub retries: u32,
+    /// Delay before the first retry, doubled after every other one.
+    #[serde(default = "UpstreamConfig::default_retry_backoff_ms")]
+    pub retry_backoff_ms: u64,
+    /// PEM file of the CA the service's certificate is checked against, the
+    /// system roots when unset (`tls` feature).
+    pub ca_cert: Option<String>,
+    /// Name the service's certificate is checked for, the host of `url`
+    /// when unset (`tls` feature).
+    pub tls_domain: Option<String>,
+}
+
+impl UpstreamConfig {
+    fn default_connections() -> usize {
+        2
+    }
+
+    fn default_timeout_ms() -> u64 {
+        1000
+    }
+
+    fn default_connect_timeout_ms() -> u64 {
+        1000
+    }
+
+    fn default_retries() -> u32 {
+        2
+    }
+
+    fn default_retry_backoff_ms() -> u64 {
+        50
+    }
+}
+
+/// A `[method_limits."<route>"]` table of the config file.
+#[derive(Deserialize, Clone, Debug)]
+pub struct MethodLimit {
+    /// Calls in progress at once, the most an adaptive limit goes to.
+    pub max_concurrent: usize,
+    /// Calls waiting for one in progress to end, more are refused.
+    #[serde(default)]
+    pub max_queued: usize,
+    /// How long a call waits before it is refused.
+    #[serde(default = "MethodLimit::default_queue_timeout_ms")]
+    pub queue_timeout_ms: u64,
+    /// Follows the latency of the calls, see `limits::AdaptiveLimit`.
+    #[serde(default)]
+    pub adaptive: bool,
+    /// The least an adaptive limit goes to.
+    #[serde(default = "MethodLimit::default_min_concurrent")]
+    pub min_concurrent: usize,
+    /// How many times the usual latency a call may take before an adaptive
+    /// limit backs off.
+    #[serde(default = "MethodLimit::default_latency_tolerance")]
+    pub latency_tolerance: f64,
+    /// What an adaptive limit is multiplied by when backing off.
+    #[serde(default = "MethodLimit::default_backoff")]
+    pub backoff: f64,
+}
+
+impl MethodLimit {
+    fn default_queue_timeout_ms() -> u64 {
+        1000
+    }
+
+    fn default_min_concurrent() -> usize {
+        1
+    }
+
+    fn default_latency_tolerance() -> f64 {
+        2.0
+    }
+
+    fn default_backoff() -> f64 {
+        0.9
+    }
+}
+
+/// Who a call is made for, see `limits`.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum CallerClass {
+    /// Someone waiting on the reply.
+    Interactive,
+    /// Bulk work, which can wait.
+    Batch,
+}
+
+impl CallerClass {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Interactive => "interactive",
+            Self::Batch => "batch",
+        }
+    }
+}
+
+/// The `[caller_classes]` table of the config file.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct CallerClassesConfig {
+    /// Methods whose calls are `batch`, e.g.
+    /// `helloworld.Greeter/ImportMessages`.
+    pub batch_methods: Vec<String>,
+    /// Roles of callers whose calls are `batch`, see `authn`.
+    pub batch_roles: Vec<String>,
+}
+
+impl Default for CallerClassesConfig {
+    fn default() -> Self {
+        Self {
+            batch_methods: vec![
+                "helloworld.Greeter/ImportMessages".to_string(),
+                "helloworld.Greeter/ExportMessages".to_string(),
+                "helloworld.Greeter/DeleteAllForName".to_string(),
+                "helloworld.Admin/CreateBackup".to_string(),
+                "helloworld.Admin/RestoreBackup".to_string(),
+            ],
+            batch_roles: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DenyAction {
+    /// Refuse to greet the name.
+    #[default]
+    Reject,
+    /// Greet the name with what matched starred out.
+    Mask,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            worker_threads: None,
+            max_blocking_threads: 512,
+            thread_name: "greeter-worker".to_string(),
+            stream_channel_depth: 128,
+            broadcast_capacity: 16,
+            broadcast_overflow: OverflowPolicy::DropOldest,
+            broadcast_shards: None,
+            stream_buffer_bytes: 4 << 20,
+            stream_buffer_policy: SlowSubscriberPolicy::Disconnect,
+            stream_coalesce_ms: 0,
+            stream_coalesce_max: 64,
+            stream_write_queue: 4096,
+            stream_write_batch: 64,
+            stream_write_overflow: WriteOverflowPolicy::Block,
+            durable_delivery: false,
+            heartbeat_interval_secs: 0,
+            ack_timeout_ms: 10_000,
+            max_unacked_replies: 1024,
+            stream_ack_window: 0,
+            say_hello_many_delay_ms: 0,
+            greeting_cooldown_secs: 0,
+            ws_addr: "[::0]:8080".parse().unwrap(),
+            dashboard_addr: "[::0]:8081".parse().unwrap(),
+            http_addr: "[::0]:8082".parse().unwrap(),
+            graphql_addr: "[::0]:8083".parse().unwrap(),
+            export_batch_size: 1000,
+            // the default limit of tonic clients, bigger replies fail there
+            list_reply_max_bytes: 4 * 1024 * 1024,
+            import_max_message_len: 1024,
+            restore_max_bytes: 256 * 1024 * 1024,
+            payload_max_bytes: 16 * 1024 * 1024,
+            attachments_bucket: None,
+            attachments_endpoint: None,
+            attachments_region: "us-east-1".to_string(),
+            attachment_max_bytes: 1024 * 1024 * 1024,
+            attachment_url_ttl_secs: 900,
+            kafka_brokers: None,
+            kafka_topic: "greetings".to_string(),
+            outbox_poll_interval_ms: 1000,
+            events_poll_interval_ms: 1000,
+            partition_maintenance_secs: 3600,
+            partition_months_ahead: 2,
+            message_retention_months: 0,
+            db_health_interval_ms: 5000,
+            stats_refresh_secs: 60,
+            leaderboard_sync_ms: 5000,
+            cert_check_interval_secs: 3600,
+            leader_check_interval_ms: 5000,
+            startup_retry_initial_ms: 250,
+            startup_retry_max_ms: 5000,
+            startup_max_wait_secs: 60,
+            statement_timeout_ms: 30_000,
+            feature_flag_ttl_ms: 10_000,
+            embedding_dimensions: 256,
+            reflection_versions: ReflectionVersions {
+                v1: true,
+                v1alpha: true,
+            },
+            tcp_nodelay: true,
+            so_reuseport: false,
+            listeners: 1,
+            proxy_protocol: false,
+            trusted_proxies: Vec::new(),
+            max_concurrent_handshakes: 512,
+            handshake_timeout_ms: 10_000,
+            handshake_ban_failures: 20,
+            handshake_ban_window_secs: 60,
+            handshake_ban_secs: 300,
+            ip_allow: Vec::new(),
+            ip_deny: Vec::new(),
+            tenant_frontends: Vec::new(),
+            geoip_country_db: None,
+            geoip_asn_db: None,
+            message_encryption_key: None,
+            redact_logs: false,
+            slow_query_ms: 500,
+            slow_rpc_ms: 1000,
+            access_log_sampling: AccessLogSampling {
+                rules: Vec::new(),
+                default: 1.0,
+            },
+            chaos: Chaos::default(),
+            mirror_addr: None,
+            mirror_percent: 100.0,
+            tenant_quotas: TenantQuotas::default(),
+            notifications: Vec::new(),
+            moderation: ModerationConfig::default(),
+            authz: AuthzConfig::default(),
+            tokens: TokensConfig::default(),
+            upstreams: HashMap::new(),
+            method_limits: HashMap::new(),
+            caller_classes: CallerClassesConfig::default(),
+            class_limits: HashMap::new(),
+            translation_upstream: None,
+            translation_cache_secs: 3600,
+            read_only: false,
+            schema_mismatch: MismatchPolicy::Refuse,
+            tls_format: IdentityFormat::Auto,
+            tls_pkcs12_password: String::new(),
+            tls_key_passphrase: None,
+            tls_key_passphrase_file: None,
+            tls_crl_file: None,
+            crl_reload_secs: 60,
+            client_auth: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> ConfigResult<Self> {
+        let defaults = Self::default();
+
+        // tokio channels panic on a zero capacity
+        let stream_channel_depth = env_or("STREAM_CHANNEL_DEPTH", defaults.stream_channel_depth)?;
+        if stream_channel_depth == 0 {
+            return Err(ConfigError::Invalid {
+                key: "STREAM_CHANNEL_DEPTH",
+                value: stream_channel_depth.to_string(),
+            });
+        }
+
+        let broadcast_capacity = env_or("BROADCAST_CAPACITY", defaults.broadcast_capacity)?;
+        if broadcast_capacity == 0 {
+            return Err(ConfigError::Invalid {
+                key: "BROADCAST_CAPACITY",
+                value: broadcast_capacity.to_string(),
+            });
+        }
+
+        // tokio panics on a channel without room
+        let stream_write_queue = env_or("STREAM_WRITE_QUEUE", defaults.stream_write_queue)?;
+        if stream_write_queue == 0 {
+            return Err(ConfigError::Invalid {
+                key: "STREAM_WRITE_QUEUE",
+                value: stream_write_queue.to_string(),
+            });
+        }
+
+        // a batch can't hold no names
+        let stream_write_batch = env_or("STREAM_WRITE_BATCH", defaults.stream_write_batch)?;
+        if stream_write_batch == 0 {
+            return Err(ConfigError::Invalid {
+                key: "STREAM_WRITE_BATCH",
+                value: stream_write_batch.to_string(),
+            });
+        }
+
+        let broadcast_shards = env_opt::<usize>("BROADCAST_SHARDS")?;
+        if broadcast_shards == Some(0) {
+            return Err(ConfigError::Invalid {
+                key: "BROADCAST_SHARDS",
+                value: "0".to_string(),
+            });
+        }
+
+        // no reply could be sent to a named subscriber
+        let max_unacked_replies = env_or("MAX_UNACKED_REPLIES", defaults.max_unacked_replies)?;
+        if max_unacked_replies == 0 {
+            return Err(ConfigError::Invalid {
+                key: "MAX_UNACKED_REPLIES",
+                value: "0".to_string(),
+            });
+        }
+
+        // a zero period makes tokio intervals panic
+        let ack_timeout_ms = env_or("ACK_TIMEOUT_MS", defaults.ack_timeout_ms)?;
+        if ack_timeout_ms == 0 {
+            return Err(ConfigError::Invalid {
+                key: "ACK_TIMEOUT_MS",
+                value: ack_timeout_ms.to_string(),
+            });
+        }
+
+        // tokio panics on an empty thread pool
+        let worker_threads = env_opt::<usize>("WORKER_THREADS")?;
+        if worker_threads == Some(0) {
+            return Err(ConfigError::Invalid {
+                key: "WORKER_THREADS",
+                value: "0".to_string(),
+            });
+        }
+        let max_blocking_threads = env_or("MAX_BLOCKING_THREADS", defaults.max_blocking_threads)?;
+        if max_blocking_threads == 0 {
+            return Err(ConfigError::Invalid {
+                key: "MAX_BLOCKING_THREADS",
+                value: max_blocking_threads.to_string(),
+            });
+        }
+
+        let listeners = env_or("LISTENERS", defaults.listeners)?;
+        if listeners == 0 {
+            return Err(ConfigError::Invalid {
+                key: "LISTENERS",
+                value: listeners.to_string(),
+            });
+        }
+
+        // without trusted proxies anyone could claim any address in a header
+        let proxy_protocol = env_or("PROXY_PROTOCOL", defaults.proxy_protocol)?;
+        let trusted_proxies: Vec<Cidr> = env_list("TRUSTED_PROXIES")?;
+        if proxy_protocol && trusted_proxies.is_empty() {
+            return Err(ConfigError::Invalid {
+                key: "TRUSTED_PROXIES",
+                value: String::new(),
+            });
+        }
+
+        let mirror_percent: f64 = env_or("MIRROR_PERCENT", defaults.mirror_percent)?;
+        if !(0.0..=100.0).contains(&mirror_percent) {
+            return Err(ConfigError::Invalid {
+                key: "MIRROR_PERCENT",
+                value: mirror_percent.to_string(),
+            });
+        }
+
+        // the error leaves out the value, it's a secret
+        let message_encryption_key = env::var("MESSAGE_ENCRYPTION_KEY")
+            .ok()
+            .map(|value| value.parse())
+            .transpose()
+            .map_err(|_| ConfigError::Invalid {
+                key: "MESSAGE_ENCRYPTION_KEY",
+                value: "<hidden>".to_string(),
+            })?;
+
+        // embeddings without dimensions have no distance
+        let embedding_dimensions = env_or("EMBEDDING_DIMENSIONS", defaults.embedding_dimensions)?;
+        if embedding_dimensions == 0 {
+            return Err(ConfigError::Invalid {
+                key: "EMBEDDING_DIMENSIONS",
+                value: embedding_dimensions.to_string(),
+            });
+        }
+
+        Ok(Self {
+            worker_threads,
+            max_blocking_threads,
+            thread_name: env_or("THREAD_NAME", defaults.thread_name)?,
+            stream_channel_depth,
+            broadcast_capacity,
+            broadcast_overflow: env_or("BROADCAST_OVERFLOW", defaults.broadcast_overflow)?,
+            broadcast_shards,
+            stream_buffer_bytes: env_or("STREAM_BUFFER_BYTES", defaults.stream_buffer_bytes)?,
+            stream_buffer_policy: env_or("STREAM_BUFFER_POLICY", defaults.stream_buffer_policy)?,
+            stream_coalesce_ms: env_or("STREAM_COALESCE_MS", defaults.stream_coalesce_ms)?,
+            stream_coalesce_max: env_or("STREAM_COALESCE_MAX", defaults.stream_coalesce_max)?,
+            stream_write_queue,
+            stream_write_batch,
+            stream_write_overflow: env_or("STREAM_WRITE_OVERFLOW", defaults.stream_write_overflow)?,
+            durable_delivery: env_or("DURABLE_DELIVERY", defaults.durable_delivery)?,
+            heartbeat_interval_secs: env_or(
+                "HEARTBEAT_INTERVAL_SECS",
+                defaults.heartbeat_interval_secs,
+            )?,
+            ack_timeout_ms,
+            max_unacked_replies,
+            stream_ack_window: env_or("STREAM_ACK_WINDOW", defaults.stream_ack_window)?,
+            say_hello_many_delay_ms: env_or(
+                "SAY_HELLO_MANY_DELAY_MS",
+                defaults.say_hello_many_delay_ms,
+            )?,
+            greeting_cooldown_secs: env_or(
+                "GREETING_COOLDOWN_SECS",
+                defaults.greeting_cooldown_secs,
+            )?,
+            ws_addr: env_or("WS_ADDR", defaults.ws_addr)?,
+            dashboard_addr: env_or("DASHBOARD_ADDR", defaults.dashboard_addr)?,
+            http_addr: env_or("HTTP_ADDR", defaults.http_addr)?,
+            graphql_addr: env_or("GRAPHQL_ADDR", defaults.graphql_addr)?,
+            export_batch_size: env_or("EXPORT_BATCH_SIZE", defaults.export_batch_size)?,
+            list_reply_max_bytes: env_or("LIST_REPLY_MAX_BYTES", defaults.list_reply_max_bytes)?,
+            import_max_message_len: env_or(
+                "IMPORT_MAX_MESSAGE_LEN",
+                defaults.import_max_message_len,
+            )?,
+            restore_max_bytes: env_or("RESTORE_MAX_BYTES", defaults.restore_max_bytes)?,
+            payload_max_bytes: env_or("PAYLOAD_MAX_BYTES", defaults.payload_max_bytes)?,
+            attachments_bucket: env_opt("ATTACHMENTS_BUCKET")?,
+            attachments_endpoint: env_opt("ATTACHMENTS_ENDPOINT")?,
+            attachments_region: env_or("ATTACHMENTS_REGION", defaults.attachments_region)?,
+            attachment_max_bytes: env_or("ATTACHMENT_MAX_BYTES", defaults.attachment_max_bytes)?,
+            attachment_url_ttl_secs: env_or(
+                "ATTACHMENT_URL_TTL_SECS",
+                defaults.attachment_url_ttl_secs,
+            )?,
+            kafka_brokers: env::var("KAFKA_BROKERS").ok(),
+            kafka_topic: env_or("KAFKA_TOPIC", defaults.kafka_topic)?,
+            outbox_poll_interval_ms: env_or(
+                "OUTBOX_POLL_INTERVAL_MS",
+                defaults.outbox_poll_interval_ms,
+            )?,
+            events_poll_interval_ms: env_or(
+                "EVENTS_POLL_INTERVAL_MS",
+                defaults.events_poll_interval_ms,
+            )?,
+            partition_maintenance_secs: env_or(
+                "PARTITION_MAINTENANCE_SECS",
+                defaults.partition_maintenance_secs,
+            )?,
+            partition_months_ahead: env_or(
+                "PARTITION_MONTHS_AHEAD",
+                defaults.partition_months_ahead,
+            )?,
+            message_retention_months: env_or(
+                "MESSAGE_RETENTION_MONTHS",
+                defaults.message_retention_months,
+            )?,
+            db_health_interval_ms: env_or("DB_HEALTH_INTERVAL_MS", defaults.db_health_interval_ms)?,
+            stats_refresh_secs: env_or("STATS_REFRESH_SECS", defaults.stats_refresh_secs)?,
+            leaderboard_sync_ms: env_or("LEADERBOARD_SYNC_MS", defaults.leaderboard_sync_ms)?,
+            cert_check_interval_secs: env_or(
+                "CERT_CHECK_INTERVAL_SECS",
+                defaults.cert_check_interval_secs,
+            )?,
+            leader_check_interval_ms: env_or(
+                "LEADER_CHECK_INTERVAL_MS",
+                defaults.leader_check_interval_ms,
+            )?,
+            startup_retry_initial_ms: env_or(
+                "STARTUP_RETRY_INITIAL_MS",
+                defaults.startup_retry_initial_ms,
+            )?,
+            startup_retry_max_ms: env_or("STARTUP_RETRY_MAX_MS", defaults.startup_retry_max_ms)?,
+            startup_max_wait_secs: env_or("STARTUP_MAX_WAIT_SECS", defaults.startup_max_wait_secs)?,
+            statement_timeout_ms: env_or("STATEMENT_TIMEOUT_MS", defaults.statement_timeout_ms)?,
+            feature_flag_ttl_ms: env_or("FEATURE_FLAG_TTL_MS", defaults.feature_flag_ttl_ms)?,
+            embedding_dimensions,
+            reflection_versions: env_or("REFLECTION_VERSIONS", defaults.reflection_versions)?,
+            tcp_nodelay: env_or("TCP_NODELAY", defaults.tcp_nodelay)?,
+            so_reuseport: env_or("SO_REUSEPORT", defaults.so_reuseport)?,
+            listeners,
+            proxy_protocol,
+            trusted_proxies,
+            max_concurrent_handshakes: env_or(
+                "MAX_CONCURRENT_HANDSHAKES",
+                defaults.max_concurrent_handshakes,
+            )?,
+            handshake_timeout_ms: env_or("HANDSHAKE_TIMEOUT_MS", defaults.handshake_timeout_ms)?,
+            handshake_ban_failures: env_or(
+                "HANDSHAKE_BAN_FAILURES",
+                defaults.handshake_ban_failures,
+            )?,
+            handshake_ban_window_secs: env_or(
+                "HANDSHAKE_BAN_WINDOW_SECS",
+                defaults.handshake_ban_window_secs,
+            )?,
+            handshake_ban_secs: env_or("HANDSHAKE_BAN_SECS", defaults.handshake_ban_secs)?,
+            ip_allow: env_list("IP_ALLOW")?,
+            ip_deny: env_list("IP_DENY")?,
+            tenant_frontends: env_list("TENANT_FRONTENDS")?,
+            geoip_country_db: env_opt("GEOIP_COUNTRY_DB")?,
+            geoip_asn_db: env_opt("GEOIP_ASN_DB")?,
+            message_encryption_key,
+            redact_logs: env_or("REDACT_LOGS", defaults.redact_logs)?,
+            slow_query_ms: env_or("SLOW_QUERY_MS", defaults.slow_query_ms)?,
+            slow_rpc_ms: env_or("SLOW_RPC_MS", defaults.slow_rpc_ms)?,
+            access_log_sampling: env_or("ACCESS_LOG_SAMPLING", defaults.access_log_sampling)?,
+            chaos: env_or("CHAOS", defaults.chaos)?,
+            mirror_addr: env_opt("MIRROR_ADDR")?,
+            mirror_percent,
+            tenant_quotas: env_or("TENANT_QUOTAS", defaults.tenant_quotas)?,
+            notifications: defaults.notifications,
+            moderation: defaults.moderation,
+            authz: defaults.authz,
+            tokens: defaults.tokens,
+            upstreams: defaults.upstreams,
+            method_limits: defaults.method_limits,
+            caller_classes: defaults.caller_classes,
+            class_limits: defaults.class_limits,
+            translation_upstream: env_opt("TRANSLATION_UPSTREAM")?,
+            translation_cache_secs: env_or(
+                "TRANSLATION_CACHE_SECS",
+                defaults.translation_cache_secs,
+            )?,
+            read_only: env_or("READ_ONLY", defaults.read_only)?,
+            schema_mismatch: env_or("SCHEMA_MISMATCH", defaults.schema_mismatch)?,
+            tls_format: env_or("TLS_FORMAT", defaults.tls_format)?,
+            tls_pkcs12_password: env_or("TLS_PKCS12_PASSWORD", defaults.tls_pkcs12_password)?,
+            tls_key_passphrase: env::var("TLS_KEY_PASSPHRASE").ok(),
+            tls_key_passphrase_file: env_opt("TLS_KEY_PASSPHRASE_FILE")?,
+            tls_crl_file: env_opt("TLS_CRL_FILE")?,
+            crl_reload_secs: env_or("CRL_RELOAD_SECS", defaults.crl_reload_secs)?,
+            client_auth: env_opt("CLIENT_AUTH")?,
+        })
+    }
+
+    /// The passphrase of an encrypted `server.key`, if one is configured,
+    /// without the line break secret files tend to end with.
+    pub fn tls_key_passphrase(&self) -> std::io::Result<Option<String>> {
+        if let Some(passphrase) = &self.tls_key_passphrase {
+            return Ok(Some(passphrase.clone()));
+        }
+        match &self.tls_key_passphrase_file {
+            Some(path) => {
+                let passphrase = fs::read_to_string(path)?;
+                Ok(Some(passphrase.trim_end_matches(['\r', '\n']).to_string()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// How dependencies not ready at startup are retried.
+    pub fn startup_retry(&self) -> Retry {
+        Retry {
+            initial_backoff: Duration::from_millis(self.startup_retry_initial_ms),
+            max_backoff: Duration::from_millis(self.startup_retry_max_ms),
+            max_wait: Duration::from_secs(self.startup_max_wait_secs),
+        }
+    }
+
+    /// Reads the env vars plus the config file named by `CONFIG_FILE`, if any.
+    pub fn load() -> ConfigResult<Self> {
+        let mut config = Self::from_env()?;
+
+        if let Ok(path) = env::var("CONFIG_FILE") {
+            let file: FileConfig = toml::from_str(&fs::read_to_string(path)?)?;
+            config.notifications = file.notifications;
+            config.upstreams = file.upstreams;
+            config.method_limits = file.method_limits;
+            config.class_limits = file.class_limits;
+            if let Some(caller_classes) = file.caller_classes {
+                config.caller_classes = caller_classes;
+            }
+            if let Some(moderation) = file.moderation {
+                config.moderation = moderation;
+            }
+            if let Some(authz) = file.authz {
+                config.authz = authz;
+            }
+            if let Some(tokens) = file.tokens {
+                config.tokens = tokens;
+            }
+            if let Some(read_only) = file.read_only {
+                config.read_only = read_only;
+            }
+            if let Some(value) = file.tenant_quotas {
+                config.tenant_quotas = file_value("tenant_quotas", value)?;
+            }
+            if let Some(value) = file.access_log_sampling {
+                config.access_log_sampling = file_value("access_log_sampling", value)?;
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+fn file_value<T: FromStr>(key: &'static str, value: String) -> ConfigResult<T> {
+    value
+        .parse()
+        .map_err(|_| ConfigError::Invalid { key, value })
+}
+
+fn env_or<T: FromStr>(key: &'static str, default: T) -> ConfigResult<T> {
+    match env::var(key) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| ConfigError::Invalid { key, value }),
+        Err(_) => Ok(default),
+    }
+}
+
+/// A comma separated list, empty when unset.
+fn env_list<T: FromStr>(key: &'static str) -> ConfigResult<Vec<T>> {
+    match env::var(key) {
+        Ok(value) => value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| item.parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| ConfigError::Invalid { key, value }),
+        Err(_) => Ok(Vec::new()),
+    }
+}
+
+fn env_opt<T: FromStr>(key: &'static str) -> ConfigResult<Option<T>> {
+    match env::var(key) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| ConfigError::Invalid { key, value }),
+        Err(_) => Ok(None),
+    }
+}
@@ -0,0 +1,176 @@
+//! Pooled connections that cancel their query when it is dropped.
+//!
+//! A dropped diesel future only stops waiting for its query, Postgres keeps
+//! running it and the connection stays busy for whoever checks it out next.
+//! A gRPC call past its deadline or cancelled by the client drops the
+//! handler, so `Conn` sends Postgres a cancel request for the query it left
+//! behind and returns the connection to the pool once the query is gone.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use diesel::{
+    query_builder::{AsQuery, QueryFragment, QueryId},
+    ConnectionError, ConnectionResult, QueryResult,
+};
+use diesel_async::{
+    pooled_connection::AsyncDieselConnectionManager, AnsiTransactionManager, AsyncConnection,
+    AsyncPgConnection, SimpleAsyncConnection,
+};
+use tokio_stream::Stream;
+
+type Pooled = bb8::PooledConnection<'static, AsyncDieselConnectionManager<AsyncPgConnection>>;
+type PgStream<'conn, 'query> = <AsyncPgConnection as AsyncConnection>::Stream<'conn, 'query>;
+type PgRow<'conn, 'query> = <AsyncPgConnection as AsyncConnection>::Row<'conn, 'query>;
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A pooled connection, runs queries like the `AsyncPgConnection` it wraps.
+pub(crate) struct Conn {
+    conn: Option<Pooled>,
+    /// Set while a query runs, still set on drop if its future was dropped.
+    busy: Arc<AtomicBool>,
+}
+
+impl Conn {
+    pub(crate) fn new(conn: Pooled) -> Self {
+        Self {
+            conn: Some(conn),
+            busy: Arc::default(),
+        }
+    }
+
+    fn conn(&mut self) -> &mut AsyncPgConnection {
+        self.conn.as_mut().expect("connection taken before drop")
+    }
+
+    fn start(&mut self) -> (&mut AsyncPgConnection, Busy) {
+        self.busy.store(true, Ordering::Relaxed);
+        let busy = Busy(self.busy.clone());
+        (self.conn(), busy)
+    }
+}
+
+/// Clears the busy flag of a `Conn` once its query is done.
+struct Busy(Arc<AtomicBool>);
+
+impl Busy {
+    fn done(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+/// The rows of a query. The query counts as done from the first row on,
+/// `get_result` drops the rest unread.
+struct Rows<'conn, 'query> {
+    rows: PgStream<'conn, 'query>,
+    busy: Busy,
+}
+
+impl<'conn, 'query> Stream for Rows<'conn, 'query> {
+    type Item = QueryResult<PgRow<'conn, 'query>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let next = self.rows.as_mut().poll_next(cx);
+        if next.is_ready() {
+            self.busy.done();
+        }
+        next
+    }
+}
+
+#[tonic::async_trait]
+impl SimpleAsyncConnection for Conn {
+    async fn batch_execute(&mut self, query: &str) -> QueryResult<()> {
+        let (conn, busy) = self.start();
+        let result = conn.batch_execute(query).await;
+        busy.done();
+        result
+    }
+}
+
+#[tonic::async_trait]
+impl AsyncConnection for Conn {
+    type LoadFuture<'conn, 'query> = BoxFuture<'query, QueryResult<Self::Stream<'conn, 'query>>>;
+    type ExecuteFuture<'conn, 'query> = BoxFuture<'query, QueryResult<usize>>;
+    type Stream<'conn, 'query> =
+        Pin<Box<dyn Stream<Item = QueryResult<PgRow<'conn, 'query>>> + Send + 'query>>;
+    type Row<'conn, 'query> = PgRow<'conn, 'query>;
+    type Backend = diesel::pg::Pg;
+    type TransactionManager = AnsiTransactionManager;
+
+    async fn establish(_database_url: &str) -> ConnectionResult<Self> {
+        Err(ConnectionError::BadConnection(
+            "connections are checked out of the pool".into(),
+        ))
+    }
+
+    fn load<'conn, 'query, T>(&'conn mut self, source: T) -> Self::LoadFuture<'conn, 'query>
+    where
+        T: AsQuery + Send + 'query,
+        T::Query: QueryFragment<Self::Backend> + QueryId + Send + 'query,
+    {
+        let (conn, busy) = self.start();
+        let rows = conn.load(source);
+        Box::pin(async move {
+            match rows.await {
+                Ok(rows) => Ok(Box::pin(Rows { rows, busy }) as Self::Stream<'conn, 'query>),
+                Err(err) => {
+                    busy.done();
+                    Err(err)
+                }
+            }
+        })
+    }
+
+    fn execute_returning_count<'conn, 'query, T>(
+        &'conn mut self,
+        source: T,
+    ) -> Self::ExecuteFuture<'conn, 'query>
+    where
+        T: QueryFragment<Self::Backend> + QueryId + Send + 'query,
+    {
+        let (conn, busy) = self.start();
+        let count = conn.execute_returning_count(source);
+        Box::pin(async move {
+            let count = count.await;
+            busy.done();
+            count
+        })
+    }
+
+    fn transaction_state(&mut self) -> &mut AnsiTransactionManager {
+        self.conn().transaction_state()
+    }
+}
+
+impl Drop for Conn {
+    fn drop(&mut self) {
+        let Some(mut conn) = self.conn.take() else {
+            return;
+        };
+        if !self.busy.load(Ordering::Relaxed) {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        // the connection is held until the query is gone, the cancel request
+        // mustn't hit the next query run on it
+        let token = conn.cancel_token();
+        runtime.spawn(async move {
+            if let Err(err) = token.cancel_query(tokio_postgres::NoTls).await {
+                eprintln!("cancelling an abandoned query failed: {}", err);
+            }
+            // queued behind the cancelled query, left in a transaction the
+            // connection fails it and the pool discards it as broken
+            let _ = conn.batch_execute("SELECT 1").await;
+        });
+    }
+}
@@ -0,0 +1,49 @@
+//! Per-name cooldown of `SayHello`: a name greeted again within the cooldown
+//! gets the reply of the first greeting back instead of another stored one.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Values kept for `ttl` after they were inserted. Clones share the values.
+pub struct TtlMap<T> {
+    ttl: Duration,
+    entries: Arc<Mutex<HashMap<String, (Instant, T)>>>,
+}
+
+impl<T> Clone for TtlMap<T> {
+    fn clone(&self) -> Self {
+        Self {
+            ttl: self.ttl,
+            entries: self.entries.clone(),
+        }
+    }
+}
+
+impl<T: Clone> TtlMap<T> {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Arc::default(),
+        }
+    }
+
+    /// The value under `key` unless it expired.
+    pub fn get(&self, key: &str) -> Option<T> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(key)
+            .filter(|(inserted, _)| inserted.elapsed() < self.ttl)
+            .map(|(_, value)| value.clone())
+    }
+
+    /// Keeps `value` under `key` for the next `ttl`, dropping the expired
+    /// values on the way.
+    pub fn insert(&self, key: &str, value: T) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (inserted, _)| inserted.elapsed() < self.ttl);
+        entries.insert(key.to_string(), (Instant::now(), value));
+    }
+}
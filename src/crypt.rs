@@ -0,0 +1,183 @@
+//! Encryption at rest of the stored message texts, see `Db::with_encryption`.
+//!
+//! Envelope encryption with AES-256-GCM: texts are encrypted with a data
+//! key, stored wrapped by a master key next to every text. The master key
+//! never leaves its `KeyWrapper`, `LocalKey` holds it in memory, a KMS
+//! client implementing the trait keeps it in the KMS.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Nonce,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use thiserror::Error;
+use tokio::sync::OnceCell;
+
+/// Marks encrypted texts, texts without it are read as they are, so
+/// messages stored before encryption was turned on stay readable.
+const SEALED_PREFIX: &str = "enc1:";
+
+const NONCE_LEN: usize = 12;
+
+#[derive(Error, Debug)]
+pub enum CryptError {
+    #[error("Key wrapping failed: {0}")]
+    Wrap(Box<dyn Error + Send + Sync>),
+    #[error("Malformed encrypted text")]
+    Malformed,
+    #[error("Decryption failed, wrong key or tampered text")]
+    Decrypt,
+}
+
+/// Wraps and unwraps data keys with a master key it keeps to itself.
+#[tonic::async_trait]
+pub trait KeyWrapper: Send + Sync + 'static {
+    async fn wrap(&self, key: &[u8]) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>>;
+    async fn unwrap(&self, wrapped: &[u8]) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>>;
+}
+
+/// A 256-bit master key, configured base64 encoded. Its `Debug` leaves the
+/// key out.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey([u8; 32]);
+
+impl FromStr for EncryptionKey {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = BASE64.decode(s.trim()).map_err(|_| "not base64")?;
+        let key = bytes.try_into().map_err(|_| "not 32 bytes long")?;
+        Ok(Self(key))
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// Wraps data keys with a master key held in memory.
+pub struct LocalKey {
+    cipher: Aes256Gcm,
+}
+
+impl LocalKey {
+    pub fn new(key: &EncryptionKey) -> Self {
+        Self {
+            cipher: Aes256Gcm::new(&key.0.into()),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl KeyWrapper for LocalKey {
+    async fn wrap(&self, key: &[u8]) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        Ok(seal(&self.cipher, key))
+    }
+
+    async fn unwrap(&self, wrapped: &[u8]) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        Ok(open(&self.cipher, wrapped)?)
+    }
+}
+
+/// Encrypts texts under a data key made on first use and decrypts those of
+/// any data key its wrapper unwraps, keeping the unwrapped keys around.
+pub struct MessageCipher {
+    wrapper: Arc<dyn KeyWrapper>,
+    /// The wrapped data key new texts are encrypted with, and its cipher.
+    current: OnceCell<(Vec<u8>, Aes256Gcm)>,
+    unwrapped: Mutex<HashMap<Vec<u8>, Aes256Gcm>>,
+}
+
+impl MessageCipher {
+    pub fn new(wrapper: Arc<dyn KeyWrapper>) -> Self {
+        Self {
+            wrapper,
+            current: OnceCell::new(),
+            unwrapped: Mutex::default(),
+        }
+    }
+
+    /// `text` encrypted, with the wrapped data key, for storing.
+    pub async fn seal(&self, text: &str) -> Result<String, CryptError> {
+        let (wrapped, cipher) = self
+            .current
+            .get_or_try_init(|| async {
+                let key = Aes256Gcm::generate_key(OsRng);
+                let wrapped = self.wrapper.wrap(&key).await.map_err(CryptError::Wrap)?;
+                Ok::<_, CryptError>((wrapped, Aes256Gcm::new(&key)))
+            })
+            .await?;
+        let wrapped_len = u16::try_from(wrapped.len()).map_err(|_| CryptError::Malformed)?;
+        let mut sealed = wrapped_len.to_be_bytes().to_vec();
+        sealed.extend_from_slice(wrapped);
+        sealed.extend(seal(cipher, text.as_bytes()));
+        Ok(format!("{}{}", SEALED_PREFIX, BASE64.encode(sealed)))
+    }
+
+    /// The text `stored` was sealed from, `stored` itself if it wasn't.
+    pub async fn open(&self, stored: &str) -> Result<String, CryptError> {
+        let Some(sealed) = stored.strip_prefix(SEALED_PREFIX) else {
+            return Ok(stored.to_string());
+        };
+        let sealed = BASE64.decode(sealed).map_err(|_| CryptError::Malformed)?;
+        let (wrapped_len, rest) = sealed
+            .split_first_chunk::<2>()
+            .ok_or(CryptError::Malformed)?;
+        let wrapped_len = u16::from_be_bytes(*wrapped_len) as usize;
+        if rest.len() < wrapped_len {
+            return Err(CryptError::Malformed);
+        }
+        let (wrapped, encrypted) = rest.split_at(wrapped_len);
+
+        let cipher = self.unwrapped.lock().unwrap().get(wrapped).cloned();
+        let cipher = match cipher {
+            Some(cipher) => cipher,
+            None => {
+                let key = self
+                    .wrapper
+                    .unwrap(wrapped)
+                    .await
+                    .map_err(CryptError::Wrap)?;
+                let cipher = Aes256Gcm::new_from_slice(&key).map_err(|_| CryptError::Malformed)?;
+                self.unwrapped
+                    .lock()
+                    .unwrap()
+                    .insert(wrapped.to_vec(), cipher.clone());
+                cipher
+            }
+        };
+        let text = open(&cipher, encrypted)?;
+        String::from_utf8(text).map_err(|_| CryptError::Malformed)
+    }
+}
+
+/// `plain` encrypted under a fresh nonce, prefixed with the nonce.
+fn seal(cipher: &Aes256Gcm, plain: &[u8]) -> Vec<u8> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let encrypted = cipher
+        .encrypt(&nonce, plain)
+        .expect("AES-GCM encrypts any text shorter than 64 GiB");
+    let mut sealed = nonce.to_vec();
+    sealed.extend(encrypted);
+    sealed
+}
+
+fn open(cipher: &Aes256Gcm, sealed: &[u8]) -> Result<Vec<u8>, CryptError> {
+    if sealed.len() < NONCE_LEN {
+        return Err(CryptError::Malformed);
+    }
+    let (nonce, encrypted) = sealed.split_at(NONCE_LEN);
+    cipher
+        .decrypt(Nonce::from_slice(nonce), encrypted)
+        .map_err(|_| CryptError::Decrypt)
+}
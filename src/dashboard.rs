@@ -0,0 +1,101 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{Html, IntoResponse},
+    routing::get,
+    Json, Router,
+};
+use serde_json::json;
+
+use crate::certs::Certificate;
+use crate::db::Db;
+use crate::messages::Broadcaster;
+use crate::slow;
+use crate::ws;
+
+const INDEX_HTML: &str = include_str!("../static/dashboard.html");
+
+#[derive(Clone)]
+struct DashboardState {
+    db: Db,
+    broadcaster: Broadcaster,
+    certificates: Arc<[Certificate]>,
+}
+
+/// Static dashboard plus the JSON endpoints it polls, the live feed is the
+/// WebSocket bridge mounted at `/ws`.
+pub fn router(db: Db, broadcaster: Broadcaster, certificates: Arc<[Certificate]>) -> Router {
+    Router::new()
+        .route("/", get(index))
+        .route("/api/stats", get(stats))
+        .route("/healthz", get(health))
+        .merge(ws::router(broadcaster.clone()))
+        .with_state(DashboardState {
+            db,
+            broadcaster,
+            certificates,
+        })
+}
+
+pub async fn serve(
+    addr: SocketAddr,
+    db: Db,
+    broadcaster: Broadcaster,
+    certificates: Arc<[Certificate]>,
+) -> Result<(), hyper::Error> {
+    println!("Dashboard listening on {}", addr);
+    axum::Server::bind(&addr)
+        .serve(router(db, broadcaster, certificates).into_make_service())
+        .await
+}
+
+async fn index() -> Html<&'static str> {
+    Html(INDEX_HTML)
+}
+
+async fn stats(State(state): State<DashboardState>) -> impl IntoResponse {
+    let certificates = state
+        .certificates
+        .iter()
+        .map(|cert| {
+            json!({
+                "name": cert.name,
+                "subject": cert.subject,
+                "not_after": cert.not_after,
+                "days_left": cert.days_left(),
+            })
+        })
+        .collect::<Vec<_>>();
+    match state.db.count_messages().await {
+        Ok(total) => Ok(Json(json!({
+            "total_messages": total,
+            "subscribers": state.broadcaster.subscriber_count(),
+            "broadcast": state.broadcaster.stats(),
+            "slow_queries": slow::slow_queries(),
+            "slow_rpcs": slow::slow_rpcs(),
+            "canary": crate::canary::stats(),
+            "mirror": crate::mirror::stats(),
+            "upstreams": crate::clients::stats(),
+            "method_limits": crate::limits::stats(),
+            "database": state.db.health(),
+            "certificates": certificates,
+        }))),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+/// Answers from the latest health probe, queries the database itself while
+/// there is none.
+async fn health(State(state): State<DashboardState>) -> StatusCode {
+    let healthy = match state.db.health().healthy {
+        Some(healthy) => healthy,
+        None => state.db.count_messages().await.is_ok(),
+    };
+    if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
@@ -1,12 +1,30 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
 use thiserror::Error;
 
-use diesel::prelude::*;
+use diesel::{prelude::*, upsert::excluded};
 use diesel_async::{
     pooled_connection::{AsyncDieselConnectionManager, PoolError},
-    AsyncPgConnection, RunQueryDsl,
+    scoped_futures::ScopedFutureExt,
+    AsyncConnection, AsyncPgConnection, RunQueryDsl, SimpleAsyncConnection,
 };
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::mpsc;
 
-use crate::schema::messages;
+use crate::{
+    config::Quota,
+    conn::Conn,
+    crypt::{CryptError, MessageCipher},
+    schema::{
+        audit_log, events, feature_flags, messages, name_counts, outbox, pending_deliveries,
+        refresh_tokens, revoked_sessions, sessions, subscriber_acks, subscriptions, tenant_usage,
+    },
+    slow,
+};
 
 type Pool = bb8::Pool<AsyncDieselConnectionManager<AsyncPgConnection>>;
 
@@ -16,45 +34,1693 @@ pub enum DbError {
     Env(#[from] std::env::VarError),
     #[error("Pool error: {0}")]
     Pool(#[from] bb8::RunError<PoolError>),
+    #[error("Pool setup error: {0}")]
+    PoolSetup(#[from] PoolError),
     #[error("Database error: {0}")]
     Database(#[from] diesel::result::Error),
+    #[error("Connection error: {0}")]
+    Connection(#[from] ConnectionError),
+    #[error("Encryption error: {0}")]
+    Crypt(#[from] CryptError),
 }
 
 type DbResult<T> = Result<T, DbError>;
 
-#[derive(Queryable, Selectable)]
+#[derive(Queryable, QueryableByName, Selectable, Clone, Debug)]
 #[diesel(table_name = messages)]
 pub struct Message {
     pub id: i32,
     pub message: Option<String>,
     pub updated: Option<i32>,
+    pub tags: serde_json::Value,
+    pub metadata: serde_json::Value,
+    /// Messages with a higher priority are delivered to subscribers ahead of
+    /// the others, 0 is normal.
+    pub priority: i16,
+}
+
+/// A message row as stored, text encrypted or not, see `backup`.
+#[derive(Queryable, Selectable, Insertable, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[diesel(table_name = messages)]
+pub struct StoredMessage {
+    pub id: i32,
+    pub message: Option<String>,
+    pub updated: Option<i32>,
+    pub tags: serde_json::Value,
+    pub metadata: serde_json::Value,
+    pub created_at: SystemTime,
+    /// Missing from archives made before priorities.
+    #[serde(default)]
+    pub priority: i16,
+    /// Missing from archives made before payloads.
+    #[serde(default)]
+    pub payload: Option<Vec<u8>>,
+    /// The object is in the attachment store, archives don't include it.
+    #[serde(default)]
+    pub attachment_key: Option<String>,
+    /// Missing from archives made before countries.
+    #[serde(default)]
+    pub country: Option<String>,
+}
+
+/// Unset fields are inserted as `NULL` rather than `DEFAULT`, which would
+/// change the SQL with the fields set and keep the connection from caching
+/// the prepared statement.
+#[derive(Insertable, Clone, Copy)]
+#[diesel(table_name = messages)]
+#[diesel(treat_none_as_default_value = false)]
+struct NewMessage<'a> {
+    message: &'a str,
+    tags: &'a serde_json::Value,
+    metadata: &'a serde_json::Value,
+    priority: i16,
+    payload: Option<&'a [u8]>,
+    country: Option<&'a str>,
+}
+
+/// An entry of the append-only event log. `messages` is its projection,
+/// kept up to date by the transaction appending each event rather than
+/// rebuilt from the log, so reads go to `messages` and the log serves
+/// `StreamEvents`.
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = events)]
+pub struct Event {
+    pub seq: i64,
+    pub kind: String,
+    pub message_id: i32,
+    pub payload: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    Hello,
+    Update,
+    Delete,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Hello => "hello",
+            Self::Update => "update",
+            Self::Delete => "delete",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "hello" => Some(Self::Hello),
+            "update" => Some(Self::Update),
+            "delete" => Some(Self::Delete),
+            _ => None,
+        }
+    }
+}
+
+/// An entry of the audit log: `action` taken on `subject` for `tenant`.
+#[derive(Queryable, Selectable, Clone, Debug, PartialEq, Eq)]
+#[diesel(table_name = audit_log)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub tenant: String,
+    pub action: String,
+    pub subject: String,
+    pub detail: String,
+}
+
+/// The transcript of a `SayHelloStream` call.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Session {
+    pub id: i64,
+    pub tenant: String,
+    pub started_at: SystemTime,
+    /// `None` while the call goes on, or when the server stopped before it
+    /// ended.
+    pub ended_at: Option<SystemTime>,
+    pub exchanges: Vec<Exchange>,
+}
+
+/// A name a session received and the greeting replied, kept in the
+/// `exchanges` of its row.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Exchange {
+    pub name: String,
+    pub reply: String,
+    /// When the reply was sent, in milliseconds since the Unix epoch.
+    pub at_ms: i64,
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = sessions)]
+struct SessionRow {
+    id: i64,
+    tenant: String,
+    started_at: SystemTime,
+    ended_at: Option<SystemTime>,
+    exchanges: serde_json::Value,
+}
+
+/// A greeting event waiting in the outbox to be published downstream.
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = outbox)]
+pub struct OutboxEvent {
+    pub id: i64,
+    pub topic: String,
+    pub payload: String,
+}
+
+/// What a tenant stored: messages and their bytes.
+#[derive(Queryable, QueryableByName, Selectable, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[diesel(table_name = tenant_usage)]
+pub struct Usage {
+    pub messages: i64,
+    pub bytes: i64,
+}
+
+/// A row of `refresh_tokens`, see `tokens`.
+#[derive(Queryable, Selectable, Clone, Debug, PartialEq, Eq)]
+#[diesel(table_name = refresh_tokens)]
+pub struct RefreshToken {
+    pub subject: String,
+    pub groups: serde_json::Value,
+    pub expires_at: SystemTime,
+}
+
+/// A row of `feature_flags`, see `flags::FeatureFlags`.
+#[derive(Queryable, Selectable, Clone, Debug, PartialEq, Eq)]
+#[diesel(table_name = feature_flags)]
+pub struct FeatureFlag {
+    pub name: String,
+    /// `*` for the tenants without a row of their own.
+    pub tenant: String,
+    pub enabled: bool,
+}
+
+/// Greetings stored and how they spread over days and names, as of the
+/// latest `Db::refresh_stats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GreetingStats {
+    pub total: i64,
+    /// Days of the period asked for with greetings, oldest first.
+    pub per_day: Vec<DayCount>,
+    /// Names greeted most, most greeted first.
+    pub top_names: Vec<NameCount>,
+    /// Countries greeted from, most greetings first, see `geoip`.
+    pub per_country: Vec<CountryCount>,
+}
+
+#[derive(QueryableByName, Clone, Debug, PartialEq, Eq)]
+pub struct DayCount {
+    /// `YYYY-MM-DD`.
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    pub day: String,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub greetings: i64,
+}
+
+#[derive(Queryable, QueryableByName, Clone, Debug, PartialEq, Eq)]
+pub struct NameCount {
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    pub name: String,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub greetings: i64,
+}
+
+#[derive(QueryableByName, Clone, Debug, PartialEq, Eq)]
+pub struct CountryCount {
+    /// ISO 3166-1 alpha-2 code.
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    pub country: String,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub greetings: i64,
+}
+
+#[derive(QueryableByName)]
+struct StatsTotal {
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    total: i64,
+}
+
+/// Views `refresh_stats` refreshes.
+const STATS_VIEWS: [&str; 3] = [
+    "greeting_stats_daily",
+    "greeting_stats_names",
+    "greeting_stats_countries",
+];
+
+const STATS_TOTAL_SQL: &str =
+    "SELECT coalesce(sum(greetings), 0)::BIGINT AS total FROM greeting_stats_daily";
+
+const STATS_PER_DAY_SQL: &str = "\
+    SELECT to_char(day, 'YYYY-MM-DD') AS day, greetings FROM greeting_stats_daily \
+    WHERE day > CURRENT_DATE - $1 ORDER BY day";
+
+const STATS_TOP_NAMES_SQL: &str =
+    "SELECT name, greetings FROM greeting_stats_names ORDER BY greetings DESC, name LIMIT $1";
+
+const STATS_PER_COUNTRY_SQL: &str = "\
+    SELECT country, greetings FROM greeting_stats_countries \
+    ORDER BY greetings DESC, country LIMIT $1";
+
+/// Blanks the text of the outbox events of the messages in $1, published or
+/// not.
+const SCRUB_OUTBOX_SQL: &str = "\
+    UPDATE outbox SET payload = jsonb_set(payload::jsonb, '{message}', 'null')::text \
+    WHERE (payload::jsonb ->> 'id')::int = ANY($1)";
+
+/// Whether `message_embeddings` exists, it doesn't where pgvector isn't
+/// available.
+#[cfg(feature = "pgvector")]
+const HAS_EMBEDDINGS_SQL: &str = "SELECT to_regclass('message_embeddings') IS NOT NULL AS exists";
+
+#[cfg(feature = "pgvector")]
+const DELETE_EMBEDDINGS_SQL: &str = "DELETE FROM message_embeddings WHERE message_id = ANY($1)";
+
+/// Moves the id sequence of `messages` past the ids restored by
+/// `restore_messages`.
+const RESET_MESSAGES_ID_SQL: &str = "\
+    SELECT setval('messages_id_seq', coalesce(max(id), 0) + 1, false) FROM messages";
+
+/// Held by every transaction appending to the event log until it commits, so
+/// events become visible in `seq` order and a reader paging by `seq` never
+/// passes one committed later with a lower `seq`.
+const LOCK_EVENTS_SQL: &str = "SELECT pg_advisory_xact_lock(hashtext('events'))";
+
+/// Appends the exchanges in $2 to the transcripts of the sessions in $1, one
+/// array of exchanges per session.
+const APPEND_EXCHANGES_SQL: &str = "\
+    UPDATE sessions SET exchanges = sessions.exchanges || added.exchanges \
+    FROM unnest($1::BIGINT[], $2::JSONB[]) AS added(id, exchanges) \
+    WHERE sessions.id = added.id";
+
+/// Messages read per query by `backup_messages`.
+const BACKUP_PAGE_LEN: i64 = 1_000;
+
+/// Rows inserted per statement by `restore_messages`, six bind parameters
+/// each stay well below the Postgres limit.
+const RESTORE_BATCH_LEN: usize = 5_000;
+
+/// The columns of the tables in the search path, see `compat`.
+const SCHEMA_COLUMNS_SQL: &str = "\
+    SELECT table_name::TEXT AS table_name, column_name::TEXT AS column_name, \
+    udt_name::TEXT AS udt, is_nullable = 'YES' AS nullable FROM information_schema.columns \
+    WHERE table_schema = ANY(current_schemas(false))";
+
+/// Whether the diesel CLI ever ran a migration on the database.
+const HAS_MIGRATIONS_SQL: &str =
+    "SELECT to_regclass('__diesel_schema_migrations') IS NOT NULL AS exists";
+
+/// Adds to the usage of a tenant, unless the quota bound by $4 and $5 would
+/// be exceeded. A first insert isn't checked, `add_usage` does that.
+const ADD_USAGE_SQL: &str = "\
+    INSERT INTO tenant_usage AS u (tenant, messages, bytes) VALUES ($1, $2, $3) \
+    ON CONFLICT (tenant) DO UPDATE \
+    SET messages = u.messages + excluded.messages, bytes = u.bytes + excluded.bytes \
+    WHERE u.messages + excluded.messages <= $4 AND u.bytes + excluded.bytes <= $5 \
+    RETURNING messages, bytes";
+
+const CREATE_PARTITIONS_SQL: &str = "\
+    SELECT count(*) FILTER (WHERE created)::INTEGER AS changed \
+    FROM generate_series(0, $1) AS month, \
+    LATERAL create_messages_partition(LOCALTIMESTAMP + make_interval(months => month)) AS created";
+
+const DROP_PARTITIONS_SQL: &str = "\
+    SELECT drop_messages_partitions(\
+    date_trunc('month', LOCALTIMESTAMP) - make_interval(months => $1)) AS changed";
+
+#[cfg(feature = "pgvector")]
+const SET_EMBEDDING_SQL: &str = "\
+    INSERT INTO message_embeddings (message_id, embedding) VALUES ($1, $2::vector) \
+    ON CONFLICT (message_id) DO UPDATE SET embedding = excluded.embedding";
+
+#[cfg(feature = "pgvector")]
+const FIND_SIMILAR_SQL: &str = "\
+    SELECT m.id, m.message, m.updated, m.tags, m.metadata, \
+    e.embedding <=> $1::vector AS distance \
+    FROM message_embeddings e JOIN messages m ON m.id = e.message_id \
+    ORDER BY distance LIMIT $2";
+
+/// A message found by `find_similar`, with the cosine distance of its
+/// embedding to the one searched for.
+#[cfg(feature = "pgvector")]
+#[derive(QueryableByName, Clone, Debug)]
+pub struct Similar {
+    #[diesel(embed)]
+    pub message: Message,
+    #[diesel(sql_type = diesel::sql_types::Double)]
+    pub distance: f64,
+}
+
+/// Partitions created or dropped by a maintenance query.
+#[derive(QueryableByName)]
+struct PartitionsChanged {
+    #[diesel(sql_type = diesel::sql_types::Integer)]
+    changed: i32,
+}
+
+#[derive(QueryableByName)]
+struct Exists {
+    #[diesel(sql_type = diesel::sql_types::Bool)]
+    exists: bool,
+}
+
+/// A column of the live database, see `compat::check`.
+#[derive(QueryableByName, Clone, Debug, PartialEq, Eq)]
+pub struct SchemaColumn {
+    #[diesel(sql_type = diesel::sql_types::Text, column_name = table_name)]
+    pub table: String,
+    #[diesel(sql_type = diesel::sql_types::Text, column_name = column_name)]
+    pub column: String,
+    /// Type name as in `information_schema.columns.udt_name`, e.g. `int4`.
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    pub udt: String,
+    #[diesel(sql_type = diesel::sql_types::Bool)]
+    pub nullable: bool,
+}
+
+#[derive(QueryableByName)]
+struct MigrationVersion {
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    version: String,
+}
+
+/// Whether `pg_try_advisory_lock` took the lock.
+#[derive(QueryableByName)]
+struct Locked {
+    #[diesel(sql_type = diesel::sql_types::Bool)]
+    locked: bool,
+}
+
+/// A session-level advisory lock, see `Db::try_advisory_lock`. Postgres
+/// releases it when the session ends, so dropping the lock closes its
+/// connection.
+pub struct AdvisoryLock {
+    conn: AsyncPgConnection,
+}
+
+impl AdvisoryLock {
+    /// Runs `SELECT 1` on the session holding the lock. Once that fails the
+    /// lock has to be taken for gone.
+    pub async fn check(&mut self) -> DbResult<()> {
+        diesel::sql_query("SELECT 1")
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Sizing of the connection pool and setup of its connections.
+#[derive(Clone, Copy, Debug)]
+pub struct PoolOptions {
+    pub max_size: u32,
+    /// How long a query waits for a free connection before failing.
+    pub connection_timeout: Duration,
+    /// `statement_timeout` of every connection, zero keeps the server's.
+    pub statement_timeout: Duration,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        Self {
+            max_size: 10,
+            connection_timeout: Duration::from_secs(30),
+            statement_timeout: Duration::ZERO,
+        }
+    }
+}
+
+/// Outcome of the latest `Db::ping` plus the state of the pool, see
+/// `health::supervise`.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct DbHealth {
+    /// `None` until the first ping.
+    pub healthy: Option<bool>,
+    /// Pings that failed in a row.
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    pub connections: u32,
+    pub idle_connections: u32,
 }
 
 #[derive(Clone)]
 pub struct Db {
     conn_pool: Pool,
+    db_url: Arc<str>,
+    statement_timeout: Duration,
+    outbox_topic: Option<String>,
+    slow_query_threshold: Duration,
+    cipher: Option<Arc<MessageCipher>>,
+    health: Arc<Mutex<DbHealth>>,
 }
 
 impl Db {
     pub async fn new(db_url: &str) -> DbResult<Self> {
-        let config = AsyncDieselConnectionManager::<diesel_async::AsyncPgConnection>::new(db_url);
-        let conn_pool = bb8::Pool::builder().build(config).await.unwrap();
+        Self::with_pool_options(db_url, PoolOptions::default()).await
+    }
+
+    pub async fn with_pool_options(db_url: &str, options: PoolOptions) -> DbResult<Self> {
+        let statement_timeout = options.statement_timeout;
+        let config =
+            AsyncDieselConnectionManager::<AsyncPgConnection>::new_with_setup(db_url, move |url| {
+                Box::pin(establish(url, statement_timeout))
+            });
+        let conn_pool = bb8::Pool::builder()
+            .max_size(options.max_size)
+            .connection_timeout(options.connection_timeout)
+            .build(config)
+            .await?;
 
-        Ok(Self { conn_pool })
+        Ok(Self {
+            conn_pool,
+            db_url: db_url.into(),
+            statement_timeout,
+            outbox_topic: None,
+            slow_query_threshold: Duration::ZERO,
+            cipher: None,
+            health: Arc::default(),
+        })
+    }
+
+    /// Records an outbox event for `topic` in the same transaction as every
+    /// inserted message, for a relay to publish later.
+    pub fn with_outbox(mut self, topic: impl Into<String>) -> Self {
+        self.outbox_topic = Some(topic.into());
+        self
+    }
+
+    /// Stores message texts encrypted by `cipher` and decrypts them on
+    /// read. Texts stored unencrypted before stay readable. Event and outbox
+    /// payloads carry the encrypted text, the outbox relay publishes it
+    /// decrypted.
+    pub fn with_encryption(mut self, cipher: MessageCipher) -> Self {
+        self.cipher = Some(Arc::new(cipher));
+        self
+    }
+
+    /// Warns about every statement taking longer than `threshold`, zero
+    /// disables the check.
+    pub fn with_slow_query_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_query_threshold = threshold;
+        self
+    }
+
+    /// Runs `SELECT 1` through the pool and records the outcome for
+    /// `health`.
+    pub async fn ping(&self) -> DbResult<()> {
+        let result = self.select_one().await;
+        let mut health = self.health.lock().unwrap();
+        match &result {
+            Ok(()) => {
+                health.healthy = Some(true);
+                health.consecutive_failures = 0;
+                health.last_error = None;
+            }
+            Err(err) => {
+                health.healthy = Some(false);
+                health.consecutive_failures += 1;
+                health.last_error = Some(err.to_string());
+            }
+        }
+        result
+    }
+
+    /// Checks out a connection whose query is cancelled should its future
+    /// be dropped, see `Conn`.
+    async fn conn(&self) -> DbResult<Conn> {
+        Ok(Conn::new(self.conn_pool.get_owned().await?))
+    }
+
+    async fn select_one(&self) -> DbResult<()> {
+        let mut conn = self.conn().await?;
+        diesel::sql_query("SELECT 1").execute(&mut conn).await?;
+        Ok(())
+    }
+
+    /// Opens a connection outside the pool and closes it again. Fails right
+    /// away with the error of Postgres, where the pool would keep trying
+    /// for `connection_timeout`.
+    pub async fn probe(&self) -> DbResult<()> {
+        establish(&self.db_url, self.statement_timeout).await?;
+        Ok(())
+    }
+
+    /// Takes the session-level advisory lock keyed by the hash of `name`,
+    /// on a connection of its own outside the pool. `None` while another
+    /// session holds it.
+    pub async fn try_advisory_lock(&self, name: &str) -> DbResult<Option<AdvisoryLock>> {
+        let mut conn = establish(&self.db_url, self.statement_timeout).await?;
+        let query = diesel::sql_query("SELECT pg_try_advisory_lock(hashtext($1)) AS locked")
+            .bind::<diesel::sql_types::Text, _>(name);
+        let Locked { locked } = slow::query(self.slow_query_threshold, query, |q| {
+            q.get_result(&mut conn)
+        })
+        .await?;
+        Ok(locked.then_some(AdvisoryLock { conn }))
+    }
+
+    /// Checks out every idle connection once. The pool tests connections on
+    /// checkout, so the broken ones are replaced now instead of on the next
+    /// queries. Returns the connections checked.
+    pub async fn recycle_idle(&self) -> DbResult<usize> {
+        let idle = self.conn_pool.state().idle_connections;
+        let mut checked = Vec::with_capacity(idle as usize);
+        // held until all are out, so no connection is checked twice
+        for _ in 0..idle {
+            checked.push(self.conn_pool.get().await?);
+        }
+        Ok(checked.len())
+    }
+
+    /// Health as of the latest `ping`, with the current pool state.
+    pub fn health(&self) -> DbHealth {
+        let state = self.conn_pool.state();
+        DbHealth {
+            connections: state.connections,
+            idle_connections: state.idle_connections,
+            ..self.health.lock().unwrap().clone()
+        }
     }
 
     pub async fn get_messages(&self) -> DbResult<Vec<Message>> {
-        let mut conn = self.conn_pool.get().await?;
-        Ok(messages::table.load::<Message>(&mut conn).await?)
+        let mut conn = self.conn().await?;
+        let query = messages::table
+            .order(messages::id.asc())
+            .select(Message::as_select());
+        let messages = slow::query(self.slow_query_threshold, query, |q| q.load(&mut conn)).await?;
+        self.open_all(messages).await
+    }
+
+    /// Messages whose tags contain all of `tags` and whose metadata
+    /// contains `metadata`, both JSON objects.
+    pub async fn get_messages_tagged(
+        &self,
+        tags: &serde_json::Value,
+        metadata: &serde_json::Value,
+    ) -> DbResult<Vec<Message>> {
+        let mut conn = self.conn().await?;
+        let query = messages::table
+            .filter(messages::tags.contains(tags))
+            .filter(messages::metadata.contains(metadata))
+            .order(messages::id.asc())
+            .select(Message::as_select());
+        let messages = slow::query(self.slow_query_threshold, query, |q| q.load(&mut conn)).await?;
+        self.open_all(messages).await
+    }
+
+    pub async fn count_messages(&self) -> DbResult<i64> {
+        let mut conn = self.conn().await?;
+        let query = messages::table.count();
+        Ok(slow::query(self.slow_query_threshold, query, |q| {
+            q.get_result(&mut conn)
+        })
+        .await?)
+    }
+
+    pub async fn get_messages_after(&self, id: i32) -> DbResult<Vec<Message>> {
+        let mut conn = self.conn().await?;
+        let query = messages::table
+            .filter(messages::id.gt(id))
+            .order(messages::id.asc())
+            .select(Message::as_select());
+        let messages = slow::query(self.slow_query_threshold, query, |q| q.load(&mut conn)).await?;
+        self.open_all(messages).await
+    }
+
+    /// One page of a keyset scan over `messages`, ordered by id. Pass the id
+    /// of the last row of the previous page to continue the scan.
+    pub async fn get_messages_page(&self, after_id: i32, limit: i64) -> DbResult<Vec<Message>> {
+        let mut conn = self.conn().await?;
+        let query = messages::table
+            .filter(messages::id.gt(after_id))
+            .order(messages::id.asc())
+            .limit(limit)
+            .select(Message::as_select());
+        let messages = slow::query(self.slow_query_threshold, query, |q| q.load(&mut conn)).await?;
+        self.open_all(messages).await
     }
 
     pub async fn insert_message(&self, message: &str) -> DbResult<Message> {
-        let mut conn = self.conn_pool.get().await?;
-        let user = diesel::insert_into(messages::table)
-            .values(messages::message.eq(message))
-            .get_result(&mut conn)
+        let empty = serde_json::json!({});
+        self.insert_tagged_message(message, &empty, &empty, 0, None, None)
+            .await
+    }
+
+    /// Inserts a message labelled with `tags` and carrying `metadata`, both
+    /// JSON objects, delivered with `priority`. `payload` is stored as is,
+    /// only texts are encrypted.
+    pub async fn insert_tagged_message(
+        &self,
+        message: &str,
+        tags: &serde_json::Value,
+        metadata: &serde_json::Value,
+        priority: i16,
+        payload: Option<&[u8]>,
+        country: Option<&str>,
+    ) -> DbResult<Message> {
+        let row = NewMessage {
+            message,
+            tags,
+            metadata,
+            priority,
+            payload,
+            country,
+        };
+        let mut inserted = self.insert(vec![row]).await?;
+        Ok(inserted.pop().ok_or(diesel::result::Error::NotFound)?)
+    }
+
+    pub async fn insert_messages(&self, messages: &[String]) -> DbResult<Vec<Message>> {
+        let empty = serde_json::json!({});
+        let rows = messages
+            .iter()
+            .map(|message| NewMessage {
+                message,
+                tags: &empty,
+                metadata: &empty,
+                priority: 0,
+                payload: None,
+                country: None,
+            })
+            .collect();
+        self.insert(rows).await
+    }
+
+    async fn insert(&self, rows: Vec<NewMessage<'_>>) -> DbResult<Vec<Message>> {
+        let mut conn = self.conn().await?;
+        let outbox_topic = self.outbox_topic.as_deref();
+        let threshold = self.slow_query_threshold;
+
+        let sealed = self.seal_all(rows.iter().map(|row| row.message)).await?;
+        let rows = rows
+            .iter()
+            .zip(&sealed)
+            .map(|(row, message)| NewMessage { message, ..*row })
+            .collect::<Vec<_>>();
+
+        let inserted = conn
+            .transaction::<_, diesel::result::Error, _>(|conn| {
+                async move {
+                    // a single row keeps to one SQL text, which the connection
+                    // prepares once, batches vary with their size
+                    let inserted = if let [row] = rows.as_slice() {
+                        let query = diesel::insert_into(messages::table)
+                            .values(row)
+                            .returning(Message::as_returning());
+                        slow::query(threshold, query, |q| q.get_results(conn)).await?
+                    } else {
+                        let query = diesel::insert_into(messages::table)
+                            .values(&rows)
+                            .returning(Message::as_returning());
+                        slow::query(threshold, query, |q| q.get_results(conn)).await?
+                    };
+                    let events = inserted
+                        .iter()
+                        .map(|msg| (EventKind::Hello, msg.id, Some(event_payload(msg))))
+                        .collect::<Vec<_>>();
+                    append_events(conn, threshold, &events).await?;
+
+                    if let Some(topic) = outbox_topic {
+                        if let [msg] = inserted.as_slice() {
+                            let payload = json!({ "id": msg.id, "message": msg.message });
+                            let query = diesel::insert_into(outbox::table).values((
+                                outbox::topic.eq(topic),
+                                outbox::payload.eq(payload.to_string()),
+                            ));
+                            slow::query(threshold, query, |q| q.execute(conn)).await?;
+                            return Ok(inserted);
+                        }
+                        let rows = inserted
+                            .iter()
+                            .map(|msg| {
+                                let payload = json!({ "id": msg.id, "message": msg.message });
+                                (
+                                    outbox::topic.eq(topic),
+                                    outbox::payload.eq(payload.to_string()),
+                                )
+                            })
+                            .collect::<Vec<_>>();
+                        let query = diesel::insert_into(outbox::table).values(&rows);
+                        slow::query(threshold, query, |q| q.execute(conn)).await?;
+                    }
+                    Ok(inserted)
+                }
+                .scope_boxed()
+            })
+            .await?;
+
+        self.open_all(inserted).await
+    }
+
+    /// Replaces the text of a stored message, `None` if it doesn't exist.
+    pub async fn update_message(&self, id: i32, message: &str) -> DbResult<Option<Message>> {
+        let message = &self.seal(message).await?;
+        let mut conn = self.conn().await?;
+        let threshold = self.slow_query_threshold;
+        let updated = conn
+            .transaction::<_, diesel::result::Error, _>(|conn| {
+                async move {
+                    let query = diesel::update(messages::table.find(id))
+                        .set(messages::message.eq(message))
+                        .returning(Message::as_returning());
+                    let updated = slow::query(threshold, query, |q| q.get_result(conn))
+                        .await
+                        .optional()?;
+                    if let Some(msg) = &updated {
+                        let events = [(EventKind::Update, id, Some(event_payload(msg)))];
+                        append_events(conn, threshold, &events).await?;
+                    }
+                    Ok(updated)
+                }
+                .scope_boxed()
+            })
             .await?;
 
-        Ok(user)
+        match updated {
+            Some(msg) => Ok(self.open_all(vec![msg]).await?.pop()),
+            None => Ok(None),
+        }
+    }
+
+    /// The payload of message `id`, `None` when it has none or doesn't exist.
+    pub async fn get_payload(&self, id: i32) -> DbResult<Option<Vec<u8>>> {
+        let mut conn = self.conn().await?;
+        let query = messages::table.find(id).select(messages::payload);
+        let payload: Option<Option<Vec<u8>>> = slow::query(self.slow_query_threshold, query, |q| {
+            q.get_result(&mut conn)
+        })
+        .await
+        .optional()?;
+        Ok(payload.flatten())
+    }
+
+    /// Replaces the payload of message `id`, returns whether it exists.
+    pub async fn set_payload(&self, id: i32, payload: &[u8]) -> DbResult<bool> {
+        let mut conn = self.conn().await?;
+        let query = diesel::update(messages::table.find(id)).set(messages::payload.eq(payload));
+        let updated =
+            slow::query(self.slow_query_threshold, query, |q| q.execute(&mut conn)).await?;
+        Ok(updated > 0)
+    }
+
+    /// Key of the attachment of message `id`, `None` when it has none or
+    /// doesn't exist.
+    pub async fn get_attachment_key(&self, id: i32) -> DbResult<Option<String>> {
+        let mut conn = self.conn().await?;
+        let query = messages::table.find(id).select(messages::attachment_key);
+        let key: Option<Option<String>> = slow::query(self.slow_query_threshold, query, |q| {
+            q.get_result(&mut conn)
+        })
+        .await
+        .optional()?;
+        Ok(key.flatten())
+    }
+
+    /// Sets the key of the attachment of message `id`, returns whether it
+    /// exists.
+    pub async fn set_attachment_key(&self, id: i32, key: &str) -> DbResult<bool> {
+        let mut conn = self.conn().await?;
+        let query = diesel::update(messages::table.find(id)).set(messages::attachment_key.eq(key));
+        let updated =
+            slow::query(self.slow_query_threshold, query, |q| q.execute(&mut conn)).await?;
+        Ok(updated > 0)
+    }
+
+    /// Deletes a stored message, returns whether it existed.
+    pub async fn delete_message(&self, id: i32) -> DbResult<bool> {
+        let mut conn = self.conn().await?;
+        let threshold = self.slow_query_threshold;
+        let deleted = conn
+            .transaction::<_, diesel::result::Error, _>(|conn| {
+                async move {
+                    let query = diesel::delete(messages::table.find(id));
+                    let deleted = slow::query(threshold, query, |q| q.execute(conn)).await?;
+                    if deleted > 0 {
+                        let query = diesel::delete(pending_deliveries::table.find(id));
+                        slow::query(threshold, query, |q| q.execute(conn)).await?;
+                        append_events(conn, threshold, &[(EventKind::Delete, id, None)]).await?;
+                    }
+                    Ok(deleted > 0)
+                }
+                .scope_boxed()
+            })
+            .await?;
+
+        Ok(deleted)
+    }
+
+    /// Deletes the messages `ids` along with every copy of their text: the
+    /// payloads of their events and outbox events are blanked and their
+    /// pending deliveries and embeddings dropped. Returns how many existed.
+    pub async fn erase_messages(&self, ids: &[i32]) -> DbResult<usize> {
+        let mut conn = self.conn().await?;
+        let threshold = self.slow_query_threshold;
+        let erased = conn
+            .transaction::<_, diesel::result::Error, _>(|conn| {
+                async move {
+                    let query = diesel::delete(messages::table.filter(messages::id.eq_any(ids)))
+                        .returning(messages::id);
+                    let erased: Vec<i32> =
+                        slow::query(threshold, query, |q| q.get_results(conn)).await?;
+                    if erased.is_empty() {
+                        return Ok(0);
+                    }
+                    let query = diesel::delete(
+                        pending_deliveries::table
+                            .filter(pending_deliveries::message_id.eq_any(&erased)),
+                    );
+                    slow::query(threshold, query, |q| q.execute(conn)).await?;
+                    let query =
+                        diesel::update(events::table.filter(events::message_id.eq_any(&erased)))
+                            .set(events::payload.eq(None::<String>));
+                    slow::query(threshold, query, |q| q.execute(conn)).await?;
+                    let query = diesel::sql_query(SCRUB_OUTBOX_SQL)
+                        .bind::<diesel::sql_types::Array<diesel::sql_types::Integer>, _>(&erased);
+                    slow::query(threshold, query, |q| q.execute(conn)).await?;
+                    #[cfg(feature = "pgvector")]
+                    if slow::query(threshold, diesel::sql_query(HAS_EMBEDDINGS_SQL), |q| {
+                        q.get_result::<Exists>(conn)
+                    })
+                    .await?
+                    .exists
+                    {
+                        let query = diesel::sql_query(DELETE_EMBEDDINGS_SQL)
+                            .bind::<diesel::sql_types::Array<diesel::sql_types::Integer>, _>(
+                            &erased,
+                        );
+                        slow::query(threshold, query, |q| q.execute(conn)).await?;
+                    }
+                    let events = erased
+                        .iter()
+                        .map(|&id| (EventKind::Delete, id, None))
+                        .collect::<Vec<_>>();
+                    append_events(conn, threshold, &events).await?;
+                    Ok(erased.len())
+                }
+                .scope_boxed()
+            })
+            .await?;
+
+        Ok(erased)
+    }
+
+    /// The columns of every table in the search path.
+    pub async fn schema_columns(&self) -> DbResult<Vec<SchemaColumn>> {
+        let mut conn = self.conn().await?;
+        let query = diesel::sql_query(SCHEMA_COLUMNS_SQL);
+        Ok(slow::query(self.slow_query_threshold, query, |q| q.load(&mut conn)).await?)
+    }
+
+    /// Versions of the migrations the diesel CLI recorded as run, none on a
+    /// database it never migrated.
+    pub async fn applied_migrations(&self) -> DbResult<Vec<String>> {
+        let mut conn = self.conn().await?;
+        let threshold = self.slow_query_threshold;
+        let query = diesel::sql_query(HAS_MIGRATIONS_SQL);
+        let Exists { exists } = slow::query(threshold, query, |q| q.get_result(&mut conn)).await?;
+        if !exists {
+            return Ok(Vec::new());
+        }
+        let query = diesel::sql_query("SELECT version::TEXT FROM __diesel_schema_migrations");
+        let versions: Vec<MigrationVersion> =
+            slow::query(threshold, query, |q| q.load(&mut conn)).await?;
+        Ok(versions.into_iter().map(|row| row.version).collect())
+    }
+
+    /// Every message as stored, in id order, read in a single snapshot and
+    /// sent to `pages` a page at a time. Stops early once `pages` is closed.
+    pub async fn backup_messages(&self, pages: mpsc::Sender<Vec<StoredMessage>>) -> DbResult<()> {
+        let mut conn = self.conn().await?;
+        let threshold = self.slow_query_threshold;
+        let pages = &pages;
+        conn.transaction::<(), diesel::result::Error, _>(|conn| {
+            async move {
+                conn.batch_execute(
+                    "SET TRANSACTION ISOLATION LEVEL REPEATABLE READ, READ ONLY; \
+                     SET LOCAL statement_timeout = 0",
+                )
+                .await?;
+                let mut after_id = 0;
+                loop {
+                    let query = messages::table
+                        .filter(messages::id.gt(after_id))
+                        .order(messages::id.asc())
+                        .limit(BACKUP_PAGE_LEN)
+                        .select(StoredMessage::as_select());
+                    let page: Vec<StoredMessage> =
+                        slow::query(threshold, query, |q| q.load(conn)).await?;
+                    let Some(last) = page.last() else {
+                        return Ok(());
+                    };
+                    after_id = last.id;
+                    // the backup was abandoned
+                    if pages.send(page).await.is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+            .scope_boxed()
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Replaces every message with `restored`, kept as they are, ids
+    /// included. Deletes and greetings are recorded in the event log as for
+    /// any other change, pending deliveries and embeddings of the deleted
+    /// messages are dropped. Returns how many messages were deleted.
+    pub async fn restore_messages(&self, restored: &[StoredMessage]) -> DbResult<usize> {
+        let mut conn = self.conn().await?;
+        let threshold = self.slow_query_threshold;
+        let deleted = conn
+            .transaction::<_, diesel::result::Error, _>(|conn| {
+                async move {
+                    // a restore rewrites the whole table
+                    conn.batch_execute("SET LOCAL statement_timeout = 0")
+                        .await?;
+                    let query = diesel::delete(messages::table).returning(messages::id);
+                    let deleted: Vec<i32> =
+                        slow::query(threshold, query, |q| q.get_results(conn)).await?;
+                    let query = diesel::delete(pending_deliveries::table);
+                    slow::query(threshold, query, |q| q.execute(conn)).await?;
+                    #[cfg(feature = "pgvector")]
+                    if slow::query(threshold, diesel::sql_query(HAS_EMBEDDINGS_SQL), |q| {
+                        q.get_result::<Exists>(conn)
+                    })
+                    .await?
+                    .exists
+                    {
+                        let query = diesel::sql_query(DELETE_EMBEDDINGS_SQL)
+                            .bind::<diesel::sql_types::Array<diesel::sql_types::Integer>, _>(
+                            &deleted,
+                        );
+                        slow::query(threshold, query, |q| q.execute(conn)).await?;
+                    }
+
+                    for batch in restored.chunks(RESTORE_BATCH_LEN) {
+                        let query = diesel::insert_into(messages::table).values(batch);
+                        slow::query(threshold, query, |q| q.execute(conn)).await?;
+                    }
+                    let query = diesel::sql_query(RESET_MESSAGES_ID_SQL);
+                    slow::query(threshold, query, |q| q.execute(conn)).await?;
+
+                    let events = deleted
+                        .iter()
+                        .map(|&id| (EventKind::Delete, id, None))
+                        .chain(restored.iter().map(|msg| {
+                            let payload = json!({ "message": msg.message }).to_string();
+                            (EventKind::Hello, msg.id, Some(payload))
+                        }))
+                        .collect::<Vec<_>>();
+                    for batch in events.chunks(RESTORE_BATCH_LEN) {
+                        append_events(conn, threshold, batch).await?;
+                    }
+                    Ok(deleted.len())
+                }
+                .scope_boxed()
+            })
+            .await?;
+
+        Ok(deleted)
+    }
+
+    /// One page of the event log, ordered by sequence number. Appends are
+    /// serialized until they commit, so paging after the last `seq` seen
+    /// misses no event.
+    pub async fn get_events_page(&self, after_seq: i64, limit: i64) -> DbResult<Vec<Event>> {
+        let mut conn = self.conn().await?;
+        let query = events::table
+            .filter(events::seq.gt(after_seq))
+            .order(events::seq.asc())
+            .limit(limit)
+            .select(Event::as_select());
+        let mut events: Vec<Event> =
+            slow::query(self.slow_query_threshold, query, |q| q.load(&mut conn)).await?;
+        for event in &mut events {
+            if let Some(payload) = &event.payload {
+                event.payload = Some(self.open_payload(payload).await?);
+            }
+        }
+        Ok(events)
+    }
+
+    /// Seq of the newest event, 0 while the log is empty. Every write appends
+    /// an event, so it changes whenever the messages do.
+    pub async fn latest_event_seq(&self) -> DbResult<i64> {
+        let mut conn = self.conn().await?;
+        let query = events::table.select(diesel::dsl::max(events::seq));
+        let seq: Option<i64> = slow::query(self.slow_query_threshold, query, |q| {
+            q.get_result(&mut conn)
+        })
+        .await?;
+        Ok(seq.unwrap_or(0))
+    }
+
+    /// Keeps `message_id` for the next subscriber, see `durable_delivery`.
+    pub async fn add_pending_delivery(&self, message_id: i32) -> DbResult<()> {
+        let mut conn = self.conn().await?;
+        let query = diesel::insert_into(pending_deliveries::table)
+            .values(pending_deliveries::message_id.eq(message_id))
+            .on_conflict_do_nothing();
+        slow::query(self.slow_query_threshold, query, |q| q.execute(&mut conn)).await?;
+        Ok(())
+    }
+
+    /// Oldest messages waiting for a subscriber, in id order. They stay
+    /// pending until `delete_pending_deliveries`.
+    pub async fn get_pending_deliveries(&self, limit: i64) -> DbResult<Vec<Message>> {
+        let mut conn = self.conn().await?;
+        let query = pending_deliveries::table
+            .inner_join(messages::table)
+            .order(pending_deliveries::message_id.asc())
+            .limit(limit)
+            .select(Message::as_select());
+        let messages = slow::query(self.slow_query_threshold, query, |q| q.load(&mut conn)).await?;
+        self.open_all(messages).await
+    }
+
+    pub async fn delete_pending_deliveries(&self, message_ids: &[i32]) -> DbResult<()> {
+        let mut conn = self.conn().await?;
+        let query = diesel::delete(
+            pending_deliveries::table.filter(pending_deliveries::message_id.eq_any(message_ids)),
+        );
+        slow::query(self.slow_query_threshold, query, |q| q.execute(&mut conn)).await?;
+        Ok(())
+    }
+
+    /// Id up to which `subscriber` acked every message, 0 for a new one.
+    pub async fn get_acked_until(&self, subscriber: &str) -> DbResult<i32> {
+        let mut conn = self.conn().await?;
+        let query = subscriber_acks::table
+            .find(subscriber)
+            .select(subscriber_acks::acked_until);
+        let acked_until = slow::query(self.slow_query_threshold, query, |q| {
+            q.get_result(&mut conn)
+        })
+        .await
+        .optional()?;
+        Ok(acked_until.unwrap_or(0))
+    }
+
+    /// Moves the ack position of `subscriber` forward to `acked_until`,
+    /// never back.
+    pub async fn set_acked_until(&self, subscriber: &str, acked_until: i32) -> DbResult<()> {
+        let mut conn = self.conn().await?;
+        let query = diesel::insert_into(subscriber_acks::table)
+            .values((
+                subscriber_acks::subscriber.eq(subscriber),
+                subscriber_acks::acked_until.eq(acked_until),
+            ))
+            .on_conflict(subscriber_acks::subscriber)
+            .do_update()
+            .set((
+                subscriber_acks::acked_until.eq(diesel::dsl::sql::<diesel::sql_types::Integer>(
+                    "GREATEST(subscriber_acks.acked_until, excluded.acked_until)",
+                )),
+                subscriber_acks::updated_at.eq(diesel::dsl::now),
+            ));
+        slow::query(self.slow_query_threshold, query, |q| q.execute(&mut conn)).await?;
+        Ok(())
+    }
+
+    /// Id of the last message the subscription `name` delivered, 0 for a new
+    /// one.
+    pub async fn get_delivered_until(&self, name: &str) -> DbResult<i32> {
+        let mut conn = self.conn().await?;
+        let query = subscriptions::table
+            .find(name)
+            .select(subscriptions::delivered_until);
+        let delivered_until = slow::query(self.slow_query_threshold, query, |q| {
+            q.get_result(&mut conn)
+        })
+        .await
+        .optional()?;
+        Ok(delivered_until.unwrap_or(0))
+    }
+
+    /// Moves the position of the subscription `name` forward to
+    /// `delivered_until`, never back.
+    pub async fn set_delivered_until(&self, name: &str, delivered_until: i32) -> DbResult<()> {
+        let mut conn = self.conn().await?;
+        let query = diesel::insert_into(subscriptions::table)
+            .values((
+                subscriptions::name.eq(name),
+                subscriptions::delivered_until.eq(delivered_until),
+            ))
+            .on_conflict(subscriptions::name)
+            .do_update()
+            .set((
+                subscriptions::delivered_until.eq(diesel::dsl::sql::<diesel::sql_types::Integer>(
+                    "GREATEST(subscriptions.delivered_until, excluded.delivered_until)",
+                )),
+                subscriptions::updated_at.eq(diesel::dsl::now),
+            ));
+        slow::query(self.slow_query_threshold, query, |q| q.execute(&mut conn)).await?;
+        Ok(())
+    }
+
+    /// Adds `added` to the usage of `tenant` and returns the new usage, or
+    /// `None` and leaves it as is when that would exceed `quota`. Negative
+    /// amounts give usage back.
+    pub async fn add_usage(
+        &self,
+        tenant: &str,
+        added: Usage,
+        quota: Quota,
+    ) -> DbResult<Option<Usage>> {
+        let max_messages = usage_limit(quota.max_messages);
+        let max_bytes = usage_limit(quota.max_bytes);
+        if added.messages > max_messages || added.bytes > max_bytes {
+            return Ok(None);
+        }
+
+        let mut conn = self.conn().await?;
+        let query = diesel::sql_query(ADD_USAGE_SQL)
+            .bind::<diesel::sql_types::Text, _>(tenant)
+            .bind::<diesel::sql_types::BigInt, _>(added.messages)
+            .bind::<diesel::sql_types::BigInt, _>(added.bytes)
+            .bind::<diesel::sql_types::BigInt, _>(max_messages)
+            .bind::<diesel::sql_types::BigInt, _>(max_bytes);
+        Ok(slow::query(self.slow_query_threshold, query, |q| {
+            q.get_result(&mut conn)
+        })
+        .await
+        .optional()?)
+    }
+
+    /// Usage of `tenant`, zero when it never stored anything.
+    pub async fn get_usage(&self, tenant: &str) -> DbResult<Usage> {
+        let mut conn = self.conn().await?;
+        let query = tenant_usage::table.find(tenant).select(Usage::as_select());
+        let usage = slow::query(self.slow_query_threshold, query, |q| {
+            q.get_result(&mut conn)
+        })
+        .await
+        .optional()?;
+        Ok(usage.unwrap_or_default())
+    }
+
+    pub async fn get_feature_flags(&self) -> DbResult<Vec<FeatureFlag>> {
+        let mut conn = self.conn().await?;
+        let query = feature_flags::table.select(FeatureFlag::as_select());
+        Ok(slow::query(self.slow_query_threshold, query, |q| q.load(&mut conn)).await?)
+    }
+
+    /// Turns flag `name` on or off for `tenant`, `*` for the tenants without
+    /// a row of their own.
+    pub async fn set_feature_flag(&self, name: &str, tenant: &str, enabled: bool) -> DbResult<()> {
+        let mut conn = self.conn().await?;
+        let query = diesel::insert_into(feature_flags::table)
+            .values((
+                feature_flags::name.eq(name),
+                feature_flags::tenant.eq(tenant),
+                feature_flags::enabled.eq(enabled),
+            ))
+            .on_conflict((feature_flags::name, feature_flags::tenant))
+            .do_update()
+            .set(feature_flags::enabled.eq(enabled));
+        slow::query(self.slow_query_threshold, query, |q| q.execute(&mut conn)).await?;
+        Ok(())
+    }
+
+    /// Greetings per day of the last `days` days, today included, and the
+    /// `top` names and countries greeted most, as of the latest
+    /// `refresh_stats`.
+    pub async fn get_stats(&self, days: i32, top: i64) -> DbResult<GreetingStats> {
+        let mut conn = self.conn().await?;
+        let threshold = self.slow_query_threshold;
+        let query = diesel::sql_query(STATS_TOTAL_SQL);
+        let StatsTotal { total } =
+            slow::query(threshold, query, |q| q.get_result(&mut conn)).await?;
+        let query =
+            diesel::sql_query(STATS_PER_DAY_SQL).bind::<diesel::sql_types::Integer, _>(days);
+        let per_day = slow::query(threshold, query, |q| q.load(&mut conn)).await?;
+        let query =
+            diesel::sql_query(STATS_TOP_NAMES_SQL).bind::<diesel::sql_types::BigInt, _>(top);
+        let top_names = slow::query(threshold, query, |q| q.load(&mut conn)).await?;
+        let query =
+            diesel::sql_query(STATS_PER_COUNTRY_SQL).bind::<diesel::sql_types::BigInt, _>(top);
+        let per_country = slow::query(threshold, query, |q| q.load(&mut conn)).await?;
+        Ok(GreetingStats {
+            total,
+            per_day,
+            top_names,
+            per_country,
+        })
+    }
+
+    /// Recounts what `get_stats` reports, readers keep seeing the previous
+    /// counts meanwhile.
+    pub async fn refresh_stats(&self) -> DbResult<()> {
+        let mut conn = self.conn().await?;
+        let threshold = self.slow_query_threshold;
+        conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            async move {
+                // a recount of every message may well take longer than the
+                // timeout meant for requests
+                conn.batch_execute("SET LOCAL statement_timeout = 0")
+                    .await?;
+                for view in STATS_VIEWS {
+                    let refresh = format!("REFRESH MATERIALIZED VIEW CONCURRENTLY {}", view);
+                    let query = diesel::sql_query(refresh);
+                    slow::query(threshold, query, |q| q.execute(conn)).await?;
+                }
+                Ok(())
+            }
+            .scope_boxed()
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Adds `counts` to the greetings counted per name.
+    pub async fn add_name_counts(&self, counts: &[NameCount]) -> DbResult<()> {
+        if counts.is_empty() {
+            return Ok(());
+        }
+        let mut conn = self.conn().await?;
+        let rows = counts
+            .iter()
+            .map(|count| {
+                (
+                    name_counts::name.eq(&count.name),
+                    name_counts::greetings.eq(count.greetings),
+                )
+            })
+            .collect::<Vec<_>>();
+        let query = diesel::insert_into(name_counts::table)
+            .values(rows)
+            .on_conflict(name_counts::name)
+            .do_update()
+            .set(
+                name_counts::greetings
+                    .eq(name_counts::greetings + excluded(name_counts::greetings)),
+            );
+        slow::query(self.slow_query_threshold, query, |q| q.execute(&mut conn)).await?;
+        Ok(())
+    }
+
+    /// The greetings counted per name, by every server.
+    pub async fn get_name_counts(&self) -> DbResult<Vec<NameCount>> {
+        let mut conn = self.conn().await?;
+        let query = name_counts::table.select((name_counts::name, name_counts::greetings));
+        Ok(slow::query(self.slow_query_threshold, query, |q| q.load(&mut conn)).await?)
+    }
+
+    /// Drops the greetings counted for `name`, by every server.
+    pub async fn delete_name_count(&self, name: &str) -> DbResult<()> {
+        let mut conn = self.conn().await?;
+        let query = diesel::delete(name_counts::table.find(name));
+        slow::query(self.slow_query_threshold, query, |q| q.execute(&mut conn)).await?;
+        Ok(())
+    }
+
+    /// Appends to the audit log.
+    pub async fn add_audit_entry(
+        &self,
+        tenant: &str,
+        action: &str,
+        subject: &str,
+        detail: &str,
+    ) -> DbResult<()> {
+        let mut conn = self.conn().await?;
+        let query = diesel::insert_into(audit_log::table).values((
+            audit_log::tenant.eq(tenant),
+            audit_log::action.eq(action),
+            audit_log::subject.eq(subject),
+            audit_log::detail.eq(detail),
+        ));
+        slow::query(self.slow_query_threshold, query, |q| q.execute(&mut conn)).await?;
+        Ok(())
+    }
+
+    /// Keeps the refresh token hashed to `token_sha256` until `expires_at`.
+    pub async fn insert_refresh_token(
+        &self,
+        token_sha256: &str,
+        subject: &str,
+        groups: &[String],
+        expires_at: SystemTime,
+    ) -> DbResult<()> {
+        let mut conn = self.conn().await?;
+        let query = diesel::insert_into(refresh_tokens::table).values((
+            refresh_tokens::token_sha256.eq(token_sha256),
+            refresh_tokens::subject.eq(subject),
+            refresh_tokens::groups.eq(json!(groups)),
+            refresh_tokens::expires_at.eq(expires_at),
+        ));
+        slow::query(self.slow_query_threshold, query, |q| q.execute(&mut conn)).await?;
+        Ok(())
+    }
+
+    /// Uses up the refresh token hashed to `token_sha256`: revokes it and
+    /// returns it, `None` when it is unknown, expired or revoked already.
+    pub async fn take_refresh_token(&self, token_sha256: &str) -> DbResult<Option<RefreshToken>> {
+        let mut conn = self.conn().await?;
+        let query = diesel::update(
+            refresh_tokens::table
+                .find(token_sha256)
+                .filter(refresh_tokens::revoked_at.is_null())
+                .filter(refresh_tokens::expires_at.gt(diesel::dsl::now)),
+        )
+        .set(refresh_tokens::revoked_at.eq(diesel::dsl::now))
+        .returning(RefreshToken::as_returning());
+        Ok(slow::query(self.slow_query_threshold, query, |q| {
+            q.get_result(&mut conn)
+        })
+        .await
+        .optional()?)
+    }
+
+    /// Revokes the sessions of `subject`: the tokens issued to it until now
+    /// and its refresh tokens. Returns when, with the number of refresh
+    /// tokens revoked.
+    pub async fn revoke_sessions(&self, subject: &str) -> DbResult<(SystemTime, usize)> {
+        let mut conn = self.conn().await?;
+        let threshold = self.slow_query_threshold;
+        let revoked = conn
+            .transaction::<_, diesel::result::Error, _>(|conn| {
+                async move {
+                    let query = diesel::insert_into(revoked_sessions::table)
+                        .values(revoked_sessions::subject.eq(subject))
+                        .on_conflict(revoked_sessions::subject)
+                        .do_update()
+                        .set(revoked_sessions::revoked_at.eq(diesel::dsl::now))
+                        .returning(revoked_sessions::revoked_at);
+                    let revoked_at = slow::query(threshold, query, |q| q.get_result(conn)).await?;
+                    let query = diesel::update(
+                        refresh_tokens::table
+                            .filter(refresh_tokens::subject.eq(subject))
+                            .filter(refresh_tokens::revoked_at.is_null()),
+                    )
+                    .set(refresh_tokens::revoked_at.eq(diesel::dsl::now));
+                    let tokens = slow::query(threshold, query, |q| q.execute(conn)).await?;
+                    Ok((revoked_at, tokens))
+                }
+                .scope_boxed()
+            })
+            .await?;
+        Ok(revoked)
+    }
+
+    /// When the sessions of `subject` were last revoked.
+    pub async fn get_session_revocation(&self, subject: &str) -> DbResult<Option<SystemTime>> {
+        let mut conn = self.conn().await?;
+        let query = revoked_sessions::table
+            .find(subject)
+            .select(revoked_sessions::revoked_at);
+        Ok(slow::query(self.slow_query_threshold, query, |q| {
+            q.get_result(&mut conn)
+        })
+        .await
+        .optional()?)
+    }
+
+    /// The newest `limit` entries of the audit log, oldest first.
+    pub async fn get_audit_log(&self, limit: i64) -> DbResult<Vec<AuditEntry>> {
+        let mut conn = self.conn().await?;
+        let query = audit_log::table
+            .order(audit_log::id.desc())
+            .limit(limit)
+            .select(AuditEntry::as_select());
+        let mut entries: Vec<AuditEntry> =
+            slow::query(self.slow_query_threshold, query, |q| q.load(&mut conn)).await?;
+        entries.reverse();
+        Ok(entries)
+    }
+
+    /// Records a new session of `tenant`, returns its id.
+    pub async fn start_session(&self, tenant: &str) -> DbResult<i64> {
+        let mut conn = self.conn().await?;
+        let query = diesel::insert_into(sessions::table)
+            .values(sessions::tenant.eq(tenant))
+            .returning(sessions::id);
+        Ok(slow::query(self.slow_query_threshold, query, |q| {
+            q.get_result(&mut conn)
+        })
+        .await?)
+    }
+
+    /// Appends each exchange to the transcript of its session, in the order
+    /// given, their texts sealed like the messages' are.
+    pub async fn append_exchanges(&self, exchanges: &[(i64, Exchange)]) -> DbResult<()> {
+        let mut sessions = Vec::<(i64, Vec<Exchange>)>::new();
+        for (id, exchange) in exchanges {
+            let sealed = Exchange {
+                name: self.seal(&exchange.name).await?,
+                reply: self.seal(&exchange.reply).await?,
+                at_ms: exchange.at_ms,
+            };
+            match sessions.iter_mut().find(|(session, _)| session == id) {
+                Some((_, added)) => added.push(sealed),
+                None => sessions.push((*id, vec![sealed])),
+            }
+        }
+        if sessions.is_empty() {
+            return Ok(());
+        }
+        let (ids, added): (Vec<i64>, Vec<serde_json::Value>) = sessions
+            .into_iter()
+            .map(|(id, added)| (id, json!(added)))
+            .unzip();
+        let mut conn = self.conn().await?;
+        let query = diesel::sql_query(APPEND_EXCHANGES_SQL)
+            .bind::<diesel::sql_types::Array<diesel::sql_types::BigInt>, _>(&ids)
+            .bind::<diesel::sql_types::Array<diesel::sql_types::Jsonb>, _>(&added);
+        slow::query(self.slow_query_threshold, query, |q| q.execute(&mut conn)).await?;
+        Ok(())
+    }
+
+    pub async fn end_session(&self, id: i64) -> DbResult<()> {
+        let mut conn = self.conn().await?;
+        let query =
+            diesel::update(sessions::table.find(id)).set(sessions::ended_at.eq(diesel::dsl::now));
+        slow::query(self.slow_query_threshold, query, |q| q.execute(&mut conn)).await?;
+        Ok(())
+    }
+
+    pub async fn get_session(&self, id: i64) -> DbResult<Option<Session>> {
+        let mut conn = self.conn().await?;
+        let query = sessions::table.find(id).select(SessionRow::as_select());
+        let row: Option<SessionRow> = slow::query(self.slow_query_threshold, query, |q| {
+            q.get_result(&mut conn)
+        })
+        .await
+        .optional()?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let sealed: Vec<Exchange> = serde_json::from_value(row.exchanges).unwrap_or_default();
+        let mut exchanges = Vec::with_capacity(sealed.len());
+        for exchange in sealed {
+            exchanges.push(Exchange {
+                name: self.open(&exchange.name).await?,
+                reply: self.open(&exchange.reply).await?,
+                at_ms: exchange.at_ms,
+            });
+        }
+        Ok(Some(Session {
+            id: row.id,
+            tenant: row.tenant,
+            started_at: row.started_at,
+            ended_at: row.ended_at,
+            exchanges,
+        }))
+    }
+
+    /// Creates the monthly partitions of `messages` for this month and the
+    /// `months_ahead` after it where missing, returns how many it created.
+    pub async fn create_messages_partitions(&self, months_ahead: i32) -> DbResult<i32> {
+        let mut conn = self.conn().await?;
+        let query = diesel::sql_query(CREATE_PARTITIONS_SQL)
+            .bind::<diesel::sql_types::Integer, _>(months_ahead);
+        let created: PartitionsChanged = slow::query(self.slow_query_threshold, query, |q| {
+            q.get_result(&mut conn)
+        })
+        .await?;
+        Ok(created.changed)
+    }
+
+    /// Drops the monthly partitions of `messages` older than the
+    /// `retention_months` before this one along with their messages, returns
+    /// how many it dropped. Messages in the default partition are kept.
+    pub async fn drop_messages_partitions(&self, retention_months: i32) -> DbResult<i32> {
+        let mut conn = self.conn().await?;
+        let query = diesel::sql_query(DROP_PARTITIONS_SQL)
+            .bind::<diesel::sql_types::Integer, _>(retention_months);
+        let dropped: PartitionsChanged = slow::query(self.slow_query_threshold, query, |q| {
+            q.get_result(&mut conn)
+        })
+        .await?;
+        Ok(dropped.changed)
+    }
+
+    /// Stores the embedding of message `message_id`, replacing any it had.
+    #[cfg(feature = "pgvector")]
+    pub async fn set_embedding(&self, message_id: i32, embedding: &[f32]) -> DbResult<()> {
+        let mut conn = self.conn().await?;
+        let query = diesel::sql_query(SET_EMBEDDING_SQL)
+            .bind::<diesel::sql_types::Integer, _>(message_id)
+            .bind::<diesel::sql_types::Text, _>(vector_literal(embedding));
+        slow::query(self.slow_query_threshold, query, |q| q.execute(&mut conn)).await?;
+        Ok(())
+    }
+
+    /// Up to `limit` messages whose embeddings are nearest to `embedding`,
+    /// nearest first.
+    #[cfg(feature = "pgvector")]
+    pub async fn find_similar(&self, embedding: &[f32], limit: i64) -> DbResult<Vec<Similar>> {
+        let mut conn = self.conn().await?;
+        let query = diesel::sql_query(FIND_SIMILAR_SQL)
+            .bind::<diesel::sql_types::Text, _>(vector_literal(embedding))
+            .bind::<diesel::sql_types::BigInt, _>(limit);
+        let mut similar: Vec<Similar> =
+            slow::query(self.slow_query_threshold, query, |q| q.load(&mut conn)).await?;
+        for found in &mut similar {
+            if let Some(text) = &found.message.message {
+                found.message.message = Some(self.open(text).await?);
+            }
+        }
+        Ok(similar)
+    }
+
+    /// Oldest unpublished outbox events, in insertion order.
+    pub async fn get_pending_outbox(&self, limit: i64) -> DbResult<Vec<OutboxEvent>> {
+        let mut conn = self.conn().await?;
+        let query = outbox::table
+            .filter(outbox::published_at.is_null())
+            .order(outbox::id.asc())
+            .limit(limit)
+            .select(OutboxEvent::as_select());
+        let mut events: Vec<OutboxEvent> =
+            slow::query(self.slow_query_threshold, query, |q| q.load(&mut conn)).await?;
+        for event in &mut events {
+            event.payload = self.open_payload(&event.payload).await?;
+        }
+        Ok(events)
+    }
+
+    pub async fn mark_outbox_published(&self, ids: &[i64]) -> DbResult<()> {
+        let mut conn = self.conn().await?;
+        let query = diesel::update(outbox::table.filter(outbox::id.eq_any(ids)))
+            .set(outbox::published_at.eq(diesel::dsl::now));
+        slow::query(self.slow_query_threshold, query, |q| q.execute(&mut conn)).await?;
+        Ok(())
+    }
+
+    /// `text` as it is stored, encrypted with `with_encryption`.
+    async fn seal(&self, text: &str) -> DbResult<String> {
+        match &self.cipher {
+            Some(cipher) => Ok(cipher.seal(text).await?),
+            None => Ok(text.to_string()),
+        }
+    }
+
+    async fn seal_all<'a>(&self, texts: impl Iterator<Item = &'a str>) -> DbResult<Vec<String>> {
+        let mut sealed = Vec::new();
+        for text in texts {
+            sealed.push(self.seal(text).await?);
+        }
+        Ok(sealed)
+    }
+
+    /// A stored text as it was before `seal`.
+    async fn open(&self, stored: &str) -> DbResult<String> {
+        match &self.cipher {
+            Some(cipher) => Ok(cipher.open(stored).await?),
+            None => Ok(stored.to_string()),
+        }
+    }
+
+    async fn open_all(&self, mut messages: Vec<Message>) -> DbResult<Vec<Message>> {
+        if self.cipher.is_some() {
+            for msg in &mut messages {
+                if let Some(text) = &msg.message {
+                    msg.message = Some(self.open(text).await?);
+                }
+            }
+        }
+        Ok(messages)
+    }
+
+    /// An event or outbox payload with its `message` decrypted.
+    async fn open_payload(&self, payload: &str) -> DbResult<String> {
+        let Some(cipher) = &self.cipher else {
+            return Ok(payload.to_string());
+        };
+        let Ok(mut value) = serde_json::from_str::<serde_json::Value>(payload) else {
+            return Ok(payload.to_string());
+        };
+        if let Some(serde_json::Value::String(text)) = value.get_mut("message") {
+            *text = cipher.open(text).await?;
+        }
+        Ok(value.to_string())
+    }
+}
+
+/// `embedding` in the text form of pgvector's `vector`, e.g. `[1,0.5]`.
+#[cfg(feature = "pgvector")]
+fn vector_literal(embedding: &[f32]) -> String {
+    let values = embedding
+        .iter()
+        .map(f32::to_string)
+        .collect::<Vec<_>>()
+        .join(",");
+    format!("[{}]", values)
+}
+
+fn usage_limit(max: Option<u64>) -> i64 {
+    max.map_or(i64::MAX, |max| max.try_into().unwrap_or(i64::MAX))
+}
+
+/// Opens a pooled connection, with `statement_timeout` unless it is zero.
+async fn establish(url: &str, statement_timeout: Duration) -> ConnectionResult<AsyncPgConnection> {
+    let mut conn = AsyncPgConnection::establish(url).await?;
+    if !statement_timeout.is_zero() {
+        let set = format!("SET statement_timeout = {}", statement_timeout.as_millis());
+        conn.batch_execute(&set)
+            .await
+            .map_err(ConnectionError::CouldntSetupConfiguration)?;
+    }
+    Ok(conn)
+}
+
+fn event_payload(msg: &Message) -> String {
+    json!({ "message": msg.message }).to_string()
+}
+
+/// Appends to the event log, has to run in the transaction that changes the
+/// `messages` projection. The log is serialized on `LOCK_EVENTS_SQL` from
+/// here to the commit.
+async fn append_events(
+    conn: &mut Conn,
+    threshold: Duration,
+    entries: &[(EventKind, i32, Option<String>)],
+) -> QueryResult<()> {
+    slow::query(threshold, diesel::sql_query(LOCK_EVENTS_SQL), |q| {
+        q.execute(conn)
+    })
+    .await?;
+    // a single event keeps to one SQL text, which the connection prepares once
+    if let [(kind, message_id, payload)] = entries {
+        let query = diesel::insert_into(events::table).values((
+            events::kind.eq(kind.as_str()),
+            events::message_id.eq(message_id),
+            events::payload.eq(payload),
+        ));
+        slow::query(threshold, query, |q| q.execute(conn)).await?;
+        return Ok(());
     }
+    let rows = entries
+        .iter()
+        .map(|(kind, message_id, payload)| {
+            (
+                events::kind.eq(kind.as_str()),
+                events::message_id.eq(message_id),
+                events::payload.eq(payload),
+            )
+        })
+        .collect::<Vec<_>>();
+    let query = diesel::insert_into(events::table).values(&rows);
+    slow::query(threshold, query, |q| q.execute(conn)).await?;
+    Ok(())
 }
@@ -0,0 +1,72 @@
+//! A self-signed certificate for local development (`dev-tls` feature), so
+//! the server can start over TLS without making one with openssl first.
+//! Nothing is generated when `server.pem` or a `server.p12` bundle is
+//! there, mounted certificates always win.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::identity::pem_block;
+
+/// Names the certificate is valid for, those of a server run locally.
+const SUBJECT_ALT_NAMES: [&str; 3] = ["localhost", "127.0.0.1", "::1"];
+
+#[derive(Error, Debug)]
+pub enum DevTlsError {
+    #[error("Certificate generation: {0}")]
+    Generate(#[from] rcgen::RcgenError),
+    #[error("Writing the certificate: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// Writes a self-signed certificate and its key to `server.pem` and
+/// `server.key` of `dir` when it has no identity yet. Returns the
+/// SHA-256 fingerprint of the certificate generated, if one was.
+pub fn ensure_identity(dir: &Path) -> Result<Option<String>, DevTlsError> {
+    let cert_path = dir.join("server.pem");
+    if cert_path.exists() || dir.join("server.p12").exists() {
+        return Ok(None);
+    }
+
+    let names = SUBJECT_ALT_NAMES.map(String::from).to_vec();
+    let cert = rcgen::generate_simple_self_signed(names)?;
+    // every serialization signs again, the fingerprint has to be of the one
+    // written
+    let der = cert.serialize_der()?;
+    fs::create_dir_all(dir)?;
+    write_key(&dir.join("server.key"), &cert.serialize_private_key_pem())?;
+    fs::write(&cert_path, pem_block("CERTIFICATE", &der))?;
+
+    let fingerprint = fingerprint(&der);
+    println!(
+        "generated a self-signed certificate in {}, SHA-256 fingerprint {}",
+        cert_path.display(),
+        fingerprint
+    );
+    Ok(Some(fingerprint))
+}
+
+/// The SHA-256 of `der` as colon separated hex, the way browsers and
+/// `openssl x509 -fingerprint -sha256` show it.
+pub fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der)
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Writes the private key readable by its owner only.
+fn write_key(path: &Path, key: &str) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(key.as_bytes())
+}
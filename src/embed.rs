@@ -0,0 +1,82 @@
+//! Embeddings of messages for similarity search (`pgvector` feature).
+//!
+//! Every stored message is embedded by the server's `Embedder` and searched
+//! by cosine distance. The default `HashingEmbedder` needs no model, plug a
+//! real one in with `ServerBuilder::with_embedder`.
+
+use std::error::Error;
+
+/// Turns text into an embedding. Every embedding of an embedder has the same
+/// dimension, and texts closer in meaning get embeddings closer in cosine
+/// distance.
+#[tonic::async_trait]
+pub trait Embedder: Send + Sync + 'static {
+    async fn embed(&self, text: &str) -> Result<Vec<f32>, Box<dyn Error + Send + Sync>>;
+}
+
+/// Embeds text by hashing its words and their trigrams into `dimensions`
+/// buckets. It catches shared words and spellings rather than meaning, but
+/// is cheap and deterministic.
+#[derive(Clone, Copy, Debug)]
+pub struct HashingEmbedder {
+    dimensions: usize,
+}
+
+impl HashingEmbedder {
+    pub fn new(dimensions: usize) -> Self {
+        Self { dimensions }
+    }
+
+    fn add(&self, embedding: &mut [f32], feature: &str, weight: f32) {
+        let hash = fnv1a(feature);
+        let bucket = (hash % self.dimensions as u64) as usize;
+        // the sign spreads out collisions instead of piling them up
+        let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+        embedding[bucket] += sign * weight;
+    }
+}
+
+#[tonic::async_trait]
+impl Embedder for HashingEmbedder {
+    async fn embed(&self, text: &str) -> Result<Vec<f32>, Box<dyn Error + Send + Sync>> {
+        let mut embedding = vec![0.0; self.dimensions];
+        let text = text.to_lowercase();
+        for word in text.split(|c: char| !c.is_alphanumeric()) {
+            if word.is_empty() {
+                continue;
+            }
+            self.add(&mut embedding, word, 1.0);
+            let padded = format!(" {} ", word).chars().collect::<Vec<_>>();
+            for trigram in padded.windows(3) {
+                self.add(&mut embedding, &trigram.iter().collect::<String>(), 0.5);
+            }
+        }
+        let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            embedding.iter_mut().for_each(|x| *x /= norm);
+        }
+        Ok(embedding)
+    }
+}
+
+/// FNV-1a, unlike `DefaultHasher` it stays the same across Rust releases,
+/// which the stored embeddings rely on.
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Cosine distance of two embeddings, what pgvector's `<=>` computes.
+pub fn cosine_distance(a: &[f32], b: &[f32]) -> f64 {
+    let (mut dot, mut norm_a, mut norm_b) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        dot += f64::from(x * y);
+        norm_a += f64::from(x * x);
+        norm_b += f64::from(y * y);
+    }
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return f64::NAN;
+    }
+    1.0 - dot / (norm_a.sqrt() * norm_b.sqrt())
+}
@@ -0,0 +1,44 @@
+use serde_json::json;
+
+use crate::db::Message;
+use crate::greeter::hello_world::ExportFormat;
+
+/// Leading line of an export, if the format has one.
+pub fn header(format: ExportFormat) -> Option<&'static str> {
+    match format {
+        ExportFormat::Csv => Some("id,message,updated\n"),
+        ExportFormat::Jsonl => None,
+    }
+}
+
+/// Appends `msg` as a single line in `format` to `out`.
+pub fn write_row(format: ExportFormat, msg: &Message, out: &mut String) {
+    match format {
+        ExportFormat::Csv => {
+            out.push_str(&msg.id.to_string());
+            out.push(',');
+            if let Some(message) = &msg.message {
+                push_csv_field(message, out);
+            }
+            out.push(',');
+            if let Some(updated) = msg.updated {
+                out.push_str(&updated.to_string());
+            }
+        }
+        ExportFormat::Jsonl => {
+            let row = json!({ "id": msg.id, "message": msg.message, "updated": msg.updated });
+            out.push_str(&row.to_string());
+        }
+    }
+    out.push('\n');
+}
+
+fn push_csv_field(field: &str, out: &mut String) {
+    if field.contains([',', '"', '\n', '\r']) {
+        out.push('"');
+        out.push_str(&field.replace('"', "\"\""));
+        out.push('"');
+    } else {
+        out.push_str(field);
+    }
+}
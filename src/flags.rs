@@ -0,0 +1,93 @@
+//! Feature flags, kept in the store and consulted by the handlers per
+//! tenant, e.g. to turn a feature on for a few tenants before the rest.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Mutex;
+
+use crate::{db::FeatureFlag, store::MessageStore};
+
+/// A flag handlers consult, and whether it's on for tenants it isn't set
+/// for.
+#[derive(Clone, Copy, Debug)]
+pub struct Flag {
+    pub name: &'static str,
+    pub default: bool,
+}
+
+/// The streaming calls, refused with `FAILED_PRECONDITION` while off.
+pub const STREAMING: Flag = Flag {
+    name: "streaming",
+    default: true,
+};
+
+/// The flags of a store, loaded again once they are `ttl` old. Clones share
+/// the cache.
+#[derive(Clone)]
+pub struct FeatureFlags<S> {
+    store: S,
+    ttl: Duration,
+    cache: Arc<Mutex<Option<Cached>>>,
+}
+
+struct Cached {
+    loaded_at: Instant,
+    flags: Arc<Vec<FeatureFlag>>,
+}
+
+impl<S: MessageStore> FeatureFlags<S> {
+    pub fn new(store: S, ttl: Duration) -> Self {
+        Self {
+            store,
+            ttl,
+            cache: Arc::default(),
+        }
+    }
+
+    /// Whether `flag` is on for `tenant`: its setting for the tenant, else
+    /// its setting for `*`, else its default.
+    pub async fn is_enabled(&self, flag: Flag, tenant: &str) -> bool {
+        let flags = self.flags().await;
+        let setting = |tenant: &str| {
+            flags
+                .iter()
+                .find(|set| set.name == flag.name && set.tenant == tenant)
+                .map(|set| set.enabled)
+        };
+        setting(tenant)
+            .or_else(|| setting("*"))
+            .unwrap_or(flag.default)
+    }
+
+    async fn flags(&self) -> Arc<Vec<FeatureFlag>> {
+        // held while loading, so callers wait for one load instead of each
+        // running their own
+        let mut cache = self.cache.lock().await;
+        if let Some(cached) = cache.as_ref() {
+            if cached.loaded_at.elapsed() < self.ttl {
+                return cached.flags.clone();
+            }
+        }
+        let flags = match self.store.get_feature_flags().await {
+            Ok(flags) => Arc::new(flags),
+            // tried again after `ttl`, not on every call while the store is down
+            Err(err) => {
+                eprintln!(
+                    "failed to load feature flags, keeping the last ones: {}",
+                    err
+                );
+                cache
+                    .as_ref()
+                    .map_or_else(Arc::default, |cached| cached.flags.clone())
+            }
+        };
+        *cache = Some(Cached {
+            loaded_at: Instant::now(),
+            flags: flags.clone(),
+        });
+        flags
+    }
+}
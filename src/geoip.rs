@@ -0,0 +1,124 @@
+//! Where calls come from: the country and autonomous system of the client
+//! address, looked up in MaxMind GeoIP2 or GeoLite2 databases (`geoip`
+//! feature). `GeoIpLayer` puts the `Location` of every call in its request
+//! extensions, for the access log and the greeter, which stores the country
+//! with the greeting for the per-country counts of `GetStats`.
+
+use std::{
+    net::IpAddr,
+    path::Path,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::peer_info::PeerInfo;
+
+/// What the databases know of a client address.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Location {
+    /// ISO 3166-1 alpha-2 code, e.g. `DE`.
+    pub country: Option<String>,
+    /// Number of the autonomous system announcing the address.
+    pub asn: Option<u32>,
+}
+
+impl Location {
+    /// The location `GeoIpLayer` found for the call of `extensions`.
+    pub fn of(extensions: &http::Extensions) -> Option<&Location> {
+        extensions.get()
+    }
+}
+
+/// The country and ASN databases, either of them optional. A City database
+/// does for the country one.
+pub struct GeoIp {
+    country: Option<Reader<Vec<u8>>>,
+    asn: Option<Reader<Vec<u8>>>,
+}
+
+impl GeoIp {
+    pub fn open(country_db: Option<&Path>, asn_db: Option<&Path>) -> Result<Self, MaxMindDBError> {
+        Ok(Self {
+            country: country_db.map(Reader::open_readfile).transpose()?,
+            asn: asn_db.map(Reader::open_readfile).transpose()?,
+        })
+    }
+
+    /// What the databases know of `ip`, nothing for private addresses.
+    pub fn locate(&self, ip: IpAddr) -> Location {
+        let ip = ip.to_canonical();
+        let country = self.country.as_ref().and_then(|reader| {
+            let found = reader.lookup::<geoip2::Country>(ip).ok()?;
+            Some(found.country?.iso_code?.to_string())
+        });
+        let asn = self.asn.as_ref().and_then(|reader| {
+            let found = reader.lookup::<geoip2::Asn>(ip).ok()?;
+            found.autonomous_system_number
+        });
+        Location { country, asn }
+    }
+}
+
+/// Adds the `Location` of the client to the extensions of every call with a
+/// known peer address.
+#[derive(Clone)]
+pub struct GeoIpLayer {
+    geoip: Option<Arc<GeoIp>>,
+}
+
+impl GeoIpLayer {
+    pub fn new(geoip: GeoIp) -> Self {
+        Self {
+            geoip: Some(Arc::new(geoip)),
+        }
+    }
+
+    /// Locates no call, for servers without a database.
+    pub fn disabled() -> Self {
+        Self { geoip: None }
+    }
+}
+
+impl<S> Layer<S> for GeoIpLayer {
+    type Service = GeoIpService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GeoIpService {
+            inner,
+            geoip: self.geoip.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct GeoIpService<S> {
+    inner: S,
+    geoip: Option<Arc<GeoIp>>,
+}
+
+impl<S, B> Service<http::Request<B>> for GeoIpService<S>
+where
+    S: Service<http::Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: http::Request<B>) -> Self::Future {
+        if let Some(geoip) = &self.geoip {
+            if let Some(addr) = PeerInfo::from_http(&req).remote_addr {
+                let location = geoip.locate(addr.ip());
+                req.extensions_mut().insert(location);
+            }
+        }
+        self.inner.call(req)
+    }
+}
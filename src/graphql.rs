@@ -0,0 +1,260 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+use async_graphql::{
+    http::{GraphiQLSource, WebSocket, WebSocketProtocols, WsMessage, ALL_WEBSOCKET_PROTOCOLS},
+    Context, ErrorExtensions, Json, Object, Schema, SimpleObject, Subscription,
+};
+use axum::{
+    extract::{
+        ws::{CloseFrame, Message as AxumWsMessage, WebSocketUpgrade},
+        ConnectInfo, State,
+    },
+    http::HeaderMap,
+    response::{Html, Response},
+    routing::get,
+    Extension, Router,
+};
+use futures_util::{future, stream, SinkExt, Stream, StreamExt};
+use tokio::sync::broadcast::error::RecvError;
+use tonic::{metadata::MetadataMap, Status};
+
+use crate::db;
+use crate::greeter::hello_world::{Priority, Salutation};
+use crate::ip_filter::Cidr;
+use crate::service::{self, GreetingService, ServiceError};
+use crate::tenant;
+
+pub type GreeterSchema = Schema<Query, Mutation, MessageSubscription>;
+
+/// A stored greeting.
+#[derive(SimpleObject)]
+pub struct Greeting {
+    id: i32,
+    message: Option<String>,
+    tags: Json<serde_json::Value>,
+    metadata: Json<serde_json::Value>,
+}
+
+impl From<db::Message> for Greeting {
+    fn from(msg: db::Message) -> Self {
+        Self {
+            id: msg.id,
+            message: msg.message,
+            tags: Json(msg.tags),
+            metadata: Json(msg.metadata),
+        }
+    }
+}
+
+/// The reply to `sayHello`.
+#[derive(SimpleObject)]
+pub struct HelloReply {
+    message: String,
+    /// Id of the stored greeting.
+    cursor: i64,
+}
+
+/// Tenant of the HTTP request a GraphQL request came in, see
+/// `tenant::TENANT_METADATA`.
+struct Tenant(String);
+
+/// Peers allowed to name the tenant, see `serve`.
+#[derive(Clone)]
+struct Frontends(Arc<Vec<Cidr>>);
+
+fn service_error(err: ServiceError) -> async_graphql::Error {
+    let status = Status::from(err);
+    async_graphql::Error::new(status.message())
+        .extend_with(|_, extensions| extensions.set("code", format!("{:?}", status.code())))
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    /// Stored greetings, only those carrying all of `tags` when given.
+    async fn messages(
+        &self,
+        ctx: &Context<'_>,
+        tags: Option<HashMap<String, String>>,
+    ) -> async_graphql::Result<Vec<Greeting>> {
+        let messages = ctx
+            .data::<GreetingService>()?
+            .list(&tags.unwrap_or_default(), &Default::default())
+            .await
+            .map_err(service_error)?;
+        Ok(messages.into_iter().map(Greeting::from).collect())
+    }
+
+    async fn message_count(&self, ctx: &Context<'_>) -> async_graphql::Result<i64> {
+        let service = ctx.data::<GreetingService>()?;
+        Ok(service.store().count_messages().await?)
+    }
+}
+
+pub struct Mutation;
+
+#[Object]
+impl Mutation {
+    /// Greets `name` the way the `SayHello` RPC does, storing and
+    /// broadcasting the greeting for the tenant named by the `x-tenant-id`
+    /// header.
+    async fn say_hello(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+        locale: Option<String>,
+        tags: Option<HashMap<String, String>>,
+    ) -> async_graphql::Result<HelloReply> {
+        let service = ctx.data::<GreetingService>()?;
+        let tenant = ctx
+            .data_opt::<Tenant>()
+            .map_or(tenant::DEFAULT_TENANT, |tenant| tenant.0.as_str());
+        let greeting = service::Greeting {
+            name,
+            locale: locale.unwrap_or_default(),
+            salutation: Salutation::Unspecified,
+            tags: tags.unwrap_or_default(),
+            metadata: Default::default(),
+            priority: Priority::Normal,
+            payload: Vec::new(),
+            country: None,
+        };
+        let message = service
+            .greet(tenant, &greeting)
+            .await
+            .map_err(service_error)?;
+        Ok(HelloReply {
+            cursor: message.id.into(),
+            message: message.message.unwrap_or_default(),
+        })
+    }
+}
+
+pub struct MessageSubscription;
+
+#[Subscription]
+impl MessageSubscription {
+    /// Greetings as they are stored. Lagging subscribers miss greetings
+    /// rather than hold the broadcast up.
+    async fn messages(
+        &self,
+        ctx: &Context<'_>,
+    ) -> async_graphql::Result<impl Stream<Item = Greeting>> {
+        let subscription = ctx
+            .data::<GreetingService>()?
+            .broadcaster()
+            .subscribe("GraphQL");
+        Ok(stream::unfold(
+            subscription,
+            |mut subscription| async move {
+                loop {
+                    match subscription.recv().await {
+                        Ok(msg) => {
+                            return Some((Greeting::from(db::Message::clone(&msg)), subscription))
+                        }
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            },
+        ))
+    }
+}
+
+/// The schema over `service`, the one the gRPC handlers run on.
+pub fn schema(service: GreetingService) -> GreeterSchema {
+    Schema::build(Query, Mutation, MessageSubscription)
+        .data(service)
+        .finish()
+}
+
+/// GraphiQL at `/graphql`, which takes queries and mutations as POSTed JSON,
+/// and subscriptions over WebSocket at `/graphql/ws`.
+pub fn router(schema: GreeterSchema) -> Router {
+    Router::new()
+        .route("/graphql", get(graphiql).post(execute))
+        .route("/graphql/ws", get(subscribe))
+        .with_state(schema)
+}
+
+/// Serves `router` on `addr`, taking the tenant of requests from `frontends`
+/// only, see `tenant::TENANT_METADATA`.
+pub async fn serve(
+    addr: SocketAddr,
+    schema: GreeterSchema,
+    frontends: Vec<Cidr>,
+) -> Result<(), hyper::Error> {
+    println!("GraphQL endpoint listening on {}", addr);
+    let router = router(schema).layer(Extension(Frontends(Arc::new(frontends))));
+    axum::Server::bind(&addr)
+        .serve(router.into_make_service_with_connect_info::<SocketAddr>())
+        .await
+}
+
+async fn graphiql() -> Html<String> {
+    Html(
+        GraphiQLSource::build()
+            .endpoint("/graphql")
+            .subscription_endpoint("/graphql/ws")
+            .finish(),
+    )
+}
+
+async fn execute(
+    State(schema): State<GreeterSchema>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    frontends: Option<Extension<Frontends>>,
+    headers: HeaderMap,
+    axum::Json(request): axum::Json<async_graphql::Request>,
+) -> axum::Json<async_graphql::Response> {
+    let frontends = frontends
+        .as_ref()
+        .map_or(&[][..], |Extension(f)| f.0.as_slice());
+    let tenant = match tenant::is_frontend(frontends, peer.map(|ConnectInfo(addr)| addr)) {
+        true => tenant::from_metadata(&MetadataMap::from_headers(headers)),
+        false => tenant::DEFAULT_TENANT.to_string(),
+    };
+    let request = request.data(Tenant(tenant));
+    axum::Json(schema.execute(request).await)
+}
+
+async fn subscribe(
+    State(schema): State<GreeterSchema>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Response {
+    // the client lists the protocols it speaks, the first one known is used
+    let protocol = headers
+        .get_all(http::header::SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|protocol| protocol.trim().parse::<WebSocketProtocols>().ok())
+        .unwrap_or(WebSocketProtocols::GraphQLWS);
+    ws.protocols(ALL_WEBSOCKET_PROTOCOLS)
+        .on_upgrade(move |socket| async move {
+            let (mut sink, incoming) = socket.split();
+            let incoming = incoming
+                .take_while(|msg| future::ready(msg.is_ok()))
+                .filter_map(|msg| {
+                    future::ready(match msg {
+                        Ok(AxumWsMessage::Text(text)) => Some(text.into_bytes()),
+                        Ok(AxumWsMessage::Binary(bytes)) => Some(bytes),
+                        _ => None,
+                    })
+                });
+            let mut outgoing = WebSocket::new(schema, incoming, protocol).map(|msg| match msg {
+                WsMessage::Text(text) => AxumWsMessage::Text(text),
+                WsMessage::Close(code, reason) => AxumWsMessage::Close(Some(CloseFrame {
+                    code,
+                    reason: reason.into(),
+                })),
+            });
+            while let Some(msg) = outgoing.next().await {
+                if sink.send(msg).await.is_err() {
+                    break;
+                }
+            }
+        })
+}
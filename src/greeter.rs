@@ -1,15 +1,53 @@
-use std::{error::Error, io::ErrorKind, pin::Pin};
+#[cfg(feature = "pgvector")]
+use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    error::Error,
+    io::ErrorKind,
+    pin::Pin,
+    sync::OnceLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use cfg_if::cfg_if;
 use tokio::sync::mpsc;
+use tokio::time;
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::{Stream, StreamExt};
-#[cfg(feature = "tls")]
-use tonic::transport::server::{TcpConnectInfo, TlsConnectInfo};
+use tokio_util::sync::CancellationToken;
 use tonic::{Request, Response, Status, Streaming};
 
+#[cfg(feature = "attachments")]
+use crate::attachments::{AttachmentError, Attachments};
+use crate::clients::Clients;
+use crate::coalesce::{self, InFlight};
+use crate::codec::{EncodedReply, Frame};
+use crate::config::{Config, SlowSubscriberPolicy};
+use crate::cooldown::TtlMap;
 use crate::db;
-use crate::messages::Broadcaster;
+#[cfg(feature = "pgvector")]
+use crate::embed::Embedder;
+use crate::export;
+use crate::flags;
+use crate::greeting;
+use crate::groups::ConsumerGroups;
+use crate::import;
+use crate::messages::{Broadcaster, Fanout, SharedMessage};
+use crate::metadata;
+use crate::moderation::Moderation;
+use crate::peer_info::PeerInfo;
+use crate::pool;
+use crate::redact;
+use crate::service::{Greeting, GreetingService, ServiceError};
+use crate::sessions::{LiveSessions, SessionHandle};
+use crate::slow::RpcTimer;
+use crate::store::MessageStore;
+use crate::stream::{
+    self, spawn_feeder, AckWindow, Buffered, CancelOnDrop, Coalesced, ErrorsLast, Heartbeat,
+    Redelivery, SendBuffer,
+};
+use crate::tenant;
+use crate::translate::Translator;
+use crate::writer::ExchangeWriter;
 
 pub mod hello_world {
     tonic::include_proto!("helloworld");
@@ -20,12 +58,65 @@ pub mod hello_world {
 
 use hello_world::greeter_server::Greeter;
 pub use hello_world::greeter_server::GreeterServer;
+#[cfg(feature = "pgvector")]
+use hello_world::SimilarMessage;
 pub use hello_world::FILE_DESCRIPTOR_SET;
-use hello_world::{HelloReply, HelloRequest, ListMessagesReply, ListMessagesRequest};
+use hello_world::{
+    AttachmentChunk, CountryStats, DayStats, DeleteAllForNameProgress, DeleteAllForNameRequest,
+    DownloadPayloadRequest, EventKind, ExportChunk, ExportMessagesRequest,
+    FindSimilarMessagesReply, FindSimilarMessagesRequest, GetAttachmentReply, GetAttachmentRequest,
+    GetSessionRequest, GetStatsRequest, GetUsageRequest, GreetingEvent, HelloReply, HelloRequest,
+    HelloSummaryReply, ImportBatchResult, ImportMessagesReply, ImportMessagesRequest,
+    LeaderboardReply, ListMessagesReply, ListMessagesRequest, NameStats, PayloadChunk,
+    SayHelloManyRequest, SessionExchange, SessionReply, StatsReply, StreamEventsRequest,
+    StreamLeaderboardRequest, UpdateMessageRequest, UploadAttachmentReply, UploadPayloadReply,
+    UsageReply,
+};
+
+/// Response metadata of `SayHelloStream` and `ListMessagesStream` with the id
+/// of their session, see `GetSession` and the `ListSessions` admin call.
+pub const SESSION_METADATA: &str = "x-session-id";
 
 type GreeterResult<T> = Result<Response<T>, Status>;
 type GreeterResponseStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
 
+/// Upper bound on the replies a single `SayHelloMany` call may ask for.
+const SAY_HELLO_MANY_MAX_REPLIES: usize = 10_000;
+
+/// Distinct names `SayHelloSummary` reports, later ones are only counted.
+pub const SUMMARY_MAX_DISTINCT_NAMES: usize = 1000;
+
+/// Events read from the log per query by `StreamEvents`.
+const EVENTS_PAGE_SIZE: i64 = 500;
+
+/// Pending deliveries replayed per query by `ListMessagesStream`.
+const PENDING_DELIVERIES_PAGE_SIZE: i64 = 500;
+
+/// Days `GetStats` counts per day unless asked for another number, and the
+/// most it counts.
+const STATS_DEFAULT_DAYS: u32 = 30;
+const STATS_MAX_DAYS: u32 = 366;
+
+/// Names `GetStats` reports unless asked for another number, and the most
+/// it reports.
+const STATS_DEFAULT_TOP: u32 = 10;
+const STATS_MAX_TOP: u32 = 100;
+
+/// Messages `DeleteAllForName` scans per query, a progress is sent after
+/// each page.
+const ERASE_PAGE_SIZE: i64 = 1000;
+
+/// Messages `FindSimilarMessages` returns unless asked for another number,
+/// and the most it returns.
+#[cfg(feature = "pgvector")]
+const SIMILAR_MESSAGES_DEFAULT_LIMIT: u32 = 10;
+#[cfg(feature = "pgvector")]
+const SIMILAR_MESSAGES_MAX_LIMIT: u32 = 100;
+
+/// Size of the chunks `DownloadPayload` streams, well under the gRPC
+/// message limit.
+const PAYLOAD_CHUNK_BYTES: usize = 64 * 1024;
+
 fn match_for_io_error(err_status: &Status) -> Option<&std::io::Error> {
     let mut err: &(dyn Error + 'static) = err_status;
 
@@ -42,62 +133,705 @@ fn match_for_io_error(err_status: &Status) -> Option<&std::io::Error> {
             }
         }
 
-        err = match err.source() {
-            Some(err) => err,
-            None => return None,
+        err = err.source()?;
+    }
+}
+
+/// Sends the pending deliveries to `tx`, skipping ids up to `skip_until`
+/// that were sent already. They are only dropped from the queue once sent,
+/// returns `false` when the stream is gone.
+async fn replay_pending<S: MessageStore>(
+    store: &S,
+    feed: &mut MessageFeed,
+    skip_until: i32,
+) -> bool {
+    loop {
+        let pending = match store
+            .get_pending_deliveries(PENDING_DELIVERIES_PAGE_SIZE)
+            .await
+        {
+            Ok(pending) if pending.is_empty() => return true,
+            Ok(pending) => pending,
+            Err(err) => {
+                feed.fail(Status::internal(err.to_string())).await;
+                return false;
+            }
         };
+
+        let mut delivered = Vec::with_capacity(pending.len());
+        let mut gone = false;
+        for msg in pending {
+            let id = msg.id;
+            if id > skip_until && !feed.send(msg.into()).await {
+                gone = true;
+                break;
+            }
+            delivered.push(id);
+        }
+        if let Err(err) = store.delete_pending_deliveries(&delivered).await {
+            feed.fail(Status::internal(err.to_string())).await;
+            return false;
+        }
+        if gone {
+            return false;
+        }
     }
 }
 
-pub struct MyGreeter {
-    db: db::Db,
-    broadcaster: Broadcaster,
+/// The `tags` and `metadata` a `ListMessagesStream` wants its messages to
+/// carry, set by its first request and replaced by the requests with
+/// `update_filters`.
+#[derive(Default)]
+struct StreamFilter {
+    tags: HashMap<String, String>,
+    metadata: serde_json::Map<String, serde_json::Value>,
 }
 
-impl MyGreeter {
-    pub fn new(db: db::Db) -> Self {
-        let broadcaster = Broadcaster::new();
-        Self { db, broadcaster }
+impl StreamFilter {
+    fn of(request: &ListMessagesRequest) -> Self {
+        Self {
+            tags: request.tags.clone(),
+            metadata: metadata::to_json(request.metadata.clone().unwrap_or_default()),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tags.is_empty() && self.metadata.is_empty()
+    }
+
+    fn matches(&self, msg: &db::Message) -> bool {
+        let tags = self
+            .tags
+            .iter()
+            .all(|(key, value)| msg.tags.get(key).and_then(|tag| tag.as_str()) == Some(value));
+        tags && self.metadata.iter().all(|(key, wanted)| {
+            msg.metadata
+                .get(key)
+                .is_some_and(|value| metadata::contains(value, wanted))
+        })
     }
 }
 
-#[tonic::async_trait]
-impl Greeter for MyGreeter {
-    async fn say_hello(&self, request: Request<HelloRequest>) -> GreeterResult<HelloReply> {
-        cfg_if! {
-            if #[cfg(feature = "tls")] {
-                let conn_info = request
-                    .extensions()
-                    .get::<TlsConnectInfo<TcpConnectInfo>>()
-                    .unwrap();
-                println!(
-                    "Got a request from '{}' with info {:?}",
-                    request
-                        .remote_addr()
-                        .map(|c| c.to_string())
-                        .unwrap_or_default(),
-                    conn_info
-                );
-            } else {
-                println!(
-                    "Got a request from '{}'",
-                    request
-                        .remote_addr()
-                        .map(|c| c.to_string())
-                        .unwrap_or_default(),
+/// The sending end of a `ListMessagesStream`, keeping replies until they are
+/// acked when the subscriber is named.
+struct MessageFeed {
+    tx: mpsc::Sender<Result<Frame, Status>>,
+    acks: Option<Redelivery<Frame>>,
+    filter: StreamFilter,
+    session: SessionHandle,
+    /// Replies the client has yet to take, `budget` bytes of them at most,
+    /// 0 for no limit.
+    buffer: SendBuffer,
+    budget: usize,
+    policy: SlowSubscriberPolicy,
+    /// Over budget, what is broadcast meanwhile is left for `catch_up`.
+    paused: bool,
+    /// Id of the latest message sent or filtered out.
+    last_sent: i32,
+}
+
+impl MessageFeed {
+    /// Sends `msg` when it passes the filter, returns `false` once the stream
+    /// is gone.
+    async fn send(&mut self, msg: SharedMessage) -> bool {
+        self.deliver(msg, false).await
+    }
+
+    /// Same as `send`, for a message sent ahead of older ones the stream has
+    /// yet to send. Its reply names where to resume from without skipping
+    /// them, the stream isn't counted as sent up to it.
+    async fn send_ahead(&mut self, msg: SharedMessage) -> bool {
+        self.deliver(msg, true).await
+    }
+
+    async fn deliver(&mut self, msg: SharedMessage, ahead: bool) -> bool {
+        if self.policy == SlowSubscriberPolicy::Pause && self.is_over_budget() {
+            self.paused = true;
+        }
+        if self.paused {
+            return true;
+        }
+        if !ahead {
+            self.last_sent = self.last_sent.max(msg.id);
+        }
+        if !self.filter.matches(&msg) {
+            return true;
+        }
+        let id = msg.id;
+        let reply = match ahead {
+            true => Frame::Reply(HelloReply {
+                resume_token: self.last_sent.into(),
+                ..msg.reply()
+            }),
+            false => Frame::Encoded(msg.encoded()),
+        };
+        if let Some(acks) = self.acks.as_mut() {
+            if acks.is_full() {
+                // the client isn't acking, keeping more would grow unbounded
+                let status = Status::resource_exhausted(
+                    "too many unacked replies, ack replies to keep the stream",
                 );
+                self.fail(status).await;
+                return false;
             }
+            acks.sent(id, reply.clone());
         }
+        self.send_reply(reply).await
+    }
 
-        let reply = hello_world::HelloReply {
-            message: format!("Hello {}!", request.into_inner().name),
+    async fn send_reply(&self, reply: Frame) -> bool {
+        if self.is_over_budget() {
+            return match self.policy {
+                // unacked replies are sent again later
+                SlowSubscriberPolicy::Pause => true,
+                SlowSubscriberPolicy::Disconnect => {
+                    eprintln!(
+                        "dropping the stream of session {}: {} bytes of replies unread",
+                        self.session.id(),
+                        self.buffer.bytes()
+                    );
+                    self.buffer.overflow();
+                    false
+                }
+            };
+        }
+        self.buffer.add(&reply);
+        let sent = self.tx.send(Ok(reply)).await.is_ok();
+        if sent {
+            self.session.sent();
+        }
+        sent
+    }
+
+    async fn heartbeat(&self) -> bool {
+        let reply = Frame::Encoded(EncodedReply::heartbeat());
+        self.buffer.add(&reply);
+        self.tx.send(Ok(reply)).await.is_ok()
+    }
+
+    fn is_over_budget(&self) -> bool {
+        self.budget > 0 && self.buffer.bytes() > self.budget
+    }
+
+    /// Sends what was stored while paused, once the client read half of its
+    /// budget. Returns the id sent up to, `None` once the stream is gone.
+    async fn catch_up<S: MessageStore>(&mut self, store: &S) -> Option<i32> {
+        self.paused = false;
+        let missed = match store.get_messages_after(self.last_sent).await {
+            Ok(missed) => missed,
+            Err(err) => {
+                self.fail(Status::internal(err.to_string())).await;
+                return None;
+            }
         };
-        self.db
-            .insert_message(&reply.message)
+        for msg in missed {
+            if !self.send(msg.into()).await {
+                return None;
+            }
+        }
+        Some(self.last_sent)
+    }
+
+    async fn fail(&self, status: Status) {
+        let _ = self.tx.send(Err(status)).await;
+    }
+
+    /// Records the ack of the reply with cursor `ack`, returns the position
+    /// the stream is acked up to when that moved.
+    fn ack(&mut self, ack: i64) -> Option<i32> {
+        let id = i32::try_from(ack).ok()?;
+        self.acks.as_mut()?.ack(id)
+    }
+
+    /// Sends the replies that went unacked for too long again.
+    async fn redeliver(&mut self) -> bool {
+        let due = self.acks.as_mut().map(Redelivery::due).unwrap_or_default();
+        for reply in due {
+            if !self.send_reply(reply).await {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// The greeter service, storing greetings in `S` and fanning them out to
+/// live subscribers through `B`.
+pub struct MyGreeter<S = db::Db, B = Broadcaster> {
+    service: GreetingService<S, B>,
+    groups: ConsumerGroups,
+    /// `SayHello` calls in flight by tenant and idempotency key.
+    hedged: InFlight<Result<HelloReply, Status>>,
+    /// Latest `SayHello` reply by tenant and name, while in cooldown.
+    cooldown: Option<TtlMap<HelloReply>>,
+    sessions: LiveSessions,
+    /// Stores what `SayHelloStream` calls greet, spawned by the first one.
+    writer: OnceLock<ExchangeWriter>,
+    config: Config,
+}
+
+impl<S: MessageStore> MyGreeter<S> {
+    pub fn new(store: S, config: Config) -> Self {
+        Self {
+            service: GreetingService::new(store, &config),
+            groups: ConsumerGroups::default(),
+            hedged: InFlight::default(),
+            cooldown: (config.greeting_cooldown_secs > 0)
+                .then(|| TtlMap::new(Duration::from_secs(config.greeting_cooldown_secs))),
+            sessions: LiveSessions::default(),
+            writer: OnceLock::new(),
+            config,
+        }
+    }
+}
+
+impl<S: MessageStore, B: Fanout> MyGreeter<S, B> {
+    /// Broadcasts greetings on `broadcaster` instead of a fresh one.
+    pub fn with_broadcaster<F: Fanout>(self, broadcaster: F) -> MyGreeter<S, F> {
+        MyGreeter {
+            service: self.service.with_broadcaster(broadcaster),
+            groups: self.groups,
+            hedged: self.hedged,
+            cooldown: self.cooldown,
+            sessions: self.sessions,
+            writer: OnceLock::new(),
+            config: self.config,
+        }
+    }
+
+    /// Runs greeted names through `moderation`.
+    pub fn with_moderation(mut self, moderation: Moderation) -> Self {
+        self.service = self.service.with_moderation(moderation);
+        self
+    }
+
+    /// Lets the handlers call the upstream services of `clients`.
+    pub fn with_clients(mut self, clients: Clients) -> Self {
+        self.service = self.service.with_clients(clients);
+        self
+    }
+
+    /// Localizes the greetings of `SayHello` with `translator`.
+    pub fn with_translator(mut self, translator: Translator) -> Self {
+        self.service = self.service.with_translator(translator);
+        self
+    }
+
+    /// Serves `UploadAttachment` and `GetAttachment` from `attachments`.
+    #[cfg(feature = "attachments")]
+    pub fn with_attachments(mut self, attachments: Attachments) -> Self {
+        self.service = self.service.with_attachments(attachments);
+        self
+    }
+
+    #[cfg(feature = "attachments")]
+    fn attachments(&self) -> Result<&Attachments, Status> {
+        self.service
+            .attachments()
+            .ok_or_else(|| Status::failed_precondition("no attachment bucket configured"))
+    }
+
+    /// Embeds messages with `embedder` instead of the `HashingEmbedder`.
+    #[cfg(feature = "pgvector")]
+    pub fn with_embedder(mut self, embedder: Arc<dyn Embedder>) -> Self {
+        self.service = self.service.with_embedder(embedder);
+        self
+    }
+
+    pub fn broadcaster(&self) -> B {
+        self.service.broadcaster().clone()
+    }
+
+    /// The streaming calls open, for the `Admin` service to list and kill.
+    pub fn sessions(&self) -> &LiveSessions {
+        &self.sessions
+    }
+
+    /// The business logic the handlers run on, to serve it over other
+    /// transports.
+    pub fn service(&self) -> &GreetingService<S, B> {
+        &self.service
+    }
+
+    fn rpc_timer(&self, method: &'static str) -> RpcTimer {
+        RpcTimer::start(method, Duration::from_millis(self.config.slow_rpc_ms))
+    }
+
+    /// Refuses a streaming call of `tenant` while `flags::STREAMING` is off
+    /// for it.
+    async fn check_streaming(&self, tenant: &str) -> Result<(), Status> {
+        if self
+            .service
+            .flags()
+            .is_enabled(flags::STREAMING, tenant)
             .await
-            .map_err(|err| Status::new(tonic::Code::Internal, err.to_string()))?;
-        self.broadcaster.broadcast(&reply.message);
+        {
+            Ok(())
+        } else {
+            Err(Status::failed_precondition(format!(
+                "streaming calls are turned off for tenant {}",
+                tenant
+            )))
+        }
+    }
+
+    /// The replies of a `ListMessagesStream`, bursts of them held back to go
+    /// out together.
+    fn coalesced<R: Stream + Unpin>(&self, replies: R) -> Coalesced<R>
+    where
+        R::Item: Unpin,
+    {
+        Coalesced::new(
+            replies,
+            Duration::from_millis(self.config.stream_coalesce_ms),
+            self.config.stream_coalesce_max,
+        )
+    }
+
+    /// Feeds a `ListMessagesStream` with its share of the messages of the
+    /// consumer group `name`.
+    fn join_group(
+        &self,
+        name: &str,
+        mut heartbeat: Heartbeat,
+        session: SessionHandle,
+    ) -> Response<GreeterResponseStream<Frame>> {
+        let depth = self.config.stream_channel_depth;
+        let group = self.groups.join(
+            name,
+            self.service.store(),
+            self.service.broadcaster(),
+            depth,
+        );
+        let (tx, rx) = mpsc::channel(depth);
+        let session_id = session.id();
+        let killed = session.killed();
+        let buffer = SendBuffer::default();
+        // a group's share can't be caught up from the store, slow members
+        // are dropped whatever the policy
+        let feed = MessageFeed {
+            tx: tx.clone(),
+            acks: None,
+            filter: StreamFilter::default(),
+            session,
+            buffer: buffer.clone(),
+            budget: self.config.stream_buffer_bytes,
+            policy: SlowSubscriberPolicy::Disconnect,
+            paused: false,
+            last_sent: 0,
+        };
+        let token = CancellationToken::new();
+        let forward_token = token.clone();
+        let service = self.service.clone();
+        let store = self.service.store().clone();
+        let name = name.to_string();
+        let feeding = async move {
+            loop {
+                let delivered = tokio::select! {
+                    _ = forward_token.cancelled() => break,
+                    _ = killed.cancelled() => {
+                        feed.fail(session_killed()).await;
+                        break;
+                    }
+                    _ = heartbeat.tick() => feed.heartbeat().await,
+                    msg = group.recv() => match msg {
+                        Some(msg) => {
+                            heartbeat.reset();
+                            let id = msg.id;
+                            let delivered = feed.send_reply(Frame::Encoded(msg.encoded())).await;
+                            if delivered {
+                                if let Err(err) = store.set_delivered_until(&name, id).await {
+                                    eprintln!("failed to store position of {}: {}", name, err);
+                                }
+                            }
+                            delivered
+                        }
+                        None => {
+                            feed.fail(Status::unavailable("subscription failed")).await;
+                            break;
+                        }
+                    },
+                };
+                if !delivered {
+                    break;
+                }
+            }
+        };
+        spawn_feeder(tx, async move {
+            feeding.await;
+            end_session(&service, session_id).await;
+        });
+        let replies = self.coalesced(ReceiverStream::new(rx));
+        let out_stream = CancelOnDrop::new(Buffered::new(replies, buffer), token);
+        Response::new(Box::pin(out_stream))
+    }
+
+    /// Records a `method` session of `tenant` and lists it as live until the
+    /// returned handle is dropped.
+    async fn open_session(
+        &self,
+        method: &'static str,
+        tenant: &str,
+        peer: &str,
+    ) -> Result<(i64, SessionHandle), Status> {
+        let id = self.service.start_session(tenant).await?;
+        Ok((id, self.sessions.register(id, method, tenant, peer)))
+    }
+
+    /// Version of the stored messages and what `request` lists of them, no
+    /// messages when its `known_version` is still current.
+    async fn listing(
+        &self,
+        request: &ListMessagesRequest,
+    ) -> Result<(String, Option<Vec<db::Message>>), Status> {
+        // read before listing, so a write in between makes the next call list
+        // again rather than hide it
+        let version = self.service.version().await?;
+        if !request.known_version.is_empty() && request.known_version == version {
+            return Ok((version, None));
+        }
+        let metadata = metadata::to_json(request.metadata.clone().unwrap_or_default());
+        let messages = self.service.list(&request.tags, &metadata).await?;
+        Ok((version, Some(messages)))
+    }
+
+    /// Stores and broadcasts a batch of greetings for `tenant`, draining
+    /// `batch`.
+    async fn insert_batch(&self, tenant: &str, batch: &mut Vec<String>) -> Result<u64, Status> {
+        let inserted = self.service.store_messages(tenant, batch, true).await?;
+        batch.clear();
+        Ok(inserted.len() as u64)
+    }
+}
+
+fn not_modified(version: String) -> ListMessagesReply {
+    ListMessagesReply {
+        version,
+        not_modified: true,
+        ..Default::default()
+    }
+}
+
+/// `messages` as listed in a reply of `version`.
+fn listed(messages: Vec<db::Message>, version: String) -> ListMessagesReply {
+    let priorities = messages.iter().map(|msg| msg.priority.into()).collect();
+    ListMessagesReply {
+        messages: messages
+            .into_iter()
+            .map(|msg| msg.message.unwrap_or_default())
+            .collect(),
+        priorities,
+        version,
+        not_modified: false,
+    }
+}
+
+/// Bytes `msg` takes up in a `ListMessagesReply`, its text and its priority
+/// packed with the others.
+fn listed_len(msg: &db::Message) -> usize {
+    let len = msg.message.as_deref().map_or(0, str::len);
+    let priority = prost::encoding::encoded_len_varint(i32::from(msg.priority) as u64);
+    prost::encoding::key_len(1) + prost::encoding::encoded_len_varint(len as u64) + len + priority
+}
+
+/// Splits `messages` into lists of at most `max_bytes` each when encoded, a
+/// message bigger than that on its own. 0 keeps them in one list.
+fn chunk(messages: Vec<db::Message>, max_bytes: usize) -> Vec<Vec<db::Message>> {
+    let mut chunks = vec![Vec::new()];
+    let mut size = 0;
+    for msg in messages {
+        let len = listed_len(&msg);
+        let current = chunks.last_mut().unwrap();
+        if max_bytes > 0 && !current.is_empty() && size + len > max_bytes {
+            chunks.push(vec![msg]);
+            size = len;
+        } else {
+            current.push(msg);
+            size += len;
+        }
+    }
+    chunks
+}
 
+impl From<ServiceError> for Status {
+    fn from(err: ServiceError) -> Self {
+        match err {
+            ServiceError::QuotaExceeded(_) => Status::resource_exhausted(err.to_string()),
+            ServiceError::ReadOnly => Status::failed_precondition(err.to_string()),
+            ServiceError::Rejected(_) | ServiceError::PayloadTooLarge(_) => {
+                Status::invalid_argument(err.to_string())
+            }
+            err => Status::internal(err.to_string()),
+        }
+    }
+}
+
+impl From<HelloRequest> for Greeting {
+    fn from(request: HelloRequest) -> Self {
+        let salutation = request.salutation();
+        let priority = request.priority();
+        Self {
+            salutation,
+            name: request.name,
+            locale: request.locale,
+            tags: request.tags,
+            metadata: metadata::to_json(request.metadata.unwrap_or_default()),
+            priority,
+            payload: request.payload,
+            country: None,
+        }
+    }
+}
+
+impl From<db::Message> for HelloReply {
+    fn from(msg: db::Message) -> Self {
+        Self {
+            message: msg.message.unwrap_or_default(),
+            cursor: msg.id.into(),
+            metadata: Some(metadata::from_json(&msg.metadata)),
+            priority: msg.priority.into(),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<db::Session> for SessionReply {
+    fn from(session: db::Session) -> Self {
+        Self {
+            id: session.id,
+            started_at_ms: unix_ms(session.started_at),
+            ended_at_ms: session.ended_at.map_or(0, unix_ms),
+            exchanges: session
+                .exchanges
+                .into_iter()
+                .map(|exchange| SessionExchange {
+                    name: exchange.name,
+                    reply: exchange.reply,
+                    at_ms: exchange.at_ms,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// How the stream of a session an operator killed ends.
+fn session_killed() -> Status {
+    Status::aborted("session killed by an operator")
+}
+
+async fn end_session<S: MessageStore, B: Fanout>(service: &GreetingService<S, B>, id: i64) {
+    if let Err(err) = service.end_session(id).await {
+        eprintln!("failed to end session {}: {}", id, err);
+    }
+}
+
+#[cfg(feature = "attachments")]
+fn attachment_status(err: AttachmentError) -> Status {
+    if err.is_not_found() {
+        Status::not_found(err.to_string())
+    } else {
+        Status::internal(err.to_string())
+    }
+}
+
+/// `time` in milliseconds since the Unix epoch.
+pub fn unix_ms(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as i64)
+}
+
+impl From<db::NameCount> for NameStats {
+    fn from(count: db::NameCount) -> Self {
+        Self {
+            name: count.name,
+            greetings: count.greetings,
+        }
+    }
+}
+
+impl From<db::CountryCount> for CountryStats {
+    fn from(count: db::CountryCount) -> Self {
+        Self {
+            country: count.country,
+            greetings: count.greetings,
+        }
+    }
+}
+
+/// Country of the caller's address, see `geoip`.
+#[cfg(feature = "geoip")]
+fn country<T>(request: &Request<T>) -> Option<String> {
+    request
+        .extensions()
+        .get::<crate::geoip::Location>()
+        .and_then(|location| location.country.clone())
+}
+
+#[cfg(not(feature = "geoip"))]
+fn country<T>(_request: &Request<T>) -> Option<String> {
+    None
+}
+
+impl From<db::Event> for GreetingEvent {
+    fn from(event: db::Event) -> Self {
+        let kind = match db::EventKind::parse(&event.kind) {
+            Some(db::EventKind::Hello) => EventKind::Hello,
+            Some(db::EventKind::Update) => EventKind::Update,
+            Some(db::EventKind::Delete) => EventKind::Delete,
+            None => EventKind::Unspecified,
+        };
+        Self {
+            seq: event.seq,
+            kind: kind.into(),
+            message_id: event.message_id,
+            payload: event.payload.unwrap_or_default(),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl<S: MessageStore, B: Fanout> Greeter for MyGreeter<S, B> {
+    async fn say_hello(&self, request: Request<HelloRequest>) -> GreeterResult<HelloReply> {
+        let _timer = self.rpc_timer("SayHello");
+        println!("Got a request from '{}'", PeerInfo::from_request(&request));
+
+        let tenant = tenant::from_request(&request);
+        let key = coalesce::idempotency_key(request.metadata());
+        let country = country(&request);
+        let request = request.into_inner();
+        if !request.client_version.is_empty() {
+            println!("\tclient version {}", request.client_version);
+        }
+
+        // a name greeted within the cooldown gets the earlier reply again
+        let cooled = pool::key(&[tenant.as_str(), request.name.as_str()]);
+        if let Some(reply) = self.cooldown.as_ref().and_then(|c| c.get(&cooled)) {
+            return Ok(Response::new(HelloReply {
+                cached: true,
+                ..reply
+            }));
+        }
+
+        let greet = async {
+            let greeting = Greeting {
+                country,
+                ..request.into()
+            };
+            let message = self.service.greet(&tenant, &greeting).await?;
+            Ok(message.into())
+        };
+        // hedged copies of a call share the greeting of the first one
+        let reply = match key {
+            Some(key) => {
+                self.hedged
+                    .run(&pool::key(&[tenant.as_str(), key.as_str()]), greet)
+                    .await
+            }
+            None => greet.await,
+        }?;
+        if let Some(cooldown) = &self.cooldown {
+            cooldown.insert(&cooled, reply.clone());
+        }
         Ok(Response::new(reply))
     }
 
@@ -107,58 +841,132 @@ impl Greeter for MyGreeter {
         &self,
         request: Request<Streaming<HelloRequest>>,
     ) -> GreeterResult<Self::SayHelloStreamStream> {
-        let remote_addr = request
-            .remote_addr()
-            .map(|c| c.to_string())
-            .unwrap_or_default();
-        cfg_if! {
-            if #[cfg(feature = "tls")] {
-                let conn_info = request
-                    .extensions()
-                    .get::<TlsConnectInfo<TcpConnectInfo>>()
-                    .unwrap();
-                println!(
-                    "Got a stream request from '{}' with info {:?}",
-                    &remote_addr,
-                    conn_info
-                );
-            } else {
-                println!(
-                    "Got a stream request from '{}'",
-                    &remote_addr,
-                );
-            }
-        }
+        let _timer = self.rpc_timer("SayHelloStream");
+        self.check_streaming(&tenant::from_request(&request))
+            .await?;
+        let remote_addr = PeerInfo::from_request(&request).to_string();
+        println!("Got a stream request from '{}'", &remote_addr);
+        let tenant = tenant::from_request(&request);
+        let (session_id, session) = self
+            .open_session("SayHelloStream", &tenant, &remote_addr)
+            .await?;
 
         let mut in_stream = request.into_inner();
-        let (tx, rx) = mpsc::channel(128);
+        let (tx, rx) = mpsc::channel(self.config.stream_channel_depth);
+
+        let reader_service = self.service.clone();
+        let reader_tenant = tenant.clone();
+        // cancelled when the response stream is dropped, see `CancelOnDrop`
+        let token = CancellationToken::new();
 
-        let db = self.db.clone();
-        let broadcaster = self.broadcaster.clone();
+        // Names are stored by the writer of the greeter so a slow insert
+        // never holds up the reply path, in batches with those of the other
+        // calls, see `writer`. The exchanges replied to are queued with it
+        // and appended to the session's transcript; the session ends once
+        // the reader stops and what it queued is stored. The writer ends the
+        // call once the tenant runs out of quota or the server turns
+        // read-only.
+        let writes = self
+            .writer
+            .get_or_init(|| ExchangeWriter::spawn(self.service.clone(), &self.config))
+            .session(tenant, session_id, tx.clone());
 
         // this spawn here is required if you want to handle connection error.
         // If we just map `in_stream` and write it back as `out_stream` the `out_stream`
         // will be drooped when connection error occurs and error will never be propagated
         // to mapped version of `in_stream`.
-        tokio::spawn(async move {
-            let db = db.clone();
-            let broadcaster = broadcaster.clone();
-            while let Some(result) = in_stream.next().await {
+        let reader_token = token.clone();
+        let killed = session.killed();
+        let mut window = AckWindow::new(self.config.stream_ack_window);
+        spawn_feeder(tx.clone(), async move {
+            // names waiting for acks while the window is full
+            let mut held = VecDeque::new();
+            'reading: loop {
+                let result = tokio::select! {
+                    _ = reader_token.cancelled() => {
+                        eprintln!("\tclient disconnected {}: stream cancelled", &remote_addr);
+                        break;
+                    }
+                    _ = killed.cancelled() => {
+                        eprintln!("\tsession {} of {} killed", session_id, &remote_addr);
+                        let _ = tx.send(Err(session_killed())).await;
+                        break;
+                    }
+                    next = in_stream.next() => match next {
+                        Some(result) => result,
+                        None => {
+                            if !held.is_empty() {
+                                // no ack can come anymore to let them through
+                                let status = Status::failed_precondition(format!(
+                                    "stream closed with {} names waiting for acks",
+                                    held.len()
+                                ));
+                                let _ = tx.send(Err(status)).await;
+                            }
+                            break;
+                        }
+                    },
+                };
                 match result {
                     Ok(v) => {
-                        println!(
-                            concat!("\t", r#"received name: "{}" from '{}'"#),
-                            v.name, &remote_addr
-                        );
-                        tx.send(Ok(HelloReply {
-                            message: format!("Hello {}!", v.name),
-                        }))
-                        .await
-                        .expect("working rx");
-                        if let Err(err) = db.insert_message(&v.name).await {
-                            eprintln!("failed to insert message: {}", err);
+                        session.received();
+                        window.ack(v.ack);
+                        if !window.is_enabled() || !v.name.is_empty() {
+                            held.push_back(v);
+                        }
+                        // the client has to ack outstanding replies before more
+                        // names are greeted, otherwise replies would pile up
+                        // unread in the server; past another window of them
+                        // it isn't waiting for its replies
+                        if window.is_enabled() && held.len() as u64 > window.size() {
+                            let status = Status::failed_precondition(
+                                "too many names waiting for acks, ack replies before sending more",
+                            );
+                            let _ = tx.send(Err(status)).await;
+                            break;
+                        }
+                        while !window.is_full() {
+                            let Some(mut v) = held.pop_front() else {
+                                break;
+                            };
+                            println!(
+                                concat!("\t", r#"received name: "{}" from '{}'"#),
+                                redact::text(&v.name),
+                                &remote_addr
+                            );
+                            v.name = match reader_service.moderate(&reader_tenant, &v.name).await {
+                                Ok(name) => name.into_owned(),
+                                Err(err) => {
+                                    let _ = tx.send(Err(err.into())).await;
+                                    break 'reading;
+                                }
+                            };
+                            let reply = HelloReply {
+                                message: greeting::greet(&v),
+                                seq: window.next_seq(),
+                                ..Default::default()
+                            };
+                            let exchange = db::Exchange {
+                                name: v.name,
+                                reply: reply.message.clone(),
+                                at_ms: unix_ms(SystemTime::now()),
+                            };
+                            if tx.send(Ok(reply)).await.is_err() {
+                                // response stream was dropped, the client went away
+                                eprintln!("\tclient dropped the response stream {}", &remote_addr);
+                                break 'reading;
+                            }
+                            session.sent();
+                            if let Err(status) = writes.write(exchange).await {
+                                eprintln!(
+                                    "\tgreetings of {} not stored: {}",
+                                    &remote_addr,
+                                    status.message()
+                                );
+                                let _ = tx.send(Err(status)).await;
+                                break 'reading;
+                            }
                         }
-                        broadcaster.broadcast(&v.name);
                     }
                     Err(err) => {
                         if let Some(io_err) = match_for_io_error(&err) {
@@ -181,74 +989,965 @@ impl Greeter for MyGreeter {
         });
 
         // echo just write the same data that was received
-        let out_stream = ReceiverStream::new(rx);
+        let out_stream = CancelOnDrop::new(ErrorsLast::new(ReceiverStream::new(rx)), token);
+
+        let mut response = Response::new(Box::pin(out_stream) as Self::SayHelloStreamStream);
+        response
+            .metadata_mut()
+            .insert(SESSION_METADATA, session_id.into());
+        Ok(response)
+    }
+
+    type SayHelloManyStream = GreeterResponseStream<HelloReply>;
+
+    async fn say_hello_many(
+        &self,
+        request: Request<SayHelloManyRequest>,
+    ) -> GreeterResult<Self::SayHelloManyStream> {
+        let _timer = self.rpc_timer("SayHelloMany");
+        self.check_streaming(&tenant::from_request(&request))
+            .await?;
+        println!(
+            "Got a many request from '{}'",
+            PeerInfo::from_request(&request)
+        );
+
+        let SayHelloManyRequest {
+            names,
+            count,
+            delay_ms,
+        } = request.into_inner();
+        let count = count.max(1) as usize;
+        if names.len().saturating_mul(count) > SAY_HELLO_MANY_MAX_REPLIES {
+            return Err(Status::invalid_argument(format!(
+                "at most {} replies can be requested",
+                SAY_HELLO_MANY_MAX_REPLIES
+            )));
+        }
+        let delay = match delay_ms {
+            0 => Duration::from_millis(self.config.say_hello_many_delay_ms),
+            ms => Duration::from_millis(ms.into()),
+        };
+
+        let (tx, rx) = mpsc::channel(self.config.stream_channel_depth);
+        let token = CancellationToken::new();
+        let sender_token = token.clone();
+        spawn_feeder(tx.clone(), async move {
+            let names = names.iter().cycle().take(names.len() * count);
+            for (seq, name) in (1..).zip(names) {
+                if seq > 1 && !delay.is_zero() {
+                    tokio::select! {
+                        _ = sender_token.cancelled() => break,
+                        _ = tokio::time::sleep(delay) => (),
+                    }
+                }
+                let reply = HelloReply {
+                    message: format!("Hello {}!", name),
+                    seq,
+                    ..Default::default()
+                };
+                if tx.send(Ok(reply)).await.is_err() {
+                    break;
+                }
+            }
+        });
 
+        let out_stream = CancelOnDrop::new(ReceiverStream::new(rx), token);
         Ok(Response::new(
-            Box::pin(out_stream) as Self::SayHelloStreamStream
+            Box::pin(out_stream) as Self::SayHelloManyStream
         ))
     }
 
+    async fn say_hello_summary(
+        &self,
+        request: Request<Streaming<HelloRequest>>,
+    ) -> GreeterResult<HelloSummaryReply> {
+        let _timer = self.rpc_timer("SayHelloSummary");
+        println!(
+            "Got a summary request from '{}'",
+            PeerInfo::from_request(&request)
+        );
+
+        let tenant = tenant::from_request(&request);
+        let mut in_stream = request.into_inner();
+        let mut summary = HelloSummaryReply::default();
+        let mut seen = HashSet::new();
+        // greetings are inserted in batches so memory stays bounded no matter
+        // how many names the client streams
+        let mut batch = Vec::with_capacity(self.config.stream_channel_depth);
+
+        while let Some(mut v) = in_stream.message().await? {
+            v.name = self.service.moderate(&tenant, &v.name).await?.into_owned();
+            summary.count += 1;
+            if seen.len() < SUMMARY_MAX_DISTINCT_NAMES {
+                if seen.insert(v.name.clone()) {
+                    summary.distinct_names.push(v.name.clone());
+                }
+            } else if !seen.contains(&v.name) {
+                summary.distinct_names_truncated = true;
+            }
+            batch.push(greeting::greet(&v));
+            if batch.len() == self.config.stream_channel_depth {
+                summary.inserted += self.insert_batch(&tenant, &mut batch).await?;
+            }
+        }
+        if !batch.is_empty() {
+            summary.inserted += self.insert_batch(&tenant, &mut batch).await?;
+        }
+
+        Ok(Response::new(summary))
+    }
+
     async fn list_messages(
         &self,
         request: Request<ListMessagesRequest>,
     ) -> GreeterResult<ListMessagesReply> {
-        cfg_if! {
-            if #[cfg(feature = "tls")] {
-                let conn_info = request
-                    .extensions()
-                    .get::<TlsConnectInfo<TcpConnectInfo>>()
-                    .unwrap();
-                println!(
-                    "Got a request from '{}' with info {:?}",
-                    request
-                        .remote_addr()
-                        .map(|c| c.to_string())
-                        .unwrap_or_default(),
-                    conn_info
-                );
-            } else {
-                println!(
-                    "Got a request from '{}'",
-                    request
-                        .remote_addr()
-                        .map(|c| c.to_string())
-                        .unwrap_or_default(),
-                );
-            }
+        let _timer = self.rpc_timer("ListMessages");
+        println!("Got a request from '{}'", PeerInfo::from_request(&request));
+        let (version, messages) = self.listing(request.get_ref()).await?;
+        let Some(messages) = messages else {
+            return Ok(Response::new(not_modified(version)));
+        };
+        let max_bytes = self.config.list_reply_max_bytes;
+        let size = messages.iter().map(listed_len).sum::<usize>();
+        if max_bytes > 0 && size > max_bytes {
+            return Err(Status::resource_exhausted(format!(
+                "{} bytes of messages exceed the reply limit of {} bytes, use ListMessagesChunked",
+                size, max_bytes
+            )));
         }
-        let messages = self
-            .db
-            .get_messages()
-            .await
-            .map_err(|err| Status::new(tonic::Code::Internal, err.to_string()))?;
-        let messages = messages
-            .into_iter()
-            .map(|d| d.message.unwrap_or_default())
-            .collect();
-        let reply = ListMessagesReply { messages };
-        Ok(Response::new(reply))
+        Ok(Response::new(listed(messages, version)))
     }
 
-    type ListMessagesStreamStream = GreeterResponseStream<HelloReply>;
+    type ListMessagesChunkedStream = GreeterResponseStream<ListMessagesReply>;
+
+    async fn list_messages_chunked(
+        &self,
+        request: Request<ListMessagesRequest>,
+    ) -> GreeterResult<Self::ListMessagesChunkedStream> {
+        let _timer = self.rpc_timer("ListMessagesChunked");
+        self.check_streaming(&tenant::from_request(&request))
+            .await?;
+        println!("Got a request from '{}'", PeerInfo::from_request(&request));
+        let (version, messages) = self.listing(request.get_ref()).await?;
+        let replies = match messages {
+            Some(messages) => chunk(messages, self.config.list_reply_max_bytes)
+                .into_iter()
+                .map(|messages| listed(messages, version.clone()))
+                .collect(),
+            None => vec![not_modified(version)],
+        };
+        Ok(Response::new(Box::pin(tokio_stream::iter(
+            replies.into_iter().map(Ok),
+        ))))
+    }
+
+    type ListMessagesStreamStream = GreeterResponseStream<Frame>;
 
     async fn list_messages_stream(
         &self,
-        _request: Request<ListMessagesRequest>,
+        request: Request<Streaming<ListMessagesRequest>>,
     ) -> GreeterResult<Self::ListMessagesStreamStream> {
-        let mut broadcast_rx = self.broadcaster.subscribe();
-        let (tx, rx) = mpsc::channel(128);
-        tokio::spawn(async move {
-            while let Ok(msg) = broadcast_rx.recv().await {
-                let msg = Ok(HelloReply { message: msg });
-                match tx.send(msg).await {
-                    Ok(_) => (),
-                    Err(_) => break,
+        let _timer = self.rpc_timer("ListMessagesStream");
+        let tenant = tenant::from_request(&request);
+        self.check_streaming(&tenant).await?;
+        let peer = PeerInfo::from_request(&request);
+        let mut in_stream = request.into_inner();
+        // the first request sets the stream up, the ones after it carry acks
+        let first = in_stream.message().await?.unwrap_or_default();
+
+        let heartbeat_secs = match first.heartbeat_interval_secs {
+            0 => self.config.heartbeat_interval_secs,
+            secs => secs.into(),
+        };
+        let heartbeat = Heartbeat::new(Duration::from_secs(heartbeat_secs));
+        let filter = StreamFilter::of(&first);
+        if !first.subscription_name.is_empty() {
+            if !first.subscriber.is_empty() || first.resume_token != 0 {
+                return Err(Status::invalid_argument(
+                    "subscription_name can't be combined with subscriber or resume_token",
+                ));
+            }
+            // the members of a subscription take turns, filtering would lose
+            // messages another member wanted
+            if !filter.is_empty() || first.replay_count != 0 {
+                return Err(Status::invalid_argument(
+                    "subscription_name can't be combined with filters or replay_count",
+                ));
+            }
+            let (session_id, session) = self
+                .open_session("ListMessagesStream", &tenant, &peer.to_string())
+                .await?;
+            session.received();
+            let mut response = self.join_group(&first.subscription_name, heartbeat, session);
+            response
+                .metadata_mut()
+                .insert(SESSION_METADATA, session_id.into());
+            return Ok(response);
+        }
+        if first.resume_token != 0 && first.replay_count != 0 {
+            return Err(Status::invalid_argument(
+                "replay_count can't be combined with resume_token",
+            ));
+        }
+        let mut resume_token = i32::try_from(first.resume_token)
+            .map_err(|_| Status::invalid_argument("resume_token is out of range"))?;
+        let subscriber = first.subscriber;
+        let acks = if subscriber.is_empty() {
+            None
+        } else {
+            let acked_until = self
+                .service
+                .store()
+                .get_acked_until(&subscriber)
+                .await
+                .map_err(|err| Status::new(tonic::Code::Internal, err.to_string()))?;
+            if resume_token == 0 {
+                resume_token = acked_until;
+            }
+            let timeout = Duration::from_millis(self.config.ack_timeout_ms);
+            Some(Redelivery::new(
+                timeout,
+                self.config.max_unacked_replies,
+                resume_token,
+            ))
+        };
+
+        let mut heartbeat = heartbeat;
+        let mut redelivery = time::interval(Duration::from_millis(self.config.ack_timeout_ms));
+        // subscribe before backfilling so nothing stored in between is missed,
+        // live messages already covered by the backfill are skipped below
+        let label = if subscriber.is_empty() {
+            format!("ListMessagesStream {}", peer)
+        } else {
+            format!("ListMessagesStream {} {}", subscriber, peer)
+        };
+        let (session_id, session) = self
+            .open_session("ListMessagesStream", &tenant, &peer.to_string())
+            .await?;
+        // the first request set the stream up
+        session.received();
+        let killed = session.killed();
+        let mut broadcast_rx = self.service.broadcaster().subscribe(&label);
+        let (tx, rx) = mpsc::channel(self.config.stream_channel_depth);
+        let replay_count = first.replay_count as usize;
+        // filtered streams leave the pending deliveries to the others
+        let durable = self.config.durable_delivery && filter.is_empty();
+        let buffer = SendBuffer::default();
+        let mut feed = MessageFeed {
+            tx: tx.clone(),
+            acks,
+            filter,
+            session,
+            buffer: buffer.clone(),
+            budget: self.config.stream_buffer_bytes,
+            policy: self.config.stream_buffer_policy,
+            paused: false,
+            last_sent: resume_token,
+        };
+        let token = CancellationToken::new();
+        let forward_token = token.clone();
+        let service = self.service.clone();
+        let replay_service = self.service.clone();
+        let store = self.service.store().clone();
+        let feeding = async move {
+            let mut backfilled_until = resume_token;
+            if resume_token > 0 {
+                let backfill = tokio::select! {
+                    _ = forward_token.cancelled() => return,
+                    backfill = store.get_messages_after(resume_token) => backfill,
+                };
+                let backfill = match backfill {
+                    Ok(backfill) => backfill,
+                    Err(err) => {
+                        feed.fail(Status::internal(err.to_string())).await;
+                        return;
+                    }
+                };
+                for msg in backfill {
+                    backfilled_until = msg.id;
+                    if !feed.send(msg.into()).await {
+                        return;
+                    }
+                }
+            } else if replay_count > 0 {
+                let filter = &feed.filter;
+                let listed = tokio::select! {
+                    _ = forward_token.cancelled() => return,
+                    listed = replay_service.list(&filter.tags, &filter.metadata) => listed,
+                };
+                let mut listed = match listed {
+                    Ok(listed) => listed,
+                    Err(err) => {
+                        feed.fail(err.into()).await;
+                        return;
+                    }
+                };
+                let latest = listed.split_off(listed.len().saturating_sub(replay_count));
+                for msg in latest {
+                    backfilled_until = msg.id;
+                    if !feed.send(msg.into()).await {
+                        return;
+                    }
+                }
+            }
+
+            // greetings nobody got go to the first subscriber
+            if durable {
+                let replayed = tokio::select! {
+                    _ = forward_token.cancelled() => return,
+                    replayed = replay_pending(&store, &mut feed, backfilled_until) => replayed,
+                };
+                if !replayed {
+                    return;
+                }
+            }
+
+            let mut reading = true;
+            loop {
+                let delivered = tokio::select! {
+                    _ = forward_token.cancelled() => break,
+                    _ = killed.cancelled() => {
+                        feed.fail(session_killed()).await;
+                        break;
+                    }
+                    _ = heartbeat.tick() => feed.heartbeat().await,
+                    msg = broadcast_rx.recv() => match msg {
+                        Ok(msg) if msg.id <= backfilled_until => continue,
+                        Ok(msg) => {
+                            heartbeat.reset();
+                            match broadcast_rx.overtook() {
+                                true => feed.send_ahead(msg).await,
+                                false => feed.send(msg).await,
+                            }
+                        }
+                        Err(_) => break,
+                    },
+                    request = in_stream.message(), if reading => match request {
+                        Ok(Some(request)) => {
+                            feed.session.received();
+                            if request.update_filters {
+                                feed.filter = StreamFilter::of(&request);
+                            }
+                            if let Some(acked_until) = feed.ack(request.ack) {
+                                let stored = store.set_acked_until(&subscriber, acked_until).await;
+                                if let Err(err) = stored {
+                                    eprintln!("failed to store acks of {}: {}", subscriber, err);
+                                }
+                            }
+                            continue;
+                        }
+                        // the client can't ack anymore, what it got stays
+                        // unacked and is neither kept nor sent again
+                        Ok(None) => {
+                            reading = false;
+                            feed.acks = None;
+                            continue;
+                        }
+                        Err(_) => break,
+                    },
+                    _ = redelivery.tick(), if reading && feed.acks.is_some() => {
+                        feed.redeliver().await
+                    }
+                    _ = feed.buffer.drained(feed.budget / 2), if feed.paused => {
+                        match feed.catch_up(&store).await {
+                            Some(sent_until) => {
+                                backfilled_until = backfilled_until.max(sent_until);
+                                true
+                            }
+                            None => false,
+                        }
+                    }
+                };
+                if !delivered {
+                    break;
+                }
+            }
+        };
+        spawn_feeder(tx, async move {
+            feeding.await;
+            end_session(&service, session_id).await;
+        });
+        let replies = self.coalesced(ReceiverStream::new(rx));
+        let out_stream = CancelOnDrop::new(Buffered::new(replies, buffer), token);
+        let mut response = Response::new(Box::pin(out_stream) as Self::ListMessagesStreamStream);
+        response
+            .metadata_mut()
+            .insert(SESSION_METADATA, session_id.into());
+        Ok(response)
+    }
+
+    type ExportMessagesStream = GreeterResponseStream<ExportChunk>;
+
+    async fn export_messages(
+        &self,
+        request: Request<ExportMessagesRequest>,
+    ) -> GreeterResult<Self::ExportMessagesStream> {
+        let _timer = self.rpc_timer("ExportMessages");
+        self.check_streaming(&tenant::from_request(&request))
+            .await?;
+        let request = request.into_inner();
+        let format = request.format();
+        let batch_size = match request.batch_size {
+            0 => self.config.export_batch_size,
+            size => size,
+        };
+
+        let (tx, rx) = mpsc::channel(self.config.stream_channel_depth);
+        let token = CancellationToken::new();
+        let export_token = token.clone();
+        let store = self.service.store().clone();
+        spawn_feeder(tx.clone(), async move {
+            if let Some(header) = export::header(format) {
+                let chunk = ExportChunk {
+                    data: header.into(),
+                };
+                if tx.send(Ok(chunk)).await.is_err() {
+                    return;
+                }
+            }
+
+            // keyset pagination keeps a single page in memory at a time, no
+            // matter how large the table is
+            let mut last_id = 0;
+            loop {
+                let page = tokio::select! {
+                    _ = export_token.cancelled() => return,
+                    page = store.get_messages_page(last_id, batch_size.into()) => page,
+                };
+                let page = match page {
+                    Ok(page) if page.is_empty() => return,
+                    Ok(page) => page,
+                    Err(err) => {
+                        let _ = tx.send(Err(Status::internal(err.to_string()))).await;
+                        return;
+                    }
+                };
+
+                let mut data = String::new();
+                for msg in &page {
+                    export::write_row(format, msg, &mut data);
+                }
+                last_id = page.last().map(|msg| msg.id).unwrap_or(last_id);
+
+                let chunk = ExportChunk { data: data.into() };
+                if tx.send(Ok(chunk)).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        let out_stream = CancelOnDrop::new(ReceiverStream::new(rx), token);
+        Ok(Response::new(
+            Box::pin(out_stream) as Self::ExportMessagesStream
+        ))
+    }
+
+    async fn import_messages(
+        &self,
+        request: Request<Streaming<ImportMessagesRequest>>,
+    ) -> GreeterResult<ImportMessagesReply> {
+        let _timer = self.rpc_timer("ImportMessages");
+        let tenant = tenant::from_request(&request);
+        let mut in_stream = request.into_inner();
+        let mut reply = ImportMessagesReply::default();
+
+        while let Some(req) = in_stream.message().await? {
+            let mut result = ImportBatchResult {
+                batch: reply.batches.len() as u32 + 1,
+                ..Default::default()
+            };
+
+            if req.messages.len() > import::MAX_BATCH_LEN {
+                result.rejected = req.messages.len() as u32;
+                result.errors.push(format!(
+                    "batch larger than {} messages",
+                    import::MAX_BATCH_LEN
+                ));
+                reply.batches.push(result);
+                continue;
+            }
+
+            let (valid, errors) =
+                import::validate(req.messages, self.config.import_max_message_len);
+            result.rejected = errors.len() as u32;
+            result.errors = errors;
+
+            if !valid.is_empty() {
+                let broadcast = !req.suppress_broadcast;
+                match self
+                    .service
+                    .store_messages(&tenant, &valid, broadcast)
+                    .await
+                {
+                    Ok(inserted) => {
+                        result.inserted = inserted.len() as u32;
+                        reply.total_inserted += inserted.len() as u64;
+                    }
+                    Err(err @ (ServiceError::QuotaExceeded(_) | ServiceError::ReadOnly)) => {
+                        return Err(err.into())
+                    }
+                    // the batch is inserted in one statement, so it either
+                    // lands completely or not at all
+                    Err(err) => {
+                        result.rejected += valid.len() as u32;
+                        result.errors.push(err.to_string());
+                    }
+                }
+            }
+            reply.batches.push(result);
+        }
+
+        Ok(Response::new(reply))
+    }
+
+    async fn update_message(
+        &self,
+        request: Request<UpdateMessageRequest>,
+    ) -> GreeterResult<HelloReply> {
+        let _timer = self.rpc_timer("UpdateMessage");
+        let UpdateMessageRequest { id, message } = request.into_inner();
+        if message.is_empty() {
+            return Err(Status::invalid_argument("message is required"));
+        }
+        match self.service.update_message(id, &message).await? {
+            Some(msg) => Ok(Response::new(msg.into())),
+            None => Err(Status::not_found(format!("no message {}", id))),
+        }
+    }
+
+    type StreamEventsStream = GreeterResponseStream<GreetingEvent>;
+
+    async fn stream_events(
+        &self,
+        request: Request<StreamEventsRequest>,
+    ) -> GreeterResult<Self::StreamEventsStream> {
+        let _timer = self.rpc_timer("StreamEvents");
+        self.check_streaming(&tenant::from_request(&request))
+            .await?;
+        let StreamEventsRequest { after_seq, follow } = request.into_inner();
+        let poll_interval = Duration::from_millis(self.config.events_poll_interval_ms);
+
+        let (tx, rx) = mpsc::channel(self.config.stream_channel_depth);
+        let token = CancellationToken::new();
+        let events_token = token.clone();
+        let store = self.service.store().clone();
+        spawn_feeder(tx.clone(), async move {
+            let mut last_seq = after_seq;
+            loop {
+                let page = tokio::select! {
+                    _ = events_token.cancelled() => return,
+                    page = store.get_events_page(last_seq, EVENTS_PAGE_SIZE) => page,
+                };
+                let page = match page {
+                    Ok(page) => page,
+                    Err(err) => {
+                        let _ = tx.send(Err(Status::internal(err.to_string()))).await;
+                        return;
+                    }
+                };
+
+                if page.is_empty() {
+                    if !follow {
+                        return;
+                    }
+                    // caught up with the log, wait for new events
+                    tokio::select! {
+                        _ = events_token.cancelled() => return,
+                        _ = tokio::time::sleep(poll_interval) => continue,
+                    }
+                }
+                for event in page {
+                    last_seq = event.seq;
+                    if tx.send(Ok(event.into())).await.is_err() {
+                        return;
+                    }
                 }
             }
         });
-        let out_stream = ReceiverStream::new(rx);
+
+        let out_stream = CancelOnDrop::new(ReceiverStream::new(rx), token);
         Ok(Response::new(
-            Box::pin(out_stream) as Self::ListMessagesStreamStream
+            Box::pin(out_stream) as Self::StreamEventsStream
         ))
     }
+
+    async fn get_usage(&self, request: Request<GetUsageRequest>) -> GreeterResult<UsageReply> {
+        let _timer = self.rpc_timer("GetUsage");
+        let tenant = tenant::from_request(&request);
+        let usage = self.service.usage(&tenant).await?;
+        let quota = self.service.quota(&tenant);
+        Ok(Response::new(UsageReply {
+            tenant,
+            messages: usage.messages,
+            bytes: usage.bytes,
+            max_messages: quota.max_messages,
+            max_bytes: quota.max_bytes,
+        }))
+    }
+
+    async fn get_stats(&self, request: Request<GetStatsRequest>) -> GreeterResult<StatsReply> {
+        let _timer = self.rpc_timer("GetStats");
+        let request = request.into_inner();
+        let days = match request.days {
+            0 => STATS_DEFAULT_DAYS,
+            days => days.min(STATS_MAX_DAYS),
+        };
+        let top = match request.top {
+            0 => STATS_DEFAULT_TOP,
+            top => top.min(STATS_MAX_TOP),
+        };
+        let stats = self.service.stats(days as i32, top.into()).await?;
+        Ok(Response::new(StatsReply {
+            total_greetings: stats.total,
+            per_day: stats
+                .per_day
+                .into_iter()
+                .map(|day| DayStats {
+                    day: day.day,
+                    greetings: day.greetings,
+                })
+                .collect(),
+            top_names: stats.top_names.into_iter().map(NameStats::from).collect(),
+            active_streams: stream::active_streams(),
+            per_country: stats
+                .per_country
+                .into_iter()
+                .map(CountryStats::from)
+                .collect(),
+        }))
+    }
+
+    type StreamLeaderboardStream = GreeterResponseStream<LeaderboardReply>;
+
+    async fn stream_leaderboard(
+        &self,
+        request: Request<StreamLeaderboardRequest>,
+    ) -> GreeterResult<Self::StreamLeaderboardStream> {
+        let _timer = self.rpc_timer("StreamLeaderboard");
+        self.check_streaming(&tenant::from_request(&request))
+            .await?;
+        let top = match request.into_inner().top {
+            0 => STATS_DEFAULT_TOP,
+            top => top.min(STATS_MAX_TOP),
+        };
+
+        let (tx, rx) = mpsc::channel(self.config.stream_channel_depth);
+        let token = CancellationToken::new();
+        let ranking_token = token.clone();
+        let leaderboard = self.service.leaderboard().clone();
+        let mut changed = leaderboard.subscribe();
+        spawn_feeder(tx.clone(), async move {
+            let mut sent = None;
+            loop {
+                // counts change far more often than the top of the ranking
+                let names = leaderboard.top(top as usize);
+                if sent.as_ref() != Some(&names) {
+                    let reply = LeaderboardReply {
+                        names: names.iter().cloned().map(NameStats::from).collect(),
+                    };
+                    if tx.send(Ok(reply)).await.is_err() {
+                        return;
+                    }
+                    sent = Some(names);
+                }
+                tokio::select! {
+                    _ = ranking_token.cancelled() => return,
+                    changed = changed.changed() => {
+                        if changed.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+
+        let out_stream = CancelOnDrop::new(ReceiverStream::new(rx), token);
+        Ok(Response::new(
+            Box::pin(out_stream) as Self::StreamLeaderboardStream
+        ))
+    }
+
+    async fn find_similar_messages(
+        &self,
+        request: Request<FindSimilarMessagesRequest>,
+    ) -> GreeterResult<FindSimilarMessagesReply> {
+        let _timer = self.rpc_timer("FindSimilarMessages");
+        #[cfg(feature = "pgvector")]
+        {
+            let request = request.into_inner();
+            let limit = match request.limit {
+                0 => SIMILAR_MESSAGES_DEFAULT_LIMIT,
+                limit => limit.min(SIMILAR_MESSAGES_MAX_LIMIT),
+            };
+            let similar = self
+                .service
+                .find_similar(&request.text, limit.into())
+                .await?;
+            let messages = similar
+                .into_iter()
+                .map(|similar| SimilarMessage {
+                    cursor: similar.message.id.into(),
+                    message: similar.message.message.unwrap_or_default(),
+                    distance: similar.distance,
+                })
+                .collect();
+            Ok(Response::new(FindSimilarMessagesReply { messages }))
+        }
+        #[cfg(not(feature = "pgvector"))]
+        {
+            let _ = request;
+            Err(Status::unimplemented(
+                "similarity search needs the pgvector feature",
+            ))
+        }
+    }
+
+    type DeleteAllForNameStream = GreeterResponseStream<DeleteAllForNameProgress>;
+
+    async fn delete_all_for_name(
+        &self,
+        request: Request<DeleteAllForNameRequest>,
+    ) -> GreeterResult<Self::DeleteAllForNameStream> {
+        let _timer = self.rpc_timer("DeleteAllForName");
+        let tenant = tenant::from_request(&request);
+        self.check_streaming(&tenant).await?;
+        let name = request.into_inner().name;
+        if name.is_empty() {
+            return Err(Status::invalid_argument("name is required"));
+        }
+
+        // the deletion goes on once the client is gone, it isn't cancelled
+        // half way
+        let (tx, rx) = mpsc::channel(self.config.stream_channel_depth);
+        let service = self.service.clone();
+        spawn_feeder(tx.clone(), async move {
+            let mut progress = DeleteAllForNameProgress::default();
+            let mut after_id = 0;
+            loop {
+                let page = match service.erase_name(&name, after_id, ERASE_PAGE_SIZE).await {
+                    Ok(page) => page,
+                    Err(err) => {
+                        let _ = tx.send(Err(err.into())).await;
+                        return;
+                    }
+                };
+                let Some(last_id) = page.last_id else {
+                    break;
+                };
+                after_id = last_id;
+                progress.scanned += page.scanned;
+                progress.deleted += page.erased;
+                let _ = tx.send(Ok(progress.clone())).await;
+            }
+            let done = match service.forget_name(&tenant, &name, progress.deleted).await {
+                Ok(()) => Ok(DeleteAllForNameProgress {
+                    done: true,
+                    ..progress
+                }),
+                Err(err) => Err(err.into()),
+            };
+            let _ = tx.send(done).await;
+        });
+
+        Ok(Response::new(
+            Box::pin(ReceiverStream::new(rx)) as Self::DeleteAllForNameStream
+        ))
+    }
+
+    async fn get_session(
+        &self,
+        request: Request<GetSessionRequest>,
+    ) -> GreeterResult<SessionReply> {
+        let _timer = self.rpc_timer("GetSession");
+        let tenant = tenant::from_request(&request);
+        let id = request.into_inner().id;
+        match self.service.session(&tenant, id).await? {
+            Some(session) => Ok(Response::new(session.into())),
+            None => Err(Status::not_found(format!("no session {}", id))),
+        }
+    }
+
+    async fn upload_payload(
+        &self,
+        request: Request<Streaming<PayloadChunk>>,
+    ) -> GreeterResult<UploadPayloadReply> {
+        let _timer = self.rpc_timer("UploadPayload");
+        let tenant = tenant::from_request(&request);
+        let max_bytes = self.service.payload_max_bytes();
+        let mut in_stream = request.into_inner();
+        let Some(first) = in_stream.message().await? else {
+            return Err(Status::invalid_argument("no payload chunk received"));
+        };
+        let id =
+            i32::try_from(first.id).map_err(|_| Status::invalid_argument("id is out of range"))?;
+        let mut payload = first.data;
+        while let Some(chunk) = in_stream.message().await? {
+            payload.extend_from_slice(&chunk.data);
+            if payload.len() > max_bytes {
+                return Err(ServiceError::PayloadTooLarge(max_bytes).into());
+            }
+        }
+
+        if !self.service.upload_payload(&tenant, id, &payload).await? {
+            return Err(Status::not_found(format!("no message {}", id)));
+        }
+        Ok(Response::new(UploadPayloadReply {
+            size: payload.len() as u64,
+        }))
+    }
+
+    type DownloadPayloadStream = GreeterResponseStream<PayloadChunk>;
+
+    async fn download_payload(
+        &self,
+        request: Request<DownloadPayloadRequest>,
+    ) -> GreeterResult<Self::DownloadPayloadStream> {
+        let _timer = self.rpc_timer("DownloadPayload");
+        let id = request.into_inner().id;
+        let payload = match i32::try_from(id) {
+            Ok(key) => self.service.payload(key).await?,
+            Err(_) => None,
+        };
+        let Some(payload) = payload else {
+            return Err(Status::not_found(format!("no payload for message {}", id)));
+        };
+        let chunks = payload
+            .chunks(PAYLOAD_CHUNK_BYTES)
+            .map(|data| PayloadChunk {
+                id,
+                data: data.to_vec(),
+            })
+            .collect::<Vec<_>>();
+        Ok(Response::new(Box::pin(tokio_stream::iter(chunks).map(Ok))))
+    }
+
+    async fn upload_attachment(
+        &self,
+        request: Request<Streaming<AttachmentChunk>>,
+    ) -> GreeterResult<UploadAttachmentReply> {
+        let _timer = self.rpc_timer("UploadAttachment");
+        #[cfg(feature = "attachments")]
+        {
+            let attachments = self.attachments()?;
+            if self.service.read_only().is_enabled() {
+                return Err(ServiceError::ReadOnly.into());
+            }
+            let tenant = tenant::from_request(&request);
+            let max_bytes = self.config.attachment_max_bytes;
+            let mut in_stream = request.into_inner();
+            let Some(first) = in_stream.message().await? else {
+                return Err(Status::invalid_argument("no attachment chunk received"));
+            };
+            let id = i32::try_from(first.id)
+                .map_err(|_| Status::invalid_argument("id is out of range"))?;
+            let previous = self.service.attachment_key(id).await?;
+
+            let key = Attachments::new_key(id);
+            let mut upload = attachments.upload(&key).await.map_err(attachment_status)?;
+            let written = async {
+                let mut data = first.data;
+                loop {
+                    if upload.size() + data.len() as u64 > max_bytes {
+                        return Err(Status::invalid_argument(format!(
+                            "attachment larger than {} bytes",
+                            max_bytes
+                        )));
+                    }
+                    upload.write(&data).await.map_err(attachment_status)?;
+                    match in_stream.message().await? {
+                        Some(chunk) => data = chunk.data,
+                        None => return Ok(()),
+                    }
+                }
+            }
+            .await;
+            if let Err(status) = written {
+                upload.abort().await;
+                return Err(status);
+            }
+            let size = upload.finish().await.map_err(attachment_status)?;
+
+            // the new object is only referenced once the message points to it
+            match self.service.attach(&tenant, id, &key, size).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    attachments.delete(&key).await;
+                    return Err(Status::not_found(format!("no message {}", id)));
+                }
+                Err(err) => {
+                    attachments.delete(&key).await;
+                    return Err(err.into());
+                }
+            }
+            if let Some(previous) = previous {
+                attachments.delete(&previous).await;
+            }
+            Ok(Response::new(UploadAttachmentReply { size }))
+        }
+        #[cfg(not(feature = "attachments"))]
+        {
+            let _ = request;
+            Err(Status::unimplemented(
+                "attachments need the attachments feature",
+            ))
+        }
+    }
+
+    type GetAttachmentStream = GreeterResponseStream<GetAttachmentReply>;
+
+    async fn get_attachment(
+        &self,
+        request: Request<GetAttachmentRequest>,
+    ) -> GreeterResult<Self::GetAttachmentStream> {
+        let _timer = self.rpc_timer("GetAttachment");
+        #[cfg(feature = "attachments")]
+        {
+            let attachments = self.attachments()?;
+            let GetAttachmentRequest { id, presigned } = request.into_inner();
+            let key = match i32::try_from(id) {
+                Ok(key) => self.service.attachment_key(key).await?,
+                Err(_) => None,
+            };
+            let Some(key) = key else {
+                return Err(Status::not_found(format!(
+                    "no attachment for message {}",
+                    id
+                )));
+            };
+
+            if presigned {
+                let url = attachments
+                    .presigned_url(&key)
+                    .await
+                    .map_err(attachment_status)?;
+                let reply = GetAttachmentReply {
+                    url,
+                    url_expires_at_ms: unix_ms(SystemTime::now() + attachments.url_ttl()),
+                    ..Default::default()
+                };
+                return Ok(Response::new(Box::pin(tokio_stream::iter([Ok(reply)]))));
+            }
+            let chunks = attachments
+                .download(&key)
+                .await
+                .map_err(attachment_status)?;
+            let replies = chunks.map(|chunk| {
+                chunk
+                    .map(|data| GetAttachmentReply {
+                        data,
+                        ..Default::default()
+                    })
+                    .map_err(attachment_status)
+            });
+            Ok(Response::new(Box::pin(replies)))
+        }
+        #[cfg(not(feature = "attachments"))]
+        {
+            let _ = request;
+            Err(Status::unimplemented(
+                "attachments need the attachments feature",
+            ))
+        }
+    }
 }
@@ -0,0 +1,56 @@
+use crate::greeter::hello_world::{HelloRequest, Salutation};
+
+/// Builds the greeting for a request. An explicit salutation wins over the
+/// locale, unknown locales fall back to English.
+pub fn greet(req: &HelloRequest) -> String {
+    compose(&req.name, &req.locale, req.salutation())
+}
+
+/// Same as `greet`, from the request fields it reads.
+pub fn compose(name: &str, locale: &str, salutation: Salutation) -> String {
+    let mut message = String::new();
+    compose_into(&mut message, name, locale, salutation);
+    message
+}
+
+/// Same as `compose`, appending the greeting to `out`, e.g. a buffer from
+/// `pool`.
+pub fn compose_into(out: &mut String, name: &str, locale: &str, salutation: Salutation) {
+    let salutation = salutation_for(locale, salutation);
+    out.reserve(salutation.len() + name.len() + 2);
+    out.push_str(salutation);
+    out.push(' ');
+    out.push_str(name);
+    out.push('!');
+}
+
+/// The name greeted by a greeting `compose` built, `None` for messages
+/// that don't read like one.
+pub fn greeted_name(message: &str) -> Option<&str> {
+    let (_, name) = message.strip_suffix('!')?.split_once(' ')?;
+    (!name.is_empty()).then_some(name)
+}
+
+/// Salutations by language, compared without allocating a lowercase copy.
+const SALUTATIONS: [(&str, &str); 5] = [
+    ("de", "Hallo"),
+    ("es", "Hola"),
+    ("fr", "Bonjour"),
+    ("it", "Ciao"),
+    ("pt", "Olá"),
+];
+
+fn salutation_for(locale: &str, salutation: Salutation) -> &'static str {
+    match salutation {
+        Salutation::Hello => return "Hello",
+        Salutation::Hi => return "Hi",
+        Salutation::Greetings => return "Greetings",
+        Salutation::Unspecified => (),
+    }
+
+    let language = locale.split(['-', '_']).next().unwrap_or_default();
+    SALUTATIONS
+        .iter()
+        .find(|(code, _)| code.eq_ignore_ascii_case(language))
+        .map_or("Hello", |(_, salutation)| salutation)
+}
@@ -0,0 +1,124 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, Weak},
+};
+
+use tokio::sync::{self, broadcast::error::RecvError, mpsc};
+
+use crate::{
+    messages::{Fanout, SharedMessage, Subscription},
+    store::MessageStore,
+};
+
+/// Messages a group reads from the store at a time while catching up.
+const CATCH_UP_PAGE_SIZE: i64 = 500;
+
+/// Named subscriptions of `ListMessagesStream`. The streams subscribing
+/// under one name form a consumer group: every message goes to one of them,
+/// and a group started again resumes after the last message it delivered.
+#[derive(Clone, Default)]
+pub struct ConsumerGroups {
+    groups: Arc<Mutex<HashMap<String, Weak<ConsumerGroup>>>>,
+}
+
+/// A member's handle on its group, the group stops once every handle is
+/// dropped.
+pub struct ConsumerGroup {
+    queue: sync::Mutex<mpsc::Receiver<SharedMessage>>,
+}
+
+impl ConsumerGroups {
+    /// Joins the group `name`, starting it on `store` and `fanout` when it
+    /// has no members yet. `depth` messages are queued ahead of the members.
+    pub fn join<S: MessageStore, B: Fanout>(
+        &self,
+        name: &str,
+        store: &S,
+        fanout: &B,
+        depth: usize,
+    ) -> Arc<ConsumerGroup> {
+        let mut groups = self.groups.lock().unwrap();
+        if let Some(group) = groups.get(name).and_then(Weak::upgrade) {
+            return group;
+        }
+        groups.retain(|_, group| group.strong_count() > 0);
+
+        // subscribe before catching up so nothing stored in between is missed
+        let live = fanout.subscribe(&format!("group {}", name));
+        let (tx, rx) = mpsc::channel(depth);
+        tokio::spawn(dispatch(name.to_string(), store.clone(), live, tx));
+        let group = Arc::new(ConsumerGroup {
+            queue: sync::Mutex::new(rx),
+        });
+        groups.insert(name.to_string(), Arc::downgrade(&group));
+        group
+    }
+}
+
+impl ConsumerGroup {
+    /// Next message for this member, `None` once the group failed.
+    pub async fn recv(&self) -> Option<SharedMessage> {
+        self.queue.lock().await.recv().await
+    }
+}
+
+/// Queues the messages after the stored position of group `name`, then the
+/// live ones. Having lagged behind the live feed it catches up from the store
+/// again.
+async fn dispatch<S: MessageStore>(
+    name: String,
+    store: S,
+    mut live: Subscription,
+    tx: mpsc::Sender<SharedMessage>,
+) {
+    let mut queued_until = match store.get_delivered_until(&name).await {
+        Ok(delivered_until) => delivered_until,
+        Err(err) => {
+            eprintln!("consumer group {} failed to start: {}", name, err);
+            return;
+        }
+    };
+    loop {
+        loop {
+            let page = tokio::select! {
+                _ = tx.closed() => return,
+                page = store.get_messages_page(queued_until, CATCH_UP_PAGE_SIZE) => page,
+            };
+            let page = match page {
+                Ok(page) => page,
+                Err(err) => {
+                    eprintln!("consumer group {} failed to catch up: {}", name, err);
+                    return;
+                }
+            };
+            let caught_up = (page.len() as i64) < CATCH_UP_PAGE_SIZE;
+            for msg in page {
+                queued_until = msg.id;
+                if tx.send(msg.into()).await.is_err() {
+                    return;
+                }
+            }
+            if caught_up {
+                break;
+            }
+        }
+
+        loop {
+            let msg = tokio::select! {
+                _ = tx.closed() => return,
+                msg = live.recv() => msg,
+            };
+            match msg {
+                Ok(msg) if msg.id <= queued_until => continue,
+                Ok(msg) => {
+                    queued_until = msg.id;
+                    if tx.send(msg).await.is_err() {
+                        return;
+                    }
+                }
+                Err(RecvError::Lagged(_)) => break,
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! Protection of the accept loop against TLS handshakes used to exhaust the
+//! server: at most `max_concurrent` handshakes at a time, each cut off after
+//! `timeout`, and clients failing too many of them banned for a while.
+//!
+//! tonic runs the handshake itself, on the `Connection` the accept loop
+//! hands it. It asks for the connect info once the handshake is done, which
+//! is when a `Handshake` completes. Plain TCP connections complete theirs
+//! right away.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::IpAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::Sleep,
+};
+
+use crate::server_events::{self, EventKind, ServerEvent};
+
+/// Handshake limits of the listeners. Zero turns a limit off.
+#[derive(Clone, Copy, Debug)]
+pub struct HandshakeLimits {
+    /// Handshakes in progress at once, the connections beyond wait for one
+    /// to finish as long as their own timeout allows.
+    pub max_concurrent: usize,
+    pub timeout: Duration,
+    /// Failed handshakes within `ban_window` that get a client banned.
+    pub ban_failures: u32,
+    pub ban_window: Duration,
+    /// How long the connections of a banned client are dropped on accept.
+    pub ban_duration: Duration,
+}
+
+impl Default for HandshakeLimits {
+    fn default() -> Self {
+        Self {
+            max_concurrent: 512,
+            timeout: Duration::from_secs(10),
+            ban_failures: 20,
+            ban_window: Duration::from_secs(60),
+            ban_duration: Duration::from_secs(300),
+        }
+    }
+}
+
+/// The handshakes of the listeners of one server, cheap to clone.
+#[derive(Clone)]
+pub struct Handshakes {
+    limits: HandshakeLimits,
+    permits: Option<Arc<Semaphore>>,
+    clients: Arc<Mutex<HashMap<IpAddr, Client>>>,
+}
+
+#[derive(Default)]
+struct Client {
+    /// Failures since `since`, the start of the current window.
+    failures: u32,
+    since: Option<Instant>,
+    banned_until: Option<Instant>,
+}
+
+impl Handshakes {
+    pub fn new(limits: HandshakeLimits) -> Self {
+        Self {
+            limits,
+            permits: (limits.max_concurrent > 0)
+                .then(|| Arc::new(Semaphore::new(limits.max_concurrent))),
+            clients: Arc::default(),
+        }
+    }
+
+    /// Whether connections from `ip` are dropped for now.
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let mut clients = self.clients.lock().unwrap();
+        let Some(client) = clients.get(&ip) else {
+            return false;
+        };
+        match client.banned_until {
+            Some(until) if until > Instant::now() => true,
+            Some(_) => {
+                clients.remove(&ip);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Starts the handshake of a connection from `ip`, once there is room
+    /// for it. `None` when there was none before its timeout.
+    pub async fn start(&self, ip: Option<IpAddr>) -> Option<Handshake> {
+        let deadline = (!self.limits.timeout.is_zero())
+            .then(|| Box::pin(tokio::time::sleep(self.limits.timeout)));
+        let permit = match &self.permits {
+            Some(permits) => {
+                let acquire = permits.clone().acquire_owned();
+                match self.limits.timeout {
+                    timeout if timeout.is_zero() => acquire.await.ok(),
+                    timeout => tokio::time::timeout(timeout, acquire).await.ok()?.ok(),
+                }
+            }
+            None => None,
+        };
+        Some(Handshake {
+            handshakes: self.clone(),
+            ip,
+            permit: Mutex::new(permit),
+            done: AtomicBool::new(false),
+            deadline,
+            started: false,
+        })
+    }
+
+    fn failed(&self, ip: IpAddr) {
+        if self.limits.ban_failures == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        let client = clients.entry(ip).or_default();
+        if client
+            .since
+            .is_none_or(|since| now - since > self.limits.ban_window)
+        {
+            client.failures = 0;
+            client.since = Some(now);
+        }
+        client.failures += 1;
+        if client.failures >= self.limits.ban_failures && client.banned_until.is_none() {
+            client.banned_until = Some(now + self.limits.ban_duration);
+            eprintln!(
+                "banned {} for {:?} after {} failed TLS handshakes",
+                ip, self.limits.ban_duration, client.failures
+            );
+            server_events::emit(|| {
+                ServerEvent::new(EventKind::ClientBanned, "client banned")
+                    .with("peer", ip)
+                    .with("failures", client.failures)
+            });
+        }
+        // failures of clients that stopped long ago are of no use
+        clients.retain(|_, client| {
+            client.banned_until.is_some_and(|until| until > now)
+                || client
+                    .since
+                    .is_some_and(|since| now - since <= self.limits.ban_window)
+        });
+    }
+}
+
+/// The handshake of one connection, from accept until tonic asks for the
+/// connect info. Dropped before that, after the client sent anything, it
+/// counts as a failure of the client.
+pub struct Handshake {
+    handshakes: Handshakes,
+    ip: Option<IpAddr>,
+    permit: Mutex<Option<OwnedSemaphorePermit>>,
+    done: AtomicBool,
+    deadline: Option<Pin<Box<Sleep>>>,
+    /// Whether the client sent anything, TCP health checks of load balancers
+    /// don't.
+    started: bool,
+}
+
+impl Handshake {
+    /// The handshake succeeded, its room goes to the next one.
+    pub fn complete(&self) {
+        self.done.store(true, Ordering::Relaxed);
+        self.permit.lock().unwrap().take();
+    }
+
+    /// The client sent data.
+    pub fn received(&mut self) {
+        self.started = true;
+    }
+
+    /// Ready once the handshake is past its timeout without completing.
+    pub fn poll_expired(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.done.load(Ordering::Relaxed) {
+            return Poll::Pending;
+        }
+        match &mut self.deadline {
+            Some(deadline) => deadline.as_mut().poll(cx),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for Handshake {
+    fn drop(&mut self) {
+        if self.done.load(Ordering::Relaxed) || !self.started {
+            return;
+        }
+        if let Some(ip) = self.ip {
+            self.handshakes.failed(ip);
+        }
+    }
+}
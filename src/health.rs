@@ -0,0 +1,31 @@
+use std::time::Duration;
+
+use crate::db::Db;
+
+/// Pings the database every `interval` until the task is dropped, logging
+/// when it becomes unreachable and when it is back. Idle connections are
+/// recycled after each successful ping, so the first queries after a
+/// Postgres restart don't run into broken connections. The outcome is
+/// exposed by `Db::health`.
+pub async fn supervise(db: Db, interval: Duration) {
+    loop {
+        probe(&db).await;
+        tokio::time::sleep(interval).await;
+    }
+}
+
+async fn probe(db: &Db) {
+    let was_healthy = db.health().healthy;
+    if let Err(err) = db.ping().await {
+        if was_healthy != Some(false) {
+            eprintln!("database unreachable: {}", err);
+        }
+        return;
+    }
+    if was_healthy == Some(false) {
+        println!("database reachable again");
+    }
+    if let Err(err) = db.recycle_idle().await {
+        eprintln!("failed to recycle idle database connections: {}", err);
+    }
+}
@@ -0,0 +1,162 @@
+//! The TLS identity of the server as read from its directory: a certificate
+//! chain in `server.pem` with its key in `server.key`, either of them PEM or
+//! DER, or both in a PKCS#12 bundle, `server.p12`, as many corporate PKIs
+//! hand them out. Whatever the files, the identity comes out as PEM, the
+//! one format tonic takes.
+//!
+//! `server.key` may be encrypted, as PKCS#8 (`ENCRYPTED PRIVATE KEY`), with
+//! a passphrase from the config: it is decrypted in memory, the key never
+//! sits on disk in the clear. `openssl pkcs8 -topk8` turns a key with the
+//! older OpenSSL encryption (`Proc-Type: 4,ENCRYPTED`) into one.
+
+use std::{fs, io, path::Path};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use pkcs8::{EncryptedPrivateKeyInfo, SecretDocument};
+use thiserror::Error;
+
+use crate::config::IdentityFormat;
+
+const PEM_PREFIX: &[u8] = b"-----BEGIN";
+const ENCRYPTED_KEY_LABEL: &str = "ENCRYPTED PRIVATE KEY";
+
+#[derive(Error, Debug)]
+pub enum IdentityError {
+    #[error("TLS identity: {0}")]
+    Io(#[from] io::Error),
+    #[error("TLS identity: {0} isn't PEM")]
+    NotPem(&'static str),
+    #[error("PKCS#12 bundle: {0}")]
+    Pkcs12(String),
+    #[error("PKCS#12 bundle: no {0}")]
+    Missing(&'static str),
+    #[error("server.key is encrypted but no passphrase is configured")]
+    NoPassphrase,
+    #[error("server.key has the legacy OpenSSL encryption, convert it to PKCS#8")]
+    LegacyEncryption,
+    #[error("Decrypting server.key: {0}")]
+    Decrypt(pkcs8::Error),
+}
+
+/// A certificate chain and its private key, PEM encoded.
+#[derive(Clone, Debug)]
+pub struct PemIdentity {
+    pub cert: String,
+    pub key: String,
+}
+
+/// Reads the identity in `dir` as `format` says, `password` opening a
+/// PKCS#12 bundle and `passphrase` an encrypted key. DER keys have to be
+/// PKCS#8, as those of bundles are.
+pub fn load(
+    dir: &Path,
+    format: IdentityFormat,
+    password: &str,
+    passphrase: Option<&str>,
+) -> Result<PemIdentity, IdentityError> {
+    let bundle = dir.join("server.p12");
+    let format = match format {
+        IdentityFormat::Auto if !dir.join("server.pem").exists() && bundle.exists() => {
+            IdentityFormat::Pkcs12
+        }
+        format => format,
+    };
+    if format == IdentityFormat::Pkcs12 {
+        return from_pkcs12(&fs::read(bundle)?, password);
+    }
+
+    let cert = fs::read(dir.join("server.pem"))?;
+    let key = fs::read(dir.join("server.key"))?;
+    Ok(PemIdentity {
+        cert: to_pem(cert, "CERTIFICATE", "server.pem", format)?,
+        key: key_to_pem(key, format, passphrase)?,
+    })
+}
+
+/// The PEM of the key in `data`, decrypted when it is encrypted.
+fn key_to_pem(
+    data: Vec<u8>,
+    format: IdentityFormat,
+    passphrase: Option<&str>,
+) -> Result<String, IdentityError> {
+    let encrypted = if is_der(&data, format) {
+        // a plain PKCS#8 key starts with its version, not a sequence
+        if EncryptedPrivateKeyInfo::try_from(data.as_slice()).is_err() {
+            return Ok(pem_block("PRIVATE KEY", &data));
+        }
+        data
+    } else {
+        let pem = String::from_utf8(data).map_err(|_| IdentityError::NotPem("server.key"))?;
+        if pem.contains("Proc-Type: 4,ENCRYPTED") {
+            return Err(IdentityError::LegacyEncryption);
+        }
+        if !pem.contains(ENCRYPTED_KEY_LABEL) {
+            return Ok(pem);
+        }
+        let (_, document) =
+            SecretDocument::from_pem(&pem).map_err(|err| IdentityError::Decrypt(err.into()))?;
+        document.as_bytes().to_vec()
+    };
+
+    let passphrase = passphrase.ok_or(IdentityError::NoPassphrase)?;
+    let key = EncryptedPrivateKeyInfo::try_from(encrypted.as_slice())
+        .map_err(pkcs8::Error::from)
+        .and_then(|info| info.decrypt(passphrase))
+        .map_err(IdentityError::Decrypt)?;
+    Ok(pem_block("PRIVATE KEY", key.as_bytes()))
+}
+
+/// The identity in the PKCS#12 bundle `der`, its certificates in the order
+/// they are stored, the server's first.
+pub fn from_pkcs12(der: &[u8], password: &str) -> Result<PemIdentity, IdentityError> {
+    let pkcs12 = |err: p12::ASN1Error| IdentityError::Pkcs12(format!("{:?}", err));
+    let pfx = p12::PFX::parse(der).map_err(pkcs12)?;
+    if !pfx.verify_mac(password) {
+        return Err(IdentityError::Pkcs12("wrong password".to_string()));
+    }
+    let certs = pfx.cert_x509_bags(password).map_err(pkcs12)?;
+    if certs.is_empty() {
+        return Err(IdentityError::Missing("certificate"));
+    }
+    let keys = pfx.key_bags(password).map_err(pkcs12)?;
+    let key = keys.first().ok_or(IdentityError::Missing("private key"))?;
+    Ok(PemIdentity {
+        cert: certs
+            .iter()
+            .map(|cert| pem_block("CERTIFICATE", cert))
+            .collect(),
+        key: pem_block("PRIVATE KEY", key),
+    })
+}
+
+/// `der` as a PEM block labelled `label`.
+pub fn pem_block(label: &str, der: &[u8]) -> String {
+    let encoded = STANDARD.encode(der);
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).expect("base64 is ASCII"));
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----\n", label));
+    pem
+}
+
+fn to_pem(
+    data: Vec<u8>,
+    label: &str,
+    file: &'static str,
+    format: IdentityFormat,
+) -> Result<String, IdentityError> {
+    if is_der(&data, format) {
+        return Ok(pem_block(label, &data));
+    }
+    String::from_utf8(data).map_err(|_| IdentityError::NotPem(file))
+}
+
+fn is_der(data: &[u8], format: IdentityFormat) -> bool {
+    match format {
+        IdentityFormat::Der => true,
+        IdentityFormat::Auto => !data.trim_ascii_start().starts_with(PEM_PREFIX),
+        _ => false,
+    }
+}
@@ -0,0 +1,25 @@
+/// Largest batch accepted in one `ImportMessages` request, well below the
+/// Postgres bind parameter limit of a single multi-row insert.
+pub const MAX_BATCH_LEN: usize = 10_000;
+
+/// Splits a batch into the messages that can be stored and the reasons the
+/// others were rejected.
+pub fn validate(messages: Vec<String>, max_message_len: usize) -> (Vec<String>, Vec<String>) {
+    let mut valid = Vec::with_capacity(messages.len());
+    let mut errors = Vec::new();
+
+    for (idx, message) in messages.into_iter().enumerate() {
+        if message.trim().is_empty() {
+            errors.push(format!("message {}: empty", idx));
+        } else if message.chars().count() > max_message_len {
+            errors.push(format!(
+                "message {}: longer than {} characters",
+                idx, max_message_len
+            ));
+        } else {
+            valid.push(message);
+        }
+    }
+
+    (valid, errors)
+}
@@ -0,0 +1,154 @@
+//! Client address allow and deny lists, checked against the peer address
+//! of every call before it reaches a service. Behind a load balancer that is
+//! the address of the PROXY header, taken from trusted proxies only (see
+//! `listener::ListenerOptions`), so clients can't claim an allowed one. Calls from denied addresses fail with
+//! `PERMISSION_DENIED`. The lists are set by the config and replaced at
+//! runtime with the `SetIpRules` admin call, which they apply to as well.
+
+use std::{
+    fmt,
+    future::Future,
+    net::IpAddr,
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+};
+
+use tonic::{body::BoxBody, Status};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::{peer_info::PeerInfo, reload::Reloadable};
+
+/// A block of addresses, `10.0.0.0/8` or `2001:db8::/32`. A bare address is
+/// a block of one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // a dual-stack listener sees IPv4 clients as `::ffff:a.b.c.d`
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: &[u8], ip: &[u8], prefix: u8) -> bool {
+    let (bytes, bits) = (prefix as usize / 8, prefix % 8);
+    if net[..bytes] != ip[..bytes] {
+        return false;
+    }
+    bits == 0 || (net[bytes] ^ ip[bytes]) >> (8 - bits) == 0
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid address block {}", s);
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s.trim(), None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// The address blocks calls are allowed from and denied from.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IpRules {
+    /// Blocks calls are only taken from, any address when empty.
+    pub allow: Vec<Cidr>,
+    /// Blocks calls are refused from, also when allowed.
+    pub deny: Vec<Cidr>,
+}
+
+impl IpRules {
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+/// Refuses the calls `rules` don't permit. Calls of unknown origin, e.g.
+/// forwarded in-process by the HTTP/JSON gateway, go through.
+#[derive(Clone)]
+pub struct IpFilterLayer {
+    rules: Reloadable<IpRules>,
+}
+
+impl IpFilterLayer {
+    pub fn new(rules: Reloadable<IpRules>) -> Self {
+        Self { rules }
+    }
+}
+
+impl<S> Layer<S> for IpFilterLayer {
+    type Service = IpFilter<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        IpFilter {
+            inner,
+            rules: self.rules.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct IpFilter<S> {
+    inner: S,
+    rules: Reloadable<IpRules>,
+}
+
+impl<S, B> Service<http::Request<B>> for IpFilter<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<BoxBody>>,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        if let Some(addr) = PeerInfo::from_http(&req).remote_addr {
+            if !self.rules.read(|rules| rules.permits(addr.ip())) {
+                return Box::pin(async {
+                    Ok(Status::permission_denied("address not allowed").to_http())
+                });
+            }
+        }
+        Box::pin(self.inner.call(req))
+    }
+}
@@ -0,0 +1,49 @@
+//! Leader election for the background tasks that must run on one replica
+//! only, such as the outbox relay and partition maintenance.
+//!
+//! The leader of a task is the replica holding the Postgres advisory lock
+//! named after it. The lock goes with the session holding it, so a leader
+//! that crashes or loses its database connection gives it up, and another
+//! replica takes over within one check interval. A leader only notices a
+//! lost session on its next check, so the task may briefly run on two
+//! replicas; the tasks are written to tolerate that.
+
+use std::{future::Future, time::Duration};
+
+use crate::db::{AdvisoryLock, Db, DbError};
+
+/// Runs the task started by `task` while this replica leads `name`, until the
+/// task completes. Replicas not leading try to take over every `interval`,
+/// the leader checks its lock as often and stops the task once it is lost.
+pub async fn lead<F, Fut>(db: Db, name: &str, interval: Duration, mut task: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    loop {
+        match db.try_advisory_lock(name).await {
+            Ok(Some(mut lock)) => {
+                println!("leading {}", name);
+                tokio::select! {
+                    () = task() => return,
+                    err = hold(&mut lock, interval) => {
+                        eprintln!("lost the lead of {}: {}", name, err);
+                    }
+                }
+            }
+            Ok(None) => (),
+            Err(err) => eprintln!("leader election of {} failed: {}", name, err),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Checks `lock` every `interval`, returns once that fails.
+async fn hold(lock: &mut AdvisoryLock, interval: Duration) -> DbError {
+    loop {
+        tokio::time::sleep(interval).await;
+        if let Err(err) = lock.check().await {
+            return err;
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! Live ranking of the names greeted most, streamed by `StreamLeaderboard`.
+//!
+//! Every server counts the greetings it broadcasts in memory, see
+//! `GreetingService::publish`, and adds them to the store on each sync,
+//! reading back what all servers counted, so the greetings of other servers
+//! show up with the next sync.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::sync::watch;
+
+use crate::{db::NameCount, store::MessageStore};
+
+/// Greetings per name, the synced counts of every server plus the ones made
+/// here since. Clones share the counts.
+#[derive(Clone)]
+pub struct Leaderboard<S> {
+    store: S,
+    counts: Arc<Mutex<Counts>>,
+    changed: Arc<watch::Sender<()>>,
+}
+
+#[derive(Default)]
+struct Counts {
+    /// As of the latest sync.
+    synced: HashMap<String, i64>,
+    /// Counted here since.
+    unsynced: HashMap<String, i64>,
+}
+
+impl<S: MessageStore> Leaderboard<S> {
+    pub fn new(store: S) -> Self {
+        Self {
+            store,
+            counts: Arc::default(),
+            changed: Arc::new(watch::channel(()).0),
+        }
+    }
+
+    /// Counts a greeting of `name`.
+    pub fn count(&self, name: &str) {
+        let mut counts = self.counts.lock().unwrap();
+        *counts.unsynced.entry(name.to_string()).or_default() += 1;
+        drop(counts);
+        self.changed.send_replace(());
+    }
+
+    /// Drops the counts of `name`, synced or not, after its greetings were
+    /// deleted. The store's count has to be dropped separately.
+    pub fn forget(&self, name: &str) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.synced.remove(name);
+        counts.unsynced.remove(name);
+        drop(counts);
+        self.changed.send_replace(());
+    }
+
+    /// The `top` names greeted most, most greeted first.
+    pub fn top(&self, top: usize) -> Vec<NameCount> {
+        let counts = self.counts.lock().unwrap();
+        let mut ranked = counts
+            .synced
+            .iter()
+            .map(|(name, greetings)| NameCount {
+                name: name.clone(),
+                greetings: greetings + counts.unsynced.get(name).copied().unwrap_or_default(),
+            })
+            .collect::<Vec<_>>();
+        ranked.extend(
+            counts
+                .unsynced
+                .iter()
+                .filter(|(name, _)| !counts.synced.contains_key(*name))
+                .map(|(name, greetings)| NameCount {
+                    name: name.clone(),
+                    greetings: *greetings,
+                }),
+        );
+        drop(counts);
+        ranked.sort_by(|a, b| b.greetings.cmp(&a.greetings).then(a.name.cmp(&b.name)));
+        ranked.truncate(top);
+        ranked
+    }
+
+    /// Changes on every count and sync, for streams to send the new
+    /// ranking.
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.changed.subscribe()
+    }
+
+    /// Adds the counts made here to the store and reads back those of every
+    /// server.
+    pub async fn sync(&self) -> Result<(), S::Error> {
+        let unsynced = self
+            .counts
+            .lock()
+            .unwrap()
+            .unsynced
+            .iter()
+            .map(|(name, greetings)| NameCount {
+                name: name.clone(),
+                greetings: *greetings,
+            })
+            .collect::<Vec<_>>();
+        self.store.add_name_counts(&unsynced).await?;
+        let loaded = self.store.get_name_counts().await;
+
+        let mut counts = self.counts.lock().unwrap();
+        // stored now, counts made meanwhile stay unsynced
+        for count in unsynced {
+            if let Some(greetings) = counts.unsynced.get_mut(&count.name) {
+                *greetings -= count.greetings;
+                if *greetings == 0 {
+                    counts.unsynced.remove(&count.name);
+                }
+            }
+            *counts.synced.entry(count.name).or_default() += count.greetings;
+        }
+        if let Ok(loaded) = &loaded {
+            counts.synced = loaded
+                .iter()
+                .map(|count| (count.name.clone(), count.greetings))
+                .collect();
+        }
+        drop(counts);
+        self.changed.send_replace(());
+        loaded.map(drop)
+    }
+}
+
+/// Syncs `leaderboard` every `interval`, the first time right away, until
+/// the task is dropped.
+pub async fn keep_synced<S: MessageStore>(leaderboard: Leaderboard<S>, interval: Duration) {
+    loop {
+        if let Err(err) = leaderboard.sync().await {
+            eprintln!("leaderboard sync failed: {}", err);
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockMessageStore;
+
+    #[tokio::test]
+    async fn leaderboards_sync_through_the_store() {
+        let store = MockMessageStore::new();
+        let here = Leaderboard::new(store.clone());
+        let there = Leaderboard::new(store.clone());
+        let count = |name: &str, greetings| NameCount {
+            name: name.to_string(),
+            greetings,
+        };
+
+        here.count("Alice");
+        here.count("Bob");
+        here.count("Alice");
+        there.count("Bob");
+        assert_eq!(here.top(1), [count("Alice", 2)]);
+
+        here.sync().await.unwrap();
+        there.sync().await.unwrap();
+        assert_eq!(there.top(2), [count("Alice", 2), count("Bob", 2)]);
+        // synced counts aren't added twice
+        there.sync().await.unwrap();
+        here.sync().await.unwrap();
+        assert_eq!(here.top(2), [count("Alice", 2), count("Bob", 2)]);
+    }
+}
@@ -1,4 +1,88 @@
+pub mod access_log;
+pub mod admin;
+#[cfg(feature = "attachments")]
+pub mod attachments;
+pub mod authn;
+pub mod authz;
+pub mod backup;
+pub mod canary;
+pub mod certs;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+#[cfg(feature = "tls")]
+pub mod client_auth;
+pub mod clients;
+pub mod coalesce;
+pub mod codec;
+pub mod compat;
+pub mod config;
+mod conn;
+pub mod cooldown;
+pub mod crypt;
+#[cfg(feature = "dashboard")]
+pub mod dashboard;
 pub mod db;
+#[cfg(feature = "dev-tls")]
+pub mod dev_tls;
+#[cfg(feature = "pgvector")]
+pub mod embed;
+mod export;
+pub mod flags;
+#[cfg(feature = "geoip")]
+pub mod geoip;
+#[cfg(feature = "graphql")]
+pub mod graphql;
 pub mod greeter;
-mod messages;
+pub mod greeting;
+pub mod groups;
+pub mod handshake;
+pub mod health;
+#[cfg(feature = "tls")]
+pub mod identity;
+mod import;
+pub mod ip_filter;
+pub mod leader;
+pub mod leaderboard;
+pub mod limits;
+pub mod listener;
+pub mod messages;
+pub mod metadata;
+pub mod mirror;
+#[cfg(any(test, feature = "test-util"))]
+pub mod mock;
+pub mod moderation;
+#[cfg(feature = "notifications")]
+pub mod notify;
+#[cfg(feature = "kafka")]
+pub mod outbox;
+pub mod panic;
+pub mod partitions;
+pub mod peer_info;
+pub mod pool;
+pub mod proxy_protocol;
+pub mod read_only;
+pub mod redact;
+pub mod reflection;
+pub mod reload;
+#[cfg(feature = "tls")]
+pub mod revocation;
 mod schema;
+pub mod server;
+pub mod server_events;
+pub mod server_info;
+pub mod service;
+pub mod sessions;
+pub mod slow;
+pub mod startup;
+pub mod stats;
+pub mod store;
+mod stream;
+pub mod tenant;
+pub mod tokens;
+#[cfg(feature = "transcoding")]
+pub mod transcode;
+pub mod translate;
+pub mod versions;
+mod writer;
+#[cfg(feature = "websocket")]
+pub mod ws;
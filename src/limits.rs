@@ -0,0 +1,590 @@
+//! Concurrency limits per method, set by the `[method_limits."<route>"]`
+//! tables of the config file, so that expensive calls like `ExportMessages`
+//! can't take what cheap ones like `SayHello` need. A route is a method,
+//! `helloworld.Greeter/SayHello`, or a whole service, `helloworld.Admin`,
+//! whose methods then share the limit. The limit of a method wins over the
+//! one of its service. Methods without either aren't limited.
+//!
+//! Calls beyond the limit wait for one in progress to end, as long as fewer
+//! than `max_queued` are waiting already and for `queue_timeout_ms` at most.
+//! The others fail fast with `UNAVAILABLE`, whose `RetryInfo` tells clients
+//! to come back after the queue timeout. A call is in progress until its
+//! response ends, the whole stream of a streaming call. The counts, queue
+//! depth and wait times are on the dashboard.
+//!
+//! An `adaptive` limit moves between `min_concurrent` and `max_concurrent`
+//! with the latency of the calls, see `AdaptiveLimit`: when the database
+//! slows down the calls waiting on it are cut back without anyone having to
+//! tune the limit.
+//!
+//! Calls are also held to the budget of their caller class, the
+//! `[class_limits.<class>]` tables, so batch work like imports and exports
+//! can't take the room of interactive greetings. A call is `batch` when its
+//! method or a role of its caller is listed as such by the
+//! `[caller_classes]` table, or when the caller says so with the
+//! `x-caller-class` metadata. Interactive otherwise, which the metadata
+//! can't claim for a batch call.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use http::HeaderMap;
+use http_body::Body;
+use serde::Serialize;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tonic::{body::BoxBody, Code, Status};
+use tonic_types::{ErrorDetails, StatusExt};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::{
+    authn::Caller,
+    config::{CallerClass, CallerClassesConfig, MethodLimit},
+};
+
+/// Request metadata a caller puts its calls in the `batch` class with.
+pub const CALLER_CLASS_METADATA: &str = "x-caller-class";
+
+static STATS: Mutex<BTreeMap<String, Arc<Limit>>> = Mutex::new(BTreeMap::new());
+
+/// Samples the baseline latency of an adaptive limit is averaged over.
+const BASELINE_SAMPLES: f64 = 100.0;
+
+/// A limit following the latency of the calls it lets in, additive increase
+/// and multiplicative decrease. The baseline is the mean latency over the
+/// last hundred calls or so: a call slower than `latency_tolerance` times
+/// the baseline, or failing as `UNAVAILABLE` or `DEADLINE_EXCEEDED`, cuts
+/// the limit by `backoff`, the others raise it by about one every `limit`
+/// calls, as long as the calls in progress use at least half of it. A
+/// lasting slowdown raises the baseline in turn, the limit then settles at
+/// what the slower backend gets through.
+#[derive(Clone, Debug)]
+pub struct AdaptiveLimit {
+    limit: f64,
+    min: f64,
+    max: f64,
+    tolerance: f64,
+    backoff: f64,
+    /// Mean latency in seconds, none before the first call.
+    baseline: Option<f64>,
+}
+
+impl AdaptiveLimit {
+    /// Starts at `max_concurrent`, so a healthy backend sees no difference
+    /// from a static limit.
+    pub fn new(config: &MethodLimit) -> Self {
+        let max = config.max_concurrent.max(1) as f64;
+        Self {
+            limit: max,
+            min: (config.min_concurrent.max(1) as f64).min(max),
+            max,
+            tolerance: config.latency_tolerance,
+            backoff: config.backoff,
+            baseline: None,
+        }
+    }
+
+    /// Calls let in at once.
+    pub fn limit(&self) -> usize {
+        self.limit as usize
+    }
+
+    /// Adjusts the limit to a call that took `latency`, with `in_flight`
+    /// calls in progress, and returns it.
+    pub fn sample(&mut self, latency: Duration, overloaded: bool, in_flight: usize) -> usize {
+        let latency = latency.as_secs_f64();
+        let baseline = *self.baseline.get_or_insert(latency);
+        if overloaded || latency > baseline * self.tolerance {
+            self.limit = (self.limit * self.backoff).max(self.min);
+        } else if in_flight as f64 * 2.0 >= self.limit {
+            self.limit = (self.limit + 1.0 / self.limit).min(self.max);
+        }
+        self.baseline = Some(baseline + (latency - baseline) / BASELINE_SAMPLES);
+        self.limit()
+    }
+}
+
+/// The limit of one route.
+struct Limit {
+    route: String,
+    max_concurrent: usize,
+    max_queued: usize,
+    queue_timeout: Duration,
+    /// Permits of the calls let in, as many as the limit less those in
+    /// progress.
+    permits: Arc<Semaphore>,
+    adaptive: Option<Mutex<Adaptive>>,
+    in_flight: AtomicUsize,
+    queued: AtomicUsize,
+    admitted: AtomicU64,
+    /// Calls refused as the queue was full.
+    rejected: AtomicU64,
+    /// Calls refused after waiting `queue_timeout`.
+    timed_out: AtomicU64,
+    /// Calls that waited in the queue, and how long in total.
+    waited: AtomicU64,
+    wait_us: AtomicU64,
+}
+
+struct Adaptive {
+    limit: AdaptiveLimit,
+    /// What the permits were last sized for.
+    current: usize,
+    /// Permits to forget as calls end, the limit having shrunk below the
+    /// calls in progress.
+    debt: usize,
+}
+
+impl Limit {
+    fn new(route: &str, config: &MethodLimit) -> Self {
+        let adaptive = config.adaptive.then(|| {
+            let limit = AdaptiveLimit::new(config);
+            Mutex::new(Adaptive {
+                current: limit.limit(),
+                limit,
+                debt: 0,
+            })
+        });
+        Self {
+            route: route.to_string(),
+            max_concurrent: config.max_concurrent,
+            max_queued: config.max_queued,
+            queue_timeout: Duration::from_millis(config.queue_timeout_ms),
+            permits: Arc::new(Semaphore::new(config.max_concurrent)),
+            adaptive,
+            in_flight: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+            admitted: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            timed_out: AtomicU64::new(0),
+            waited: AtomicU64::new(0),
+            wait_us: AtomicU64::new(0),
+        }
+    }
+
+    /// Lets the call in once there is room, refuses it when the queue is
+    /// full or it waited too long.
+    async fn acquire(self: &Arc<Self>) -> Result<Admission, Status> {
+        let permit = match self.permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) if self.enqueue() => self.wait().await.ok_or_else(|| self.unavailable())?,
+            Err(_) => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                return Err(self.unavailable());
+            }
+        };
+        Ok(self.admit(permit))
+    }
+
+    /// Waits in the queue for a call in progress to end, `None` when none
+    /// did within the queue timeout.
+    async fn wait(self: &Arc<Self>) -> Option<OwnedSemaphorePermit> {
+        let _queued = Queued(self.clone());
+        let start = Instant::now();
+        let acquire = self.permits.clone().acquire_owned();
+        let permit = tokio::time::timeout(self.queue_timeout, acquire).await;
+        self.waited.fetch_add(1, Ordering::Relaxed);
+        self.wait_us
+            .fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+        match permit {
+            Ok(permit) => Some(permit.expect("the semaphore is never closed")),
+            Err(_) => {
+                self.timed_out.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// What a call refused gets, retrying after the queue timeout stands a
+    /// chance of finding room.
+    fn unavailable(&self) -> Status {
+        Status::with_error_details(
+            Code::Unavailable,
+            format!("too many concurrent calls to {}", self.route),
+            ErrorDetails::with_retry_info(Some(self.queue_timeout)),
+        )
+    }
+
+    /// Takes a place in the queue, `false` when it is full.
+    fn enqueue(&self) -> bool {
+        self.queued
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |queued| {
+                (queued < self.max_queued).then_some(queued + 1)
+            })
+            .is_ok()
+    }
+
+    /// Lets a call in with `permit`.
+    fn admit(self: &Arc<Self>, permit: OwnedSemaphorePermit) -> Admission {
+        self.admitted.fetch_add(1, Ordering::Relaxed);
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        Admission {
+            limit: self.clone(),
+            permit: Some(permit),
+        }
+    }
+
+    /// Feeds the latency of a call to an adaptive limit and resizes the
+    /// permits to it.
+    fn sample(&self, latency: Duration, overloaded: bool) {
+        let Some(adaptive) = &self.adaptive else {
+            return;
+        };
+        let mut adaptive = adaptive.lock().unwrap();
+        let in_flight = self.in_flight.load(Ordering::Relaxed);
+        let limit = adaptive.limit.sample(latency, overloaded, in_flight);
+        if limit > adaptive.current {
+            let grown = limit - adaptive.current;
+            let paid = grown.min(adaptive.debt);
+            adaptive.debt -= paid;
+            self.permits.add_permits(grown - paid);
+        } else {
+            for _ in limit..adaptive.current {
+                match self.permits.try_acquire() {
+                    Ok(permit) => permit.forget(),
+                    Err(_) => adaptive.debt += 1,
+                }
+            }
+        }
+        adaptive.current = limit;
+    }
+
+    /// What calls are let in up to now.
+    fn current(&self) -> usize {
+        match &self.adaptive {
+            Some(adaptive) => adaptive.lock().unwrap().current,
+            None => self.max_concurrent,
+        }
+    }
+
+    /// Whether a permit given back has to be forgotten instead.
+    fn take_debt(&self) -> bool {
+        let Some(adaptive) = &self.adaptive else {
+            return false;
+        };
+        let mut adaptive = adaptive.lock().unwrap();
+        if adaptive.debt == 0 {
+            return false;
+        }
+        adaptive.debt -= 1;
+        true
+    }
+}
+
+/// Leaves the queue when dropped, also when the caller gives up waiting.
+struct Queued(Arc<Limit>);
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        self.0.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A call in progress, which ends when dropped.
+struct Admission {
+    limit: Arc<Limit>,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        self.limit.in_flight.fetch_sub(1, Ordering::Relaxed);
+        if self.limit.take_debt() {
+            if let Some(permit) = self.permit.take() {
+                permit.forget();
+            }
+        }
+    }
+}
+
+/// Holds the calls of every route to its limit, and of every caller class
+/// to its budget.
+#[derive(Clone, Default)]
+pub struct MethodLimitLayer {
+    limits: Arc<HashMap<String, Arc<Limit>>>,
+    classes: Option<Arc<Classes>>,
+}
+
+struct Classes {
+    config: CallerClassesConfig,
+    limits: HashMap<CallerClass, Arc<Limit>>,
+}
+
+impl Classes {
+    fn classify<B>(&self, req: &http::Request<B>) -> CallerClass {
+        let method = req.uri().path().trim_start_matches('/');
+        let by_method = self.config.batch_methods.iter().any(|m| m == method);
+        let by_role = req.extensions().get::<Caller>().is_some_and(|caller| {
+            caller
+                .roles
+                .iter()
+                .any(|role| self.config.batch_roles.contains(role))
+        });
+        let by_metadata = req
+            .headers()
+            .get(CALLER_CLASS_METADATA)
+            .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"batch"));
+        if by_method || by_role || by_metadata {
+            CallerClass::Batch
+        } else {
+            CallerClass::Interactive
+        }
+    }
+}
+
+impl MethodLimitLayer {
+    pub fn new(config: &HashMap<String, MethodLimit>) -> Self {
+        let limits = config
+            .iter()
+            .map(|(route, config)| {
+                let route = route.trim_start_matches('/');
+                let limit = Arc::new(Limit::new(route, config));
+                STATS
+                    .lock()
+                    .unwrap()
+                    .insert(route.to_string(), limit.clone());
+                (route.to_string(), limit)
+            })
+            .collect();
+        Self {
+            limits: Arc::new(limits),
+            classes: None,
+        }
+    }
+
+    /// Holds the calls of the classes of `config` to the budgets of
+    /// `limits`, the classes without one aren't limited.
+    pub fn with_classes(
+        mut self,
+        config: &CallerClassesConfig,
+        limits: &HashMap<CallerClass, MethodLimit>,
+    ) -> Self {
+        let limits = limits
+            .iter()
+            .map(|(class, config)| {
+                let name = format!("{} callers", class.as_str());
+                let limit = Arc::new(Limit::new(&name, config));
+                STATS.lock().unwrap().insert(name, limit.clone());
+                (*class, limit)
+            })
+            .collect();
+        self.classes = Some(Arc::new(Classes {
+            config: config.clone(),
+            limits,
+        }));
+        self
+    }
+
+    /// The limits a call is held to, the budget of its class first.
+    fn find<B>(&self, req: &http::Request<B>) -> Vec<Arc<Limit>> {
+        let mut limits = Vec::with_capacity(2);
+        if let Some(classes) = &self.classes {
+            if let Some(limit) = classes.limits.get(&classes.classify(req)) {
+                limits.push(limit.clone());
+            }
+        }
+        let method = req.uri().path().trim_start_matches('/');
+        let service = method
+            .split_once('/')
+            .map_or(method, |(service, _)| service);
+        if let Some(limit) = self.limits.get(method).or_else(|| self.limits.get(service)) {
+            limits.push(limit.clone());
+        }
+        limits
+    }
+}
+
+impl<S> Layer<S> for MethodLimitLayer {
+    type Service = MethodLimits<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MethodLimits {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MethodLimits<S> {
+    inner: S,
+    layer: MethodLimitLayer,
+}
+
+impl<S, B> Service<http::Request<B>> for MethodLimits<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    B: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let limits = self.layer.find(&req);
+        if limits.is_empty() {
+            return Box::pin(self.inner.call(req));
+        }
+        // the clone isn't ready, keep the service `poll_ready` was called on
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            let mut admissions = Vec::with_capacity(limits.len());
+            for limit in &limits {
+                match limit.acquire().await {
+                    Ok(admission) => admissions.push(admission),
+                    Err(status) => return Ok(status.to_http()),
+                }
+            }
+            let start = Instant::now();
+            let res = inner.call(req).await?;
+            let overloaded = is_overloaded(res.headers());
+            for limit in &limits {
+                limit.sample(start.elapsed(), overloaded);
+            }
+            Ok(res.map(|inner| {
+                PermitBody {
+                    inner,
+                    _admissions: admissions,
+                }
+                .boxed_unsync()
+            }))
+        })
+    }
+}
+
+/// Whether a call failed for the service being overloaded, which only shows
+/// this early for calls failing before their first message.
+fn is_overloaded(headers: &HeaderMap) -> bool {
+    let code = headers
+        .get("grpc-status")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<i32>().ok())
+        .map(Code::from_i32);
+    matches!(code, Some(Code::Unavailable | Code::DeadlineExceeded))
+}
+
+/// Response body keeping the call in progress until it ends.
+struct PermitBody {
+    inner: BoxBody,
+    _admissions: Vec<Admission>,
+}
+
+impl Body for PermitBody {
+    type Data = <BoxBody as Body>::Data;
+    type Error = Status;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Pin::new(&mut self.inner).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+}
+
+/// The calls of a route, in progress and waiting now, and since startup
+/// those admitted, those refused with the queue full and those that waited
+/// too long.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct LimitStats {
+    pub max_concurrent: usize,
+    /// What calls are let in up to now, below `max_concurrent` while an
+    /// adaptive limit backs off.
+    pub limit: usize,
+    pub in_flight: usize,
+    pub queued: usize,
+    pub admitted: u64,
+    pub rejected: u64,
+    pub timed_out: u64,
+    /// Mean time the calls that had to wait did, those timed out included.
+    pub mean_wait_ms: u64,
+}
+
+/// Counts of every limited route, by route.
+pub fn stats() -> BTreeMap<String, LimitStats> {
+    STATS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(route, limit)| {
+            let waited = limit.waited.load(Ordering::Relaxed);
+            let wait_us = limit.wait_us.load(Ordering::Relaxed);
+            let stats = LimitStats {
+                max_concurrent: limit.max_concurrent,
+                limit: limit.current(),
+                in_flight: limit.in_flight.load(Ordering::Relaxed),
+                queued: limit.queued.load(Ordering::Relaxed),
+                admitted: limit.admitted.load(Ordering::Relaxed),
+                rejected: limit.rejected.load(Ordering::Relaxed),
+                timed_out: limit.timed_out.load(Ordering::Relaxed),
+                mean_wait_ms: wait_us.checked_div(waited).unwrap_or(0) / 1000,
+            };
+            (route.clone(), stats)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adaptive_limits_back_off_when_calls_slow_down() {
+        let mut limit = AdaptiveLimit::new(&MethodLimit {
+            max_concurrent: 100,
+            max_queued: 0,
+            queue_timeout_ms: 0,
+            adaptive: true,
+            min_concurrent: 5,
+            latency_tolerance: 2.0,
+            backoff: 0.5,
+        });
+        let ms = std::time::Duration::from_millis;
+        for _ in 0..10 {
+            assert_eq!(limit.sample(ms(10), false, 100), 100);
+        }
+        assert_eq!(limit.sample(ms(100), false, 100), 50);
+        assert_eq!(limit.sample(ms(100), false, 50), 25);
+        assert_eq!(limit.sample(ms(10), true, 25), 12);
+        for _ in 0..10 {
+            limit.sample(ms(100), false, 12);
+        }
+        assert_eq!(limit.limit(), 5);
+
+        // an idle limit has no reason to grow
+        for _ in 0..100 {
+            limit.sample(ms(10), false, 0);
+        }
+        assert_eq!(limit.limit(), 5);
+        for _ in 0..100 {
+            limit.sample(ms(10), false, limit.limit());
+        }
+        assert!(limit.limit() > 10, "limit {}", limit.limit());
+    }
+}
@@ -0,0 +1,315 @@
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpSocket, TcpStream},
+    sync::mpsc,
+    time,
+};
+use tokio_stream::{wrappers::ReceiverStream, Stream};
+use tonic::transport::server::Connected;
+
+use crate::handshake::{Handshake, HandshakeLimits, Handshakes};
+use crate::ip_filter::Cidr;
+use crate::proxy_protocol;
+use crate::server_events::{self, EventKind, ServerEvent};
+
+const BACKLOG: u32 = 1024;
+/// Bounds of the pause after an accept error such as running out of file
+/// descriptors, doubled on every consecutive error.
+const MIN_BACKOFF: Duration = Duration::from_millis(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Clone, Debug, Default)]
+pub struct ListenerOptions {
+    /// Disable Nagle's algorithm on accepted sockets.
+    pub nodelay: bool,
+    /// Let several sockets bind the same address, see `bind`.
+    pub reuseport: bool,
+    /// Expect a PROXY protocol header on connections from `trusted_proxies`.
+    pub proxy_protocol: bool,
+    /// Peers whose PROXY header is read. Connections from anyone else are
+    /// taken as direct ones, a client can't claim an address of its choice.
+    pub trusted_proxies: Vec<Cidr>,
+    /// Limits on the TLS handshakes of the connections, see `handshake`.
+    pub handshakes: HandshakeLimits,
+}
+
+/// Connect info of the connections served by `incoming`, found in the
+/// request extensions (see `PeerInfo`).
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    /// Process-wide unique id, to correlate the calls of one connection.
+    pub id: u64,
+    /// Client address, as reported by the PROXY header of a trusted proxy.
+    pub remote_addr: Option<SocketAddr>,
+    /// Other end of the socket, the proxy when there is one.
+    pub peer_addr: Option<SocketAddr>,
+    pub local_addr: Option<SocketAddr>,
+    pub accepted_at: SystemTime,
+}
+
+/// An accepted connection, ready for HTTP/2.
+pub struct Connection {
+    inner: TcpStream,
+    /// Traffic read along with a PROXY header, handed out before `inner`.
+    buffered: Vec<u8>,
+    info: ConnectionInfo,
+    handshake: Option<Handshake>,
+}
+
+impl Connected for Connection {
+    type ConnectInfo = ConnectionInfo;
+
+    /// Asked for by tonic once the TLS handshake is done, if any.
+    fn connect_info(&self) -> Self::ConnectInfo {
+        if let Some(handshake) = &self.handshake {
+            handshake.complete();
+        }
+        self.info.clone()
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = if !self.buffered.is_empty() {
+            let len = self.buffered.len().min(buf.remaining());
+            buf.put_slice(&self.buffered[..len]);
+            self.buffered.drain(..len);
+            Poll::Ready(Ok(()))
+        } else {
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        };
+        if let Some(handshake) = &mut self.handshake {
+            if buf.filled().len() > filled {
+                handshake.received();
+            }
+            if poll.is_pending() && handshake.poll_expired(cx).is_ready() {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "TLS handshake timed out",
+                )));
+            }
+        }
+        poll
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let info = &self.info;
+        server_events::emit(|| {
+            let open_for = info.accepted_at.elapsed().unwrap_or_default();
+            connection_event(EventKind::Disconnected, "connection closed", info)
+                .with("open_ms", open_for.as_millis())
+        });
+    }
+}
+
+fn connection_event(kind: EventKind, message: &str, info: &ConnectionInfo) -> ServerEvent {
+    let peer = info
+        .remote_addr
+        .map_or_else(|| "unknown".to_string(), |addr| addr.to_string());
+    ServerEvent::new(kind, message)
+        .with("connection_id", info.id)
+        .with("peer", peer)
+}
+
+/// Binds a listener on `addr`. With `reuseport` set other listeners, in this
+/// process or another one, can bind the same address and the kernel spreads
+/// incoming connections between them.
+pub fn bind(addr: SocketAddr, options: &ListenerOptions) -> io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    #[cfg(unix)]
+    socket.set_reuseport(options.reuseport)?;
+    socket.bind(addr)?;
+    socket.listen(BACKLOG)
+}
+
+/// Accepts connections from `listeners` for `Server::serve_with_incoming`,
+/// with an accept loop per listener. Binding several listeners with
+/// `reuseport` on the same address spreads the accept work across workers.
+///
+/// Accept errors never end the stream, as tonic would stop serving: errors
+/// tied to a single connection are skipped, others (out of file descriptors)
+/// pause accepting with a growing backoff. Sockets are set up, and PROXY
+/// headers read, on a task per connection so one slow client never holds up
+/// the others. Connections then wait for room for their TLS handshake, and
+/// those of banned clients are dropped, see `handshake`.
+pub fn incoming(
+    listeners: Vec<TcpListener>,
+    options: ListenerOptions,
+) -> impl Stream<Item = io::Result<Connection>> {
+    let (tx, rx) = mpsc::channel(BACKLOG as usize);
+    let handshakes = Handshakes::new(options.handshakes);
+    let options = Arc::new(options);
+    for listener in listeners {
+        tokio::spawn(accept_loop(
+            listener,
+            options.clone(),
+            handshakes.clone(),
+            tx.clone(),
+        ));
+    }
+    ReceiverStream::new(rx)
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    options: Arc<ListenerOptions>,
+    handshakes: Handshakes,
+    tx: mpsc::Sender<io::Result<Connection>>,
+) {
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => {
+                backoff = MIN_BACKOFF;
+                stream
+            }
+            Err(err) if is_connection_error(&err) => continue,
+            Err(err) => {
+                eprintln!(
+                    "failed to accept connection, retrying in {:?}: {}",
+                    backoff, err
+                );
+                time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                continue;
+            }
+        };
+        // the server shut down
+        if tx.is_closed() {
+            break;
+        }
+        let tx = tx.clone();
+        let options = options.clone();
+        let handshakes = handshakes.clone();
+        tokio::spawn(async move {
+            let peer = stream.peer_addr().ok();
+            let mut conn = match accept(stream, &options).await {
+                Ok(conn) => conn,
+                Err(err) => {
+                    eprintln!("dropped connection from {:?}: {}", peer, err);
+                    return;
+                }
+            };
+            let ip = conn.info.remote_addr.map(|addr| addr.ip());
+            if ip.is_some_and(|ip| handshakes.is_banned(ip)) {
+                return;
+            }
+            match handshakes.start(ip).await {
+                Some(handshake) => conn.handshake = Some(handshake),
+                None => {
+                    eprintln!(
+                        "dropped connection from {:?}: no room for its handshake",
+                        peer
+                    );
+                    return;
+                }
+            }
+            let _ = tx.send(Ok(conn)).await;
+        });
+    }
+}
+
+async fn accept(
+    mut stream: TcpStream,
+    options: &ListenerOptions,
+) -> Result<Connection, proxy_protocol::ProxyError> {
+    let accepted_at = SystemTime::now();
+    stream.set_nodelay(options.nodelay)?;
+
+    let peer_addr = stream.peer_addr().ok();
+    let mut remote_addr = peer_addr;
+    let mut local_addr = stream.local_addr().ok();
+    let mut buffered = Vec::new();
+    let trusted = peer_addr.is_some_and(|addr| {
+        let ip = addr.ip();
+        options.trusted_proxies.iter().any(|cidr| cidr.contains(ip))
+    });
+    if options.proxy_protocol && trusted {
+        let (addrs, rest) = proxy_protocol::read_header(&mut stream).await?;
+        if let Some((src, dst)) = addrs {
+            remote_addr = Some(src);
+            local_addr = Some(dst);
+        }
+        buffered = rest;
+    }
+
+    let info = ConnectionInfo {
+        id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+        remote_addr,
+        peer_addr,
+        local_addr,
+        accepted_at,
+    };
+    server_events::emit(|| connection_event(EventKind::Connected, "connection accepted", &info));
+    Ok(Connection {
+        inner: stream,
+        buffered,
+        info,
+        handshake: None,
+    })
+}
+
+/// Errors only affecting the connection being accepted.
+fn is_connection_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
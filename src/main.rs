@@ -1,54 +1,87 @@
-use cfg_if::cfg_if;
-
-use tonic::transport::Server;
 #[cfg(feature = "tls")]
-use tonic::transport::{Identity, ServerTlsConfig};
+use tonic::transport::{Certificate, Identity};
+
+use std::time::Duration;
 
 use tonic_hello_tls::{
-    db,
-    greeter::{GreeterServer, MyGreeter, FILE_DESCRIPTOR_SET},
+    config::Config,
+    db::{Db, PoolOptions},
+    server::ServerBuilder,
+    startup,
 };
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenvy::dotenv().ok();
 
-    #[cfg(feature = "tls")]
-    let identity = {
-        let tls_dir = std::path::PathBuf::from("tls");
-        let cert = std::fs::read_to_string(tls_dir.join("server.pem"))?;
-        let key = std::fs::read_to_string(tls_dir.join("server.key"))?;
+    let config = Config::load()?;
 
-        Identity::from_pem(cert, key)
-    };
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime
+        .enable_all()
+        .max_blocking_threads(config.max_blocking_threads)
+        .thread_name(&config.thread_name);
+    if let Some(worker_threads) = config.worker_threads {
+        runtime.worker_threads(worker_threads);
+    }
 
-    let addr = "[::0]:50051".parse().unwrap();
+    runtime.build()?.block_on(serve(config))
+}
 
-    let db_url = std::env::var("DATABASE_URL")?;
-    let db = db::Db::new(&db_url).await?;
+async fn serve(config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    let addr = "[::0]:50051".parse().unwrap();
+    let retry = config.startup_retry();
 
-    let greeter = MyGreeter::new(db);
+    // never replaces a certificate, mounted ones included
+    #[cfg(feature = "dev-tls")]
+    tonic_hello_tls::dev_tls::ensure_identity(std::path::Path::new("tls"))?;
 
-    let reflection_service = tonic_reflection::server::Builder::configure()
-        .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
-        .build()
-        .unwrap();
+    // mounted secrets may show up after the process starts
+    #[cfg(feature = "tls")]
+    let identity = startup::retry("TLS identity", retry, || async {
+        tonic_hello_tls::identity::load(
+            std::path::Path::new("tls"),
+            config.tls_format,
+            &config.tls_pkcs12_password,
+            config.tls_key_passphrase()?.as_deref(),
+        )
+    })
+    .await?;
 
-    println!("GreeterServer listening on {}", addr);
+    // clients need a certificate of this CA once it is there
+    #[cfg(feature = "tls")]
+    let client_ca = match std::fs::read("tls/client_ca.pem") {
+        Ok(pem) => Some(pem),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err.into()),
+    };
 
-    let mut server_builder = Server::builder();
+    let db_url = std::env::var("DATABASE_URL")?;
+    let options = PoolOptions {
+        statement_timeout: Duration::from_millis(config.statement_timeout_ms),
+        ..PoolOptions::default()
+    };
+    let db = Db::with_pool_options(&db_url, options).await?;
+    startup::retry("database", retry, || db.probe()).await?;
+    let server = ServerBuilder::new(config).with_db(db);
 
-    cfg_if! {
-        if #[cfg(feature = "tls")] {
-            server_builder = server_builder.tls_config(ServerTlsConfig::new().identity(identity))?;
+    #[cfg(feature = "tls")]
+    let server = {
+        let certificates = tonic_hello_tls::certs::parse("server", identity.cert.as_bytes())?;
+        let server = server
+            .with_certificates(certificates)
+            .with_tls(Identity::from_pem(identity.cert, identity.key));
+        match client_ca {
+            Some(pem) => {
+                let certificates = tonic_hello_tls::certs::parse("client CA", &pem)?;
+                server
+                    .with_certificates(certificates)
+                    .with_client_ca(Certificate::from_pem(pem))
+            }
+            None => server,
         }
-    }
+    };
 
-    server_builder
-        .add_service(reflection_service)
-        .add_service(GreeterServer::new(greeter))
-        .serve(addr)
-        .await?;
+    server.serve(addr).await?;
 
     Ok(())
 }
@@ -1,21 +1,435 @@
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    mpsc,
+};
+
+use crate::{
+    codec::EncodedReply, config::OverflowPolicy, db::Message, greeter::hello_world::HelloReply,
+};
+
+/// How often a blocked broadcast checks whether the slowest subscriber caught
+/// up, the channel has no way to wait for that.
+const BLOCK_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Delivers stored greetings to the live subscribers of `ListMessagesStream`.
+/// `Broadcaster` fans out within the process; implement this to fan out
+/// through a message bus, so subscribers on every replica see every
+/// greeting, and hand it to `MyGreeter::with_broadcaster`.
+pub trait Fanout: Clone + Send + Sync + 'static {
+    /// Sends `msg` to the current subscribers, returns whether any got it.
+    fn broadcast(&self, msg: Message) -> impl Future<Output = bool> + Send;
+
+    /// Subscribes to the messages broadcast from now on, `subscriber` names
+    /// the consumer in stats. Lagging subscriptions may miss messages,
+    /// subscribers resume from the store by id.
+    fn subscribe(&self, subscriber: &str) -> Subscription;
+}
+
+/// A broadcast message, shared by the subscribers rather than copied for
+/// each of them. Its `HelloReply` is built and encoded once, by the first
+/// subscriber asking for it.
+#[derive(Clone, Debug)]
+pub struct SharedMessage(Arc<Shared>);
+
+#[derive(Debug)]
+struct Shared {
+    message: Message,
+    reply: OnceLock<HelloReply>,
+    encoded: OnceLock<EncodedReply>,
+}
+
+impl SharedMessage {
+    /// The `HelloReply` of the message.
+    pub fn reply(&self) -> HelloReply {
+        self.built_reply().clone()
+    }
+
+    /// The `HelloReply` of the message, encoded.
+    pub fn encoded(&self) -> EncodedReply {
+        self.0
+            .encoded
+            .get_or_init(|| EncodedReply::new(self.built_reply()))
+            .clone()
+    }
+
+    fn built_reply(&self) -> &HelloReply {
+        self.0
+            .reply
+            .get_or_init(|| HelloReply::from(self.0.message.clone()))
+    }
+}
+
+impl From<Message> for SharedMessage {
+    fn from(message: Message) -> Self {
+        Self(Arc::new(Shared {
+            message,
+            reply: OnceLock::new(),
+            encoded: OnceLock::new(),
+        }))
+    }
+}
+
+impl Deref for SharedMessage {
+    type Target = Message;
+
+    fn deref(&self) -> &Message {
+        &self.0.message
+    }
+}
+
+/// Fans messages out to the subscribers in two lanes: messages with a
+/// priority above 0 go through `urgent_tx`, which subscribers read ahead of
+/// `tx`. Each lane keeps the order messages were broadcast in.
+///
+/// Subscribers are spread over shards, each a pair of channels of its own,
+/// so that thousands of them don't contend for the locks of a single
+/// channel. With more than one shard, a worker per shard sends to its
+/// channels, waking its subscribers in parallel with the other workers.
 #[derive(Clone)]
 pub struct Broadcaster {
-    tx: tokio::sync::broadcast::Sender<String>,
+    shards: Arc<[Shard]>,
+    /// Queues of the shard workers, started by the first broadcast.
+    workers: Arc<OnceLock<Vec<mpsc::Sender<SharedMessage>>>>,
+    /// Held while handing a message to the workers, so that every shard
+    /// gets messages in the same order.
+    handoff: Arc<tokio::sync::Mutex<()>>,
+    capacity: usize,
+    overflow: OverflowPolicy,
+    registry: Arc<Registry>,
+}
+
+struct Shard {
+    tx: broadcast::Sender<SharedMessage>,
+    urgent_tx: broadcast::Sender<SharedMessage>,
+}
+
+impl Shard {
+    fn lane(&self, urgent: bool) -> &broadcast::Sender<SharedMessage> {
+        if urgent {
+            &self.urgent_tx
+        } else {
+            &self.tx
+        }
+    }
+}
+
+/// Counters shared by a broadcaster and its subscriptions.
+#[derive(Default)]
+struct Registry {
+    broadcast: AtomicU64,
+    dropped: AtomicU64,
+    missed: AtomicU64,
+    next_id: AtomicU64,
+    subscribers: Mutex<BTreeMap<u64, Arc<Subscriber>>>,
+}
+
+struct Subscriber {
+    name: String,
+    since: Instant,
+    /// `broadcast` when subscribing, what was broadcast before isn't owed.
+    start: u64,
+    received: AtomicU64,
+    missed: AtomicU64,
+}
+
+/// Counts since startup, for spotting subscribers that leak or lag.
+#[derive(Clone, Debug, Serialize)]
+pub struct BroadcastStats {
+    /// Messages handed to at least one subscriber.
+    pub broadcast: u64,
+    /// Messages dropped by the `drop-newest` overflow policy.
+    pub dropped: u64,
+    /// Messages subscribers missed by lagging, gone subscribers included.
+    pub missed: u64,
+    pub subscribers: Vec<SubscriberStats>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SubscriberStats {
+    pub id: u64,
+    pub name: String,
+    pub subscribed_secs: u64,
+    pub received: u64,
+    pub missed: u64,
+    /// Messages broadcast since subscribing that weren't read yet.
+    pub behind: u64,
+}
+
+impl Default for Broadcaster {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Broadcaster {
     pub fn new() -> Self {
-        let (tx, _rx) = tokio::sync::broadcast::channel(16);
-        Self { tx }
+        Self::with_capacity(16, OverflowPolicy::DropOldest)
     }
 
-    pub fn broadcast<T: Into<String>>(&self, msg: T) {
-        if let Err(err) = self.tx.send(msg.into()) {
-            eprintln!("Error broadcasting message: {}", err)
+    /// Buffers `capacity` messages for the slowest subscriber, `overflow`
+    /// decides what happens past that.
+    pub fn with_capacity(capacity: usize, overflow: OverflowPolicy) -> Self {
+        Self::with_shards(capacity, overflow, 1)
+    }
+
+    /// Like `with_capacity`, spreading subscribers over `shards` channels.
+    pub fn with_shards(capacity: usize, overflow: OverflowPolicy, shards: usize) -> Self {
+        let shards = (0..shards.max(1))
+            .map(|_| Shard {
+                tx: broadcast::channel(capacity).0,
+                urgent_tx: broadcast::channel(capacity).0,
+            })
+            .collect();
+        Self {
+            shards,
+            workers: Arc::default(),
+            handoff: Arc::default(),
+            capacity,
+            overflow,
+            registry: Arc::default(),
+        }
+    }
+
+    /// Sends `msg` to the current subscribers, returns whether any got it.
+    pub async fn broadcast(&self, msg: Message) -> bool {
+        let msg = SharedMessage::from(msg);
+        let urgent = msg.priority > 0;
+        match self.overflow {
+            OverflowPolicy::DropOldest => (),
+            OverflowPolicy::DropNewest => {
+                if self.is_full(urgent) {
+                    eprintln!("Dropping broadcast of message {}: subscribers lag", msg.id);
+                    self.registry.dropped.fetch_add(1, Ordering::Relaxed);
+                    return false;
+                }
+            }
+            OverflowPolicy::Block => {
+                while self.is_full(urgent) {
+                    tokio::time::sleep(BLOCK_POLL_INTERVAL).await;
+                }
+            }
+        }
+        if let [shard] = &*self.shards {
+            if let Err(err) = shard.lane(urgent).send(msg) {
+                eprintln!("Error broadcasting message: {}", err);
+                return false;
+            }
+        } else {
+            if self.subscriber_count() == 0 {
+                eprintln!("Error broadcasting message {}: no subscribers", msg.id);
+                return false;
+            }
+            let workers = self.workers.get_or_init(|| self.start_workers());
+            let _handoff = self.handoff.lock().await;
+            for worker in workers {
+                // workers live as long as their queue
+                let _ = worker.send(msg.clone()).await;
+            }
+        }
+        self.registry.broadcast.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Spawns a worker per shard, sending what comes through its queue to
+    /// the shard's channels until the broadcaster is dropped.
+    fn start_workers(&self) -> Vec<mpsc::Sender<SharedMessage>> {
+        (0..self.shards.len())
+            .map(|index| {
+                let (queue_tx, mut queue) = mpsc::channel::<SharedMessage>(self.capacity);
+                let shards = self.shards.clone();
+                tokio::spawn(async move {
+                    let shard = &shards[index];
+                    while let Some(msg) = queue.recv().await {
+                        // no subscriber left on this shard
+                        let _ = shard.lane(msg.priority > 0).send(msg);
+                    }
+                });
+                queue_tx
+            })
+            .collect()
+    }
+
+    /// Whether the slowest subscriber has `capacity` messages of its lane
+    /// left to read, counting those its shard's worker didn't send yet.
+    fn is_full(&self, urgent: bool) -> bool {
+        let workers = self.workers.get();
+        self.shards.iter().enumerate().any(|(index, shard)| {
+            let queued = workers.map_or(0, |workers| {
+                workers[index].max_capacity() - workers[index].capacity()
+            });
+            shard.lane(urgent).len() + queued >= self.capacity
+        })
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.tx.receiver_count())
+            .sum()
+    }
+
+    pub fn subscribe(&self, subscriber: &str) -> Subscription {
+        let registry = &self.registry;
+        let id = registry.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let entry = Arc::new(Subscriber {
+            name: subscriber.to_string(),
+            since: Instant::now(),
+            start: registry.broadcast.load(Ordering::Relaxed),
+            received: AtomicU64::new(0),
+            missed: AtomicU64::new(0),
+        });
+        registry
+            .subscribers
+            .lock()
+            .unwrap()
+            .insert(id, entry.clone());
+
+        let shard = &self.shards[id as usize % self.shards.len()];
+        Subscription {
+            rx: shard.tx.subscribe(),
+            urgent_rx: Some(shard.urgent_tx.subscribe()),
+            tracked: Some(Tracked {
+                registry: registry.clone(),
+                id,
+                entry,
+            }),
+            overtook: false,
+        }
+    }
+
+    pub fn stats(&self) -> BroadcastStats {
+        let registry = &self.registry;
+        let broadcast = registry.broadcast.load(Ordering::Relaxed);
+        let subscribers = registry
+            .subscribers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, entry)| {
+                let received = entry.received.load(Ordering::Relaxed);
+                let missed = entry.missed.load(Ordering::Relaxed);
+                SubscriberStats {
+                    id: *id,
+                    name: entry.name.clone(),
+                    subscribed_secs: entry.since.elapsed().as_secs(),
+                    received,
+                    missed,
+                    behind: broadcast.saturating_sub(entry.start + received + missed),
+                }
+            })
+            .collect();
+
+        BroadcastStats {
+            broadcast,
+            dropped: registry.dropped.load(Ordering::Relaxed),
+            missed: registry.missed.load(Ordering::Relaxed),
+            subscribers,
         }
     }
+}
+
+impl Fanout for Broadcaster {
+    async fn broadcast(&self, msg: Message) -> bool {
+        Broadcaster::broadcast(self, msg).await
+    }
+
+    fn subscribe(&self, subscriber: &str) -> Subscription {
+        Broadcaster::subscribe(self, subscriber)
+    }
+}
+
+/// A subscriber's end of a `Fanout`, counted in the stats of the
+/// `Broadcaster` it came from until dropped.
+pub struct Subscription {
+    rx: broadcast::Receiver<SharedMessage>,
+    urgent_rx: Option<broadcast::Receiver<SharedMessage>>,
+    tracked: Option<Tracked>,
+    /// See `overtook`.
+    overtook: bool,
+}
+
+struct Tracked {
+    registry: Arc<Registry>,
+    id: u64,
+    entry: Arc<Subscriber>,
+}
 
-    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<String> {
-        self.tx.subscribe()
+impl Subscription {
+    /// A subscription no stats are kept for, for `Fanout` implementations
+    /// other than `Broadcaster`. Messages come in the order of `rx`,
+    /// whatever their priority.
+    pub fn new(rx: broadcast::Receiver<SharedMessage>) -> Self {
+        Self {
+            rx,
+            urgent_rx: None,
+            tracked: None,
+            overtook: false,
+        }
+    }
+
+    /// The next message, high priority ones that are waiting ahead of the
+    /// others.
+    pub async fn recv(&mut self) -> Result<SharedMessage, RecvError> {
+        let result = match &mut self.urgent_rx {
+            Some(urgent_rx) => tokio::select! {
+                biased;
+                msg = urgent_rx.recv() => {
+                    self.overtook = !self.rx.is_empty();
+                    msg
+                }
+                msg = self.rx.recv() => {
+                    self.overtook = false;
+                    msg
+                }
+            },
+            None => self.rx.recv().await,
+        };
+        if let Some(tracked) = &self.tracked {
+            match &result {
+                Ok(_) => {
+                    tracked.entry.received.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    tracked.entry.missed.fetch_add(*skipped, Ordering::Relaxed);
+                    tracked
+                        .registry
+                        .missed
+                        .fetch_add(*skipped, Ordering::Relaxed);
+                }
+                Err(RecvError::Closed) => (),
+            }
+        }
+        result
+    }
+
+    /// Whether the message last received went ahead of older ones still
+    /// waiting, so its id is no position to resume the subscription from.
+    pub fn overtook(&self) -> bool {
+        self.overtook
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Some(tracked) = &self.tracked {
+            tracked
+                .registry
+                .subscribers
+                .lock()
+                .unwrap()
+                .remove(&tracked.id);
+        }
     }
 }
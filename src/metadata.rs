@@ -0,0 +1,82 @@
+//! Message metadata travels as `google.protobuf.Struct` and is stored as
+//! JSONB, these convert between the two and match it like Postgres does.
+
+use prost_types::{value::Kind, ListValue, Struct, Value};
+use serde_json::{Map, Number};
+
+/// `metadata` as a JSON object.
+pub fn to_json(metadata: Struct) -> Map<String, serde_json::Value> {
+    metadata
+        .fields
+        .into_iter()
+        .map(|(key, value)| (key, value_to_json(value)))
+        .collect()
+}
+
+/// The JSON object `metadata` as a `Struct`, an empty one for anything else.
+pub fn from_json(metadata: &serde_json::Value) -> Struct {
+    match metadata {
+        serde_json::Value::Object(fields) => struct_from_json(fields),
+        _ => Struct::default(),
+    }
+}
+
+/// Whether `value` contains `wanted` the way Postgres' `@>` on JSONB has it.
+pub fn contains(value: &serde_json::Value, wanted: &serde_json::Value) -> bool {
+    use serde_json::Value;
+    match (value, wanted) {
+        (Value::Object(value), Value::Object(wanted)) => wanted
+            .iter()
+            .all(|(key, wanted)| value.get(key).is_some_and(|value| contains(value, wanted))),
+        (Value::Array(value), Value::Array(wanted)) => wanted
+            .iter()
+            .all(|wanted| value.iter().any(|value| contains(value, wanted))),
+        (Value::Array(value), wanted) => value.contains(wanted),
+        (value, wanted) => value == wanted,
+    }
+}
+
+fn value_to_json(value: Value) -> serde_json::Value {
+    match value.kind {
+        None | Some(Kind::NullValue(_)) => serde_json::Value::Null,
+        // Struct numbers are all doubles, whole ones are stored as integers
+        Some(Kind::NumberValue(number))
+            if number.fract() == 0.0 && number.abs() < i64::MAX as f64 =>
+        {
+            serde_json::Value::Number(Number::from(number as i64))
+        }
+        // NaN and the infinities have no JSON form
+        Some(Kind::NumberValue(number)) => Number::from_f64(number)
+            .map(serde_json::Value::Number)
+            .unwrap_or(serde_json::Value::Null),
+        Some(Kind::StringValue(string)) => serde_json::Value::String(string),
+        Some(Kind::BoolValue(boolean)) => serde_json::Value::Bool(boolean),
+        Some(Kind::StructValue(fields)) => serde_json::Value::Object(to_json(fields)),
+        Some(Kind::ListValue(list)) => {
+            serde_json::Value::Array(list.values.into_iter().map(value_to_json).collect())
+        }
+    }
+}
+
+fn struct_from_json(fields: &Map<String, serde_json::Value>) -> Struct {
+    Struct {
+        fields: fields
+            .iter()
+            .map(|(key, value)| (key.clone(), value_from_json(value)))
+            .collect(),
+    }
+}
+
+fn value_from_json(value: &serde_json::Value) -> Value {
+    let kind = match value {
+        serde_json::Value::Null => Kind::NullValue(0),
+        serde_json::Value::Bool(boolean) => Kind::BoolValue(*boolean),
+        serde_json::Value::Number(number) => Kind::NumberValue(number.as_f64().unwrap_or_default()),
+        serde_json::Value::String(string) => Kind::StringValue(string.clone()),
+        serde_json::Value::Array(values) => Kind::ListValue(ListValue {
+            values: values.iter().map(value_from_json).collect(),
+        }),
+        serde_json::Value::Object(fields) => Kind::StructValue(struct_from_json(fields)),
+    };
+    Value { kind: Some(kind) }
+}
@@ -0,0 +1,230 @@
+//! Shadow traffic: a share of the unary calls is sent again to a secondary
+//! backend, e.g. a new deployment, whose responses are thrown away. The
+//! caller only ever sees the response of this server.
+
+use std::{
+    collections::HashSet,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use http_body::Body as HttpBody;
+use prost::{bytes::Bytes, Message};
+use prost_types::FileDescriptorSet;
+use serde::Serialize;
+use tokio::sync::Semaphore;
+use tonic::{
+    body::BoxBody,
+    transport::{Body, Channel, Endpoint},
+    Status,
+};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::greeter::FILE_DESCRIPTOR_SET;
+
+/// Request metadata set on the mirrored calls, so the secondary backend can
+/// tell them apart. Calls carrying it are never mirrored again.
+pub const MIRRORED_METADATA: &str = "x-mirrored";
+
+/// Mirrored calls still waiting for the secondary backend, more are
+/// skipped rather than queued.
+const MAX_IN_FLIGHT: usize = 64;
+const MIRROR_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Mutating the data of another deployment isn't what shadow traffic is
+/// for, calls of these services stay here.
+const SKIPPED_SERVICES: &[&str] = &["helloworld.Admin"];
+
+static MIRRORED: AtomicU64 = AtomicU64::new(0);
+static SKIPPED: AtomicU64 = AtomicU64::new(0);
+static FAILED: AtomicU64 = AtomicU64::new(0);
+
+/// Calls mirrored since startup, the sampled ones skipped as too many were
+/// in flight, and the ones the secondary backend couldn't be reached for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct MirrorStats {
+    pub mirrored: u64,
+    pub skipped: u64,
+    pub failed: u64,
+}
+
+pub fn stats() -> MirrorStats {
+    MirrorStats {
+        mirrored: MIRRORED.load(Ordering::Relaxed),
+        skipped: SKIPPED.load(Ordering::Relaxed),
+        failed: FAILED.load(Ordering::Relaxed),
+    }
+}
+
+/// Mirrors `percent` of the unary calls to the backend at `MIRROR_ADDR`,
+/// spread evenly: with 10 every tenth call is mirrored. Streaming calls
+/// aren't, their requests would have to be held back until they end.
+///
+/// The request of a mirrored call is read in full before it is passed on.
+#[derive(Clone)]
+pub struct MirrorLayer {
+    target: Option<Arc<Target>>,
+}
+
+impl MirrorLayer {
+    pub fn new(endpoint: Endpoint, percent: f64) -> Self {
+        let channel = endpoint.timeout(MIRROR_TIMEOUT).connect_lazy();
+        Self {
+            target: Some(Arc::new(Target {
+                channel,
+                rate: percent / 100.0,
+                unary: unary_paths(),
+                counter: AtomicU64::new(0),
+                in_flight: Arc::new(Semaphore::new(MAX_IN_FLIGHT)),
+            })),
+        }
+    }
+
+    /// Mirrors nothing.
+    pub fn disabled() -> Self {
+        Self { target: None }
+    }
+}
+
+impl<S> Layer<S> for MirrorLayer {
+    type Service = Mirror<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Mirror {
+            inner,
+            target: self.target.clone(),
+        }
+    }
+}
+
+struct Target {
+    channel: Channel,
+    rate: f64,
+    unary: HashSet<String>,
+    counter: AtomicU64,
+    in_flight: Arc<Semaphore>,
+}
+
+impl Target {
+    fn sample(&self, req: &http::Request<Body>) -> bool {
+        if !self.unary.contains(req.uri().path()) || req.headers().contains_key(MIRRORED_METADATA) {
+            return false;
+        }
+        let n = self.counter.fetch_add(1, Ordering::Relaxed) as f64;
+        ((n + 1.0) * self.rate).floor() > (n * self.rate).floor()
+    }
+}
+
+#[derive(Clone)]
+pub struct Mirror<S> {
+    inner: S,
+    target: Option<Arc<Target>>,
+}
+
+impl<S> Service<http::Request<Body>> for Mirror<S>
+where
+    S: Service<http::Request<Body>, Response = http::Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<Body>) -> Self::Future {
+        let target = match &self.target {
+            Some(target) if target.sample(&req) => target,
+            _ => return Box::pin(self.inner.call(req)),
+        };
+        let Ok(permit) = target.in_flight.clone().try_acquire_owned() else {
+            SKIPPED.fetch_add(1, Ordering::Relaxed);
+            return Box::pin(self.inner.call(req));
+        };
+        let mut channel = target.channel.clone();
+        // the clone isn't ready, keep the service `poll_ready` was called on
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            let (parts, body) = req.into_parts();
+            let data = match collect(body).await {
+                Ok(data) => data,
+                Err(err) => return Ok(Status::unknown(format!("request body: {}", err)).to_http()),
+            };
+
+            let body = http_body::Full::new(Bytes::from(data.clone()))
+                .map_err(|never| match never {})
+                .boxed_unsync();
+            let mut mirrored = http::Request::new(body);
+            *mirrored.method_mut() = parts.method.clone();
+            *mirrored.uri_mut() = parts.uri.clone();
+            *mirrored.headers_mut() = parts.headers.clone();
+            mirrored
+                .headers_mut()
+                .insert(MIRRORED_METADATA, http::HeaderValue::from_static("true"));
+            MIRRORED.fetch_add(1, Ordering::Relaxed);
+            tokio::spawn(async move {
+                let _permit = permit;
+                if send(&mut channel, mirrored).await.is_err() {
+                    FAILED.fetch_add(1, Ordering::Relaxed);
+                }
+            });
+
+            inner
+                .call(http::Request::from_parts(parts, Body::from(data)))
+                .await
+        })
+    }
+}
+
+/// Sends `req` and reads the response to its end, only to drop it.
+async fn send(channel: &mut Channel, req: http::Request<BoxBody>) -> Result<(), ()> {
+    std::future::poll_fn(|cx| channel.poll_ready(cx))
+        .await
+        .map_err(drop)?;
+    let mut body = channel.call(req).await.map_err(drop)?.into_body();
+    while let Some(chunk) = body.data().await {
+        chunk.map_err(drop)?;
+    }
+    Ok(())
+}
+
+async fn collect(mut body: Body) -> Result<Vec<u8>, <Body as HttpBody>::Error> {
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        data.extend_from_slice(&chunk?);
+    }
+    Ok(data)
+}
+
+/// Paths of the unary methods served, `SKIPPED_SERVICES` left out.
+fn unary_paths() -> HashSet<String> {
+    let Ok(descriptors) = FileDescriptorSet::decode(FILE_DESCRIPTOR_SET) else {
+        return HashSet::new();
+    };
+    let mut paths = HashSet::new();
+    for file in &descriptors.file {
+        for service in &file.service {
+            let service_name = format!("{}.{}", file.package(), service.name());
+            if SKIPPED_SERVICES.contains(&service_name.as_str()) {
+                continue;
+            }
+            for method in &service.method {
+                if !method.client_streaming() && !method.server_streaming() {
+                    paths.insert(format!("/{}/{}", service_name, method.name()));
+                }
+            }
+        }
+    }
+    paths
+}
@@ -0,0 +1,1276 @@
+//! Stand-ins for the greeter service and its storage, for teams testing
+//! clients of this service without a server or database (`test-util`
+//! feature).
+//!
+//! `MockGreeter` implements the generated `Greeter` trait on top of an
+//! in-memory `MockMessageStore`: greetings are stored and listed like the
+//! real thing, every call is recorded, and any method can be made to fail.
+//!
+//! ```ignore
+//! let greeter = MockGreeter::new().fail("ListMessages", Status::unavailable("down"));
+//! let server = Server::builder().add_service(GreeterServer::new(greeter.clone()));
+//! tokio::spawn(server.serve(addr));
+//! // ... exercise the client, then
+//! assert_eq!(greeter.calls()[0], Call::SayHello(request));
+//! ```
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, Stream};
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::{
+    codec::Frame,
+    config::Quota,
+    db::{
+        AuditEntry, CountryCount, DbError, Event, EventKind, Exchange, FeatureFlag, GreetingStats,
+        Message, NameCount, Session, Usage,
+    },
+    export,
+    greeter::hello_world::{
+        greeter_server::Greeter, AttachmentChunk, CountryStats, DeleteAllForNameProgress,
+        DeleteAllForNameRequest, DownloadPayloadRequest, ExportChunk, ExportMessagesRequest,
+        FindSimilarMessagesReply, FindSimilarMessagesRequest, GetAttachmentReply,
+        GetAttachmentRequest, GetSessionRequest, GetStatsRequest, GetUsageRequest, GreetingEvent,
+        HelloReply, HelloRequest, HelloSummaryReply, ImportBatchResult, ImportMessagesReply,
+        ImportMessagesRequest, LeaderboardReply, ListMessagesReply, ListMessagesRequest, NameStats,
+        PayloadChunk, SayHelloManyRequest, SessionReply, StatsReply, StreamEventsRequest,
+        StreamLeaderboardRequest, UpdateMessageRequest, UploadAttachmentReply, UploadPayloadReply,
+        UsageReply,
+    },
+    greeter::SUMMARY_MAX_DISTINCT_NAMES,
+    greeting,
+    messages::Broadcaster,
+    metadata,
+    store::MessageStore,
+    tenant,
+};
+#[cfg(feature = "pgvector")]
+use crate::{config::Config, db::Similar, embed, greeter::hello_world::SimilarMessage};
+
+type MockResult<T> = Result<Response<T>, Status>;
+type MockStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+/// A `MessageStore` in memory. Clones share the same messages.
+#[derive(Clone, Default)]
+pub struct MockMessageStore {
+    inner: Arc<Mutex<Store>>,
+}
+
+#[derive(Default)]
+struct Store {
+    messages: Vec<Message>,
+    payloads: HashMap<i32, Vec<u8>>,
+    attachment_keys: HashMap<i32, String>,
+    countries: HashMap<i32, String>,
+    events: Vec<Event>,
+    next_id: i32,
+    usage: HashMap<String, Usage>,
+    flags: Vec<FeatureFlag>,
+    name_counts: HashMap<String, i64>,
+    audit_log: Vec<AuditEntry>,
+    sessions: Vec<Session>,
+    pending: BTreeSet<i32>,
+    acked_until: HashMap<String, i32>,
+    delivered_until: HashMap<String, i32>,
+    /// The lengths of the `insert_messages` batches, in order.
+    batches: Vec<usize>,
+    #[cfg(feature = "pgvector")]
+    embeddings: HashMap<i32, Vec<f32>>,
+}
+
+impl Store {
+    fn insert(
+        &mut self,
+        message: &str,
+        tags: &serde_json::Value,
+        metadata: &serde_json::Value,
+        priority: i16,
+    ) -> Message {
+        self.next_id += 1;
+        let msg = Message {
+            id: self.next_id,
+            message: Some(message.to_string()),
+            updated: None,
+            tags: tags.clone(),
+            metadata: metadata.clone(),
+            priority,
+        };
+        self.messages.push(msg.clone());
+        self.record(EventKind::Hello, msg.id, msg.message.clone());
+        msg
+    }
+
+    fn record(&mut self, kind: EventKind, message_id: i32, payload: Option<String>) {
+        self.events.push(Event {
+            seq: self.events.len() as i64 + 1,
+            kind: kind.as_str().to_string(),
+            message_id,
+            payload,
+        });
+    }
+}
+
+impl MockMessageStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A store holding `messages` already, with ids from 1.
+    pub fn with_messages<I, S>(messages: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let store = Self::new();
+        {
+            let mut inner = store.inner.lock().unwrap();
+            let empty = serde_json::json!({});
+            for message in messages {
+                inner.insert(message.as_ref(), &empty, &empty, 0);
+            }
+        }
+        store
+    }
+
+    /// The number of messages each `insert_messages` call inserted, in order.
+    pub fn batches(&self) -> Vec<usize> {
+        self.inner.lock().unwrap().batches.clone()
+    }
+}
+
+impl MessageStore for MockMessageStore {
+    type Error = DbError;
+
+    async fn get_messages(&self) -> Result<Vec<Message>, DbError> {
+        Ok(self.inner.lock().unwrap().messages.clone())
+    }
+
+    async fn get_messages_tagged(
+        &self,
+        tags: &serde_json::Value,
+        metadata: &serde_json::Value,
+    ) -> Result<Vec<Message>, DbError> {
+        let messages = self.inner.lock().unwrap().messages.clone();
+        Ok(messages
+            .into_iter()
+            .filter(|msg| {
+                metadata::contains(&msg.tags, tags) && metadata::contains(&msg.metadata, metadata)
+            })
+            .collect())
+    }
+
+    async fn count_messages(&self) -> Result<i64, DbError> {
+        Ok(self.inner.lock().unwrap().messages.len() as i64)
+    }
+
+    async fn get_messages_after(&self, id: i32) -> Result<Vec<Message>, DbError> {
+        self.get_messages_page(id, i64::MAX).await
+    }
+
+    async fn get_messages_page(&self, after_id: i32, limit: i64) -> Result<Vec<Message>, DbError> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner
+            .messages
+            .iter()
+            .filter(|msg| msg.id > after_id)
+            .take(limit.try_into().unwrap_or(usize::MAX))
+            .cloned()
+            .collect())
+    }
+
+    async fn insert_message(&self, message: &str) -> Result<Message, DbError> {
+        let empty = serde_json::json!({});
+        self.insert_tagged_message(message, &empty, &empty, 0, None, None)
+            .await
+    }
+
+    async fn insert_tagged_message(
+        &self,
+        message: &str,
+        tags: &serde_json::Value,
+        metadata: &serde_json::Value,
+        priority: i16,
+        payload: Option<&[u8]>,
+        country: Option<&str>,
+    ) -> Result<Message, DbError> {
+        let mut inner = self.inner.lock().unwrap();
+        let msg = inner.insert(message, tags, metadata, priority);
+        if let Some(payload) = payload {
+            inner.payloads.insert(msg.id, payload.to_vec());
+        }
+        if let Some(country) = country {
+            inner.countries.insert(msg.id, country.to_string());
+        }
+        Ok(msg)
+    }
+
+    async fn insert_messages(&self, messages: &[String]) -> Result<Vec<Message>, DbError> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.batches.push(messages.len());
+        let empty = serde_json::json!({});
+        Ok(messages
+            .iter()
+            .map(|message| inner.insert(message, &empty, &empty, 0))
+            .collect())
+    }
+
+    async fn update_message(&self, id: i32, message: &str) -> Result<Option<Message>, DbError> {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(msg) = inner.messages.iter_mut().find(|msg| msg.id == id) else {
+            return Ok(None);
+        };
+        msg.message = Some(message.to_string());
+        let updated = msg.clone();
+        inner.record(EventKind::Update, id, updated.message.clone());
+        Ok(Some(updated))
+    }
+
+    async fn get_payload(&self, id: i32) -> Result<Option<Vec<u8>>, DbError> {
+        let inner = self.inner.lock().unwrap();
+        if !inner.messages.iter().any(|msg| msg.id == id) {
+            return Ok(None);
+        }
+        Ok(inner.payloads.get(&id).cloned())
+    }
+
+    async fn set_payload(&self, id: i32, payload: &[u8]) -> Result<bool, DbError> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.messages.iter().any(|msg| msg.id == id) {
+            return Ok(false);
+        }
+        inner.payloads.insert(id, payload.to_vec());
+        Ok(true)
+    }
+
+    async fn get_attachment_key(&self, id: i32) -> Result<Option<String>, DbError> {
+        let inner = self.inner.lock().unwrap();
+        if !inner.messages.iter().any(|msg| msg.id == id) {
+            return Ok(None);
+        }
+        Ok(inner.attachment_keys.get(&id).cloned())
+    }
+
+    async fn set_attachment_key(&self, id: i32, key: &str) -> Result<bool, DbError> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.messages.iter().any(|msg| msg.id == id) {
+            return Ok(false);
+        }
+        inner.attachment_keys.insert(id, key.to_string());
+        Ok(true)
+    }
+
+    async fn delete_message(&self, id: i32) -> Result<bool, DbError> {
+        let mut inner = self.inner.lock().unwrap();
+        let before = inner.messages.len();
+        inner.messages.retain(|msg| msg.id != id);
+        let deleted = inner.messages.len() < before;
+        if deleted {
+            inner.pending.remove(&id);
+            inner.record(EventKind::Delete, id, None);
+        }
+        Ok(deleted)
+    }
+
+    async fn erase_messages(&self, ids: &[i32]) -> Result<usize, DbError> {
+        let mut inner = self.inner.lock().unwrap();
+        let erased = inner
+            .messages
+            .iter()
+            .map(|msg| msg.id)
+            .filter(|id| ids.contains(id))
+            .collect::<Vec<_>>();
+        inner.messages.retain(|msg| !erased.contains(&msg.id));
+        for event in &mut inner.events {
+            if erased.contains(&event.message_id) {
+                event.payload = None;
+            }
+        }
+        for &id in &erased {
+            inner.pending.remove(&id);
+            inner.record(EventKind::Delete, id, None);
+        }
+        Ok(erased.len())
+    }
+
+    async fn get_events_page(&self, after_seq: i64, limit: i64) -> Result<Vec<Event>, DbError> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner
+            .events
+            .iter()
+            .filter(|event| event.seq > after_seq)
+            .take(limit.try_into().unwrap_or(usize::MAX))
+            .cloned()
+            .collect())
+    }
+
+    async fn latest_event_seq(&self) -> Result<i64, DbError> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.events.last().map_or(0, |event| event.seq))
+    }
+
+    async fn add_pending_delivery(&self, message_id: i32) -> Result<(), DbError> {
+        self.inner.lock().unwrap().pending.insert(message_id);
+        Ok(())
+    }
+
+    async fn get_pending_deliveries(&self, limit: i64) -> Result<Vec<Message>, DbError> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner
+            .messages
+            .iter()
+            .filter(|msg| inner.pending.contains(&msg.id))
+            .take(limit.try_into().unwrap_or(usize::MAX))
+            .cloned()
+            .collect())
+    }
+
+    async fn delete_pending_deliveries(&self, message_ids: &[i32]) -> Result<(), DbError> {
+        let mut inner = self.inner.lock().unwrap();
+        for id in message_ids {
+            inner.pending.remove(id);
+        }
+        Ok(())
+    }
+
+    async fn get_acked_until(&self, subscriber: &str) -> Result<i32, DbError> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.acked_until.get(subscriber).copied().unwrap_or(0))
+    }
+
+    async fn set_acked_until(&self, subscriber: &str, acked_until: i32) -> Result<(), DbError> {
+        let mut inner = self.inner.lock().unwrap();
+        let position = inner.acked_until.entry(subscriber.to_string()).or_default();
+        *position = acked_until.max(*position);
+        Ok(())
+    }
+
+    async fn get_delivered_until(&self, name: &str) -> Result<i32, DbError> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.delivered_until.get(name).copied().unwrap_or(0))
+    }
+
+    async fn set_delivered_until(&self, name: &str, delivered_until: i32) -> Result<(), DbError> {
+        let mut inner = self.inner.lock().unwrap();
+        let position = inner.delivered_until.entry(name.to_string()).or_default();
+        *position = delivered_until.max(*position);
+        Ok(())
+    }
+
+    async fn add_usage(
+        &self,
+        tenant: &str,
+        added: Usage,
+        quota: Quota,
+    ) -> Result<Option<Usage>, DbError> {
+        let mut inner = self.inner.lock().unwrap();
+        let usage = inner.usage.entry(tenant.to_string()).or_default();
+        let messages = usage.messages + added.messages;
+        let bytes = usage.bytes + added.bytes;
+        let within = |used: i64, max: Option<u64>| {
+            max.is_none_or(|max| used <= i64::try_from(max).unwrap_or(i64::MAX))
+        };
+        if !within(messages, quota.max_messages) || !within(bytes, quota.max_bytes) {
+            return Ok(None);
+        }
+        *usage = Usage { messages, bytes };
+        Ok(Some(*usage))
+    }
+
+    async fn get_usage(&self, tenant: &str) -> Result<Usage, DbError> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.usage.get(tenant).copied().unwrap_or_default())
+    }
+
+    async fn get_feature_flags(&self) -> Result<Vec<FeatureFlag>, DbError> {
+        Ok(self.inner.lock().unwrap().flags.clone())
+    }
+
+    async fn set_feature_flag(
+        &self,
+        name: &str,
+        tenant: &str,
+        enabled: bool,
+    ) -> Result<(), DbError> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner
+            .flags
+            .iter_mut()
+            .find(|flag| flag.name == name && flag.tenant == tenant)
+        {
+            Some(flag) => flag.enabled = enabled,
+            None => inner.flags.push(FeatureFlag {
+                name: name.to_string(),
+                tenant: tenant.to_string(),
+                enabled,
+            }),
+        }
+        Ok(())
+    }
+
+    /// Counts the messages as they are, without days: the mock doesn't date
+    /// them.
+    async fn get_stats(&self, _days: i32, top: i64) -> Result<GreetingStats, DbError> {
+        let inner = self.inner.lock().unwrap();
+        let mut names = HashMap::<&str, i64>::new();
+        for message in inner
+            .messages
+            .iter()
+            .filter_map(|msg| msg.message.as_deref())
+        {
+            if let Some(name) = greeting::greeted_name(message) {
+                *names.entry(name).or_default() += 1;
+            }
+        }
+        let mut top_names = names
+            .into_iter()
+            .map(|(name, greetings)| NameCount {
+                name: name.to_string(),
+                greetings,
+            })
+            .collect::<Vec<_>>();
+        top_names.sort_by(|a, b| b.greetings.cmp(&a.greetings).then(a.name.cmp(&b.name)));
+        top_names.truncate(top.try_into().unwrap_or_default());
+        let mut countries = HashMap::<&str, i64>::new();
+        for country in inner
+            .messages
+            .iter()
+            .filter_map(|msg| inner.countries.get(&msg.id))
+        {
+            *countries.entry(country).or_default() += 1;
+        }
+        let mut per_country = countries
+            .into_iter()
+            .map(|(country, greetings)| CountryCount {
+                country: country.to_string(),
+                greetings,
+            })
+            .collect::<Vec<_>>();
+        per_country.sort_by(|a, b| {
+            b.greetings
+                .cmp(&a.greetings)
+                .then(a.country.cmp(&b.country))
+        });
+        per_country.truncate(top.try_into().unwrap_or_default());
+        Ok(GreetingStats {
+            total: inner.messages.len() as i64,
+            per_day: Vec::new(),
+            top_names,
+            per_country,
+        })
+    }
+
+    async fn add_name_counts(&self, counts: &[NameCount]) -> Result<(), DbError> {
+        let mut inner = self.inner.lock().unwrap();
+        for count in counts {
+            *inner.name_counts.entry(count.name.clone()).or_default() += count.greetings;
+        }
+        Ok(())
+    }
+
+    async fn get_name_counts(&self) -> Result<Vec<NameCount>, DbError> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner
+            .name_counts
+            .iter()
+            .map(|(name, greetings)| NameCount {
+                name: name.clone(),
+                greetings: *greetings,
+            })
+            .collect())
+    }
+
+    async fn add_audit_entry(
+        &self,
+        tenant: &str,
+        action: &str,
+        subject: &str,
+        detail: &str,
+    ) -> Result<(), DbError> {
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.audit_log.len() as i64 + 1;
+        inner.audit_log.push(AuditEntry {
+            id,
+            tenant: tenant.to_string(),
+            action: action.to_string(),
+            subject: subject.to_string(),
+            detail: detail.to_string(),
+        });
+        Ok(())
+    }
+
+    async fn delete_name_count(&self, name: &str) -> Result<(), DbError> {
+        self.inner.lock().unwrap().name_counts.remove(name);
+        Ok(())
+    }
+
+    async fn get_audit_log(&self, limit: i64) -> Result<Vec<AuditEntry>, DbError> {
+        let inner = self.inner.lock().unwrap();
+        let skip = inner.audit_log.len().saturating_sub(limit as usize);
+        Ok(inner.audit_log[skip..].to_vec())
+    }
+
+    async fn start_session(&self, tenant: &str) -> Result<i64, DbError> {
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.sessions.len() as i64 + 1;
+        inner.sessions.push(Session {
+            id,
+            tenant: tenant.to_string(),
+            started_at: SystemTime::now(),
+            ended_at: None,
+            exchanges: Vec::new(),
+        });
+        Ok(id)
+    }
+
+    async fn append_exchanges(&self, exchanges: &[(i64, Exchange)]) -> Result<(), DbError> {
+        let mut inner = self.inner.lock().unwrap();
+        for (id, exchange) in exchanges {
+            if let Some(session) = inner.sessions.iter_mut().find(|s| s.id == *id) {
+                session.exchanges.push(exchange.clone());
+            }
+        }
+        Ok(())
+    }
+
+    async fn end_session(&self, id: i64) -> Result<(), DbError> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(session) = inner.sessions.iter_mut().find(|s| s.id == id) {
+            session.ended_at = Some(SystemTime::now());
+        }
+        Ok(())
+    }
+
+    async fn get_session(&self, id: i64) -> Result<Option<Session>, DbError> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.sessions.iter().find(|s| s.id == id).cloned())
+    }
+
+    #[cfg(feature = "pgvector")]
+    async fn set_embedding(&self, message_id: i32, embedding: &[f32]) -> Result<(), DbError> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.embeddings.insert(message_id, embedding.to_vec());
+        Ok(())
+    }
+
+    #[cfg(feature = "pgvector")]
+    async fn find_similar(&self, embedding: &[f32], limit: i64) -> Result<Vec<Similar>, DbError> {
+        let inner = self.inner.lock().unwrap();
+        let mut similar = inner
+            .messages
+            .iter()
+            .filter_map(|msg| {
+                let distance = embed::cosine_distance(inner.embeddings.get(&msg.id)?, embedding);
+                Some(Similar {
+                    message: msg.clone(),
+                    distance,
+                })
+            })
+            .collect::<Vec<_>>();
+        similar.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        similar.truncate(limit.try_into().unwrap_or(usize::MAX));
+        Ok(similar)
+    }
+}
+
+/// A call received by `MockGreeter`, with the request messages it carried.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Call {
+    SayHello(HelloRequest),
+    SayHelloStream(Vec<HelloRequest>),
+    SayHelloMany(SayHelloManyRequest),
+    SayHelloSummary(Vec<HelloRequest>),
+    ListMessages(ListMessagesRequest),
+    ListMessagesChunked(ListMessagesRequest),
+    FindSimilarMessages(FindSimilarMessagesRequest),
+    ListMessagesStream(ListMessagesRequest),
+    ExportMessages(ExportMessagesRequest),
+    ImportMessages(Vec<ImportMessagesRequest>),
+    UpdateMessage(UpdateMessageRequest),
+    StreamEvents(StreamEventsRequest),
+    GetUsage(GetUsageRequest),
+    GetStats(GetStatsRequest),
+    StreamLeaderboard(StreamLeaderboardRequest),
+    DeleteAllForName(DeleteAllForNameRequest),
+    GetSession(GetSessionRequest),
+    UploadPayload(Vec<PayloadChunk>),
+    DownloadPayload(DownloadPayloadRequest),
+    UploadAttachment(Vec<AttachmentChunk>),
+    GetAttachment(GetAttachmentRequest),
+}
+
+/// A `Greeter` answering from a `MockMessageStore`. Clones share the store,
+/// the recorded calls and the canned failures.
+#[derive(Clone, Default)]
+pub struct MockGreeter {
+    store: MockMessageStore,
+    broadcaster: Broadcaster,
+    calls: Arc<Mutex<Vec<Call>>>,
+    failures: Arc<Mutex<HashMap<&'static str, Status>>>,
+    /// Attachment objects by key, in place of an object store.
+    attachments: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+impl MockGreeter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_store(store: MockMessageStore) -> Self {
+        Self {
+            store,
+            ..Self::default()
+        }
+    }
+
+    /// Makes `method`, named as in the proto (`SayHello`), fail with
+    /// `status` from now on.
+    pub fn fail(self, method: &'static str, status: Status) -> Self {
+        self.failures.lock().unwrap().insert(method, status);
+        self
+    }
+
+    /// Lets `method` succeed again.
+    pub fn recover(&self, method: &str) {
+        self.failures.lock().unwrap().remove(method);
+    }
+
+    pub fn store(&self) -> &MockMessageStore {
+        &self.store
+    }
+
+    /// Calls received so far, in order.
+    pub fn calls(&self) -> Vec<Call> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// Records `call`, returns the status `method` should fail with if any.
+    fn record(&self, method: &'static str, call: Call) -> Option<Status> {
+        self.calls.lock().unwrap().push(call);
+        self.failures.lock().unwrap().get(method).cloned()
+    }
+
+    async fn greet(&self, request: &HelloRequest) -> Result<HelloReply, Status> {
+        let message = greeting::greet(request);
+        let tags = serde_json::to_value(&request.tags).unwrap_or_default();
+        let metadata = metadata::to_json(request.metadata.clone().unwrap_or_default());
+        let priority = request.priority() as i16;
+        let payload = (!request.payload.is_empty()).then_some(request.payload.as_slice());
+        let msg = self
+            .store
+            .insert_tagged_message(&message, &tags, &metadata.into(), priority, payload, None)
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?;
+        let reply = HelloReply {
+            message,
+            cursor: msg.id.into(),
+            metadata: Some(metadata::from_json(&msg.metadata)),
+            priority: msg.priority.into(),
+            ..Default::default()
+        };
+        self.broadcaster.broadcast(msg).await;
+        Ok(reply)
+    }
+
+    async fn listing(&self, request: &ListMessagesRequest) -> Result<ListMessagesReply, Status> {
+        let version = self.store.latest_event_seq().await.unwrap_or_default();
+        let version = version.to_string();
+        if request.known_version == version {
+            return Ok(ListMessagesReply {
+                version,
+                not_modified: true,
+                ..Default::default()
+            });
+        }
+        let tags = serde_json::to_value(&request.tags).unwrap_or_default();
+        let metadata = metadata::to_json(request.metadata.clone().unwrap_or_default());
+        let messages = self
+            .store
+            .get_messages_tagged(&tags, &metadata.into())
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?;
+        Ok(ListMessagesReply {
+            priorities: messages.iter().map(|msg| msg.priority.into()).collect(),
+            messages: messages
+                .into_iter()
+                .map(|msg| msg.message.unwrap_or_default())
+                .collect(),
+            version,
+            not_modified: false,
+        })
+    }
+}
+
+async fn collect<T>(mut stream: Streaming<T>) -> Result<Vec<T>, Status> {
+    let mut requests = Vec::new();
+    while let Some(request) = stream.message().await? {
+        requests.push(request);
+    }
+    Ok(requests)
+}
+
+fn replies<T: Send + 'static>(replies: Vec<T>) -> MockStream<T> {
+    Box::pin(tokio_stream::iter(replies.into_iter().map(Ok)))
+}
+
+#[tonic::async_trait]
+impl Greeter for MockGreeter {
+    async fn say_hello(&self, request: Request<HelloRequest>) -> MockResult<HelloReply> {
+        let request = request.into_inner();
+        if let Some(status) = self.record("SayHello", Call::SayHello(request.clone())) {
+            return Err(status);
+        }
+        Ok(Response::new(self.greet(&request).await?))
+    }
+
+    type SayHelloStreamStream = MockStream<HelloReply>;
+
+    /// Reads the whole request stream before replying, so the recorded call
+    /// is complete.
+    async fn say_hello_stream(
+        &self,
+        request: Request<Streaming<HelloRequest>>,
+    ) -> MockResult<Self::SayHelloStreamStream> {
+        let requests = collect(request.into_inner()).await?;
+        if let Some(status) = self.record("SayHelloStream", Call::SayHelloStream(requests.clone()))
+        {
+            return Err(status);
+        }
+        let mut out = Vec::with_capacity(requests.len());
+        for (seq, request) in (1..).zip(&requests) {
+            out.push(HelloReply {
+                seq,
+                ..self.greet(request).await?
+            });
+        }
+        Ok(Response::new(replies(out)))
+    }
+
+    type SayHelloManyStream = MockStream<HelloReply>;
+
+    async fn say_hello_many(
+        &self,
+        request: Request<SayHelloManyRequest>,
+    ) -> MockResult<Self::SayHelloManyStream> {
+        let request = request.into_inner();
+        if let Some(status) = self.record("SayHelloMany", Call::SayHelloMany(request.clone())) {
+            return Err(status);
+        }
+        let count = request.count.max(1) as usize;
+        let out = (1..)
+            .zip(
+                request
+                    .names
+                    .iter()
+                    .cycle()
+                    .take(request.names.len() * count),
+            )
+            .map(|(seq, name)| HelloReply {
+                message: format!("Hello {}!", name),
+                seq,
+                ..Default::default()
+            })
+            .collect();
+        Ok(Response::new(replies(out)))
+    }
+
+    async fn say_hello_summary(
+        &self,
+        request: Request<Streaming<HelloRequest>>,
+    ) -> MockResult<HelloSummaryReply> {
+        let requests = collect(request.into_inner()).await?;
+        if let Some(status) =
+            self.record("SayHelloSummary", Call::SayHelloSummary(requests.clone()))
+        {
+            return Err(status);
+        }
+        let mut summary = HelloSummaryReply::default();
+        for request in &requests {
+            self.greet(request).await?;
+            summary.count += 1;
+            summary.inserted += 1;
+            if summary.distinct_names.contains(&request.name) {
+                continue;
+            }
+            if summary.distinct_names.len() < SUMMARY_MAX_DISTINCT_NAMES {
+                summary.distinct_names.push(request.name.clone());
+            } else {
+                summary.distinct_names_truncated = true;
+            }
+        }
+        Ok(Response::new(summary))
+    }
+
+    async fn list_messages(
+        &self,
+        request: Request<ListMessagesRequest>,
+    ) -> MockResult<ListMessagesReply> {
+        let request = request.into_inner();
+        if let Some(status) = self.record("ListMessages", Call::ListMessages(request.clone())) {
+            return Err(status);
+        }
+        Ok(Response::new(self.listing(&request).await?))
+    }
+
+    type ListMessagesChunkedStream = MockStream<ListMessagesReply>;
+
+    /// Lists the stored messages in a single chunk.
+    async fn list_messages_chunked(
+        &self,
+        request: Request<ListMessagesRequest>,
+    ) -> MockResult<Self::ListMessagesChunkedStream> {
+        let request = request.into_inner();
+        if let Some(status) = self.record(
+            "ListMessagesChunked",
+            Call::ListMessagesChunked(request.clone()),
+        ) {
+            return Err(status);
+        }
+        Ok(Response::new(replies(vec![self.listing(&request).await?])))
+    }
+
+    type ListMessagesStreamStream = MockStream<Frame>;
+
+    /// Replays the stored messages after a resume token, then follows the
+    /// greetings made through this mock. Heartbeats are never sent and acks
+    /// are ignored, the first request is recorded.
+    async fn list_messages_stream(
+        &self,
+        request: Request<Streaming<ListMessagesRequest>>,
+    ) -> MockResult<Self::ListMessagesStreamStream> {
+        let request = request.into_inner().message().await?.unwrap_or_default();
+        if let Some(status) = self.record(
+            "ListMessagesStream",
+            Call::ListMessagesStream(request.clone()),
+        ) {
+            return Err(status);
+        }
+        let resume_token = i32::try_from(request.resume_token)
+            .map_err(|_| Status::invalid_argument("resume_token is out of range"))?;
+        let mut live = self.broadcaster.subscribe("ListMessagesStream");
+        let backfill = match resume_token {
+            0 => Vec::new(),
+            id => self
+                .store
+                .get_messages_after(id)
+                .await
+                .map_err(|err| Status::internal(err.to_string()))?,
+        };
+
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(async move {
+            let mut last_id = resume_token;
+            for msg in backfill {
+                last_id = msg.id;
+                if tx.send(Ok(HelloReply::from(msg).into())).await.is_err() {
+                    return;
+                }
+            }
+            while let Ok(msg) = live.recv().await {
+                if msg.id > last_id && tx.send(Ok(msg.reply().into())).await.is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+
+    type ExportMessagesStream = MockStream<ExportChunk>;
+
+    /// Exports every message in a single chunk, after the header if any.
+    async fn export_messages(
+        &self,
+        request: Request<ExportMessagesRequest>,
+    ) -> MockResult<Self::ExportMessagesStream> {
+        let request = request.into_inner();
+        if let Some(status) = self.record("ExportMessages", Call::ExportMessages(request.clone())) {
+            return Err(status);
+        }
+        let format = request.format();
+        let messages = self
+            .store
+            .get_messages()
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?;
+
+        let mut data = export::header(format).unwrap_or_default().to_string();
+        for msg in &messages {
+            export::write_row(format, msg, &mut data);
+        }
+        Ok(Response::new(replies(vec![ExportChunk {
+            data: data.into(),
+        }])))
+    }
+
+    async fn import_messages(
+        &self,
+        request: Request<Streaming<ImportMessagesRequest>>,
+    ) -> MockResult<ImportMessagesReply> {
+        let requests = collect(request.into_inner()).await?;
+        if let Some(status) = self.record("ImportMessages", Call::ImportMessages(requests.clone()))
+        {
+            return Err(status);
+        }
+        let mut reply = ImportMessagesReply::default();
+        for request in requests {
+            let inserted = self
+                .store
+                .insert_messages(&request.messages)
+                .await
+                .map_err(|err| Status::internal(err.to_string()))?;
+            reply.total_inserted += inserted.len() as u64;
+            reply.batches.push(ImportBatchResult {
+                batch: reply.batches.len() as u32 + 1,
+                inserted: inserted.len() as u32,
+                ..Default::default()
+            });
+        }
+        Ok(Response::new(reply))
+    }
+
+    async fn update_message(
+        &self,
+        request: Request<UpdateMessageRequest>,
+    ) -> MockResult<HelloReply> {
+        let request = request.into_inner();
+        if let Some(status) = self.record("UpdateMessage", Call::UpdateMessage(request.clone())) {
+            return Err(status);
+        }
+        let updated = self
+            .store
+            .update_message(request.id, &request.message)
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?;
+        match updated {
+            Some(msg) => Ok(Response::new(HelloReply {
+                message: msg.message.unwrap_or_default(),
+                cursor: msg.id.into(),
+                metadata: Some(metadata::from_json(&msg.metadata)),
+                priority: msg.priority.into(),
+                ..Default::default()
+            })),
+            None => Err(Status::not_found(format!("no message {}", request.id))),
+        }
+    }
+
+    type StreamEventsStream = MockStream<GreetingEvent>;
+
+    /// Replays the recorded events, `follow` is ignored.
+    async fn stream_events(
+        &self,
+        request: Request<StreamEventsRequest>,
+    ) -> MockResult<Self::StreamEventsStream> {
+        let request = request.into_inner();
+        if let Some(status) = self.record("StreamEvents", Call::StreamEvents(request.clone())) {
+            return Err(status);
+        }
+        let events = self
+            .store
+            .get_events_page(request.after_seq, i64::MAX)
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?;
+        Ok(Response::new(replies(
+            events.into_iter().map(GreetingEvent::from).collect(),
+        )))
+    }
+
+    /// Reports the usage held by the store, the mock enforces no quotas.
+    async fn get_usage(&self, request: Request<GetUsageRequest>) -> MockResult<UsageReply> {
+        let tenant = tenant::from_request(&request);
+        if let Some(status) = self.record("GetUsage", Call::GetUsage(request.into_inner())) {
+            return Err(status);
+        }
+        let usage = self
+            .store
+            .get_usage(&tenant)
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?;
+        Ok(Response::new(UsageReply {
+            tenant,
+            messages: usage.messages,
+            bytes: usage.bytes,
+            ..Default::default()
+        }))
+    }
+
+    /// Counts the stored messages per name, the mock neither dates them nor
+    /// tracks open streams.
+    async fn get_stats(&self, request: Request<GetStatsRequest>) -> MockResult<StatsReply> {
+        let request = request.into_inner();
+        if let Some(status) = self.record("GetStats", Call::GetStats(request.clone())) {
+            return Err(status);
+        }
+        let top = match request.top {
+            0 => 10,
+            top => top,
+        };
+        let stats = self
+            .store
+            .get_stats(request.days as i32, top.into())
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?;
+        Ok(Response::new(StatsReply {
+            total_greetings: stats.total,
+            top_names: stats.top_names.into_iter().map(NameStats::from).collect(),
+            per_country: stats
+                .per_country
+                .into_iter()
+                .map(CountryStats::from)
+                .collect(),
+            ..Default::default()
+        }))
+    }
+
+    type StreamLeaderboardStream = MockStream<LeaderboardReply>;
+
+    /// Ranks the stored messages once, the stream ends there.
+    async fn stream_leaderboard(
+        &self,
+        request: Request<StreamLeaderboardRequest>,
+    ) -> MockResult<Self::StreamLeaderboardStream> {
+        let request = request.into_inner();
+        if let Some(status) = self.record(
+            "StreamLeaderboard",
+            Call::StreamLeaderboard(request.clone()),
+        ) {
+            return Err(status);
+        }
+        let top = match request.top {
+            0 => 10,
+            top => top,
+        };
+        let stats = self
+            .store
+            .get_stats(0, top.into())
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?;
+        Ok(Response::new(replies(vec![LeaderboardReply {
+            names: stats.top_names.into_iter().map(NameStats::from).collect(),
+        }])))
+    }
+
+    /// Ranks the stored messages by the `HashingEmbedder` the server uses by
+    /// default, computing their embeddings on the spot.
+    async fn find_similar_messages(
+        &self,
+        request: Request<FindSimilarMessagesRequest>,
+    ) -> MockResult<FindSimilarMessagesReply> {
+        let request = request.into_inner();
+        if let Some(status) = self.record(
+            "FindSimilarMessages",
+            Call::FindSimilarMessages(request.clone()),
+        ) {
+            return Err(status);
+        }
+        #[cfg(feature = "pgvector")]
+        {
+            use embed::Embedder;
+
+            let embedder = embed::HashingEmbedder::new(Config::default().embedding_dimensions);
+            let wanted = embedder
+                .embed(&request.text)
+                .await
+                .map_err(|err| Status::internal(err.to_string()))?;
+            let mut messages = Vec::new();
+            for msg in self.store.get_messages().await.unwrap_or_default() {
+                let message = msg.message.unwrap_or_default();
+                let embedding = embedder
+                    .embed(&message)
+                    .await
+                    .map_err(|err| Status::internal(err.to_string()))?;
+                messages.push(SimilarMessage {
+                    cursor: msg.id.into(),
+                    message,
+                    distance: embed::cosine_distance(&embedding, &wanted),
+                });
+            }
+            messages.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+            if request.limit > 0 {
+                messages.truncate(request.limit as usize);
+            }
+            Ok(Response::new(FindSimilarMessagesReply { messages }))
+        }
+        #[cfg(not(feature = "pgvector"))]
+        Err(Status::unimplemented(
+            "similarity search needs the pgvector feature",
+        ))
+    }
+
+    type DeleteAllForNameStream = MockStream<DeleteAllForNameProgress>;
+
+    /// Deletes in one go, replying with the final progress only.
+    async fn delete_all_for_name(
+        &self,
+        request: Request<DeleteAllForNameRequest>,
+    ) -> MockResult<Self::DeleteAllForNameStream> {
+        let tenant = tenant::from_request(&request);
+        let request = request.into_inner();
+        if let Some(status) =
+            self.record("DeleteAllForName", Call::DeleteAllForName(request.clone()))
+        {
+            return Err(status);
+        }
+        let internal = |err: DbError| Status::internal(err.to_string());
+        let messages = self.store.get_messages().await.map_err(internal)?;
+        let ids = messages
+            .iter()
+            .filter(|msg| {
+                msg.message.as_deref().and_then(greeting::greeted_name) == Some(&request.name)
+            })
+            .map(|msg| msg.id)
+            .collect::<Vec<_>>();
+        let deleted = self.store.erase_messages(&ids).await.map_err(internal)?;
+        let subject = format!("sha256:{:x}", Sha256::digest(request.name.as_bytes()));
+        self.store
+            .delete_name_count(&request.name)
+            .await
+            .map_err(internal)?;
+        self.store
+            .add_audit_entry(&tenant, "delete_name", &subject, "")
+            .await
+            .map_err(internal)?;
+        Ok(Response::new(replies(vec![DeleteAllForNameProgress {
+            scanned: messages.len() as u64,
+            deleted: deleted as u64,
+            done: true,
+        }])))
+    }
+
+    async fn get_session(&self, request: Request<GetSessionRequest>) -> MockResult<SessionReply> {
+        let tenant = tenant::from_request(&request);
+        let request = request.into_inner();
+        if let Some(status) = self.record("GetSession", Call::GetSession(request.clone())) {
+            return Err(status);
+        }
+        let session = self
+            .store
+            .get_session(request.id)
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?
+            .filter(|session| session.tenant == tenant)
+            .ok_or_else(|| Status::not_found(format!("no session {}", request.id)))?;
+        Ok(Response::new(session.into()))
+    }
+
+    /// Stores the payload with no size limit.
+    async fn upload_payload(
+        &self,
+        request: Request<Streaming<PayloadChunk>>,
+    ) -> MockResult<UploadPayloadReply> {
+        let chunks = collect(request.into_inner()).await?;
+        if let Some(status) = self.record("UploadPayload", Call::UploadPayload(chunks.clone())) {
+            return Err(status);
+        }
+        let id = chunks.first().map_or(0, |chunk| chunk.id);
+        let payload = chunks
+            .iter()
+            .flat_map(|chunk| chunk.data.iter().copied())
+            .collect::<Vec<_>>();
+        let stored = self
+            .store
+            .set_payload(id.try_into().unwrap_or_default(), &payload)
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?;
+        if !stored {
+            return Err(Status::not_found(format!("no message {}", id)));
+        }
+        Ok(Response::new(UploadPayloadReply {
+            size: payload.len() as u64,
+        }))
+    }
+
+    type DownloadPayloadStream = MockStream<PayloadChunk>;
+
+    /// Downloads the payload in a single chunk.
+    async fn download_payload(
+        &self,
+        request: Request<DownloadPayloadRequest>,
+    ) -> MockResult<Self::DownloadPayloadStream> {
+        let request = request.into_inner();
+        if let Some(status) = self.record("DownloadPayload", Call::DownloadPayload(request.clone()))
+        {
+            return Err(status);
+        }
+        let payload = self
+            .store
+            .get_payload(request.id.try_into().unwrap_or_default())
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?
+            .ok_or_else(|| Status::not_found(format!("no payload for message {}", request.id)))?;
+        Ok(Response::new(replies(vec![PayloadChunk {
+            id: request.id,
+            data: payload,
+        }])))
+    }
+
+    /// Keeps the attachment in memory, with no size limit.
+    async fn upload_attachment(
+        &self,
+        request: Request<Streaming<AttachmentChunk>>,
+    ) -> MockResult<UploadAttachmentReply> {
+        let chunks = collect(request.into_inner()).await?;
+        if let Some(status) =
+            self.record("UploadAttachment", Call::UploadAttachment(chunks.clone()))
+        {
+            return Err(status);
+        }
+        let id = chunks.first().map_or(0, |chunk| chunk.id);
+        let data = chunks
+            .iter()
+            .flat_map(|chunk| chunk.data.iter().copied())
+            .collect::<Vec<_>>();
+        let key = format!("messages/{}", id);
+        let stored = self
+            .store
+            .set_attachment_key(id.try_into().unwrap_or_default(), &key)
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?;
+        if !stored {
+            return Err(Status::not_found(format!("no message {}", id)));
+        }
+        let size = data.len() as u64;
+        self.attachments.lock().unwrap().insert(key, data);
+        Ok(Response::new(UploadAttachmentReply { size }))
+    }
+
+    type GetAttachmentStream = MockStream<GetAttachmentReply>;
+
+    /// Replies with the attachment in a single chunk, or with a `mock://`
+    /// URL when asked for a presigned one.
+    async fn get_attachment(
+        &self,
+        request: Request<GetAttachmentRequest>,
+    ) -> MockResult<Self::GetAttachmentStream> {
+        let request = request.into_inner();
+        if let Some(status) = self.record("GetAttachment", Call::GetAttachment(request.clone())) {
+            return Err(status);
+        }
+        let not_found = || Status::not_found(format!("no attachment for message {}", request.id));
+        let key = self
+            .store
+            .get_attachment_key(request.id.try_into().unwrap_or_default())
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?
+            .ok_or_else(not_found)?;
+        let reply = if request.presigned {
+            GetAttachmentReply {
+                url: format!("mock://attachments/{}", key),
+                ..Default::default()
+            }
+        } else {
+            let data = self.attachments.lock().unwrap().get(&key).cloned();
+            GetAttachmentReply {
+                data: data.ok_or_else(not_found)?,
+                ..Default::default()
+            }
+        };
+        Ok(Response::new(replies(vec![reply])))
+    }
+}
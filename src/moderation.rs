@@ -0,0 +1,329 @@
+//! Moderation of the names greeted, before they are stored and broadcast.
+//!
+//! A name goes through the stages of a `Moderation` in turn, each lets it
+//! pass, masks parts of it for the stages after it or rejects it. The
+//! built-in stages are set up from the `[moderation]` table of the config
+//! file, more are plugged in with `ServerBuilder::with_moderator`.
+
+use std::{error::Error, sync::Arc};
+
+use regex::Regex;
+use thiserror::Error;
+
+use crate::config::{DenyAction, ModerationConfig};
+
+#[derive(Error, Debug)]
+pub enum ModerationError {
+    #[error("Invalid deny pattern: {0}")]
+    Pattern(#[from] regex::Error),
+    #[error("Moderation api_url needs the `moderation-api` feature")]
+    ApiDisabled,
+    #[cfg(feature = "moderation-api")]
+    #[error("Moderation API client error: {0}")]
+    Api(#[from] reqwest::Error),
+}
+
+/// What a stage makes of a name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// Greet `masked` instead.
+    Mask {
+        masked: String,
+        reason: String,
+    },
+    Reject {
+        reason: String,
+    },
+}
+
+/// A moderation stage.
+#[tonic::async_trait]
+pub trait Moderator: Send + Sync + 'static {
+    async fn moderate(&self, name: &str) -> Result<Verdict, Box<dyn Error + Send + Sync>>;
+}
+
+/// Rejects names too long or repeating a character too often, 0 disables
+/// either limit.
+#[derive(Clone, Copy, Debug)]
+pub struct LengthLimits {
+    pub max_chars: usize,
+    pub max_repeated: usize,
+}
+
+#[tonic::async_trait]
+impl Moderator for LengthLimits {
+    async fn moderate(&self, name: &str) -> Result<Verdict, Box<dyn Error + Send + Sync>> {
+        if self.max_chars > 0 && name.chars().count() > self.max_chars {
+            return Ok(Verdict::Reject {
+                reason: format!("longer than {} characters", self.max_chars),
+            });
+        }
+        if self.max_repeated > 0 && longest_run(name) > self.max_repeated {
+            return Ok(Verdict::Reject {
+                reason: format!("repeats a character more than {} times", self.max_repeated),
+            });
+        }
+        Ok(Verdict::Allow)
+    }
+}
+
+/// Length of the longest run of one character in `text`.
+fn longest_run(text: &str) -> usize {
+    let mut longest = 0;
+    let mut run = 0;
+    let mut previous = None;
+    for c in text.chars() {
+        run = if previous == Some(c) { run + 1 } else { 1 };
+        longest = longest.max(run);
+        previous = Some(c);
+    }
+    longest
+}
+
+/// Rejects or masks names matching any of its patterns.
+#[derive(Clone, Debug)]
+pub struct DenyList {
+    patterns: Vec<Regex>,
+    action: DenyAction,
+}
+
+impl DenyList {
+    pub fn new(patterns: &[String], action: DenyAction) -> Result<Self, regex::Error> {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| Regex::new(pattern))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { patterns, action })
+    }
+}
+
+#[tonic::async_trait]
+impl Moderator for DenyList {
+    async fn moderate(&self, name: &str) -> Result<Verdict, Box<dyn Error + Send + Sync>> {
+        let mut masked = name.to_string();
+        let mut matched = Vec::new();
+        for pattern in &self.patterns {
+            if !pattern.is_match(&masked) {
+                continue;
+            }
+            if self.action == DenyAction::Reject {
+                return Ok(Verdict::Reject {
+                    reason: format!("matches {:?}", pattern.as_str()),
+                });
+            }
+            masked = pattern
+                .replace_all(&masked, |caps: &regex::Captures| {
+                    "*".repeat(caps[0].chars().count())
+                })
+                .into_owned();
+            matched.push(format!("{:?}", pattern.as_str()));
+        }
+        if matched.is_empty() {
+            return Ok(Verdict::Allow);
+        }
+        Ok(Verdict::Mask {
+            masked,
+            reason: format!("matches {}", matched.join(", ")),
+        })
+    }
+}
+
+/// Posts `{"name": ...}` to an endpoint answering with the verdict, e.g.
+/// `{"verdict": "mask", "masked": "J**n", "reason": "profanity"}`.
+#[cfg(feature = "moderation-api")]
+#[derive(Clone, Debug)]
+pub struct ApiModerator {
+    client: reqwest::Client,
+    url: String,
+}
+
+#[cfg(feature = "moderation-api")]
+impl ApiModerator {
+    pub fn new(url: &str, timeout: std::time::Duration) -> Result<Self, reqwest::Error> {
+        Ok(Self {
+            client: reqwest::Client::builder().timeout(timeout).build()?,
+            url: url.to_string(),
+        })
+    }
+}
+
+#[cfg(feature = "moderation-api")]
+#[derive(serde::Deserialize)]
+#[serde(tag = "verdict", rename_all = "snake_case")]
+enum ApiVerdict {
+    Allow,
+    Mask {
+        masked: String,
+        #[serde(default)]
+        reason: String,
+    },
+    Reject {
+        #[serde(default)]
+        reason: String,
+    },
+}
+
+#[cfg(feature = "moderation-api")]
+#[tonic::async_trait]
+impl Moderator for ApiModerator {
+    async fn moderate(&self, name: &str) -> Result<Verdict, Box<dyn Error + Send + Sync>> {
+        let verdict = self
+            .client
+            .post(&self.url)
+            .json(&serde_json::json!({ "name": name }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<ApiVerdict>()
+            .await?;
+        Ok(match verdict {
+            ApiVerdict::Allow => Verdict::Allow,
+            ApiVerdict::Mask { masked, reason } => Verdict::Mask { masked, reason },
+            ApiVerdict::Reject { reason } => Verdict::Reject { reason },
+        })
+    }
+}
+
+/// The stages names go through, none by default. Clones share the stages.
+#[derive(Clone, Default)]
+pub struct Moderation {
+    stages: Vec<Arc<dyn Moderator>>,
+}
+
+impl Moderation {
+    /// The built-in stages `config` turns on.
+    pub fn from_config(config: &ModerationConfig) -> Result<Self, ModerationError> {
+        let mut moderation = Self::default();
+        if config.max_name_chars > 0 || config.max_repeated_chars > 0 {
+            moderation = moderation.with(Arc::new(LengthLimits {
+                max_chars: config.max_name_chars,
+                max_repeated: config.max_repeated_chars,
+            }));
+        }
+        if !config.deny.is_empty() {
+            moderation =
+                moderation.with(Arc::new(DenyList::new(&config.deny, config.deny_action)?));
+        }
+        if let Some(url) = &config.api_url {
+            #[cfg(feature = "moderation-api")]
+            {
+                let timeout = std::time::Duration::from_millis(config.api_timeout_ms);
+                moderation = moderation.with(Arc::new(ApiModerator::new(url, timeout)?));
+            }
+            #[cfg(not(feature = "moderation-api"))]
+            {
+                let _ = url;
+                return Err(ModerationError::ApiDisabled);
+            }
+        }
+        Ok(moderation)
+    }
+
+    /// Runs `stage` after the others.
+    pub fn with(mut self, stage: Arc<dyn Moderator>) -> Self {
+        self.stages.push(stage);
+        self
+    }
+
+    /// Runs `name` through the stages, the first rejection ends it. A stage
+    /// failing, e.g. an unreachable API, is logged and lets the name pass,
+    /// so moderation never takes greeting down with it.
+    pub async fn moderate(&self, name: &str) -> Verdict {
+        let mut masked = None::<String>;
+        let mut reasons = Vec::new();
+        for stage in &self.stages {
+            let current = masked.as_deref().unwrap_or(name);
+            match stage.moderate(current).await {
+                Ok(Verdict::Allow) => (),
+                Ok(Verdict::Mask {
+                    masked: now,
+                    reason,
+                }) => {
+                    masked = Some(now);
+                    reasons.push(reason);
+                }
+                Ok(reject @ Verdict::Reject { .. }) => return reject,
+                Err(err) => eprintln!(
+                    "moderation of {:?} failed: {}",
+                    crate::redact::text(current),
+                    err
+                ),
+            }
+        }
+        match masked {
+            Some(masked) => Verdict::Mask {
+                masked,
+                reason: reasons.join("; "),
+            },
+            None => Verdict::Allow,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Config,
+        mock::MockMessageStore,
+        service::{Greeting, GreetingService, ServiceError},
+        store::MessageStore,
+    };
+
+    struct Unreachable;
+
+    #[tonic::async_trait]
+    impl Moderator for Unreachable {
+        async fn moderate(&self, _name: &str) -> Result<Verdict, Box<dyn Error + Send + Sync>> {
+            Err("connection refused".into())
+        }
+    }
+
+    struct RejectStars;
+
+    #[tonic::async_trait]
+    impl Moderator for RejectStars {
+        async fn moderate(&self, name: &str) -> Result<Verdict, Box<dyn Error + Send + Sync>> {
+            Ok(match name.matches('*').count() {
+                0..=2 => Verdict::Allow,
+                _ => Verdict::Reject {
+                    reason: "mostly masked".to_string(),
+                },
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn moderation_stages_run_in_turn() {
+        let deny = DenyList::new(&["(?i)heck".to_string()], DenyAction::Mask).unwrap();
+        let moderation = Moderation::default()
+            .with(Arc::new(Unreachable))
+            .with(Arc::new(deny))
+            .with(Arc::new(RejectStars));
+        let store = MockMessageStore::new();
+        let service =
+            GreetingService::new(store.clone(), &Config::default()).with_moderation(moderation);
+        let greeting = |name: &str| Greeting {
+            name: name.to_string(),
+            ..Default::default()
+        };
+
+        // a failing stage lets names through
+        let message = service.greet("acme", &greeting("Ann")).await.unwrap();
+        assert_eq!(message.message.as_deref(), Some("Hello Ann!"));
+        // later stages see the masked name
+        assert!(matches!(
+            service.greet("acme", &greeting("Heckler")).await,
+            Err(ServiceError::Rejected(reason)) if reason == "mostly masked"
+        ));
+
+        let audited = store.get_audit_log(10).await.unwrap();
+        assert_eq!(audited.len(), 1);
+        assert_eq!(
+            (audited[0].action.as_str(), audited[0].subject.as_str()),
+            ("reject_name", "Heckler")
+        );
+        assert_eq!(store.get_messages().await.unwrap().len(), 1);
+    }
+}
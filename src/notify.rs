@@ -0,0 +1,157 @@
+use std::time::{Duration, Instant};
+
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Tokio1Executor,
+};
+use regex::Regex;
+use serde_json::json;
+use thiserror::Error;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::config::{NotificationSink, NotificationTarget};
+use crate::messages::{Broadcaster, Subscription};
+
+#[derive(Error, Debug)]
+pub enum NotifyError {
+    #[error("Invalid filter: {0}")]
+    Filter(#[from] regex::Error),
+    #[error("Invalid address: {0}")]
+    Address(#[from] lettre::address::AddressError),
+    #[error("Email error: {0}")]
+    Email(#[from] lettre::error::Error),
+    #[error("SMTP error: {0}")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+}
+
+type NotifyResult<T> = Result<T, NotifyError>;
+
+/// Subscribes every configured sink to the broadcaster, each on its own task
+/// so a slow sink only lags itself.
+pub fn spawn_sinks(sinks: &[NotificationSink], broadcaster: &Broadcaster) -> NotifyResult<()> {
+    for config in sinks {
+        let sink = Sink::new(config)?;
+        tokio::spawn(sink.run(broadcaster.subscribe("notifications")));
+    }
+    Ok(())
+}
+
+enum Target {
+    Slack {
+        client: reqwest::Client,
+        webhook_url: String,
+    },
+    Smtp {
+        transport: Box<AsyncSmtpTransport<Tokio1Executor>>,
+        from: Mailbox,
+        to: Mailbox,
+    },
+}
+
+impl Target {
+    fn new(config: &NotificationTarget) -> NotifyResult<Self> {
+        Ok(match config {
+            NotificationTarget::Slack { webhook_url } => Self::Slack {
+                client: reqwest::Client::new(),
+                webhook_url: webhook_url.clone(),
+            },
+            NotificationTarget::Smtp {
+                relay,
+                from,
+                to,
+                username,
+                password,
+            } => {
+                let mut transport = AsyncSmtpTransport::<Tokio1Executor>::relay(relay)?;
+                if let (Some(username), Some(password)) = (username, password) {
+                    transport =
+                        transport.credentials(Credentials::new(username.clone(), password.clone()));
+                }
+                Self::Smtp {
+                    transport: Box::new(transport.build()),
+                    from: from.parse()?,
+                    to: to.parse()?,
+                }
+            }
+        })
+    }
+
+    async fn send(&self, text: &str) -> NotifyResult<()> {
+        match self {
+            Self::Slack {
+                client,
+                webhook_url,
+            } => {
+                client
+                    .post(webhook_url)
+                    .json(&json!({ "text": text }))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+            Self::Smtp {
+                transport,
+                from,
+                to,
+            } => {
+                let email = lettre::Message::builder()
+                    .from(from.clone())
+                    .to(to.clone())
+                    .subject("New greeting")
+                    .body(text.to_string())?;
+                transport.send(email).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Sink {
+    target: Target,
+    filter: Option<Regex>,
+    min_interval: Duration,
+    last_sent: Option<Instant>,
+}
+
+impl Sink {
+    fn new(config: &NotificationSink) -> NotifyResult<Self> {
+        Ok(Self {
+            target: Target::new(&config.target)?,
+            filter: config.filter.as_deref().map(Regex::new).transpose()?,
+            min_interval: Duration::from_secs(config.min_interval_secs),
+            last_sent: None,
+        })
+    }
+
+    async fn run(mut self, mut rx: Subscription) {
+        loop {
+            match rx.recv().await {
+                Ok(msg) => self.notify(msg.message.clone().unwrap_or_default()).await,
+                Err(RecvError::Lagged(skipped)) => {
+                    eprintln!("notification sink lagged, skipped {} messages", skipped);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+
+    async fn notify(&mut self, text: String) {
+        if let Some(filter) = &self.filter {
+            if !filter.is_match(&text) {
+                return;
+            }
+        }
+        if let Some(last_sent) = self.last_sent {
+            if last_sent.elapsed() < self.min_interval {
+                return;
+            }
+        }
+
+        self.last_sent = Some(Instant::now());
+        if let Err(err) = self.target.send(&text).await {
+            eprintln!("failed to send notification: {}", err);
+        }
+    }
+}
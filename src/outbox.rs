@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use rdkafka::{
+    config::ClientConfig,
+    error::KafkaError,
+    producer::{FutureProducer, FutureRecord},
+    util::Timeout,
+};
+use thiserror::Error;
+
+use crate::db::{Db, DbError};
+
+/// Outbox events published per relay round.
+const RELAY_BATCH_SIZE: i64 = 100;
+
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Error, Debug)]
+pub enum RelayError {
+    #[error("Database error: {0}")]
+    Db(#[from] DbError),
+    #[error("Kafka error: {0}")]
+    Kafka(#[from] KafkaError),
+}
+
+pub fn producer(brokers: &str) -> Result<FutureProducer, KafkaError> {
+    ClientConfig::new()
+        .set("bootstrap.servers", brokers)
+        .set("acks", "all")
+        .set("enable.idempotence", "true")
+        .create()
+}
+
+/// Publishes outbox events to Kafka until the task is dropped. Events are
+/// only marked published once the broker acked them, so a crash in between
+/// publishes them again: delivery is at-least-once, keyed by outbox id so
+/// consumers can deduplicate.
+pub async fn relay(db: Db, producer: FutureProducer, poll_interval: Duration) {
+    loop {
+        match relay_batch(&db, &producer).await {
+            // more events may be pending, keep going
+            Ok(published) if published as i64 == RELAY_BATCH_SIZE => continue,
+            Ok(_) => (),
+            Err(err) => eprintln!("outbox relay failed: {}", err),
+        }
+        tokio::time::sleep(poll_interval).await;
+    }
+}
+
+async fn relay_batch(db: &Db, producer: &FutureProducer) -> Result<usize, RelayError> {
+    let events = db.get_pending_outbox(RELAY_BATCH_SIZE).await?;
+    let mut published = Vec::with_capacity(events.len());
+
+    for event in &events {
+        let key = event.id.to_string();
+        let record = FutureRecord::to(&event.topic)
+            .key(&key)
+            .payload(&event.payload);
+        if let Err((err, _)) = producer.send(record, Timeout::After(SEND_TIMEOUT)).await {
+            // keep the progress made so far, the rest is retried next round
+            db.mark_outbox_published(&published).await?;
+            return Err(err.into());
+        }
+        published.push(event.id);
+    }
+
+    db.mark_outbox_published(&published).await?;
+    Ok(published.len())
+}
@@ -0,0 +1,102 @@
+use std::{
+    any::Any,
+    future::Future,
+    panic::{catch_unwind, AssertUnwindSafe},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tonic::{body::BoxBody, Status};
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// Turns a panicking handler into an `INTERNAL` status instead of tearing
+/// down the whole HTTP/2 connection along with every other call on it.
+#[derive(Clone, Copy, Default)]
+pub struct CatchPanicLayer;
+
+impl<S> Layer<S> for CatchPanicLayer {
+    type Service = CatchPanic<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CatchPanic { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct CatchPanic<S> {
+    inner: S,
+}
+
+impl<S, B> Service<http::Request<B>> for CatchPanic<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<BoxBody>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = CatchPanicFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let path = req.uri().path().to_string();
+        match catch_unwind(AssertUnwindSafe(|| self.inner.call(req))) {
+            Ok(inner) => CatchPanicFuture {
+                inner: Some(Box::pin(inner)),
+                path,
+            },
+            Err(payload) => {
+                log(&path, payload.as_ref());
+                CatchPanicFuture { inner: None, path }
+            }
+        }
+    }
+}
+
+pub struct CatchPanicFuture<F> {
+    /// `None` once the call panicked.
+    inner: Option<Pin<Box<F>>>,
+    path: String,
+}
+
+impl<F, E> Future for CatchPanicFuture<F>
+where
+    F: Future<Output = Result<http::Response<BoxBody>, E>>,
+{
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let Some(inner) = self.inner.as_mut() else {
+            return Poll::Ready(Ok(internal()));
+        };
+        match catch_unwind(AssertUnwindSafe(|| inner.as_mut().poll(cx))) {
+            Ok(poll) => poll,
+            Err(payload) => {
+                log(&self.path, payload.as_ref());
+                self.inner = None;
+                Poll::Ready(Ok(internal()))
+            }
+        }
+    }
+}
+
+fn internal() -> http::Response<BoxBody> {
+    Status::internal("internal error").to_http()
+}
+
+fn log(path: &str, payload: &(dyn Any + Send)) {
+    eprintln!("handler for {} panicked: {}", path, message(payload));
+}
+
+/// The message a panic was raised with, when it has one.
+pub(crate) fn message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "Box<dyn Any>"
+    }
+}
@@ -0,0 +1,30 @@
+use std::time::Duration;
+
+use crate::db::{Db, DbError};
+
+/// Keeps the monthly partitions of `messages` until the task is dropped:
+/// creates the partitions of this month and the `months_ahead` after it
+/// before messages arrive for them, and drops those older than the
+/// `retention_months` before this one, unless that is 0.
+pub async fn maintain(db: Db, months_ahead: u16, retention_months: u16, interval: Duration) {
+    loop {
+        if let Err(err) = maintain_once(&db, months_ahead, retention_months).await {
+            eprintln!("partition maintenance failed: {}", err);
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+async fn maintain_once(db: &Db, months_ahead: u16, retention_months: u16) -> Result<(), DbError> {
+    let created = db.create_messages_partitions(months_ahead.into()).await?;
+    if created > 0 {
+        println!("created {} partitions of messages", created);
+    }
+    if retention_months > 0 {
+        let dropped = db.drop_messages_partitions(retention_months.into()).await?;
+        if dropped > 0 {
+            println!("dropped {} expired partitions of messages", dropped);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,101 @@
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::{fmt, net::SocketAddr};
+
+#[cfg(feature = "tls")]
+use tonic::transport::{server::TlsConnectInfo, Certificate};
+use tonic::Request;
+
+use crate::listener::ConnectionInfo;
+
+/// What the transport knows about the other end of a call.
+///
+/// Every part is optional: calls forwarded in-process, e.g. by the HTTP/JSON
+/// gateway, carry no connect info at all, and plain TCP connections have no
+/// TLS details even when the server is built with the `tls` feature.
+#[derive(Clone, Debug, Default)]
+pub struct PeerInfo {
+    /// Id of the connection the call came in on, see `ConnectionInfo`.
+    pub connection_id: Option<u64>,
+    pub remote_addr: Option<SocketAddr>,
+    /// Other end of the socket, differs from `remote_addr` behind a trusted
+    /// proxy.
+    pub peer_addr: Option<SocketAddr>,
+    pub local_addr: Option<SocketAddr>,
+    /// Whether the call came over a TLS connection.
+    pub tls: bool,
+    /// Certificates presented by the client, with client authentication.
+    #[cfg(feature = "tls")]
+    pub peer_certs: Option<Arc<Vec<Certificate>>>,
+}
+
+impl PeerInfo {
+    pub fn from_request<T>(request: &Request<T>) -> Self {
+        let extensions = request.extensions();
+        #[cfg(feature = "tls")]
+        if let Some(info) = extensions.get::<TlsConnectInfo<ConnectionInfo>>() {
+            return Self::from_tls(info);
+        }
+        Self::from_conn(extensions.get())
+    }
+
+    /// Same as `from_request`, for layers working on the raw HTTP request.
+    pub fn from_http<B>(request: &http::Request<B>) -> Self {
+        let extensions = request.extensions();
+        #[cfg(feature = "tls")]
+        if let Some(info) = extensions.get::<TlsConnectInfo<ConnectionInfo>>() {
+            return Self::from_tls(info);
+        }
+        Self::from_conn(extensions.get())
+    }
+
+    fn from_conn(info: Option<&ConnectionInfo>) -> Self {
+        Self {
+            connection_id: info.map(|info| info.id),
+            remote_addr: info.and_then(|info| info.remote_addr),
+            peer_addr: info.and_then(|info| info.peer_addr),
+            local_addr: info.and_then(|info| info.local_addr),
+            ..Default::default()
+        }
+    }
+
+    /// Subject of the leaf client certificate, which the handshake checked
+    /// against the client CA.
+    #[cfg(feature = "tls")]
+    pub fn client_subject(&self) -> Option<String> {
+        let certs = self.peer_certs.as_ref()?;
+        let (_, cert) = x509_parser::parse_x509_certificate(certs.first()?.get_ref()).ok()?;
+        Some(cert.subject().to_string())
+    }
+
+    #[cfg(feature = "tls")]
+    fn from_tls(info: &TlsConnectInfo<ConnectionInfo>) -> Self {
+        Self {
+            tls: true,
+            peer_certs: info.peer_certs(),
+            ..Self::from_conn(Some(info.get_ref()))
+        }
+    }
+}
+
+/// `[::1]:50000 (tls, 1 client cert)`, `-` stands in for an unknown address.
+impl fmt::Display for PeerInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.remote_addr {
+            Some(addr) => write!(f, "{}", addr)?,
+            None => f.write_str("-")?,
+        }
+        if self.tls {
+            f.write_str(" (tls")?;
+            #[cfg(feature = "tls")]
+            if let Some(certs) = &self.peer_certs {
+                write!(f, ", {} client cert", certs.len())?;
+                if certs.len() != 1 {
+                    f.write_str("s")?;
+                }
+            }
+            f.write_str(")")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,82 @@
+//! Reused buffers for the strings the request path builds and drops right
+//! away, like the greeting `SayHello` stores and the keys it looks calls up
+//! by. Each thread keeps a few, so at high rates calls stop going to the
+//! allocator for them.
+
+use std::{
+    cell::RefCell,
+    fmt,
+    ops::{Deref, DerefMut},
+};
+
+/// Buffers kept per thread.
+const POOLED: usize = 32;
+/// Larger buffers are freed rather than kept, one odd call shouldn't pin
+/// its memory.
+const MAX_CAPACITY: usize = 4096;
+
+thread_local! {
+    static STRINGS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+/// An empty string from the pool of the thread, going back to the pool of
+/// whatever thread drops it.
+pub fn string() -> PooledString {
+    let buf = STRINGS
+        .with(|strings| strings.borrow_mut().pop())
+        .unwrap_or_default();
+    PooledString { buf }
+}
+
+/// `parts` joined by `/`, the way calls are keyed by tenant.
+pub fn key(parts: &[&str]) -> PooledString {
+    let mut key = string();
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            key.push('/');
+        }
+        key.push_str(part);
+    }
+    key
+}
+
+pub struct PooledString {
+    buf: String,
+}
+
+impl Deref for PooledString {
+    type Target = String;
+
+    fn deref(&self) -> &String {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledString {
+    fn deref_mut(&mut self) -> &mut String {
+        &mut self.buf
+    }
+}
+
+impl fmt::Display for PooledString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.buf.fmt(f)
+    }
+}
+
+impl Drop for PooledString {
+    fn drop(&mut self) {
+        if self.buf.capacity() == 0 || self.buf.capacity() > MAX_CAPACITY {
+            return;
+        }
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.clear();
+        // the thread may be going away, its pool with it
+        let _ = STRINGS.try_with(|strings| {
+            let mut strings = strings.borrow_mut();
+            if strings.len() < POOLED {
+                strings.push(buf);
+            }
+        });
+    }
+}
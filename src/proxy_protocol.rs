@@ -0,0 +1,138 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use thiserror::Error;
+use tokio::{io::AsyncReadExt, net::TcpStream, time};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// Longest v1 header, CRLF included.
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+/// Time a connection gets to send its header before it is dropped.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Error, Debug)]
+pub enum ProxyError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Missing PROXY protocol header")]
+    Missing,
+    #[error("Malformed PROXY protocol header")]
+    Malformed,
+    #[error("Timed out waiting for the PROXY protocol header")]
+    Timeout,
+}
+
+/// Reads the PROXY header (v1 or v2) a load balancer sends ahead of the
+/// client's traffic. Returns the client and destination addresses it carries,
+/// `None` for `LOCAL` and `UNKNOWN` headers such as health checks, along with
+/// any bytes read past the header.
+pub async fn read_header(stream: &mut TcpStream) -> Result<(Option<Addrs>, Vec<u8>), ProxyError> {
+    time::timeout(HEADER_TIMEOUT, async {
+        let mut buf = Vec::with_capacity(V1_MAX_LEN);
+        loop {
+            if stream.read_buf(&mut buf).await? == 0 {
+                return Err(ProxyError::Missing);
+            }
+            if let Some((len, addrs)) = parse(&buf)? {
+                buf.drain(..len);
+                return Ok((addrs, buf));
+            }
+        }
+    })
+    .await
+    .map_err(|_| ProxyError::Timeout)?
+}
+
+/// Source and destination of the proxied connection.
+pub type Addrs = (SocketAddr, SocketAddr);
+type Header = (usize, Option<Addrs>);
+
+/// Parses a v1 or v2 header at the start of `buf`, `None` until it is
+/// complete. Returns the header length along with its addresses.
+fn parse(buf: &[u8]) -> Result<Option<Header>, ProxyError> {
+    if buf.starts_with(V2_SIGNATURE) {
+        parse_v2(buf)
+    } else if buf.starts_with(V1_PREFIX) {
+        parse_v1(buf)
+    } else if V2_SIGNATURE.starts_with(buf) || V1_PREFIX.starts_with(buf) {
+        Ok(None)
+    } else {
+        Err(ProxyError::Missing)
+    }
+}
+
+fn parse_v1(buf: &[u8]) -> Result<Option<Header>, ProxyError> {
+    let end = match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => end,
+        None if buf.len() < V1_MAX_LEN => return Ok(None),
+        None => return Err(ProxyError::Malformed),
+    };
+    let line = std::str::from_utf8(&buf[..end]).map_err(|_| ProxyError::Malformed)?;
+    let fields = line.split(' ').collect::<Vec<_>>();
+
+    let addrs = match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", "TCP4" | "TCP6", src, dst, src_port, dst_port] => {
+            let addr = |ip: &str, port: &str| -> Result<SocketAddr, ProxyError> {
+                let ip: IpAddr = ip.parse().map_err(|_| ProxyError::Malformed)?;
+                let port: u16 = port.parse().map_err(|_| ProxyError::Malformed)?;
+                Ok(SocketAddr::new(ip, port))
+            };
+            Some((addr(src, src_port)?, addr(dst, dst_port)?))
+        }
+        _ => return Err(ProxyError::Malformed),
+    };
+    Ok(Some((end + 2, addrs)))
+}
+
+fn parse_v2(buf: &[u8]) -> Result<Option<Header>, ProxyError> {
+    if buf.len() < V2_HEADER_LEN {
+        return Ok(None);
+    }
+    let version_command = buf[12];
+    let family = buf[13];
+    let len = V2_HEADER_LEN + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if version_command >> 4 != 2 {
+        return Err(ProxyError::Malformed);
+    }
+    if buf.len() < len {
+        return Ok(None);
+    }
+
+    let body = &buf[V2_HEADER_LEN..len];
+    let port = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+    let addrs = match (version_command & 0x0f, family) {
+        // LOCAL, sent by the balancer itself
+        (0x0, _) => None,
+        // PROXY over TCP/IPv4
+        (0x1, 0x11) if body.len() >= 12 => {
+            let src = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let dst = Ipv4Addr::new(body[4], body[5], body[6], body[7]);
+            Some((
+                SocketAddr::new(src.into(), port(8)),
+                SocketAddr::new(dst.into(), port(10)),
+            ))
+        }
+        // PROXY over TCP/IPv6
+        (0x1, 0x21) if body.len() >= 36 => {
+            let ip = |at: usize| {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&body[at..at + 16]);
+                Ipv6Addr::from(octets)
+            };
+            Some((
+                SocketAddr::new(ip(0).into(), port(32)),
+                SocketAddr::new(ip(16).into(), port(34)),
+            ))
+        }
+        // other families (UDP, unix sockets) don't apply to a gRPC listener
+        (0x1, _) => None,
+        _ => return Err(ProxyError::Malformed),
+    };
+    Ok(Some((len, addrs)))
+}
@@ -0,0 +1,29 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// Read-only maintenance mode, e.g. for the time of a database migration.
+/// While it is on greetings are refused, listing and streaming go on. Shared
+/// by the greeting service, the `Admin` service and the config reload.
+#[derive(Clone, Debug, Default)]
+pub struct ReadOnly(Arc<AtomicBool>);
+
+impl ReadOnly {
+    pub fn new(enabled: bool) -> Self {
+        Self(Arc::new(AtomicBool::new(enabled)))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Turns the mode on or off, returns whether it was on.
+    pub fn set(&self, enabled: bool) -> bool {
+        let was = self.0.swap(enabled, Ordering::Relaxed);
+        if was != enabled {
+            println!("read-only mode {}", if enabled { "on" } else { "off" });
+        }
+        was
+    }
+}
@@ -0,0 +1,53 @@
+//! Redaction of personal data, the names greeted, from the logs.
+
+use std::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turns redaction on or off for the whole process.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// `text` as it goes into a log line, `<redacted>` while redaction is on.
+pub fn text(text: &str) -> Redacted<'_> {
+    Redacted(text)
+}
+
+pub struct Redacted<'a>(&'a str);
+
+impl fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if is_enabled() {
+            f.write_str("<redacted>")
+        } else {
+            f.write_str(self.0)
+        }
+    }
+}
+
+impl fmt::Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if is_enabled() {
+            f.write_str("<redacted>")
+        } else {
+            fmt::Debug::fmt(self.0, f)
+        }
+    }
+}
+
+/// `sql` rendered by `diesel::debug_query` without its bind values while
+/// redaction is on.
+pub fn sql(sql: &str) -> &str {
+    match sql.split_once(" -- binds: ") {
+        Some((sql, _)) if is_enabled() => sql,
+        _ => sql,
+    }
+}
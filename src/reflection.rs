@@ -0,0 +1,57 @@
+use std::{
+    str::FromStr,
+    task::{Context, Poll},
+};
+
+use http::uri::PathAndQuery;
+use tonic::server::NamedService;
+use tower_service::Service;
+
+const V1_PREFIX: &str = "/grpc.reflection.v1.ServerReflection/";
+const V1ALPHA_PREFIX: &str = "/grpc.reflection.v1alpha.ServerReflection/";
+
+/// Serves gRPC reflection `v1` on top of a `v1alpha` reflection service.
+///
+/// Both versions share their message definitions and field numbers, only the
+/// package differs, so forwarding requests under the `v1alpha` path is enough.
+#[derive(Clone)]
+pub struct ReflectionV1<S> {
+    inner: S,
+}
+
+impl<S> ReflectionV1<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S> NamedService for ReflectionV1<S> {
+    const NAME: &'static str = "grpc.reflection.v1.ServerReflection";
+}
+
+impl<S, B> Service<http::Request<B>> for ReflectionV1<S>
+where
+    S: Service<http::Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: http::Request<B>) -> Self::Future {
+        if let Some(method) = req.uri().path().strip_prefix(V1_PREFIX) {
+            let path = format!("{}{}", V1ALPHA_PREFIX, method);
+            let mut parts = req.uri().clone().into_parts();
+            if let Ok(path) = PathAndQuery::from_str(&path) {
+                parts.path_and_query = Some(path);
+                if let Ok(uri) = http::Uri::from_parts(parts) {
+                    *req.uri_mut() = uri;
+                }
+            }
+        }
+        self.inner.call(req)
+    }
+}
@@ -0,0 +1,146 @@
+//! Settings that take effect without a restart.
+//!
+//! Env vars can't change under a running process, so a reload reads them
+//! again along with the config file, and it's the file's values that change
+//! the settings. Everything else in the config still needs a restart.
+
+use std::sync::{Arc, RwLock};
+
+use crate::{
+    access_log::AccessLogLayer,
+    config::{Config, TenantQuotas},
+    read_only::ReadOnly,
+    server_events::{self, EventKind, ServerEvent},
+};
+
+/// A setting shared with what it configures, replaced on reload.
+#[derive(Debug, Default)]
+pub struct Reloadable<T>(Arc<RwLock<T>>);
+
+impl<T> Reloadable<T> {
+    pub fn new(value: T) -> Self {
+        Self(Arc::new(RwLock::new(value)))
+    }
+
+    /// Runs `f` on the current value.
+    pub fn read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        f(&self.0.read().unwrap())
+    }
+
+    pub fn set(&self, value: T) {
+        *self.0.write().unwrap() = value;
+    }
+}
+
+impl<T: Clone> Reloadable<T> {
+    pub fn get(&self) -> T {
+        self.read(T::clone)
+    }
+}
+
+// not derived, that would require `T: Clone`
+impl<T> Clone for Reloadable<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+/// The reloadable settings of a running server: read-only mode, tenant
+/// quotas and access log sampling.
+#[derive(Clone)]
+pub struct Settings {
+    pub read_only: ReadOnly,
+    pub tenant_quotas: Reloadable<TenantQuotas>,
+    pub access_log: AccessLogLayer,
+}
+
+impl Settings {
+    /// Applies the reloadable settings of `config`.
+    pub fn apply(&self, config: &Config) {
+        self.read_only.set(config.read_only);
+        self.tenant_quotas.set(config.tenant_quotas.clone());
+        self.access_log
+            .set_sampling(config.access_log_sampling.clone());
+    }
+
+    /// Loads the config again and applies it, keeping the current settings
+    /// when it doesn't load.
+    pub fn reload(&self) {
+        match Config::load() {
+            Ok(config) => {
+                self.apply(&config);
+                println!("config reloaded");
+                server_events::emit(|| {
+                    ServerEvent::new(EventKind::ConfigReloaded, "config reloaded")
+                });
+            }
+            Err(err) => {
+                eprintln!("config reload failed, keeping the current one: {}", err);
+                server_events::emit(|| {
+                    ServerEvent::new(EventKind::ConfigReloadFailed, err.to_string())
+                });
+            }
+        }
+    }
+}
+
+/// Reloads the settings on every SIGHUP.
+#[cfg(unix)]
+pub async fn on_sighup(settings: Settings) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            eprintln!("failed to listen for SIGHUP: {}", err);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        settings.reload();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mock::MockMessageStore,
+        service::{Greeting, GreetingService, ServiceError},
+    };
+
+    #[tokio::test]
+    async fn reloaded_settings_apply_to_the_running_service() {
+        let config = Config {
+            tenant_quotas: "*=1:".parse().unwrap(),
+            ..Config::default()
+        };
+        let service = GreetingService::new(MockMessageStore::new(), &config);
+        let settings = Settings {
+            read_only: service.read_only().clone(),
+            tenant_quotas: service.tenant_quotas().clone(),
+            access_log: AccessLogLayer::new(config.access_log_sampling.clone()),
+        };
+        let greeting = Greeting {
+            name: "Eve".to_string(),
+            ..Default::default()
+        };
+        service.greet("acme", &greeting).await.unwrap();
+
+        settings.apply(&Config {
+            tenant_quotas: "*=2:".parse().unwrap(),
+            ..Config::default()
+        });
+        service.greet("acme", &greeting).await.unwrap();
+        assert_eq!(service.quota("acme").max_messages, Some(2));
+
+        settings.apply(&Config {
+            read_only: true,
+            ..Config::default()
+        });
+        assert!(matches!(
+            service.greet("acme", &greeting).await,
+            Err(ServiceError::ReadOnly)
+        ));
+    }
+}
@@ -0,0 +1,230 @@
+//! Revocation of client certificates with mutual TLS (`tls` feature): the
+//! certificates listed in a CRL file are refused, calls made with one fail
+//! with `UNAUTHENTICATED`. The file is read again whenever it changes, a CA
+//! publishing a new list takes effect without a restart.
+//!
+//! Only CRLs are supported, no OCSP. The file is trusted as the client CA
+//! is, the signatures of the lists aren't checked.
+
+use std::{
+    collections::HashSet,
+    fs,
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use thiserror::Error;
+use tonic::{body::BoxBody, Status};
+use tower_layer::Layer;
+use tower_service::Service;
+use x509_parser::{pem::Pem, revocation_list::CertificateRevocationList};
+
+use crate::{peer_info::PeerInfo, reload::Reloadable};
+
+const PEM_PREFIX: &[u8] = b"-----BEGIN";
+
+#[derive(Error, Debug)]
+pub enum RevocationError {
+    #[error("CRL file: {0}")]
+    Io(#[from] io::Error),
+    #[error("Invalid CRL: {0}")]
+    Invalid(String),
+}
+
+/// The certificates revoked by one or more CRLs, by issuer and serial.
+#[derive(Debug, Default)]
+pub struct RevocationList {
+    /// The DER of the issuer's name with the serial of each certificate.
+    revoked: HashSet<(Vec<u8>, Vec<u8>)>,
+}
+
+impl RevocationList {
+    /// Parses a DER CRL or any number of PEM ones, warning about those past
+    /// their next update.
+    pub fn parse(data: &[u8]) -> Result<Self, RevocationError> {
+        let mut list = Self::default();
+        if !data.trim_ascii_start().starts_with(PEM_PREFIX) {
+            list.add(data)?;
+            return Ok(list);
+        }
+        for pem in Pem::iter_from_buffer(data) {
+            let pem = pem.map_err(|err| RevocationError::Invalid(err.to_string()))?;
+            list.add(&pem.contents)?;
+        }
+        Ok(list)
+    }
+
+    fn add(&mut self, der: &[u8]) -> Result<(), RevocationError> {
+        let (_, crl) = x509_parser::parse_x509_crl(der)
+            .map_err(|err| RevocationError::Invalid(err.to_string()))?;
+        warn_if_stale(&crl);
+        let issuer = crl.issuer().as_raw();
+        for revoked in crl.iter_revoked_certificates() {
+            self.revoked
+                .insert((issuer.to_vec(), revoked.raw_serial().to_vec()));
+        }
+        Ok(())
+    }
+
+    /// Whether the certificate `der` is revoked. One that doesn't parse
+    /// isn't, the handshake already vetted it.
+    pub fn is_revoked(&self, der: &[u8]) -> bool {
+        let Ok((_, cert)) = x509_parser::parse_x509_certificate(der) else {
+            return false;
+        };
+        self.revoked
+            .contains(&(cert.issuer().as_raw().to_vec(), cert.raw_serial().to_vec()))
+    }
+
+    /// Number of certificates revoked.
+    pub fn len(&self) -> usize {
+        self.revoked.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.revoked.is_empty()
+    }
+}
+
+fn warn_if_stale(crl: &CertificateRevocationList) {
+    let Some(next_update) = crl.next_update() else {
+        return;
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() as i64);
+    if next_update.timestamp() < now {
+        eprintln!(
+            "warning: CRL of '{}' is past its next update, {}",
+            crl.issuer(),
+            next_update
+        );
+    }
+}
+
+/// The revocation list in force, shared by the layer and the task reloading
+/// it, cheap to clone.
+#[derive(Clone)]
+pub struct Revocations {
+    path: PathBuf,
+    list: Reloadable<RevocationList>,
+}
+
+impl Revocations {
+    /// Reads the CRL file at `path`.
+    pub fn load(path: &Path) -> Result<Self, RevocationError> {
+        let list = RevocationList::parse(&fs::read(path)?)?;
+        println!("{} client certificates revoked", list.len());
+        Ok(Self {
+            path: path.to_path_buf(),
+            list: Reloadable::new(list),
+        })
+    }
+
+    pub fn is_revoked(&self, der: &[u8]) -> bool {
+        self.list.read(|list| list.is_revoked(der))
+    }
+
+    /// Checks the file every `interval` and reads it again once modified,
+    /// until the task is dropped. A file that doesn't read or parse leaves
+    /// the list in force as it is.
+    pub async fn watch(self, interval: Duration) {
+        let mut modified = modified(&self.path);
+        loop {
+            tokio::time::sleep(interval).await;
+            let now = modified(&self.path);
+            if now == modified {
+                continue;
+            }
+            match fs::read(&self.path)
+                .map_err(RevocationError::from)
+                .and_then(|data| RevocationList::parse(&data))
+            {
+                Ok(list) => {
+                    println!("CRL reloaded, {} client certificates revoked", list.len());
+                    self.list.set(list);
+                    modified = now;
+                }
+                Err(err) => eprintln!("CRL reload failed, keeping the current one: {}", err),
+            }
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+/// Fails the calls of clients whose certificate is revoked with
+/// `UNAUTHENTICATED`. Calls without a client certificate go through, the
+/// TLS config decides whether one is required.
+#[derive(Clone)]
+pub struct RevocationLayer {
+    revocations: Option<Revocations>,
+}
+
+impl RevocationLayer {
+    pub fn new(revocations: Revocations) -> Self {
+        Self {
+            revocations: Some(revocations),
+        }
+    }
+
+    /// Lets every call through, for servers without a CRL.
+    pub fn disabled() -> Self {
+        Self { revocations: None }
+    }
+}
+
+impl<S> Layer<S> for RevocationLayer {
+    type Service = Revocation<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Revocation {
+            inner,
+            revocations: self.revocations.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Revocation<S> {
+    inner: S,
+    revocations: Option<Revocations>,
+}
+
+impl<S, B> Service<http::Request<B>> for Revocation<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<BoxBody>>,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        if let Some(revocations) = &self.revocations {
+            // the leaf comes first, the rest of the chain belongs to the CAs
+            let revoked = PeerInfo::from_http(&req)
+                .peer_certs
+                .and_then(|certs| certs.first().cloned())
+                .is_some_and(|cert| revocations.is_revoked(cert.get_ref()));
+            if revoked {
+                eprintln!("refused a call with a revoked client certificate");
+                return Box::pin(async {
+                    Ok(Status::unauthenticated("client certificate revoked").to_http())
+                });
+            }
+        }
+        Box::pin(self.inner.call(req))
+    }
+}
@@ -1,9 +1,124 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    audit_log (id) {
+        id -> Int8,
+        tenant -> Text,
+        action -> Text,
+        subject -> Text,
+        detail -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    events (seq) {
+        seq -> Int8,
+        kind -> Text,
+        message_id -> Int4,
+        payload -> Nullable<Text>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    feature_flags (name, tenant) {
+        name -> Text,
+        tenant -> Text,
+        enabled -> Bool,
+    }
+}
+
 diesel::table! {
     messages (id) {
         id -> Int4,
         message -> Nullable<Text>,
         updated -> Nullable<Int4>,
+        tags -> Jsonb,
+        created_at -> Timestamp,
+        metadata -> Jsonb,
+        priority -> Int2,
+        payload -> Nullable<Bytea>,
+        attachment_key -> Nullable<Text>,
+        country -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    name_counts (name) {
+        name -> Text,
+        greetings -> Int8,
+    }
+}
+
+diesel::table! {
+    outbox (id) {
+        id -> Int8,
+        topic -> Text,
+        payload -> Text,
+        created_at -> Timestamp,
+        published_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    pending_deliveries (message_id) {
+        message_id -> Int4,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    refresh_tokens (token_sha256) {
+        token_sha256 -> Text,
+        subject -> Text,
+        groups -> Jsonb,
+        expires_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    revoked_sessions (subject) {
+        subject -> Text,
+        revoked_at -> Timestamp,
     }
 }
+
+diesel::table! {
+    sessions (id) {
+        id -> Int8,
+        tenant -> Text,
+        started_at -> Timestamp,
+        ended_at -> Nullable<Timestamp>,
+        exchanges -> Jsonb,
+    }
+}
+
+diesel::table! {
+    subscriber_acks (subscriber) {
+        subscriber -> Text,
+        acked_until -> Int4,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    subscriptions (name) {
+        name -> Text,
+        delivered_until -> Int4,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    tenant_usage (tenant) {
+        tenant -> Text,
+        messages -> Int8,
+        bytes -> Int8,
+    }
+}
+
+diesel::joinable!(pending_deliveries -> messages (message_id));
+
+diesel::allow_tables_to_appear_in_same_query!(messages, outbox, pending_deliveries,);
@@ -0,0 +1,616 @@
+use std::{future::Future, io, net::SocketAddr, sync::Arc, time::Duration};
+
+use thiserror::Error;
+use tokio::net::TcpListener;
+use tonic::transport::{server::Routes, Endpoint, Server};
+#[cfg(feature = "tls")]
+use tonic::transport::{Certificate as TlsCertificate, Identity, ServerTlsConfig};
+
+use crate::{
+    access_log::AccessLogLayer,
+    admin::{AdminServer, MyAdmin},
+    authn::{self, Authenticator, FirstOf, IdentityProvider},
+    authz::{self, AuthzError, AuthzLayer, Policy},
+    canary::CanaryRouter,
+    certs::Certificate,
+    clients::{ClientError, Clients},
+    compat::{self, MismatchPolicy},
+    config::Config,
+    crypt::{KeyWrapper, LocalKey, MessageCipher},
+    db::{Db, DbError},
+    greeter::{
+        hello_world::greeter_server::Greeter, GreeterServer, MyGreeter, FILE_DESCRIPTOR_SET,
+    },
+    handshake::HandshakeLimits,
+    ip_filter::{IpFilterLayer, IpRules},
+    limits::MethodLimitLayer,
+    listener::{self, ListenerOptions},
+    messages::Broadcaster,
+    mirror::MirrorLayer,
+    moderation::{Moderation, ModerationError, Moderator},
+    panic::CatchPanicLayer,
+    reflection::ReflectionV1,
+    reload::{Reloadable, Settings},
+    server_info::ServerInfoLayer,
+    tenant::TenantLayer,
+    tokens::{MyTokens, SessionRevocations, TokenIssuer, TokensServer},
+    translate::Translator,
+    versions::{
+        v1::greeter_server::GreeterServer as V1GreeterServer,
+        v2::greeter_server::GreeterServer as V2GreeterServer, GreeterV1, GreeterV2,
+    },
+};
+
+#[derive(Error, Debug)]
+pub enum ServerError {
+    #[error("No database, see `ServerBuilder::with_db`")]
+    MissingDb,
+    #[error("Database error: {0}")]
+    Db(#[from] DbError),
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Transport error: {0}")]
+    Transport(#[from] tonic::transport::Error),
+    #[error("Database schema incompatible with this build, {0} mismatches")]
+    Schema(usize),
+    #[error("Moderation setup error: {0}")]
+    Moderation(#[from] ModerationError),
+    #[error("Upstream setup error: {0}")]
+    Clients(#[from] ClientError),
+    #[error("Reflection error: {0}")]
+    Reflection(#[from] tonic_reflection::server::Error),
+    #[error("Authorization setup error: {0}")]
+    Authz(#[from] AuthzError),
+    #[cfg(feature = "kafka")]
+    #[error("Kafka error: {0}")]
+    Kafka(#[from] rdkafka::error::KafkaError),
+    #[cfg(feature = "notifications")]
+    #[error("Notification setup error: {0}")]
+    Notify(#[from] crate::notify::NotifyError),
+    #[cfg(feature = "attachments")]
+    #[error("Attachment store setup error: {0}")]
+    Attachments(#[from] crate::attachments::AttachmentError),
+    #[cfg(feature = "transcoding")]
+    #[error("Transcoding setup error: {0}")]
+    Transcode(#[from] crate::transcode::TranscodeError),
+    #[cfg(feature = "geoip")]
+    #[error("GeoIP database error: {0}")]
+    GeoIp(#[from] maxminddb::MaxMindDBError),
+    #[cfg(feature = "tls")]
+    #[error("Revocation list error: {0}")]
+    Revocation(#[from] crate::revocation::RevocationError),
+}
+
+type ServerResult<T> = Result<T, ServerError>;
+
+/// Assembles the greeter server: the gRPC services, `Admin` among them,
+/// behind their layers plus whatever side servers the enabled features add
+/// (WebSocket feed, dashboard, HTTP/JSON gateway, GraphQL endpoint,
+/// notification sinks, Kafka outbox relay), the database health probes, the
+/// maintenance of the `messages` partitions, the greeting statistics
+/// refresh, the leaderboard counts, the certificate expiry checks and the
+/// SIGHUP config reload.
+///
+/// ```ignore
+/// ServerBuilder::new(Config::load()?)
+///     .with_db(Db::new(&db_url).await?)
+///     .serve("[::0]:50051".parse()?)
+///     .await?;
+/// ```
+pub struct ServerBuilder {
+    config: Config,
+    db: Option<Db>,
+    broadcaster: Option<Broadcaster>,
+    certificates: Vec<Certificate>,
+    moderators: Vec<Arc<dyn Moderator>>,
+    policy: Option<Arc<dyn Policy>>,
+    key_wrapper: Option<Arc<dyn KeyWrapper>>,
+    canary: Option<Routes>,
+    #[cfg(feature = "tls")]
+    identity: Option<Identity>,
+    #[cfg(feature = "tls")]
+    client_ca: Option<TlsCertificate>,
+    #[cfg(feature = "pgvector")]
+    embedder: Option<Arc<dyn crate::embed::Embedder>>,
+}
+
+impl ServerBuilder {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            db: None,
+            broadcaster: None,
+            certificates: Vec::new(),
+            moderators: Vec::new(),
+            policy: None,
+            key_wrapper: None,
+            canary: None,
+            #[cfg(feature = "tls")]
+            identity: None,
+            #[cfg(feature = "tls")]
+            client_ca: None,
+            #[cfg(feature = "pgvector")]
+            embedder: None,
+        }
+    }
+
+    /// The store greetings go to, required. The slow query threshold and the
+    /// Kafka outbox of the config are applied to it.
+    pub fn with_db(mut self, db: Db) -> Self {
+        self.db = Some(db);
+        self
+    }
+
+    /// Serves over TLS with `identity`.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, identity: Identity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Requires clients to present a certificate issued by `client_ca`,
+    /// checked against the `tls_crl_file` of the config when there is one.
+    #[cfg(feature = "tls")]
+    pub fn with_client_ca(mut self, client_ca: TlsCertificate) -> Self {
+        self.client_ca = Some(client_ca);
+        self
+    }
+
+    /// Reports the expiry of `certificates`, e.g. those of the TLS identity
+    /// parsed by `certs::parse`, and warns as it approaches.
+    pub fn with_certificates(mut self, certificates: Vec<Certificate>) -> Self {
+        self.certificates.extend(certificates);
+        self
+    }
+
+    /// Shares `broadcaster` with the greeter instead of a fresh one, to
+    /// follow its greetings from outside the server.
+    pub fn with_broadcaster(mut self, broadcaster: Broadcaster) -> Self {
+        self.broadcaster = Some(broadcaster);
+        self
+    }
+
+    /// Encrypts the stored messages with data keys wrapped by
+    /// `key_wrapper`, e.g. a KMS client, instead of the
+    /// `message_encryption_key` of the config.
+    pub fn with_key_wrapper(mut self, key_wrapper: Arc<dyn KeyWrapper>) -> Self {
+        self.key_wrapper = Some(key_wrapper);
+        self
+    }
+
+    /// Runs greeted names through `moderator` after the stages configured in
+    /// `Config::moderation`.
+    pub fn with_moderator(mut self, moderator: Arc<dyn Moderator>) -> Self {
+        self.moderators.push(moderator);
+        self
+    }
+
+    /// Authorizes every call with `policy` instead of the one of
+    /// `Config::authz`.
+    pub fn with_policy(mut self, policy: Arc<dyn Policy>) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Answers the `Greeter` calls carrying `x-canary: true` with `canary`,
+    /// e.g. a greeter with new logic, see `canary::CanaryRouter`.
+    pub fn with_canary<G: Greeter>(mut self, canary: G) -> Self {
+        self.canary = Some(Routes::new(GreeterServer::new(canary)));
+        self
+    }
+
+    /// Embeds greetings for `FindSimilarMessages` with `embedder` instead of
+    /// the default `HashingEmbedder`.
+    #[cfg(feature = "pgvector")]
+    pub fn with_embedder(mut self, embedder: Arc<dyn crate::embed::Embedder>) -> Self {
+        self.embedder = Some(embedder);
+        self
+    }
+
+    /// Binds `addr`, once per configured listener, and serves until the
+    /// process ends.
+    pub async fn serve(self, addr: SocketAddr) -> ServerResult<()> {
+        let options = listener_options(&self.config);
+        let listeners = (0..self.config.listeners)
+            .map(|_| listener::bind(addr, &options))
+            .collect::<Result<Vec<_>, _>>()?;
+        println!("GreeterServer listening on {}", addr);
+        self.serve_with_listeners(listeners, std::future::pending())
+            .await
+    }
+
+    /// Serves on listeners bound by the caller, e.g. on port 0 with
+    /// `listener::bind`, until `shutdown` completes.
+    pub async fn serve_with_listeners<F>(
+        self,
+        listeners: Vec<TcpListener>,
+        shutdown: F,
+    ) -> ServerResult<()>
+    where
+        F: Future<Output = ()>,
+    {
+        let mut config = self.config;
+        crate::redact::set_enabled(config.redact_logs);
+        let mut db = self
+            .db
+            .ok_or(ServerError::MissingDb)?
+            .with_slow_query_threshold(Duration::from_millis(config.slow_query_ms));
+
+        let mismatches = compat::check(&db).await?;
+        for mismatch in &mismatches {
+            eprintln!("schema mismatch: {}", mismatch);
+        }
+        if !mismatches.is_empty() {
+            match config.schema_mismatch {
+                MismatchPolicy::Refuse => return Err(ServerError::Schema(mismatches.len())),
+                MismatchPolicy::ReadOnly => {
+                    eprintln!("starting read-only until the database schema is fixed");
+                    config.read_only = true;
+                }
+                MismatchPolicy::Ignore => {}
+            }
+        }
+        let key_wrapper = self.key_wrapper.or_else(|| {
+            let key = config.message_encryption_key.as_ref()?;
+            Some(Arc::new(LocalKey::new(key)) as Arc<dyn KeyWrapper>)
+        });
+        if let Some(key_wrapper) = key_wrapper {
+            db = db.with_encryption(MessageCipher::new(key_wrapper));
+        }
+
+        #[cfg(feature = "kafka")]
+        let db = match &config.kafka_brokers {
+            Some(brokers) => {
+                let producer = crate::outbox::producer(brokers)?;
+                let db = db.with_outbox(&config.kafka_topic);
+                let relay_db = db.clone();
+                let poll_interval = Duration::from_millis(config.outbox_poll_interval_ms);
+                spawn_singleton(&db, "outbox-relay", &config, move || {
+                    crate::outbox::relay(relay_db.clone(), producer.clone(), poll_interval)
+                });
+                db
+            }
+            None => db,
+        };
+
+        if config.db_health_interval_ms > 0 {
+            tokio::spawn(crate::health::supervise(
+                db.clone(),
+                Duration::from_millis(config.db_health_interval_ms),
+            ));
+        }
+
+        if config.partition_maintenance_secs > 0 {
+            let maintenance_db = db.clone();
+            let months_ahead = config.partition_months_ahead;
+            let retention_months = config.message_retention_months;
+            let interval = Duration::from_secs(config.partition_maintenance_secs);
+            spawn_singleton(&db, "partition-maintenance", &config, move || {
+                crate::partitions::maintain(
+                    maintenance_db.clone(),
+                    months_ahead,
+                    retention_months,
+                    interval,
+                )
+            });
+        }
+
+        if config.stats_refresh_secs > 0 {
+            let stats_db = db.clone();
+            let interval = Duration::from_secs(config.stats_refresh_secs);
+            spawn_singleton(&db, "stats-refresh", &config, move || {
+                crate::stats::refresh(stats_db.clone(), interval)
+            });
+        }
+
+        let certificates: Arc<[Certificate]> = self.certificates.into();
+        if !certificates.is_empty() && config.cert_check_interval_secs > 0 {
+            tokio::spawn(crate::certs::monitor(
+                certificates.clone(),
+                Duration::from_secs(config.cert_check_interval_secs),
+            ));
+        }
+
+        #[cfg(feature = "dashboard")]
+        let dashboard_db = db.clone();
+        let admin_db = db.clone();
+
+        let mut moderation = Moderation::from_config(&config.moderation)?;
+        for moderator in self.moderators {
+            moderation = moderation.with(moderator);
+        }
+        let clients = Clients::new(&config.upstreams)?;
+        let mut greeter = MyGreeter::new(db, config.clone())
+            .with_moderation(moderation)
+            .with_clients(clients.clone());
+        if let Some(upstream) = &config.translation_upstream {
+            let ttl = Duration::from_secs(config.translation_cache_secs);
+            greeter =
+                greeter.with_translator(Translator::new(clients.require(upstream)?.clone(), ttl));
+        }
+        if let Some(broadcaster) = self.broadcaster {
+            greeter = greeter.with_broadcaster(broadcaster);
+        }
+        #[cfg(feature = "attachments")]
+        if let Some(bucket) = &config.attachments_bucket {
+            let attachments = crate::attachments::Attachments::new(bucket, &config)?;
+            greeter = greeter.with_attachments(attachments);
+        }
+        #[cfg(feature = "pgvector")]
+        if let Some(embedder) = self.embedder {
+            greeter = greeter.with_embedder(embedder);
+        }
+
+        tokio::spawn(crate::leaderboard::keep_synced(
+            greeter.service().leaderboard().clone(),
+            Duration::from_millis(config.leaderboard_sync_ms),
+        ));
+
+        #[cfg(feature = "websocket")]
+        {
+            let broadcaster = greeter.broadcaster();
+            let addr = config.ws_addr;
+            tokio::spawn(async move {
+                if let Err(err) = crate::ws::serve(addr, broadcaster).await {
+                    eprintln!("WebSocket feed failed: {}", err);
+                }
+            });
+        }
+
+        #[cfg(feature = "notifications")]
+        crate::notify::spawn_sinks(&config.notifications, &greeter.broadcaster())?;
+
+        #[cfg(feature = "dashboard")]
+        {
+            let broadcaster = greeter.broadcaster();
+            let certificates = certificates.clone();
+            let addr = config.dashboard_addr;
+            tokio::spawn(async move {
+                if let Err(err) =
+                    crate::dashboard::serve(addr, dashboard_db, broadcaster, certificates).await
+                {
+                    eprintln!("Dashboard failed: {}", err);
+                }
+            });
+        }
+
+        let access_log = AccessLogLayer::new(config.access_log_sampling.clone());
+        let settings = Settings {
+            read_only: greeter.service().read_only().clone(),
+            tenant_quotas: greeter.service().tenant_quotas().clone(),
+            access_log: access_log.clone(),
+        };
+        #[cfg(unix)]
+        tokio::spawn(crate::reload::on_sighup(settings.clone()));
+
+        let sessions = greeter.sessions().clone();
+        let greeter = Arc::new(greeter);
+
+        #[cfg(feature = "graphql")]
+        {
+            let schema = crate::graphql::schema(greeter.service().clone());
+            let addr = config.graphql_addr;
+            let frontends = config.tenant_frontends.clone();
+            tokio::spawn(async move {
+                if let Err(err) = crate::graphql::serve(addr, schema, frontends).await {
+                    eprintln!("GraphQL endpoint failed: {}", err);
+                }
+            });
+        }
+
+        let greeter_v1 = V1GreeterServer::new(GreeterV1::new(greeter.clone()));
+        let greeter_v2 = V2GreeterServer::new(GreeterV2::new(greeter.clone()));
+        let greeter_server = CanaryRouter::new(GreeterServer::from_arc(greeter), self.canary);
+
+        #[cfg(feature = "transcoding")]
+        {
+            let router = crate::transcode::router(
+                greeter_server.clone(),
+                FILE_DESCRIPTOR_SET,
+                "helloworld.Greeter",
+            )?;
+            let addr = config.http_addr;
+            tokio::spawn(async move {
+                if let Err(err) = crate::transcode::serve(addr, router).await {
+                    eprintln!("HTTP/JSON gateway failed: {}", err);
+                }
+            });
+        }
+
+        let reflection_service = tonic_reflection::server::Builder::configure()
+            .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
+            .build()?;
+        let versions = config.reflection_versions;
+        let reflection_v1 = versions
+            .v1
+            .then(|| ReflectionV1::new(reflection_service.clone()));
+        let reflection_v1alpha = versions.v1alpha.then_some(reflection_service);
+
+        let server = Server::builder();
+        #[cfg(feature = "tls")]
+        let server = match self.identity {
+            Some(identity) => {
+                let mut tls = ServerTlsConfig::new().identity(identity);
+                if let Some(client_ca) = self.client_ca {
+                    // the policy decides per route, see `client_auth`
+                    tls = tls
+                        .client_ca_root(client_ca)
+                        .client_auth_optional(config.client_auth.is_some());
+                }
+                server.tls_config(tls)?
+            }
+            None => server,
+        };
+
+        #[cfg(feature = "tls")]
+        let revocation = match &config.tls_crl_file {
+            Some(path) => {
+                let revocations = crate::revocation::Revocations::load(path)?;
+                if config.crl_reload_secs > 0 {
+                    tokio::spawn(
+                        revocations
+                            .clone()
+                            .watch(Duration::from_secs(config.crl_reload_secs)),
+                    );
+                }
+                crate::revocation::RevocationLayer::new(revocations)
+            }
+            None => crate::revocation::RevocationLayer::disabled(),
+        };
+        #[cfg(not(feature = "tls"))]
+        let revocation = tower_layer::Identity::new();
+
+        #[cfg(feature = "tls")]
+        let client_auth = match config.client_auth.clone() {
+            Some(policy) => crate::client_auth::ClientAuthLayer::new(policy),
+            None => crate::client_auth::ClientAuthLayer::disabled(),
+        };
+        #[cfg(not(feature = "tls"))]
+        let client_auth = tower_layer::Identity::new();
+
+        let policy = match self.policy {
+            Some(policy) => Some(policy),
+            None => authz::from_config(&config.authz)?,
+        };
+        let mut authz = match policy {
+            Some(policy) => AuthzLayer::new(policy, config.authz.fail_open),
+            None => AuthzLayer::disabled(),
+        };
+        let tokens = config
+            .tokens
+            .enabled
+            .then(|| TokenIssuer::new(&config.tokens));
+        let mut providers = Vec::<Arc<dyn IdentityProvider>>::new();
+        if let Some(issuer) = &tokens {
+            providers.push(Arc::new(issuer.clone()));
+        }
+        if let Some(provider) = authn::from_config(&config.authz).await? {
+            providers.push(provider);
+        }
+        let revocations = SessionRevocations::new(
+            admin_db.clone(),
+            Duration::from_secs(config.tokens.revocation_cache_secs),
+        );
+        if !providers.is_empty() {
+            authz = authz.with_authenticator(
+                Authenticator::new(
+                    Arc::new(FirstOf(providers)),
+                    config.authz.roles.clone(),
+                    config.authz.anonymous.clone(),
+                    Duration::from_secs(config.authz.identity_cache_secs),
+                )
+                .with_revocations(revocations.clone()),
+            );
+        }
+        let tokens = tokens.map(|issuer| {
+            TokensServer::new(MyTokens::new(
+                issuer,
+                config.tokens.api_keys.clone(),
+                admin_db.clone(),
+                Duration::from_secs(config.tokens.refresh_ttl_secs),
+            ))
+        });
+
+        // faults go in under the panic handler, as close to the services as can be
+        #[cfg(feature = "chaos")]
+        let chaos = crate::chaos::ChaosLayer::new(config.chaos);
+        #[cfg(not(feature = "chaos"))]
+        let chaos = tower_layer::Identity::new();
+
+        let mirror = match &config.mirror_addr {
+            Some(addr) => {
+                MirrorLayer::new(Endpoint::from_shared(addr.clone())?, config.mirror_percent)
+            }
+            None => MirrorLayer::disabled(),
+        };
+
+        #[cfg(feature = "geoip")]
+        let geoip = match (&config.geoip_country_db, &config.geoip_asn_db) {
+            (None, None) => crate::geoip::GeoIpLayer::disabled(),
+            (country_db, asn_db) => crate::geoip::GeoIpLayer::new(crate::geoip::GeoIp::open(
+                country_db.as_deref(),
+                asn_db.as_deref(),
+            )?),
+        };
+        #[cfg(not(feature = "geoip"))]
+        let geoip = tower_layer::Identity::new();
+
+        let ip_rules = Reloadable::new(IpRules {
+            allow: config.ip_allow.clone(),
+            deny: config.ip_deny.clone(),
+        });
+
+        let router = server
+            .layer(ServerInfoLayer::default())
+            .layer(TenantLayer::new(config.tenant_frontends.clone()))
+            .layer(geoip)
+            .layer(access_log)
+            .layer(IpFilterLayer::new(ip_rules.clone()))
+            .layer(client_auth)
+            .layer(revocation)
+            .layer(authz)
+            .layer(
+                MethodLimitLayer::new(&config.method_limits)
+                    .with_classes(&config.caller_classes, &config.class_limits),
+            )
+            .layer(mirror)
+            .layer(CatchPanicLayer)
+            .layer(chaos)
+            .add_optional_service(reflection_v1)
+            .add_optional_service(reflection_v1alpha)
+            .add_optional_service(tokens)
+            .add_service(greeter_server)
+            .add_service(greeter_v1)
+            .add_service(greeter_v2)
+            .add_service(AdminServer::new(MyAdmin::new(
+                admin_db,
+                settings.read_only,
+                certificates,
+                config.restore_max_bytes,
+                sessions,
+                ip_rules,
+                revocations,
+            )));
+
+        let options = listener_options(&config);
+        router
+            .serve_with_incoming_shutdown(listener::incoming(listeners, options), shutdown)
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// Spawns the background task started by `task` on the replica leading
+/// `name`, see `leader::lead`, or on this one when leader election is off.
+fn spawn_singleton<F, Fut>(db: &Db, name: &'static str, config: &Config, mut task: F)
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    match config.leader_check_interval_ms {
+        0 => tokio::spawn(task()),
+        interval_ms => tokio::spawn(crate::leader::lead(
+            db.clone(),
+            name,
+            Duration::from_millis(interval_ms),
+            task,
+        )),
+    };
+}
+
+fn listener_options(config: &Config) -> ListenerOptions {
+    ListenerOptions {
+        nodelay: config.tcp_nodelay,
+        reuseport: config.so_reuseport || config.listeners > 1,
+        proxy_protocol: config.proxy_protocol,
+        trusted_proxies: config.trusted_proxies.clone(),
+        handshakes: HandshakeLimits {
+            max_concurrent: config.max_concurrent_handshakes,
+            timeout: Duration::from_millis(config.handshake_timeout_ms),
+            ban_failures: config.handshake_ban_failures,
+            ban_window: Duration::from_secs(config.handshake_ban_window_secs),
+            ban_duration: Duration::from_secs(config.handshake_ban_secs),
+        },
+    }
+}
@@ -0,0 +1,68 @@
+//! Operational events of the running server, connections opened and closed,
+//! database errors, config reloads, certificate warnings and clients banned,
+//! for operators to tail with the `StreamServerEvents` admin call.
+//!
+//! Events only go to the subscribers listening when they happen, nothing is
+//! kept. A subscriber `CAPACITY` events behind misses the oldest ones.
+
+use std::{collections::BTreeMap, sync::OnceLock, time::SystemTime};
+
+use tokio::sync::broadcast;
+
+const CAPACITY: usize = 1024;
+
+static EVENTS: OnceLock<broadcast::Sender<ServerEvent>> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    Connected,
+    Disconnected,
+    DbError,
+    ConfigReloaded,
+    ConfigReloadFailed,
+    Certificate,
+    ClientBanned,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerEvent {
+    pub kind: EventKind,
+    pub at: SystemTime,
+    pub message: String,
+    /// Details depending on the kind, e.g. the `peer` of a connection.
+    pub fields: BTreeMap<&'static str, String>,
+}
+
+impl ServerEvent {
+    pub fn new(kind: EventKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            at: SystemTime::now(),
+            message: message.into(),
+            fields: BTreeMap::new(),
+        }
+    }
+
+    pub fn with(mut self, field: &'static str, value: impl ToString) -> Self {
+        self.fields.insert(field, value.to_string());
+        self
+    }
+}
+
+fn sender() -> &'static broadcast::Sender<ServerEvent> {
+    EVENTS.get_or_init(|| broadcast::channel(CAPACITY).0)
+}
+
+/// Sends the event `make` builds to the subscribers, it isn't built when
+/// there are none.
+pub fn emit(make: impl FnOnce() -> ServerEvent) {
+    let tx = sender();
+    if tx.receiver_count() > 0 {
+        let _ = tx.send(make());
+    }
+}
+
+/// The events emitted from now on.
+pub fn subscribe() -> broadcast::Receiver<ServerEvent> {
+    sender().subscribe()
+}
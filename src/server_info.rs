@@ -0,0 +1,126 @@
+//! What build of the server runs, reported by `GetServerInfo` and in the
+//! metadata of every response, so clients and operators can tell what is
+//! deployed.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::OnceLock,
+    task::{Context, Poll},
+};
+
+use http::HeaderValue;
+use sha2::{Digest, Sha256};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::greeter::FILE_DESCRIPTOR_SET;
+
+/// Response metadata carrying `version`, e.g. `0.1.0+3f2a1bc`.
+pub const VERSION_METADATA: &str = "x-server-version";
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Commit the server was built from, `unknown` outside a git checkout.
+pub const GIT_SHA: &str = env!("GIT_SHA");
+
+/// When the server was built, in seconds since the Unix epoch.
+pub fn built_at() -> i64 {
+    env!("BUILD_TIMESTAMP").parse().unwrap_or_default()
+}
+
+/// Cargo features the server was built with.
+pub fn features() -> Vec<&'static str> {
+    [
+        ("tls", cfg!(feature = "tls")),
+        ("dev-tls", cfg!(feature = "dev-tls")),
+        ("websocket", cfg!(feature = "websocket")),
+        ("dashboard", cfg!(feature = "dashboard")),
+        ("kafka", cfg!(feature = "kafka")),
+        ("chaos", cfg!(feature = "chaos")),
+        ("notifications", cfg!(feature = "notifications")),
+        ("moderation-api", cfg!(feature = "moderation-api")),
+        ("transcoding", cfg!(feature = "transcoding")),
+        ("graphql", cfg!(feature = "graphql")),
+        ("pgvector", cfg!(feature = "pgvector")),
+        ("attachments", cfg!(feature = "attachments")),
+        ("geoip", cfg!(feature = "geoip")),
+        ("opa", cfg!(feature = "opa")),
+        ("rego", cfg!(feature = "rego")),
+        ("oidc", cfg!(feature = "oidc")),
+        ("ldap", cfg!(feature = "ldap")),
+    ]
+    .into_iter()
+    .filter_map(|(feature, enabled)| enabled.then_some(feature))
+    .collect()
+}
+
+/// Hex SHA-256 of the file descriptor set of the served protos.
+pub fn descriptor_sha256() -> &'static str {
+    static HASH: OnceLock<String> = OnceLock::new();
+    HASH.get_or_init(|| format!("{:x}", Sha256::digest(FILE_DESCRIPTOR_SET)))
+}
+
+/// The version with the short commit, as sent in `VERSION_METADATA`.
+pub fn version_string() -> String {
+    match GIT_SHA {
+        "unknown" => VERSION.to_string(),
+        sha => format!("{}+{}", VERSION, &sha[..sha.len().min(7)]),
+    }
+}
+
+/// Adds `VERSION_METADATA` to the headers of every response.
+#[derive(Clone)]
+pub struct ServerInfoLayer {
+    version: HeaderValue,
+}
+
+impl Default for ServerInfoLayer {
+    fn default() -> Self {
+        Self {
+            version: HeaderValue::from_str(&version_string())
+                .unwrap_or_else(|_| HeaderValue::from_static(VERSION)),
+        }
+    }
+}
+
+impl<S> Layer<S> for ServerInfoLayer {
+    type Service = ServerInfo<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ServerInfo {
+            inner,
+            version: self.version.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ServerInfo<S> {
+    inner: S,
+    version: HeaderValue,
+}
+
+impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for ServerInfo<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<ReqBody>) -> Self::Future {
+        let version = self.version.clone();
+        let response = self.inner.call(req);
+        Box::pin(async move {
+            let mut response = response.await?;
+            response.headers_mut().insert(VERSION_METADATA, version);
+            Ok(response)
+        })
+    }
+}
@@ -0,0 +1,738 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+#[cfg(feature = "pgvector")]
+use std::sync::Arc;
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+#[cfg(feature = "attachments")]
+use crate::attachments::Attachments;
+use crate::clients::Clients;
+use crate::config::{Config, Quota, TenantQuotas};
+use crate::db::{self, Db};
+#[cfg(feature = "pgvector")]
+use crate::embed::{Embedder, HashingEmbedder};
+use crate::flags::FeatureFlags;
+use crate::greeter::hello_world::{Priority, Salutation};
+use crate::greeting;
+use crate::leaderboard::Leaderboard;
+use crate::messages::{Broadcaster, Fanout};
+use crate::moderation::{Moderation, Verdict};
+use crate::pool;
+use crate::read_only::ReadOnly;
+use crate::redact;
+use crate::reload::Reloadable;
+use crate::server_events::{self, EventKind, ServerEvent};
+use crate::store::MessageStore;
+use crate::translate::Translator;
+
+#[derive(Error, Debug)]
+pub enum ServiceError {
+    #[error("quota of tenant {0} exceeded")]
+    QuotaExceeded(String),
+    #[error("the server is read-only for maintenance, greetings are refused until it is over")]
+    ReadOnly,
+    #[error("name rejected by moderation: {0}")]
+    Rejected(String),
+    #[error("payload larger than {0} bytes")]
+    PayloadTooLarge(usize),
+    #[error("invalid tags: {0}")]
+    Tags(#[from] serde_json::Error),
+    #[error(transparent)]
+    Store(Box<dyn std::error::Error + Send + Sync>),
+    #[cfg(feature = "pgvector")]
+    #[error("embedding failed: {0}")]
+    Embed(Box<dyn std::error::Error + Send + Sync>),
+}
+
+type ServiceResult<T> = Result<T, ServiceError>;
+
+fn store_error<E: std::error::Error + Send + Sync + 'static>(err: E) -> ServiceError {
+    server_events::emit(|| ServerEvent::new(EventKind::DbError, err.to_string()));
+    ServiceError::Store(Box::new(err))
+}
+
+/// What a greeting is made of, the fields of `HelloRequest` the greeting
+/// depends on.
+#[derive(Clone, Debug, Default)]
+pub struct Greeting {
+    pub name: String,
+    /// BCP 47 language tag the greeting is localized for.
+    pub locale: String,
+    /// Overrides the locale based salutation.
+    pub salutation: Salutation,
+    /// Free-form labels stored with the greeting.
+    pub tags: HashMap<String, String>,
+    /// Structured data stored with the greeting.
+    pub metadata: serde_json::Map<String, serde_json::Value>,
+    /// How urgently the greeting is delivered to subscribers.
+    pub priority: Priority,
+    /// Binary data stored with the greeting, none when empty.
+    pub payload: Vec<u8>,
+    /// Country of the client address, see `geoip`.
+    pub country: Option<String>,
+}
+
+/// What one page of `erase_name` went through.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ErasedPage {
+    pub scanned: u64,
+    pub erased: u64,
+    /// Id of the last message scanned, to continue after. `None` once there
+    /// are no messages left.
+    pub last_id: Option<i32>,
+}
+
+/// The greeter's business logic free of any transport: greeting, storing
+/// within the tenant's quota, broadcasting and listing. The gRPC handlers
+/// and the GraphQL endpoint run on it, tests can call it directly.
+#[derive(Clone)]
+pub struct GreetingService<S = Db, B = Broadcaster> {
+    store: S,
+    broadcaster: B,
+    durable_delivery: bool,
+    payload_max_bytes: usize,
+    tenant_quotas: Reloadable<TenantQuotas>,
+    read_only: ReadOnly,
+    flags: FeatureFlags<S>,
+    leaderboard: Leaderboard<S>,
+    moderation: Moderation,
+    clients: Clients,
+    translator: Option<Translator>,
+    #[cfg(feature = "attachments")]
+    attachments: Option<Attachments>,
+    #[cfg(feature = "pgvector")]
+    embedder: Arc<dyn Embedder>,
+}
+
+impl<S: MessageStore> GreetingService<S> {
+    pub fn new(store: S, config: &Config) -> Self {
+        let shards = config
+            .broadcast_shards
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |cores| cores.get()));
+        let broadcaster =
+            Broadcaster::with_shards(config.broadcast_capacity, config.broadcast_overflow, shards);
+        let flags = FeatureFlags::new(
+            store.clone(),
+            Duration::from_millis(config.feature_flag_ttl_ms),
+        );
+        Self {
+            leaderboard: Leaderboard::new(store.clone()),
+            store,
+            broadcaster,
+            durable_delivery: config.durable_delivery,
+            payload_max_bytes: config.payload_max_bytes,
+            tenant_quotas: Reloadable::new(config.tenant_quotas.clone()),
+            read_only: ReadOnly::new(config.read_only),
+            flags,
+            moderation: Moderation::default(),
+            clients: Clients::default(),
+            translator: None,
+            #[cfg(feature = "attachments")]
+            attachments: None,
+            #[cfg(feature = "pgvector")]
+            embedder: Arc::new(HashingEmbedder::new(config.embedding_dimensions)),
+        }
+    }
+}
+
+impl<S: MessageStore, B: Fanout> GreetingService<S, B> {
+    /// Broadcasts greetings on `broadcaster` instead of a fresh one.
+    pub fn with_broadcaster<F: Fanout>(self, broadcaster: F) -> GreetingService<S, F> {
+        GreetingService {
+            store: self.store,
+            broadcaster,
+            durable_delivery: self.durable_delivery,
+            payload_max_bytes: self.payload_max_bytes,
+            tenant_quotas: self.tenant_quotas,
+            read_only: self.read_only,
+            flags: self.flags,
+            leaderboard: self.leaderboard,
+            moderation: self.moderation,
+            clients: self.clients,
+            translator: self.translator,
+            #[cfg(feature = "attachments")]
+            attachments: self.attachments,
+            #[cfg(feature = "pgvector")]
+            embedder: self.embedder,
+        }
+    }
+
+    /// Runs greeted names through `moderation`, none are moderated by
+    /// default.
+    pub fn with_moderation(mut self, moderation: Moderation) -> Self {
+        self.moderation = moderation;
+        self
+    }
+
+    /// Lets handlers call the upstream services of `clients`, there are
+    /// none by default.
+    pub fn with_clients(mut self, clients: Clients) -> Self {
+        self.clients = clients;
+        self
+    }
+
+    /// Localizes greetings with `translator` instead of the salutations of
+    /// `greeting`.
+    pub fn with_translator(mut self, translator: Translator) -> Self {
+        self.translator = Some(translator);
+        self
+    }
+
+    /// Stores attachments in `attachments`, they are refused without.
+    #[cfg(feature = "attachments")]
+    pub fn with_attachments(mut self, attachments: Attachments) -> Self {
+        self.attachments = Some(attachments);
+        self
+    }
+
+    /// Embeds messages with `embedder` instead of the `HashingEmbedder`.
+    #[cfg(feature = "pgvector")]
+    pub fn with_embedder(mut self, embedder: Arc<dyn Embedder>) -> Self {
+        self.embedder = embedder;
+        self
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn broadcaster(&self) -> &B {
+        &self.broadcaster
+    }
+
+    /// The read-only mode greetings are refused in.
+    pub fn read_only(&self) -> &ReadOnly {
+        &self.read_only
+    }
+
+    /// The upstream services handlers can call.
+    pub fn clients(&self) -> &Clients {
+        &self.clients
+    }
+
+    /// Where attachments are stored, if anywhere.
+    #[cfg(feature = "attachments")]
+    pub fn attachments(&self) -> Option<&Attachments> {
+        self.attachments.as_ref()
+    }
+
+    /// The feature flags of the store, for handlers to consult.
+    pub fn flags(&self) -> &FeatureFlags<S> {
+        &self.flags
+    }
+
+    /// The greetings per name counted as they are published.
+    pub fn leaderboard(&self) -> &Leaderboard<S> {
+        &self.leaderboard
+    }
+
+    /// The tenant quotas `quota` looks up.
+    pub fn tenant_quotas(&self) -> &Reloadable<TenantQuotas> {
+        &self.tenant_quotas
+    }
+
+    pub fn quota(&self, tenant: &str) -> Quota {
+        self.tenant_quotas.read(|quotas| quotas.quota(tenant))
+    }
+
+    /// Greets, stores and broadcasts `greeting` for `tenant`.
+    pub async fn greet(&self, tenant: &str, greeting: &Greeting) -> ServiceResult<db::Message> {
+        self.check_payload(&greeting.payload)?;
+        let name = self.moderate(tenant, &greeting.name).await?;
+        let mut message = pool::string();
+        match &self.translator {
+            Some(translator) => {
+                *message = translator
+                    .compose(&name, &greeting.locale, greeting.salutation)
+                    .await;
+            }
+            None => {
+                greeting::compose_into(&mut message, &name, &greeting.locale, greeting.salutation)
+            }
+        }
+        let tags = serde_json::to_value(&greeting.tags)?;
+        let metadata = serde_json::Value::Object(greeting.metadata.clone());
+        let mut charged = usage_of([message.as_str()]);
+        charged.bytes += greeting.payload.len() as i64;
+        self.charge(tenant, charged).await?;
+        let payload = (!greeting.payload.is_empty()).then_some(greeting.payload.as_slice());
+        match self
+            .store
+            .insert_tagged_message(
+                &message,
+                &tags,
+                &metadata,
+                greeting.priority as i16,
+                payload,
+                greeting.country.as_deref(),
+            )
+            .await
+        {
+            Ok(message) => {
+                self.index(std::slice::from_ref(&message)).await;
+                self.publish(message.clone()).await;
+                Ok(message)
+            }
+            Err(err) => {
+                self.refund(tenant, charged).await;
+                Err(store_error(err))
+            }
+        }
+    }
+
+    /// The name to greet in place of `name`, masked where the moderation
+    /// says so, or `ServiceError::Rejected`. Masked and rejected names are
+    /// recorded in the audit log.
+    pub async fn moderate<'a>(&self, tenant: &str, name: &'a str) -> ServiceResult<Cow<'a, str>> {
+        let (action, reason, moderated) = match self.moderation.moderate(name).await {
+            Verdict::Allow => return Ok(Cow::Borrowed(name)),
+            Verdict::Mask { masked, reason } => ("mask_name", reason, Ok(Cow::Owned(masked))),
+            Verdict::Reject { reason } => (
+                "reject_name",
+                reason.clone(),
+                Err(ServiceError::Rejected(reason)),
+            ),
+        };
+        if let Err(err) = self
+            .store
+            .add_audit_entry(tenant, action, name, &reason)
+            .await
+        {
+            eprintln!(
+                "failed to audit moderation of {:?}: {}",
+                redact::text(name),
+                err
+            );
+        }
+        moderated
+    }
+
+    /// Deletes the greetings of `name` among the `limit` messages after
+    /// `after_id`, see `MessageStore::erase_messages`. Names are compared
+    /// with the ones `greeting::greeted_name` reads out of the messages, so
+    /// encrypted messages are found too.
+    pub async fn erase_name(
+        &self,
+        name: &str,
+        after_id: i32,
+        limit: i64,
+    ) -> ServiceResult<ErasedPage> {
+        if self.read_only.is_enabled() {
+            return Err(ServiceError::ReadOnly);
+        }
+        let page = self
+            .store
+            .get_messages_page(after_id, limit)
+            .await
+            .map_err(store_error)?;
+        let ids = page
+            .iter()
+            .filter(|msg| msg.message.as_deref().and_then(greeting::greeted_name) == Some(name))
+            .map(|msg| msg.id)
+            .collect::<Vec<_>>();
+        let erased = match ids.is_empty() {
+            true => 0,
+            false => self.store.erase_messages(&ids).await.map_err(store_error)?,
+        };
+        Ok(ErasedPage {
+            scanned: page.len() as u64,
+            erased: erased as u64,
+            last_id: page.last().map(|msg| msg.id),
+        })
+    }
+
+    /// Forgets `name` once `erase_name` went through every message: its
+    /// counted greetings are dropped and a tombstone naming it by its
+    /// SHA-256 only is recorded in the audit log for `tenant`.
+    pub async fn forget_name(&self, tenant: &str, name: &str, erased: u64) -> ServiceResult<()> {
+        self.store
+            .delete_name_count(name)
+            .await
+            .map_err(store_error)?;
+        self.leaderboard.forget(name);
+        let subject = format!("sha256:{:x}", Sha256::digest(name.as_bytes()));
+        let detail = format!("{} messages deleted", erased);
+        self.store
+            .add_audit_entry(tenant, "delete_name", &subject, &detail)
+            .await
+            .map_err(store_error)
+    }
+
+    /// Records a new streaming session of `tenant`, returns its id.
+    pub async fn start_session(&self, tenant: &str) -> ServiceResult<i64> {
+        self.store.start_session(tenant).await.map_err(store_error)
+    }
+
+    /// Appends each exchange to the transcript of its session.
+    pub async fn record_exchanges(&self, exchanges: &[(i64, db::Exchange)]) -> ServiceResult<()> {
+        self.store
+            .append_exchanges(exchanges)
+            .await
+            .map_err(store_error)
+    }
+
+    pub async fn end_session(&self, id: i64) -> ServiceResult<()> {
+        self.store.end_session(id).await.map_err(store_error)
+    }
+
+    /// Session `id` when it is one of `tenant`'s.
+    pub async fn session(&self, tenant: &str, id: i64) -> ServiceResult<Option<db::Session>> {
+        let session = self.store.get_session(id).await.map_err(store_error)?;
+        Ok(session.filter(|session| session.tenant == tenant))
+    }
+
+    /// Stores `payload` as that of message `id` in place of the one it had,
+    /// charged to `tenant`. Returns `false` when there is no such message.
+    pub async fn upload_payload(
+        &self,
+        tenant: &str,
+        id: i32,
+        payload: &[u8],
+    ) -> ServiceResult<bool> {
+        self.check_payload(payload)?;
+        let charged = db::Usage {
+            messages: 0,
+            bytes: payload.len() as i64,
+        };
+        self.charge(tenant, charged).await?;
+        match self.store.set_payload(id, payload).await {
+            Ok(true) => Ok(true),
+            Ok(false) => {
+                self.refund(tenant, charged).await;
+                Ok(false)
+            }
+            Err(err) => {
+                self.refund(tenant, charged).await;
+                Err(store_error(err))
+            }
+        }
+    }
+
+    /// The payload of message `id`, `None` when it has none or there is no
+    /// such message.
+    pub async fn payload(&self, id: i32) -> ServiceResult<Option<Vec<u8>>> {
+        self.store.get_payload(id).await.map_err(store_error)
+    }
+
+    /// Points message `id` to the attachment uploaded as `key`, charging its
+    /// `size` to `tenant`. Returns `false` when there is no such message.
+    pub async fn attach(&self, tenant: &str, id: i32, key: &str, size: u64) -> ServiceResult<bool> {
+        let charged = db::Usage {
+            messages: 0,
+            bytes: size as i64,
+        };
+        self.charge(tenant, charged).await?;
+        match self.store.set_attachment_key(id, key).await {
+            Ok(true) => Ok(true),
+            Ok(false) => {
+                self.refund(tenant, charged).await;
+                Ok(false)
+            }
+            Err(err) => {
+                self.refund(tenant, charged).await;
+                Err(store_error(err))
+            }
+        }
+    }
+
+    /// Key of the attachment of message `id`, `None` when it has none or
+    /// there is no such message.
+    pub async fn attachment_key(&self, id: i32) -> ServiceResult<Option<String>> {
+        self.store.get_attachment_key(id).await.map_err(store_error)
+    }
+
+    /// The largest payload a message may carry, in bytes.
+    pub fn payload_max_bytes(&self) -> usize {
+        self.payload_max_bytes
+    }
+
+    fn check_payload(&self, payload: &[u8]) -> ServiceResult<()> {
+        if payload.len() > self.payload_max_bytes {
+            return Err(ServiceError::PayloadTooLarge(self.payload_max_bytes));
+        }
+        Ok(())
+    }
+
+    /// Stores and broadcasts `message` for `tenant` as is.
+    pub async fn store_message(&self, tenant: &str, message: &str) -> ServiceResult<db::Message> {
+        let charged = usage_of([message]);
+        self.charge(tenant, charged).await?;
+        match self.store.insert_message(message).await {
+            Ok(message) => {
+                self.index(std::slice::from_ref(&message)).await;
+                self.publish(message.clone()).await;
+                Ok(message)
+            }
+            Err(err) => {
+                self.refund(tenant, charged).await;
+                Err(store_error(err))
+            }
+        }
+    }
+
+    /// Stores all of `messages` for `tenant` or none of them, broadcasting
+    /// them unless told not to.
+    pub async fn store_messages(
+        &self,
+        tenant: &str,
+        messages: &[String],
+        broadcast: bool,
+    ) -> ServiceResult<Vec<db::Message>> {
+        let charged = usage_of(messages.iter().map(String::as_str));
+        self.charge(tenant, charged).await?;
+        let inserted = match self.store.insert_messages(messages).await {
+            Ok(inserted) => inserted,
+            Err(err) => {
+                self.refund(tenant, charged).await;
+                return Err(store_error(err));
+            }
+        };
+        self.index(&inserted).await;
+        if broadcast {
+            for message in &inserted {
+                self.publish(message.clone()).await;
+            }
+        }
+        Ok(inserted)
+    }
+
+    /// Replaces the text of message `id`, `None` if it doesn't exist.
+    pub async fn update_message(
+        &self,
+        id: i32,
+        message: &str,
+    ) -> ServiceResult<Option<db::Message>> {
+        if self.read_only.is_enabled() {
+            return Err(ServiceError::ReadOnly);
+        }
+        let updated = self
+            .store
+            .update_message(id, message)
+            .await
+            .map_err(store_error)?;
+        if let Some(msg) = &updated {
+            self.index(std::slice::from_ref(msg)).await;
+        }
+        Ok(updated)
+    }
+
+    /// Stored messages, only those carrying all of `tags` and whose metadata
+    /// contains `metadata` when either is given.
+    pub async fn list(
+        &self,
+        tags: &HashMap<String, String>,
+        metadata: &serde_json::Map<String, serde_json::Value>,
+    ) -> ServiceResult<Vec<db::Message>> {
+        let messages = if tags.is_empty() && metadata.is_empty() {
+            self.store.get_messages().await
+        } else {
+            let metadata = serde_json::Value::Object(metadata.clone());
+            self.store
+                .get_messages_tagged(&serde_json::to_value(tags)?, &metadata)
+                .await
+        };
+        messages.map_err(store_error)
+    }
+
+    /// Token that changes whenever the stored messages do, for clients to
+    /// tell whether what they listed before is still current.
+    pub async fn version(&self) -> ServiceResult<String> {
+        let seq = self.store.latest_event_seq().await.map_err(store_error)?;
+        Ok(seq.to_string())
+    }
+
+    /// Up to `limit` stored messages closest in meaning to `text`, nearest
+    /// first.
+    #[cfg(feature = "pgvector")]
+    pub async fn find_similar(&self, text: &str, limit: i64) -> ServiceResult<Vec<db::Similar>> {
+        let embedding = self
+            .embedder
+            .embed(text)
+            .await
+            .map_err(ServiceError::Embed)?;
+        self.store
+            .find_similar(&embedding, limit)
+            .await
+            .map_err(store_error)
+    }
+
+    /// What `tenant` stored so far.
+    pub async fn usage(&self, tenant: &str) -> ServiceResult<db::Usage> {
+        self.store.get_usage(tenant).await.map_err(store_error)
+    }
+
+    /// Greetings per day of the last `days` days and the `top` names
+    /// greeted most, as last counted by the store.
+    pub async fn stats(&self, days: i32, top: i64) -> ServiceResult<db::GreetingStats> {
+        self.store.get_stats(days, top).await.map_err(store_error)
+    }
+
+    /// Broadcasts `message`, with durable delivery it's kept for the next
+    /// subscriber when no live one got it.
+    pub async fn publish(&self, message: db::Message) {
+        let id = message.id;
+        if let Some(name) = message.message.as_deref().and_then(greeting::greeted_name) {
+            self.leaderboard.count(name);
+        }
+        if !self.broadcaster.broadcast(message).await && self.durable_delivery {
+            if let Err(err) = self.store.add_pending_delivery(id).await {
+                eprintln!("failed to keep message {} for delivery: {}", id, err);
+            }
+        }
+    }
+
+    /// Stores the embeddings of `messages` for `find_similar`. A message
+    /// failing to embed is only left out of the search.
+    #[cfg(feature = "pgvector")]
+    async fn index(&self, messages: &[db::Message]) {
+        for msg in messages {
+            let text = msg.message.as_deref().unwrap_or_default();
+            let stored = match self.embedder.embed(text).await {
+                Ok(embedding) => self
+                    .store
+                    .set_embedding(msg.id, &embedding)
+                    .await
+                    .map_err(store_error),
+                Err(err) => Err(ServiceError::Embed(err)),
+            };
+            if let Err(err) = stored {
+                eprintln!("failed to embed message {}: {}", msg.id, err);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "pgvector"))]
+    async fn index(&self, _messages: &[db::Message]) {}
+
+    /// Counts `added` against the quota of `tenant`, ahead of storing it.
+    /// Nothing is stored in read-only mode.
+    async fn charge(&self, tenant: &str, added: db::Usage) -> ServiceResult<()> {
+        if self.read_only.is_enabled() {
+            return Err(ServiceError::ReadOnly);
+        }
+        match self
+            .store
+            .add_usage(tenant, added, self.quota(tenant))
+            .await
+        {
+            Ok(Some(_)) => Ok(()),
+            Ok(None) => Err(ServiceError::QuotaExceeded(tenant.to_string())),
+            Err(err) => Err(store_error(err)),
+        }
+    }
+
+    /// Gives back what `charge` counted for messages that weren't stored
+    /// after all.
+    async fn refund(&self, tenant: &str, charged: db::Usage) {
+        let given_back = db::Usage {
+            messages: -charged.messages,
+            bytes: -charged.bytes,
+        };
+        if let Err(err) = self
+            .store
+            .add_usage(tenant, given_back, Quota::default())
+            .await
+        {
+            eprintln!("failed to refund usage of tenant {}: {}", tenant, err);
+        }
+    }
+}
+
+/// Usage taken up by storing `messages`.
+fn usage_of<'a>(messages: impl IntoIterator<Item = &'a str>) -> db::Usage {
+    messages
+        .into_iter()
+        .fold(db::Usage::default(), |usage, message| db::Usage {
+            messages: usage.messages + 1,
+            bytes: usage.bytes + message.len() as i64,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::CountryCount;
+    use crate::mock::MockMessageStore;
+
+    #[tokio::test]
+    async fn greeting_service_needs_no_transport() {
+        let config = Config {
+            tenant_quotas: "*=1:".parse().unwrap(),
+            ..Config::default()
+        };
+        let service = GreetingService::new(MockMessageStore::new(), &config);
+        let greeting = Greeting {
+            name: "Dora".to_string(),
+            locale: "es".to_string(),
+            ..Default::default()
+        };
+
+        let message = service.greet("acme", &greeting).await.unwrap();
+        assert_eq!(message.message.as_deref(), Some("Hola Dora!"));
+        assert!(matches!(
+            service.greet("acme", &greeting).await,
+            Err(ServiceError::QuotaExceeded(_))
+        ));
+
+        let listed = service
+            .list(&Default::default(), &Default::default())
+            .await
+            .unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(service.usage("acme").await.unwrap().messages, 1);
+    }
+
+    #[cfg(feature = "pgvector")]
+    #[tokio::test]
+    async fn similar_messages_are_found_by_embedding() {
+        let service = GreetingService::new(MockMessageStore::new(), &Config::default());
+        for message in ["Hello Dora!", "Good night moon", "Hello Dorothy!"] {
+            service.store_message("acme", message).await.unwrap();
+        }
+
+        let similar = service.find_similar("dora", 2).await.unwrap();
+        let messages = similar
+            .iter()
+            .map(|similar| similar.message.message.as_deref().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(messages, ["Hello Dora!", "Hello Dorothy!"]);
+        assert!(similar[0].distance < similar[1].distance);
+    }
+
+    #[tokio::test]
+    async fn greetings_are_counted_per_country() {
+        let service = GreetingService::new(MockMessageStore::new(), &Config::default());
+        for (name, country) in [
+            ("Ana", Some("DE")),
+            ("Bo", Some("FR")),
+            ("Cy", Some("DE")),
+            ("Di", None),
+        ] {
+            let greeting = Greeting {
+                name: name.to_string(),
+                country: country.map(str::to_string),
+                ..Default::default()
+            };
+            service.greet("acme", &greeting).await.unwrap();
+        }
+
+        let stats = service.stats(7, 10).await.unwrap();
+        assert_eq!(stats.total, 4);
+        assert_eq!(
+            stats.per_country,
+            [
+                CountryCount {
+                    country: "DE".to_string(),
+                    greetings: 2,
+                },
+                CountryCount {
+                    country: "FR".to_string(),
+                    greetings: 1,
+                },
+            ]
+        );
+    }
+}
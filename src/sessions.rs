@@ -0,0 +1,145 @@
+//! The streaming calls open right now, `SayHelloStream` and
+//! `ListMessagesStream`, listed and ended by operators through the `Admin`
+//! service. A session is known by the id sent back in the `x-session-id`
+//! metadata of its response.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+
+use tokio_util::sync::CancellationToken;
+
+/// What a live session is, as listed by `LiveSessions::list`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LiveSession {
+    pub id: i64,
+    pub method: &'static str,
+    pub tenant: String,
+    pub peer: String,
+    pub created_at: SystemTime,
+    /// Requests read from the client.
+    pub received: u64,
+    /// Replies sent to the client, heartbeats not counted.
+    pub sent: u64,
+}
+
+struct Entry {
+    method: &'static str,
+    tenant: String,
+    peer: String,
+    created_at: SystemTime,
+    counters: Arc<Counters>,
+    kill: CancellationToken,
+}
+
+#[derive(Default)]
+struct Counters {
+    received: AtomicU64,
+    sent: AtomicU64,
+}
+
+/// The live sessions by id, cheap to clone.
+#[derive(Clone, Default)]
+pub struct LiveSessions {
+    entries: Arc<Mutex<BTreeMap<i64, Entry>>>,
+}
+
+impl LiveSessions {
+    /// Adds the session `id`, which stays listed until the handle is dropped.
+    pub fn register(
+        &self,
+        id: i64,
+        method: &'static str,
+        tenant: &str,
+        peer: &str,
+    ) -> SessionHandle {
+        let counters = Arc::new(Counters::default());
+        let kill = CancellationToken::new();
+        self.entries.lock().unwrap().insert(
+            id,
+            Entry {
+                method,
+                tenant: tenant.to_string(),
+                peer: peer.to_string(),
+                created_at: SystemTime::now(),
+                counters: counters.clone(),
+                kill: kill.clone(),
+            },
+        );
+        SessionHandle {
+            id,
+            sessions: self.clone(),
+            counters,
+            kill,
+        }
+    }
+
+    /// The live sessions, oldest first.
+    pub fn list(&self) -> Vec<LiveSession> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, entry)| LiveSession {
+                id: *id,
+                method: entry.method,
+                tenant: entry.tenant.clone(),
+                peer: entry.peer.clone(),
+                created_at: entry.created_at,
+                received: entry.counters.received.load(Ordering::Relaxed),
+                sent: entry.counters.sent.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Ends the session `id` with `ABORTED`, returns `false` when there is no
+    /// such session.
+    pub fn kill(&self, id: i64) -> bool {
+        match self.entries.lock().unwrap().get(&id) {
+            Some(entry) => {
+                entry.kill.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// A session as seen by the task serving it, which counts what goes through
+/// and stops once `killed` is cancelled.
+pub struct SessionHandle {
+    id: i64,
+    sessions: LiveSessions,
+    counters: Arc<Counters>,
+    kill: CancellationToken,
+}
+
+impl SessionHandle {
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+
+    pub fn received(&self) {
+        self.counters.received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self) {
+        self.counters.sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Cancelled once an operator killed the session.
+    pub fn killed(&self) -> CancellationToken {
+        self.kill.clone()
+    }
+}
+
+impl Drop for SessionHandle {
+    fn drop(&mut self) {
+        self.sessions.entries.lock().unwrap().remove(&self.id);
+    }
+}
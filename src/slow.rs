@@ -0,0 +1,78 @@
+use std::{
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use diesel::{debug_query, pg::Pg, query_builder::QueryFragment};
+
+static SLOW_QUERIES: AtomicU64 = AtomicU64::new(0);
+static SLOW_RPCS: AtomicU64 = AtomicU64::new(0);
+
+/// Queries that went over the slow query threshold since startup.
+pub fn slow_queries() -> u64 {
+    SLOW_QUERIES.load(Ordering::Relaxed)
+}
+
+/// RPCs that went over the slow RPC threshold since startup.
+pub fn slow_rpcs() -> u64 {
+    SLOW_RPCS.load(Ordering::Relaxed)
+}
+
+/// Runs `query` through `run` and warns with its SQL when it takes longer than
+/// `threshold`. A zero threshold disables the check.
+///
+/// The SQL is rendered up front, batch inserts and most other statements
+/// can't be kept around once executed.
+pub(crate) async fn query<Q, F, Fut, T>(threshold: Duration, query: Q, run: F) -> T
+where
+    Q: QueryFragment<Pg>,
+    F: FnOnce(Q) -> Fut,
+    Fut: Future<Output = T>,
+{
+    if threshold.is_zero() {
+        return run(query).await;
+    }
+
+    let sql = debug_query::<Pg, _>(&query).to_string();
+    let start = Instant::now();
+    let result = run(query).await;
+    let elapsed = start.elapsed();
+    if elapsed > threshold {
+        SLOW_QUERIES.fetch_add(1, Ordering::Relaxed);
+        eprintln!(
+            "warning: slow query took {:?}: {}",
+            elapsed,
+            crate::redact::sql(&sql)
+        );
+    }
+    result
+}
+
+/// Warns when dropped later than `threshold` after being started, held by an
+/// RPC handler for the time it takes to produce its response or stream.
+pub(crate) struct RpcTimer {
+    method: &'static str,
+    threshold: Duration,
+    start: Instant,
+}
+
+impl RpcTimer {
+    pub(crate) fn start(method: &'static str, threshold: Duration) -> Self {
+        Self {
+            method,
+            threshold,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Drop for RpcTimer {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        if !self.threshold.is_zero() && elapsed > self.threshold {
+            SLOW_RPCS.fetch_add(1, Ordering::Relaxed);
+            eprintln!("warning: slow rpc {} took {:?}", self.method, elapsed);
+        }
+    }
+}
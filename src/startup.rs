@@ -0,0 +1,51 @@
+//! Waits for what the server depends on at startup, e.g. a database still
+//! booting next to it or secrets not mounted yet, instead of exiting on the
+//! first failed attempt.
+
+use std::{fmt::Display, future::Future, time::Duration};
+
+use tokio::time::Instant;
+
+/// How long and how often a dependency is retried, see
+/// `Config::startup_retry`.
+#[derive(Clone, Copy, Debug)]
+pub struct Retry {
+    /// Delay after the first failed attempt, doubled after every other one.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Time after which the last error is given up on, zero makes a single
+    /// attempt.
+    pub max_wait: Duration,
+}
+
+/// Runs `attempt` until it succeeds or `retry.max_wait` is over, logging
+/// the failures. Returns the last error once it gives up.
+pub async fn retry<T, E, F, Fut>(what: &str, retry: Retry, mut attempt: F) -> Result<T, E>
+where
+    E: Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let deadline = Instant::now() + retry.max_wait;
+    let mut backoff = retry.initial_backoff;
+    let mut attempts = 1;
+    loop {
+        let err = match attempt().await {
+            Ok(value) => {
+                if attempts > 1 {
+                    println!("{} ready after {} attempts", what, attempts);
+                }
+                return Ok(value);
+            }
+            Err(err) => err,
+        };
+        if Instant::now() + backoff > deadline {
+            eprintln!("{} not ready, giving up: {}", what, err);
+            return Err(err);
+        }
+        eprintln!("{} not ready, retrying in {:?}: {}", what, backoff, err);
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(retry.max_backoff);
+        attempts += 1;
+    }
+}
@@ -0,0 +1,14 @@
+use std::time::Duration;
+
+use crate::db::Db;
+
+/// Recounts what `GetStats` reports every `interval` until the task is
+/// dropped, see `Db::refresh_stats`.
+pub async fn refresh(db: Db, interval: Duration) {
+    loop {
+        if let Err(err) = db.refresh_stats().await {
+            eprintln!("stats refresh failed: {}", err);
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
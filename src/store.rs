@@ -0,0 +1,465 @@
+use std::future::Future;
+
+#[cfg(feature = "pgvector")]
+use crate::db::Similar;
+use crate::{
+    config::Quota,
+    db::{
+        AuditEntry, Db, DbError, Event, Exchange, FeatureFlag, GreetingStats, Message, NameCount,
+        Session, Usage,
+    },
+};
+
+/// Where greetings and their event log are kept. `Db` is the Postgres
+/// store the server runs on; implement this to run `MyGreeter` on other
+/// persistence:
+///
+/// ```ignore
+/// let greeter = MyGreeter::new(MyStore::connect().await?, config);
+/// Server::builder()
+///     .add_service(GreeterServer::new(greeter))
+///     .serve(addr)
+///     .await?;
+/// ```
+///
+/// Messages get increasing ids, which the streaming calls use as cursors,
+/// and every write appends to the event log read by `get_events_page`.
+pub trait MessageStore: Clone + Send + Sync + 'static {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    fn get_messages(&self) -> impl Future<Output = Result<Vec<Message>, Self::Error>> + Send;
+
+    /// Messages whose tags contain all of `tags` and whose metadata
+    /// contains `metadata`, both JSON objects.
+    fn get_messages_tagged(
+        &self,
+        tags: &serde_json::Value,
+        metadata: &serde_json::Value,
+    ) -> impl Future<Output = Result<Vec<Message>, Self::Error>> + Send;
+
+    fn count_messages(&self) -> impl Future<Output = Result<i64, Self::Error>> + Send;
+
+    /// Messages after `id` in id order.
+    fn get_messages_after(
+        &self,
+        id: i32,
+    ) -> impl Future<Output = Result<Vec<Message>, Self::Error>> + Send;
+
+    /// Up to `limit` messages after `after_id` in id order.
+    fn get_messages_page(
+        &self,
+        after_id: i32,
+        limit: i64,
+    ) -> impl Future<Output = Result<Vec<Message>, Self::Error>> + Send;
+
+    fn insert_message(
+        &self,
+        message: &str,
+    ) -> impl Future<Output = Result<Message, Self::Error>> + Send;
+
+    fn insert_tagged_message(
+        &self,
+        message: &str,
+        tags: &serde_json::Value,
+        metadata: &serde_json::Value,
+        priority: i16,
+        payload: Option<&[u8]>,
+        country: Option<&str>,
+    ) -> impl Future<Output = Result<Message, Self::Error>> + Send;
+
+    /// Inserts all of `messages` or none of them.
+    fn insert_messages(
+        &self,
+        messages: &[String],
+    ) -> impl Future<Output = Result<Vec<Message>, Self::Error>> + Send;
+
+    fn update_message(
+        &self,
+        id: i32,
+        message: &str,
+    ) -> impl Future<Output = Result<Option<Message>, Self::Error>> + Send;
+
+    /// The payload of message `id`, `None` when it has none or doesn't exist.
+    fn get_payload(
+        &self,
+        id: i32,
+    ) -> impl Future<Output = Result<Option<Vec<u8>>, Self::Error>> + Send;
+
+    /// Replaces the payload of message `id`, returns whether it exists.
+    fn set_payload(
+        &self,
+        id: i32,
+        payload: &[u8],
+    ) -> impl Future<Output = Result<bool, Self::Error>> + Send;
+
+    /// Key of the attachment of message `id`, `None` when it has none or
+    /// doesn't exist.
+    fn get_attachment_key(
+        &self,
+        id: i32,
+    ) -> impl Future<Output = Result<Option<String>, Self::Error>> + Send;
+
+    /// Sets the key of the attachment of message `id`, returns whether it
+    /// exists.
+    fn set_attachment_key(
+        &self,
+        id: i32,
+        key: &str,
+    ) -> impl Future<Output = Result<bool, Self::Error>> + Send;
+
+    fn delete_message(&self, id: i32) -> impl Future<Output = Result<bool, Self::Error>> + Send;
+
+    /// Deletes the messages `ids` along with every copy of their text kept
+    /// elsewhere, e.g. in event payloads. Returns how many existed.
+    fn erase_messages(
+        &self,
+        ids: &[i32],
+    ) -> impl Future<Output = Result<usize, Self::Error>> + Send;
+
+    /// Up to `limit` events after `after_seq` in log order.
+    fn get_events_page(
+        &self,
+        after_seq: i64,
+        limit: i64,
+    ) -> impl Future<Output = Result<Vec<Event>, Self::Error>> + Send;
+
+    /// Seq of the newest event, 0 while the log is empty.
+    fn latest_event_seq(&self) -> impl Future<Output = Result<i64, Self::Error>> + Send;
+
+    /// Keeps `message_id` for the next subscriber, see `durable_delivery`.
+    fn add_pending_delivery(
+        &self,
+        message_id: i32,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Oldest messages waiting for a subscriber, in id order. They stay
+    /// pending until `delete_pending_deliveries`.
+    fn get_pending_deliveries(
+        &self,
+        limit: i64,
+    ) -> impl Future<Output = Result<Vec<Message>, Self::Error>> + Send;
+
+    fn delete_pending_deliveries(
+        &self,
+        message_ids: &[i32],
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Id up to which `subscriber` acked every message, 0 for a new one.
+    fn get_acked_until(
+        &self,
+        subscriber: &str,
+    ) -> impl Future<Output = Result<i32, Self::Error>> + Send;
+
+    /// Moves the ack position of `subscriber` forward to `acked_until`,
+    /// never back.
+    fn set_acked_until(
+        &self,
+        subscriber: &str,
+        acked_until: i32,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Id of the last message the subscription `name` delivered, 0 for a new
+    /// one.
+    fn get_delivered_until(
+        &self,
+        name: &str,
+    ) -> impl Future<Output = Result<i32, Self::Error>> + Send;
+
+    /// Moves the position of the subscription `name` forward to
+    /// `delivered_until`, never back.
+    fn set_delivered_until(
+        &self,
+        name: &str,
+        delivered_until: i32,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Adds `added` to the usage of `tenant` and returns the new usage, or
+    /// `None` and leaves it as is when that would exceed `quota`. Negative
+    /// amounts give usage back.
+    fn add_usage(
+        &self,
+        tenant: &str,
+        added: Usage,
+        quota: Quota,
+    ) -> impl Future<Output = Result<Option<Usage>, Self::Error>> + Send;
+
+    fn get_usage(&self, tenant: &str) -> impl Future<Output = Result<Usage, Self::Error>> + Send;
+
+    fn get_feature_flags(
+        &self,
+    ) -> impl Future<Output = Result<Vec<FeatureFlag>, Self::Error>> + Send;
+
+    /// Turns flag `name` on or off for `tenant`, `*` for the tenants without
+    /// a setting of their own.
+    fn set_feature_flag(
+        &self,
+        name: &str,
+        tenant: &str,
+        enabled: bool,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Greetings per day of the last `days` days and the `top` names
+    /// greeted most. May lag behind the messages, e.g. `Db` counts them
+    /// periodically.
+    fn get_stats(
+        &self,
+        days: i32,
+        top: i64,
+    ) -> impl Future<Output = Result<GreetingStats, Self::Error>> + Send;
+
+    /// Adds `counts` to the greetings counted per name, see
+    /// `leaderboard::Leaderboard`.
+    fn add_name_counts(
+        &self,
+        counts: &[NameCount],
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    fn get_name_counts(&self) -> impl Future<Output = Result<Vec<NameCount>, Self::Error>> + Send;
+
+    /// Drops the greetings counted for `name`.
+    fn delete_name_count(&self, name: &str)
+        -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Appends `action` taken on `subject` for `tenant` to the audit log.
+    fn add_audit_entry(
+        &self,
+        tenant: &str,
+        action: &str,
+        subject: &str,
+        detail: &str,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// The newest `limit` entries of the audit log, oldest first.
+    fn get_audit_log(
+        &self,
+        limit: i64,
+    ) -> impl Future<Output = Result<Vec<AuditEntry>, Self::Error>> + Send;
+
+    /// Records a new `SayHelloStream` session of `tenant`, returns its id.
+    fn start_session(&self, tenant: &str) -> impl Future<Output = Result<i64, Self::Error>> + Send;
+
+    /// Appends each exchange to the transcript of its session, in the order
+    /// given.
+    fn append_exchanges(
+        &self,
+        exchanges: &[(i64, Exchange)],
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    fn end_session(&self, id: i64) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    fn get_session(
+        &self,
+        id: i64,
+    ) -> impl Future<Output = Result<Option<Session>, Self::Error>> + Send;
+
+    /// Stores the embedding of message `message_id`, replacing any it had.
+    #[cfg(feature = "pgvector")]
+    fn set_embedding(
+        &self,
+        message_id: i32,
+        embedding: &[f32],
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Up to `limit` messages whose embeddings are nearest to `embedding` by
+    /// cosine distance, nearest first.
+    #[cfg(feature = "pgvector")]
+    fn find_similar(
+        &self,
+        embedding: &[f32],
+        limit: i64,
+    ) -> impl Future<Output = Result<Vec<Similar>, Self::Error>> + Send;
+}
+
+impl MessageStore for Db {
+    type Error = DbError;
+
+    async fn get_messages(&self) -> Result<Vec<Message>, DbError> {
+        Db::get_messages(self).await
+    }
+
+    async fn get_messages_tagged(
+        &self,
+        tags: &serde_json::Value,
+        metadata: &serde_json::Value,
+    ) -> Result<Vec<Message>, DbError> {
+        Db::get_messages_tagged(self, tags, metadata).await
+    }
+
+    async fn count_messages(&self) -> Result<i64, DbError> {
+        Db::count_messages(self).await
+    }
+
+    async fn get_messages_after(&self, id: i32) -> Result<Vec<Message>, DbError> {
+        Db::get_messages_after(self, id).await
+    }
+
+    async fn get_messages_page(&self, after_id: i32, limit: i64) -> Result<Vec<Message>, DbError> {
+        Db::get_messages_page(self, after_id, limit).await
+    }
+
+    async fn insert_message(&self, message: &str) -> Result<Message, DbError> {
+        Db::insert_message(self, message).await
+    }
+
+    async fn insert_tagged_message(
+        &self,
+        message: &str,
+        tags: &serde_json::Value,
+        metadata: &serde_json::Value,
+        priority: i16,
+        payload: Option<&[u8]>,
+        country: Option<&str>,
+    ) -> Result<Message, DbError> {
+        Db::insert_tagged_message(self, message, tags, metadata, priority, payload, country).await
+    }
+
+    async fn insert_messages(&self, messages: &[String]) -> Result<Vec<Message>, DbError> {
+        Db::insert_messages(self, messages).await
+    }
+
+    async fn update_message(&self, id: i32, message: &str) -> Result<Option<Message>, DbError> {
+        Db::update_message(self, id, message).await
+    }
+
+    async fn get_payload(&self, id: i32) -> Result<Option<Vec<u8>>, DbError> {
+        Db::get_payload(self, id).await
+    }
+
+    async fn set_payload(&self, id: i32, payload: &[u8]) -> Result<bool, DbError> {
+        Db::set_payload(self, id, payload).await
+    }
+
+    async fn get_attachment_key(&self, id: i32) -> Result<Option<String>, DbError> {
+        Db::get_attachment_key(self, id).await
+    }
+
+    async fn set_attachment_key(&self, id: i32, key: &str) -> Result<bool, DbError> {
+        Db::set_attachment_key(self, id, key).await
+    }
+
+    async fn delete_message(&self, id: i32) -> Result<bool, DbError> {
+        Db::delete_message(self, id).await
+    }
+
+    async fn erase_messages(&self, ids: &[i32]) -> Result<usize, DbError> {
+        Db::erase_messages(self, ids).await
+    }
+
+    async fn get_events_page(&self, after_seq: i64, limit: i64) -> Result<Vec<Event>, DbError> {
+        Db::get_events_page(self, after_seq, limit).await
+    }
+
+    async fn latest_event_seq(&self) -> Result<i64, DbError> {
+        Db::latest_event_seq(self).await
+    }
+
+    async fn add_pending_delivery(&self, message_id: i32) -> Result<(), DbError> {
+        Db::add_pending_delivery(self, message_id).await
+    }
+
+    async fn get_pending_deliveries(&self, limit: i64) -> Result<Vec<Message>, DbError> {
+        Db::get_pending_deliveries(self, limit).await
+    }
+
+    async fn delete_pending_deliveries(&self, message_ids: &[i32]) -> Result<(), DbError> {
+        Db::delete_pending_deliveries(self, message_ids).await
+    }
+
+    async fn get_acked_until(&self, subscriber: &str) -> Result<i32, DbError> {
+        Db::get_acked_until(self, subscriber).await
+    }
+
+    async fn set_acked_until(&self, subscriber: &str, acked_until: i32) -> Result<(), DbError> {
+        Db::set_acked_until(self, subscriber, acked_until).await
+    }
+
+    async fn get_delivered_until(&self, name: &str) -> Result<i32, DbError> {
+        Db::get_delivered_until(self, name).await
+    }
+
+    async fn set_delivered_until(&self, name: &str, delivered_until: i32) -> Result<(), DbError> {
+        Db::set_delivered_until(self, name, delivered_until).await
+    }
+
+    async fn add_usage(
+        &self,
+        tenant: &str,
+        added: Usage,
+        quota: Quota,
+    ) -> Result<Option<Usage>, DbError> {
+        Db::add_usage(self, tenant, added, quota).await
+    }
+
+    async fn get_usage(&self, tenant: &str) -> Result<Usage, DbError> {
+        Db::get_usage(self, tenant).await
+    }
+
+    async fn get_feature_flags(&self) -> Result<Vec<FeatureFlag>, DbError> {
+        Db::get_feature_flags(self).await
+    }
+
+    async fn set_feature_flag(
+        &self,
+        name: &str,
+        tenant: &str,
+        enabled: bool,
+    ) -> Result<(), DbError> {
+        Db::set_feature_flag(self, name, tenant, enabled).await
+    }
+
+    async fn get_stats(&self, days: i32, top: i64) -> Result<GreetingStats, DbError> {
+        Db::get_stats(self, days, top).await
+    }
+
+    async fn add_name_counts(&self, counts: &[NameCount]) -> Result<(), DbError> {
+        Db::add_name_counts(self, counts).await
+    }
+
+    async fn get_name_counts(&self) -> Result<Vec<NameCount>, DbError> {
+        Db::get_name_counts(self).await
+    }
+
+    async fn delete_name_count(&self, name: &str) -> Result<(), DbError> {
+        Db::delete_name_count(self, name).await
+    }
+
+    async fn add_audit_entry(
+        &self,
+        tenant: &str,
+        action: &str,
+        subject: &str,
+        detail: &str,
+    ) -> Result<(), DbError> {
+        Db::add_audit_entry(self, tenant, action, subject, detail).await
+    }
+
+    async fn get_audit_log(&self, limit: i64) -> Result<Vec<AuditEntry>, DbError> {
+        Db::get_audit_log(self, limit).await
+    }
+
+    async fn start_session(&self, tenant: &str) -> Result<i64, DbError> {
+        Db::start_session(self, tenant).await
+    }
+
+    async fn append_exchanges(&self, exchanges: &[(i64, Exchange)]) -> Result<(), DbError> {
+        Db::append_exchanges(self, exchanges).await
+    }
+
+    async fn end_session(&self, id: i64) -> Result<(), DbError> {
+        Db::end_session(self, id).await
+    }
+
+    async fn get_session(&self, id: i64) -> Result<Option<Session>, DbError> {
+        Db::get_session(self, id).await
+    }
+
+    #[cfg(feature = "pgvector")]
+    async fn set_embedding(&self, message_id: i32, embedding: &[f32]) -> Result<(), DbError> {
+        Db::set_embedding(self, message_id, embedding).await
+    }
+
+    #[cfg(feature = "pgvector")]
+    async fn find_similar(&self, embedding: &[f32], limit: i64) -> Result<Vec<Similar>, DbError> {
+        Db::find_similar(self, embedding, limit).await
+    }
+}
@@ -0,0 +1,492 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    future::{self, Future},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::sync::{mpsc, Notify};
+use tokio::time::{self, Instant, Interval, MissedTickBehavior, Sleep};
+use tokio_stream::Stream;
+use tokio_util::sync::{CancellationToken, DropGuard};
+use tonic::Status;
+
+use crate::panic;
+
+static ACTIVE_STREAMS: AtomicU64 = AtomicU64::new(0);
+
+/// Response streams being fed, see `spawn_feeder`.
+pub fn active_streams() -> u64 {
+    ACTIVE_STREAMS.load(Ordering::Relaxed)
+}
+
+/// Spawns a task feeding a response stream through `tx`. Should it panic the
+/// stream ends with an `INTERNAL` status rather than looking complete.
+pub fn spawn_feeder<T, F>(tx: mpsc::Sender<Result<T, Status>>, task: F)
+where
+    T: Send + 'static,
+    F: Future<Output = ()> + Send + 'static,
+{
+    ACTIVE_STREAMS.fetch_add(1, Ordering::Relaxed);
+    let handle = tokio::spawn(task);
+    tokio::spawn(async move {
+        let result = handle.await;
+        ACTIVE_STREAMS.fetch_sub(1, Ordering::Relaxed);
+        if let Err(err) = result {
+            if let Ok(payload) = err.try_into_panic() {
+                eprintln!("stream task panicked: {}", panic::message(payload.as_ref()));
+                let _ = tx.send(Err(Status::internal("internal error"))).await;
+            }
+        }
+    });
+}
+
+/// Wraps a response stream so that its token is cancelled once tonic drops
+/// the stream, which happens as soon as the client disconnects or the call is
+/// torn down. Tasks feeding the stream select on the token to stop promptly.
+pub struct CancelOnDrop<S> {
+    inner: S,
+    _guard: DropGuard,
+}
+
+impl<S> CancelOnDrop<S> {
+    pub fn new(inner: S, token: CancellationToken) -> Self {
+        Self {
+            inner,
+            _guard: token.drop_guard(),
+        }
+    }
+}
+
+impl<S: Stream + Unpin> Stream for CancelOnDrop<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner).poll_next(cx)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+/// Lets the replies of a response stream out before the error ending it.
+/// tonic drops the replies it encoded but hasn't sent yet once the stream
+/// fails, so an error right behind them would take them down with it: it is
+/// held back for a poll, which has tonic send what it has first.
+pub struct ErrorsLast<S> {
+    inner: S,
+    held: Option<Status>,
+    /// A reply went out since tonic last found the stream pending.
+    replied: bool,
+}
+
+impl<S> ErrorsLast<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            held: None,
+            replied: false,
+        }
+    }
+}
+
+impl<S, T> Stream for ErrorsLast<S>
+where
+    S: Stream<Item = Result<T, Status>> + Unpin,
+{
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(status) = self.held.take() {
+            return Poll::Ready(Some(Err(status)));
+        }
+        match Pin::new(&mut self.inner).poll_next(cx) {
+            Poll::Ready(Some(Ok(reply))) => {
+                self.replied = true;
+                Poll::Ready(Some(Ok(reply)))
+            }
+            Poll::Ready(Some(Err(status))) if self.replied => {
+                self.replied = false;
+                self.held = Some(status);
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+            poll => {
+                self.replied = false;
+                poll
+            }
+        }
+    }
+}
+
+/// Bytes of the replies fed into a response stream that tonic hasn't taken
+/// yet, which a client reading slowly lets grow. Cheap to clone.
+#[derive(Clone, Default)]
+pub struct SendBuffer {
+    bytes: Arc<AtomicUsize>,
+    taken: Arc<Notify>,
+    overflowed: Arc<AtomicBool>,
+}
+
+impl SendBuffer {
+    /// Counts `reply` as buffered, until `Buffered` passes it on.
+    pub fn add<T: prost::Message>(&self, reply: &T) {
+        self.bytes.fetch_add(reply.encoded_len(), Ordering::Relaxed);
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Waits for the buffer to go down to `bytes`.
+    pub async fn drained(&self, bytes: usize) {
+        loop {
+            let taken = self.taken.notified();
+            if self.bytes() <= bytes {
+                return;
+            }
+            taken.await;
+        }
+    }
+
+    /// Ends the stream with `RESOURCE_EXHAUSTED`, the replies still buffered
+    /// are dropped.
+    pub fn overflow(&self) {
+        self.overflowed.store(true, Ordering::Relaxed);
+    }
+
+    fn take(&self, bytes: usize) {
+        self.bytes.fetch_sub(bytes, Ordering::Relaxed);
+        self.taken.notify_waiters();
+    }
+}
+
+/// Wraps a response stream whose replies are counted in `buffer` as they
+/// are fed, to take them off as tonic takes them.
+pub struct Buffered<S> {
+    inner: S,
+    buffer: SendBuffer,
+    ended: bool,
+}
+
+impl<S> Buffered<S> {
+    pub fn new(inner: S, buffer: SendBuffer) -> Self {
+        Self {
+            inner,
+            buffer,
+            ended: false,
+        }
+    }
+}
+
+impl<S, T> Stream for Buffered<S>
+where
+    S: Stream<Item = Result<T, Status>> + Unpin,
+    T: prost::Message,
+{
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.ended {
+            return Poll::Ready(None);
+        }
+        if self.buffer.overflowed.load(Ordering::Relaxed) {
+            self.ended = true;
+            let status = Status::resource_exhausted("too many replies unread, stream dropped");
+            return Poll::Ready(Some(Err(status)));
+        }
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+        if let Poll::Ready(Some(Ok(reply))) = &poll {
+            self.buffer.take(reply.encoded_len());
+        }
+        poll
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+/// Holds the replies of a burst back for `linger`, or until `max` of them
+/// are waiting, then lets them all through at once. tonic packs the replies
+/// it finds ready into one data frame, so a burst of broadcasts goes out in
+/// a few frames and writes rather than one per reply. A zero `linger` lets
+/// replies through as they come.
+pub struct Coalesced<S: Stream> {
+    inner: S,
+    linger: Duration,
+    max: usize,
+    held: VecDeque<S::Item>,
+    /// Letting the held replies through.
+    releasing: bool,
+    deadline: Option<Pin<Box<Sleep>>>,
+    ended: bool,
+}
+
+impl<S: Stream> Coalesced<S> {
+    pub fn new(inner: S, linger: Duration, max: usize) -> Self {
+        Self {
+            inner,
+            linger,
+            max: max.max(1),
+            held: VecDeque::new(),
+            releasing: false,
+            deadline: None,
+            ended: false,
+        }
+    }
+}
+
+impl<S: Stream + Unpin> Stream for Coalesced<S>
+where
+    S::Item: Unpin,
+{
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if this.releasing {
+                if let Some(item) = this.held.pop_front() {
+                    return Poll::Ready(Some(item));
+                }
+                this.releasing = false;
+                this.deadline = None;
+            }
+            while this.held.len() < this.max && !this.ended {
+                match Pin::new(&mut this.inner).poll_next(cx) {
+                    Poll::Ready(Some(item)) => this.held.push_back(item),
+                    Poll::Ready(None) => this.ended = true,
+                    Poll::Pending => break,
+                }
+            }
+            if this.held.is_empty() {
+                return if this.ended {
+                    Poll::Ready(None)
+                } else {
+                    Poll::Pending
+                };
+            }
+            if this.held.len() < this.max && !this.ended && !this.linger.is_zero() {
+                let linger = this.linger;
+                let deadline = this
+                    .deadline
+                    .get_or_insert_with(|| Box::pin(time::sleep(linger)));
+                if deadline.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+            }
+            this.releasing = true;
+        }
+    }
+}
+
+/// Keepalive timer for idle streams. A zero period disables it, in which case
+/// `tick` never completes.
+pub struct Heartbeat {
+    interval: Option<Interval>,
+}
+
+impl Heartbeat {
+    pub fn new(period: Duration) -> Self {
+        let interval = (!period.is_zero()).then(|| {
+            let mut interval = time::interval_at(Instant::now() + period, period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
+        Self { interval }
+    }
+
+    pub async fn tick(&mut self) {
+        match self.interval.as_mut() {
+            Some(interval) => {
+                interval.tick().await;
+            }
+            None => future::pending().await,
+        }
+    }
+
+    /// Pushes the next tick a full period out, called whenever real traffic
+    /// went over the stream.
+    pub fn reset(&mut self) {
+        if let Some(interval) = self.interval.as_mut() {
+            interval.reset();
+        }
+    }
+}
+
+/// Application level flow control for bidirectional streams: every reply gets
+/// a sequence number and the client acknowledges the highest one it has
+/// processed. A zero size disables the window.
+pub struct AckWindow {
+    size: u64,
+    sent: u64,
+    acked: u64,
+}
+
+impl AckWindow {
+    pub fn new(size: u64) -> Self {
+        Self {
+            size,
+            sent: 0,
+            acked: 0,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.size > 0
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Records a cumulative ack, acks for replies never sent are clamped.
+    pub fn ack(&mut self, seq: u64) {
+        self.acked = self.acked.max(seq.min(self.sent));
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.is_enabled() && self.sent - self.acked >= self.size
+    }
+
+    /// Sequence number for the next reply.
+    pub fn next_seq(&mut self) -> u64 {
+        self.sent += 1;
+        self.sent
+    }
+}
+
+/// Replies of an acked stream waiting for their ack, by message id. Acks can
+/// come in any order, the stream is acked up to the id below the oldest
+/// outstanding reply. At most `max_outstanding` replies are kept.
+pub struct Redelivery<T> {
+    timeout: Duration,
+    max_outstanding: usize,
+    outstanding: BTreeMap<i32, (T, Instant)>,
+    sent_until: i32,
+    acked_until: i32,
+}
+
+impl<T: Clone> Redelivery<T> {
+    pub fn new(timeout: Duration, max_outstanding: usize, acked_until: i32) -> Self {
+        Self {
+            timeout,
+            max_outstanding,
+            outstanding: BTreeMap::new(),
+            sent_until: acked_until,
+            acked_until,
+        }
+    }
+
+    /// Whether another reply would be one more than the client may leave
+    /// unacked.
+    pub fn is_full(&self) -> bool {
+        self.outstanding.len() >= self.max_outstanding
+    }
+
+    pub fn sent(&mut self, id: i32, reply: T) {
+        self.outstanding.insert(id, (reply, Instant::now()));
+        self.sent_until = self.sent_until.max(id);
+    }
+
+    /// Records the ack of `id`, returns the position the stream is acked up
+    /// to when that moved.
+    pub fn ack(&mut self, id: i32) -> Option<i32> {
+        self.outstanding.remove(&id)?;
+        let acked_until = match self.outstanding.keys().next() {
+            Some(oldest) => oldest - 1,
+            None => self.sent_until,
+        };
+        (acked_until > self.acked_until).then(|| {
+            self.acked_until = acked_until;
+            acked_until
+        })
+    }
+
+    /// Replies unacked for `timeout`, their timeout starts over.
+    pub fn due(&mut self) -> Vec<T> {
+        let now = Instant::now();
+        self.outstanding
+            .values_mut()
+            .filter(|(_, sent_at)| now.duration_since(*sent_at) >= self.timeout)
+            .map(|(reply, sent_at)| {
+                *sent_at = now;
+                reply.clone()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+
+    use std::task::Waker;
+
+    const LONG: Duration = Duration::from_secs(3600);
+    const SOON: Duration = Duration::from_millis(100);
+
+    #[tokio::test]
+    async fn coalesced_releases_once_max_items_are_held() {
+        let (tx, rx) = mpsc::channel(8);
+        let mut stream = Coalesced::new(ReceiverStream::new(rx), LONG, 2);
+        for i in 1..=3 {
+            tx.send(i).await.unwrap();
+        }
+
+        assert_eq!(time::timeout(SOON, stream.next()).await, Ok(Some(1)));
+        assert_eq!(time::timeout(SOON, stream.next()).await, Ok(Some(2)));
+        // the third is held for the rest of the linger
+        assert!(time::timeout(SOON, stream.next()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn coalesced_releases_after_the_linger() {
+        let linger = Duration::from_millis(20);
+        let (tx, rx) = mpsc::channel(8);
+        let mut stream = Coalesced::new(ReceiverStream::new(rx), linger, 64);
+        let start = Instant::now();
+        tx.send(1).await.unwrap();
+
+        assert_eq!(stream.next().await, Some(1));
+        assert!(start.elapsed() >= linger);
+    }
+
+    #[tokio::test]
+    async fn coalesced_lets_held_items_out_when_the_stream_ends() {
+        let (tx, rx) = mpsc::channel(8);
+        let stream = Coalesced::new(ReceiverStream::new(rx), LONG, 64);
+        tx.send(1).await.unwrap();
+        tx.send(2).await.unwrap();
+        drop(tx);
+
+        let items = time::timeout(SOON, stream.collect::<Vec<_>>()).await;
+        assert_eq!(items, Ok(vec![1, 2]));
+    }
+
+    #[test]
+    fn errors_last_lets_the_replies_before_an_error_out_first() {
+        let items = [Ok(1), Ok(2), Err(Status::unavailable("down"))];
+        let mut stream = ErrorsLast::new(tokio_stream::iter(items));
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut poll = || match Pin::new(&mut stream).poll_next(&mut cx) {
+            Poll::Ready(Some(item)) => Some(item.map_err(|status| status.code())),
+            Poll::Ready(None) => panic!("stream ended"),
+            Poll::Pending => None,
+        };
+
+        assert_eq!(poll(), Some(Ok(1)));
+        assert_eq!(poll(), Some(Ok(2)));
+        // pending for a poll, in which tonic sends the replies
+        assert_eq!(poll(), None);
+        assert_eq!(poll(), Some(Err(tonic::Code::Unavailable)));
+    }
+}
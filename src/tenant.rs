@@ -0,0 +1,96 @@
+use std::{
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use tonic::{metadata::MetadataMap, Request};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::{ip_filter::Cidr, peer_info::PeerInfo};
+
+/// Request metadata naming the tenant a call is made for. Only trusted
+/// frontends, e.g. an authenticating proxy, may set it: `TenantLayer` drops
+/// it from the calls of any other peer, which then count as `DEFAULT_TENANT`.
+pub const TENANT_METADATA: &str = "x-tenant-id";
+
+/// Tenant of the calls without tenant metadata.
+pub const DEFAULT_TENANT: &str = "default";
+
+/// The tenant `request` is made for.
+pub fn from_request<T>(request: &Request<T>) -> String {
+    from_metadata(request.metadata())
+}
+
+/// Same as `from_request`, for metadata taken from other transports.
+pub fn from_metadata(metadata: &MetadataMap) -> String {
+    metadata
+        .get(TENANT_METADATA)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|tenant| !tenant.is_empty())
+        .unwrap_or(DEFAULT_TENANT)
+        .to_string()
+}
+
+/// Whether `peer` is one of the `frontends` allowed to name the tenant.
+pub fn is_frontend(frontends: &[Cidr], peer: Option<SocketAddr>) -> bool {
+    peer.is_some_and(|addr| frontends.iter().any(|cidr| cidr.contains(addr.ip())))
+}
+
+/// Drops `TENANT_METADATA` from the calls of peers other than `frontends`,
+/// so clients can't claim the quota of another tenant. Calls of unknown
+/// origin lose it too.
+#[derive(Clone)]
+pub struct TenantLayer {
+    frontends: Arc<Vec<Cidr>>,
+}
+
+impl TenantLayer {
+    pub fn new(frontends: Vec<Cidr>) -> Self {
+        Self {
+            frontends: Arc::new(frontends),
+        }
+    }
+}
+
+impl<S> Layer<S> for TenantLayer {
+    type Service = Tenant<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Tenant {
+            inner,
+            frontends: self.frontends.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Tenant<S> {
+    inner: S,
+    frontends: Arc<Vec<Cidr>>,
+}
+
+impl<S, B> Service<http::Request<B>> for Tenant<S>
+where
+    S: Service<http::Request<B>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: http::Request<B>) -> Self::Future {
+        if !is_frontend(&self.frontends, PeerInfo::from_http(&req).peer_addr) {
+            req.headers_mut().remove(TENANT_METADATA);
+        }
+        Box::pin(self.inner.call(req))
+    }
+}
@@ -0,0 +1,355 @@
+//! Short-lived service tokens, issued by the `Tokens` service in exchange
+//! for a client certificate or an API key. Calls present them as bearer
+//! tokens, which the auth layer checks against the signing key alone, see
+//! `authn`.
+//!
+//! Tokens are HS256 JWTs. The signing key changes every `rotation_secs`,
+//! derived from the secret of the config and the rotation period, so that
+//! replicas sharing the secret accept each other's tokens without
+//! coordinating. The key of the previous periods stays valid for as long as
+//! tokens signed with it may live.
+//!
+//! Along with a token comes a refresh token, opaque and kept hashed in the
+//! database, exchanged once for the next pair with `RefreshToken`. The
+//! `RevokeSessions` admin call revokes the sessions of a subject: its
+//! refresh tokens are revoked and the tokens issued to it until then
+//! refused, see `SessionRevocations`.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tonic::{Request, Response, Status};
+
+use crate::{
+    authn::{Credentials, ExternalIdentity, IdentityProvider},
+    config::{ApiKey, TokensConfig},
+    cooldown::TtlMap,
+    db::{Db, DbError},
+    greeter::{
+        hello_world::{
+            tokens_server::Tokens, IssueTokenReply, IssueTokenRequest, RefreshTokenRequest,
+        },
+        unix_ms,
+    },
+    peer_info::PeerInfo,
+};
+
+pub use crate::greeter::hello_world::tokens_server::TokensServer;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    alg: String,
+    typ: String,
+    /// Rotation period of the signing key.
+    kid: String,
+}
+
+/// What a token says about its holder.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Claims {
+    pub iss: String,
+    pub sub: String,
+    /// Unix epoch time in seconds the token was issued at.
+    pub iat: u64,
+    /// Unix epoch time in seconds the token is refused after.
+    pub exp: u64,
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+/// Signs and checks tokens, cheap to clone.
+#[derive(Clone)]
+pub struct TokenIssuer {
+    secret: Arc<[u8]>,
+    issuer: String,
+    ttl: Duration,
+    rotation: Duration,
+}
+
+impl TokenIssuer {
+    /// Signs with keys derived from the `secret` of `config`, a random one
+    /// when unset, whose tokens only this process accepts.
+    pub fn new(config: &TokensConfig) -> Self {
+        let secret = match &config.secret {
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => {
+                let mut secret = vec![0; 32];
+                OsRng.fill_bytes(&mut secret);
+                secret
+            }
+        };
+        Self {
+            secret: secret.into(),
+            issuer: config.issuer.clone(),
+            ttl: Duration::from_secs(config.ttl_secs),
+            rotation: Duration::from_secs(config.rotation_secs.max(1)),
+        }
+    }
+
+    /// A token for `subject` in `groups`, with the time it expires at.
+    pub fn issue(&self, subject: &str, groups: &[String]) -> (String, SystemTime) {
+        let now = now_secs();
+        let claims = Claims {
+            iss: self.issuer.clone(),
+            sub: subject.to_string(),
+            iat: now,
+            exp: now + self.ttl.as_secs(),
+            groups: groups.to_vec(),
+        };
+        let header = Header {
+            alg: "HS256".to_string(),
+            typ: "JWT".to_string(),
+            kid: self.period(now).to_string(),
+        };
+        let signed = format!("{}.{}", encode(&header), encode(&claims));
+        let signature = self.key(self.period(now)).chain_update(&signed).finalize();
+        let token = format!(
+            "{}.{}",
+            signed,
+            URL_SAFE_NO_PAD.encode(signature.into_bytes())
+        );
+        (token, UNIX_EPOCH + Duration::from_secs(claims.exp))
+    }
+
+    /// The claims of `token`, `None` unless it was signed by a key still in
+    /// force, by this issuer, and hasn't expired.
+    pub fn verify(&self, token: &str) -> Option<Claims> {
+        let (signed, signature) = token.rsplit_once('.')?;
+        let (header, claims) = signed.split_once('.')?;
+        let header: Header = decode(header)?;
+        if header.alg != "HS256" {
+            return None;
+        }
+        let period: u64 = header.kid.parse().ok()?;
+        let now = now_secs();
+        // the keys of the periods tokens issued in may still be alive
+        let oldest = self
+            .period(now)
+            .saturating_sub(self.ttl.as_secs() / self.rotation.as_secs() + 1);
+        if period > self.period(now) || period < oldest {
+            return None;
+        }
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        self.key(period)
+            .chain_update(signed)
+            .verify_slice(&signature)
+            .ok()?;
+        let claims: Claims = decode(claims)?;
+        (claims.iss == self.issuer && claims.exp > now).then_some(claims)
+    }
+
+    fn period(&self, secs: u64) -> u64 {
+        secs / self.rotation.as_secs()
+    }
+
+    /// The signing key of rotation `period`.
+    fn key(&self, period: u64) -> HmacSha256 {
+        let key = HmacSha256::new_from_slice(&self.secret)
+            .expect("HMAC takes keys of any length")
+            .chain_update(period.to_be_bytes())
+            .finalize()
+            .into_bytes();
+        HmacSha256::new_from_slice(&key).expect("HMAC takes keys of any length")
+    }
+}
+
+fn encode<T: Serialize>(part: &T) -> String {
+    URL_SAFE_NO_PAD.encode(serde_json::to_vec(part).expect("serializable"))
+}
+
+fn decode<T: for<'de> Deserialize<'de>>(part: &str) -> Option<T> {
+    serde_json::from_slice(&URL_SAFE_NO_PAD.decode(part).ok()?).ok()
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// Takes the bearer tokens of the issuer, leaving the others to the next
+/// provider.
+#[tonic::async_trait]
+impl IdentityProvider for TokenIssuer {
+    async fn authenticate(
+        &self,
+        credentials: &Credentials,
+    ) -> Result<Option<ExternalIdentity>, Box<dyn Error + Send + Sync>> {
+        let Credentials::Bearer(token) = credentials else {
+            return Ok(None);
+        };
+        Ok(self.verify(token).map(|claims| ExternalIdentity {
+            user: claims.sub,
+            groups: claims.groups,
+            issued_at: Some(UNIX_EPOCH + Duration::from_secs(claims.iat)),
+        }))
+    }
+}
+
+/// When the sessions of subjects were revoked, cached for a little while
+/// so that checking every call doesn't take a query. Revocations made
+/// through this server apply right away, those made through other replicas
+/// once the cache expires. Cheap to clone.
+#[derive(Clone)]
+pub struct SessionRevocations {
+    db: Db,
+    revoked: TtlMap<Option<SystemTime>>,
+}
+
+impl SessionRevocations {
+    pub fn new(db: Db, cache_ttl: Duration) -> Self {
+        Self {
+            db,
+            revoked: TtlMap::new(cache_ttl),
+        }
+    }
+
+    /// Whether credentials of `subject` issued at `issued_at` were revoked
+    /// since. Tokens issued within the second of a revocation are refused
+    /// too, their `iat` doesn't tell them apart.
+    pub async fn is_revoked(&self, subject: &str, issued_at: SystemTime) -> Result<bool, DbError> {
+        let revoked_at = match self.revoked.get(subject) {
+            Some(revoked_at) => revoked_at,
+            None => {
+                let revoked_at = self.db.get_session_revocation(subject).await?;
+                self.revoked.insert(subject, revoked_at);
+                revoked_at
+            }
+        };
+        Ok(revoked_at.is_some_and(|revoked_at| issued_at <= revoked_at))
+    }
+
+    /// Revokes the sessions of `subject`, returns when along with the number
+    /// of refresh tokens revoked.
+    pub async fn revoke(&self, subject: &str) -> Result<(SystemTime, usize), DbError> {
+        let (revoked_at, tokens) = self.db.revoke_sessions(subject).await?;
+        self.revoked.insert(subject, Some(revoked_at));
+        Ok((revoked_at, tokens))
+    }
+}
+
+/// The `Tokens` service.
+pub struct MyTokens {
+    issuer: TokenIssuer,
+    api_keys: HashMap<String, ApiKey>,
+    db: Db,
+    refresh_ttl: Duration,
+}
+
+impl MyTokens {
+    /// Issues tokens with `issuer`, and refresh tokens valid for
+    /// `refresh_ttl` kept in `db`, none when zero.
+    pub fn new(
+        issuer: TokenIssuer,
+        api_keys: HashMap<String, ApiKey>,
+        db: Db,
+        refresh_ttl: Duration,
+    ) -> Self {
+        Self {
+            issuer,
+            api_keys,
+            db,
+            refresh_ttl,
+        }
+    }
+
+    /// A token for `subject` in `groups`, with a refresh token.
+    async fn issue(&self, subject: String, groups: Vec<String>) -> Result<IssueTokenReply, Status> {
+        let (token, expires_at) = self.issuer.issue(&subject, &groups);
+        let mut reply = IssueTokenReply {
+            token,
+            expires_at_ms: unix_ms(expires_at),
+            subject,
+            ..Default::default()
+        };
+        if !self.refresh_ttl.is_zero() {
+            let mut refresh_token = [0; 32];
+            OsRng.fill_bytes(&mut refresh_token);
+            let refresh_token = URL_SAFE_NO_PAD.encode(refresh_token);
+            let refresh_expires_at = SystemTime::now() + self.refresh_ttl;
+            self.db
+                .insert_refresh_token(
+                    &sha256_hex(&refresh_token),
+                    &reply.subject,
+                    &groups,
+                    refresh_expires_at,
+                )
+                .await
+                .map_err(|err| Status::internal(err.to_string()))?;
+            reply.refresh_token = refresh_token;
+            reply.refresh_expires_at_ms = unix_ms(refresh_expires_at);
+        }
+        Ok(reply)
+    }
+
+    /// Name and groups of the API key `key`.
+    fn api_key(&self, key: &str) -> Option<(&str, &[String])> {
+        let digest = sha256_hex(key);
+        self.api_keys
+            .iter()
+            .find(|(_, api_key)| api_key.sha256.eq_ignore_ascii_case(&digest))
+            .map(|(name, api_key)| (name.as_str(), api_key.groups.as_slice()))
+    }
+}
+
+#[tonic::async_trait]
+impl Tokens for MyTokens {
+    async fn issue_token(
+        &self,
+        request: Request<IssueTokenRequest>,
+    ) -> Result<Response<IssueTokenReply>, Status> {
+        let peer = PeerInfo::from_request(&request);
+        let request = request.into_inner();
+        let (subject, groups) = if !request.api_key.is_empty() {
+            let (name, groups) = self
+                .api_key(&request.api_key)
+                .ok_or_else(|| Status::unauthenticated("invalid API key"))?;
+            (name.to_string(), groups.to_vec())
+        } else {
+            #[cfg(feature = "tls")]
+            let subject = peer.client_subject();
+            #[cfg(not(feature = "tls"))]
+            let subject = {
+                let _ = peer;
+                None
+            };
+            let subject = subject
+                .ok_or_else(|| Status::unauthenticated("client certificate or API key required"))?;
+            (subject, Vec::new())
+        };
+        println!("issuing a token to '{}'", subject);
+        Ok(Response::new(self.issue(subject, groups).await?))
+    }
+
+    async fn refresh_token(
+        &self,
+        request: Request<RefreshTokenRequest>,
+    ) -> Result<Response<IssueTokenReply>, Status> {
+        let request = request.into_inner();
+        let refresh_token = self
+            .db
+            .take_refresh_token(&sha256_hex(&request.refresh_token))
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?
+            .ok_or_else(|| Status::unauthenticated("invalid refresh token"))?;
+        let groups = serde_json::from_value(refresh_token.groups).unwrap_or_default();
+        Ok(Response::new(
+            self.issue(refresh_token.subject, groups).await?,
+        ))
+    }
+}
+
+fn sha256_hex(data: &str) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
@@ -0,0 +1,473 @@
+use std::{collections::HashMap, convert::Infallible, future, net::SocketAddr, sync::Arc};
+
+use axum::{
+    body::Bytes,
+    extract::{Path, Query},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{on, MethodFilter, MethodRouter},
+    Router,
+};
+use bytes::{Buf, BufMut, BytesMut};
+use hyper::body::HttpBody;
+use prost::Message as _;
+use prost_reflect::{
+    DescriptorPool, DynamicMessage, FieldDescriptor, Kind, MessageDescriptor, MethodDescriptor,
+    Value,
+};
+use serde_json::json;
+use thiserror::Error;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{body::BoxBody, Code};
+use tower_service::Service;
+
+#[derive(Error, Debug)]
+pub enum TranscodeError {
+    #[error("Descriptor error: {0}")]
+    Descriptor(#[from] prost_reflect::DescriptorError),
+    #[error("Service {0} is not in the descriptor set")]
+    UnknownService(String),
+    #[error("Invalid http rule on {method}: {reason}")]
+    InvalidRule { method: String, reason: String },
+}
+
+/// One HTTP binding of an RPC, taken from its `google.api.http` annotation.
+struct Binding {
+    method: MethodDescriptor,
+    /// Whether the JSON body maps to the request message (`body: "*"`).
+    has_body: bool,
+}
+
+/// Builds an HTTP/JSON router for `service_name` from the `google.api.http`
+/// annotations in `descriptor_set`, forwarding every call to `grpc`.
+///
+/// Unary replies are JSON objects. Client-streaming calls take a JSON array of
+/// requests and server-streaming calls reply with JSON Lines, one message per
+/// line, ending with an `{"error": ..}` line if the call fails midway.
+pub fn router<S>(
+    grpc: S,
+    descriptor_set: &[u8],
+    service_name: &str,
+) -> Result<Router, TranscodeError>
+where
+    S: Service<http::Request<hyper::Body>, Response = http::Response<BoxBody>, Error = Infallible>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    S::Future: Send,
+{
+    let pool = DescriptorPool::decode(descriptor_set)?;
+    let service = pool
+        .get_service_by_name(service_name)
+        .ok_or_else(|| TranscodeError::UnknownService(service_name.to_string()))?;
+    let Some(http_ext) = pool.get_extension_by_name("google.api.http") else {
+        return Ok(Router::new());
+    };
+
+    let mut routes: HashMap<String, MethodRouter> = HashMap::new();
+    for method in service.methods() {
+        let options = method.options();
+        let rule = options.get_extension(&http_ext);
+        let Some(rule) = rule.as_message() else {
+            continue;
+        };
+
+        let mut rules = vec![rule.clone()];
+        if let Some(additional) = rule.get_field_by_name("additional_bindings") {
+            if let Some(list) = additional.as_list() {
+                rules.extend(list.iter().filter_map(|v| v.as_message().cloned()));
+            }
+        }
+
+        for rule in rules {
+            let (filter, template) =
+                http_pattern(&rule).ok_or_else(|| TranscodeError::InvalidRule {
+                    method: method.full_name().to_string(),
+                    reason: "missing or unsupported pattern".to_string(),
+                })?;
+            let path = axum_path(&template).map_err(|reason| TranscodeError::InvalidRule {
+                method: method.full_name().to_string(),
+                reason,
+            })?;
+            let body = string_field(&rule, "body");
+            if !body.is_empty() && body != "*" {
+                return Err(TranscodeError::InvalidRule {
+                    method: method.full_name().to_string(),
+                    reason: "only `body: \"*\"` is supported".to_string(),
+                });
+            }
+
+            let binding = Arc::new(Binding {
+                method: method.clone(),
+                has_body: body == "*",
+            });
+            let grpc = grpc.clone();
+            let handler = move |params: Option<Path<HashMap<String, String>>>,
+                                Query(query): Query<Vec<(String, String)>>,
+                                body: Bytes| {
+                let binding = binding.clone();
+                let grpc = grpc.clone();
+                async move {
+                    let params = params.map(|Path(params)| params).unwrap_or_default();
+                    transcode(grpc, &binding, params, query, body).await
+                }
+            };
+
+            let route = match routes.remove(&path) {
+                Some(route) => route.on(filter, handler),
+                None => on(filter, handler),
+            };
+            routes.insert(path, route);
+        }
+    }
+
+    Ok(routes
+        .into_iter()
+        .fold(Router::new(), |router, (path, route)| {
+            router.route(&path, route)
+        }))
+}
+
+pub async fn serve(addr: SocketAddr, router: Router) -> Result<(), hyper::Error> {
+    println!("HTTP/JSON gateway listening on {}", addr);
+    axum::Server::bind(&addr)
+        .serve(router.into_make_service())
+        .await
+}
+
+fn http_pattern(rule: &DynamicMessage) -> Option<(MethodFilter, String)> {
+    let verbs = [
+        ("get", MethodFilter::GET),
+        ("put", MethodFilter::PUT),
+        ("post", MethodFilter::POST),
+        ("delete", MethodFilter::DELETE),
+        ("patch", MethodFilter::PATCH),
+    ];
+    verbs.into_iter().find_map(|(verb, filter)| {
+        let path = string_field(rule, verb);
+        (!path.is_empty()).then_some((filter, path))
+    })
+}
+
+fn string_field(msg: &DynamicMessage, name: &str) -> String {
+    msg.get_field_by_name(name)
+        .and_then(|v| v.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+/// Converts a path template like `/v1/greetings/{name}` into axum's
+/// `/v1/greetings/:name`, only single segment variables are supported.
+fn axum_path(template: &str) -> Result<String, String> {
+    let segments = template
+        .split('/')
+        .map(|segment| match segment.strip_prefix('{') {
+            Some(var) => match var.strip_suffix('}') {
+                Some(var) if !var.contains(['=', '*', '.']) => Ok(format!(":{}", var)),
+                _ => Err(format!("unsupported path variable {}", segment)),
+            },
+            None if segment.contains(':') => Err("custom verbs are not supported".to_string()),
+            None => Ok(segment.to_string()),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(segments.join("/"))
+}
+
+async fn transcode<S>(
+    mut grpc: S,
+    binding: &Binding,
+    params: HashMap<String, String>,
+    query: Vec<(String, String)>,
+    body: Bytes,
+) -> Response
+where
+    S: Service<http::Request<hyper::Body>, Response = http::Response<BoxBody>, Error = Infallible>
+        + Send
+        + 'static,
+    S::Future: Send,
+{
+    let method = &binding.method;
+    let requests = match build_requests(binding, params, query, &body) {
+        Ok(requests) => requests,
+        Err(message) => return error_response(Code::InvalidArgument, &message),
+    };
+
+    let mut frames = BytesMut::new();
+    for request in requests {
+        let encoded = request.encode_to_vec();
+        frames.put_u8(0);
+        frames.put_u32(encoded.len() as u32);
+        frames.put_slice(&encoded);
+    }
+
+    let path = format!("/{}/{}", method.parent_service().full_name(), method.name());
+    let request = http::Request::builder()
+        .method(Method::POST)
+        .uri(path)
+        .header(header::CONTENT_TYPE, "application/grpc")
+        .header("te", "trailers")
+        .body(hyper::Body::from(frames.freeze()))
+        .expect("valid grpc request");
+
+    let _ = future::poll_fn(|cx| grpc.poll_ready(cx)).await;
+    let response = match grpc.call(request).await {
+        Ok(response) => response,
+        Err(never) => match never {},
+    };
+    // errors raised before any reply come back as trailers-only responses
+    if let Some((code, message)) = grpc_status(response.headers()) {
+        return error_response(code, &message);
+    }
+
+    let output = method.output();
+    let mut body = response.into_body();
+    if method.is_server_streaming() {
+        let (tx, rx) = mpsc::channel::<Result<Bytes, Infallible>>(16);
+        tokio::spawn(async move {
+            let mut decoder = FrameDecoder::default();
+            while let Some(chunk) = body.data().await {
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(status) => {
+                        let line = error_line(status.code(), status.message());
+                        let _ = tx.send(Ok(line)).await;
+                        return;
+                    }
+                };
+                decoder.push(&chunk);
+                while let Some(frame) = decoder.next_frame() {
+                    let line = match json_line(&output, frame) {
+                        Ok(line) => line,
+                        Err(message) => error_line(Code::Internal, &message),
+                    };
+                    if tx.send(Ok(line)).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            if let Ok(Some(trailers)) = body.trailers().await {
+                if let Some((code, message)) = grpc_status(&trailers) {
+                    let _ = tx.send(Ok(error_line(code, &message))).await;
+                }
+            }
+        });
+
+        let mut response = Response::new(axum::body::boxed(hyper::Body::wrap_stream(
+            ReceiverStream::new(rx),
+        )));
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/x-ndjson"),
+        );
+        return response;
+    }
+
+    let mut decoder = FrameDecoder::default();
+    while let Some(chunk) = body.data().await {
+        match chunk {
+            Ok(chunk) => decoder.push(&chunk),
+            Err(status) => return error_response(status.code(), status.message()),
+        }
+    }
+    if let Ok(Some(trailers)) = body.trailers().await {
+        if let Some((code, message)) = grpc_status(&trailers) {
+            return error_response(code, &message);
+        }
+    }
+    let Some(frame) = decoder.next_frame() else {
+        return error_response(Code::Internal, "missing reply");
+    };
+    match DynamicMessage::decode(output, frame) {
+        Ok(reply) => match serde_json::to_vec(&reply) {
+            Ok(json) => (
+                [(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static("application/json"),
+                )],
+                json,
+            )
+                .into_response(),
+            Err(err) => error_response(Code::Internal, &err.to_string()),
+        },
+        Err(err) => error_response(Code::Internal, &err.to_string()),
+    }
+}
+
+/// Decodes the request messages of a call: a JSON array for client-streaming
+/// methods, a single JSON object otherwise. Path and query parameters are
+/// applied on top of every message.
+fn build_requests(
+    binding: &Binding,
+    params: HashMap<String, String>,
+    query: Vec<(String, String)>,
+    body: &[u8],
+) -> Result<Vec<DynamicMessage>, String> {
+    let input = binding.method.input();
+    let mut requests = if !binding.has_body || body.iter().all(u8::is_ascii_whitespace) {
+        vec![DynamicMessage::new(input.clone())]
+    } else if binding.method.is_client_streaming() {
+        let values: Vec<serde_json::Value> =
+            serde_json::from_slice(body).map_err(|err| err.to_string())?;
+        values
+            .into_iter()
+            .map(|value| {
+                DynamicMessage::deserialize(input.clone(), value).map_err(|err| err.to_string())
+            })
+            .collect::<Result<_, _>>()?
+    } else {
+        let mut deserializer = serde_json::Deserializer::from_slice(body);
+        vec![
+            DynamicMessage::deserialize(input.clone(), &mut deserializer)
+                .map_err(|err| err.to_string())?,
+        ]
+    };
+
+    for request in &mut requests {
+        for (name, raw) in query.iter().map(|(k, v)| (k, v)).chain(params.iter()) {
+            set_field(&input, request, name, raw)?;
+        }
+    }
+    Ok(requests)
+}
+
+fn set_field(
+    input: &MessageDescriptor,
+    msg: &mut DynamicMessage,
+    name: &str,
+    raw: &str,
+) -> Result<(), String> {
+    let field = input
+        .get_field_by_name(name)
+        .or_else(|| input.get_field_by_json_name(name))
+        .ok_or_else(|| format!("unknown field {}", name))?;
+    let value = parse_value(&field, raw).ok_or_else(|| format!("invalid value for {}", name))?;
+
+    if field.is_list() {
+        let mut list = msg.get_field(&field).into_owned();
+        if let Value::List(items) = &mut list {
+            items.push(value);
+        }
+        msg.set_field(&field, list);
+    } else {
+        msg.set_field(&field, value);
+    }
+    Ok(())
+}
+
+fn parse_value(field: &FieldDescriptor, raw: &str) -> Option<Value> {
+    Some(match field.kind() {
+        Kind::String => Value::String(raw.to_string()),
+        Kind::Bytes => Value::Bytes(Bytes::copy_from_slice(raw.as_bytes())),
+        Kind::Bool => Value::Bool(raw.parse().ok()?),
+        Kind::Int32 | Kind::Sint32 | Kind::Sfixed32 => Value::I32(raw.parse().ok()?),
+        Kind::Int64 | Kind::Sint64 | Kind::Sfixed64 => Value::I64(raw.parse().ok()?),
+        Kind::Uint32 | Kind::Fixed32 => Value::U32(raw.parse().ok()?),
+        Kind::Uint64 | Kind::Fixed64 => Value::U64(raw.parse().ok()?),
+        Kind::Float => Value::F32(raw.parse().ok()?),
+        Kind::Double => Value::F64(raw.parse().ok()?),
+        Kind::Enum(desc) => match raw.parse() {
+            Ok(number) => Value::EnumNumber(number),
+            Err(_) => Value::EnumNumber(desc.get_value_by_name(raw)?.number()),
+        },
+        Kind::Message(_) => return None,
+    })
+}
+
+fn json_line(output: &MessageDescriptor, frame: Bytes) -> Result<Bytes, String> {
+    let reply = DynamicMessage::decode(output.clone(), frame).map_err(|err| err.to_string())?;
+    let mut line = serde_json::to_vec(&reply).map_err(|err| err.to_string())?;
+    line.push(b'\n');
+    Ok(line.into())
+}
+
+fn grpc_status(headers: &HeaderMap) -> Option<(Code, String)> {
+    let code = headers
+        .get("grpc-status")?
+        .to_str()
+        .ok()?
+        .parse::<i32>()
+        .ok()?;
+    if code == 0 {
+        return None;
+    }
+    let message = headers
+        .get("grpc-message")
+        .and_then(|v| v.to_str().ok())
+        .map(percent_decode)
+        .unwrap_or_default();
+    Some((Code::from_i32(code), message))
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let Ok(byte) = u8::from_str_radix(&s[i + 1..i + 3], 16) {
+                out.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn error_line(code: Code, message: &str) -> Bytes {
+    let mut line = json!({ "error": { "code": code as i32, "message": message } }).to_string();
+    line.push('\n');
+    line.into()
+}
+
+fn error_response(code: Code, message: &str) -> Response {
+    let body = json!({ "code": code as i32, "message": message });
+    (http_status(code), axum::Json(body)).into_response()
+}
+
+/// Maps gRPC codes to HTTP statuses the way gRPC gateways do.
+fn http_status(code: Code) -> StatusCode {
+    match code {
+        Code::Ok => StatusCode::OK,
+        Code::Cancelled => StatusCode::from_u16(499).unwrap_or(StatusCode::BAD_REQUEST),
+        Code::InvalidArgument | Code::OutOfRange | Code::FailedPrecondition => {
+            StatusCode::BAD_REQUEST
+        }
+        Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+        Code::NotFound => StatusCode::NOT_FOUND,
+        Code::AlreadyExists | Code::Aborted => StatusCode::CONFLICT,
+        Code::PermissionDenied => StatusCode::FORBIDDEN,
+        Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+        Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+        Code::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+        Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        Code::Unknown | Code::Internal | Code::DataLoss => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Splits a gRPC response body into length-prefixed message frames.
+#[derive(Default)]
+struct FrameDecoder {
+    buf: BytesMut,
+}
+
+impl FrameDecoder {
+    fn push(&mut self, chunk: &[u8]) {
+        self.buf.extend_from_slice(chunk);
+    }
+
+    fn next_frame(&mut self) -> Option<Bytes> {
+        if self.buf.len() < 5 {
+            return None;
+        }
+        let len = u32::from_be_bytes([self.buf[1], self.buf[2], self.buf[3], self.buf[4]]) as usize;
+        if self.buf.len() < 5 + len {
+            return None;
+        }
+        self.buf.advance(5);
+        Some(self.buf.split_to(len).freeze())
+    }
+}
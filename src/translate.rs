@@ -0,0 +1,90 @@
+//! Greetings localized by an upstream translation service instead of the
+//! few salutations `greeting` knows. The upstream is the one named by
+//! `TRANSLATION_UPSTREAM` among the `[upstreams]` of the config file.
+
+use std::time::Duration;
+
+use crate::clients::Upstream;
+use crate::cooldown::TtlMap;
+use crate::greeter::hello_world::Salutation;
+use crate::greeting;
+
+pub mod proto {
+    tonic::include_proto!("translation.v1");
+}
+
+use proto::{translator_client::TranslatorClient, TranslateRequest};
+
+/// The salutation translated, the name stays as given.
+const SOURCE_TEXT: &str = "Hello";
+const SOURCE_LANGUAGE: &str = "en";
+
+/// Composes greetings with salutations translated by `upstream`, cached by
+/// language for `ttl`. When the upstream fails the greeting is composed as
+/// without it, in English for the locales `greeting` doesn't know.
+#[derive(Clone)]
+pub struct Translator {
+    upstream: Upstream,
+    cache: TtlMap<String>,
+}
+
+impl Translator {
+    pub fn new(upstream: Upstream, ttl: Duration) -> Self {
+        Self {
+            upstream,
+            cache: TtlMap::new(ttl),
+        }
+    }
+
+    /// `greeting::compose` with the salutation in the language of `locale`.
+    pub async fn compose(&self, name: &str, locale: &str, salutation: Salutation) -> String {
+        let language = locale.trim();
+        let english = language.is_empty()
+            || language
+                .split(['-', '_'])
+                .next()
+                .is_some_and(|lang| lang.eq_ignore_ascii_case(SOURCE_LANGUAGE));
+        if salutation != Salutation::Unspecified || english {
+            return greeting::compose(name, locale, salutation);
+        }
+
+        match self.salutation(language).await {
+            Some(salutation) => format!("{} {}!", salutation, name),
+            None => greeting::compose(name, locale, salutation),
+        }
+    }
+
+    async fn salutation(&self, language: &str) -> Option<String> {
+        if let Some(cached) = self.cache.get(language) {
+            return Some(cached);
+        }
+        let request = TranslateRequest {
+            text: SOURCE_TEXT.to_string(),
+            source_language: SOURCE_LANGUAGE.to_string(),
+            target_language: language.to_string(),
+        };
+        let translated = self
+            .upstream
+            .call(|channel| {
+                let request = request.clone();
+                async move { TranslatorClient::new(channel).translate(request).await }
+            })
+            .await;
+        match translated {
+            Ok(reply) if !reply.text.trim().is_empty() => {
+                let salutation = reply.text.trim().to_string();
+                self.cache.insert(language, salutation.clone());
+                Some(salutation)
+            }
+            Ok(_) => None,
+            Err(status) => {
+                eprintln!(
+                    "translation into {} failed, greeting untranslated: {}",
+                    language,
+                    status.message()
+                );
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,119 @@
+//! Versioned packages of the greeter API, served next to `helloworld`.
+//! Each package is frozen once released and adapted onto `MyGreeter`, so
+//! every version stores, greets and broadcasts the same way. A new version
+//! gets its own proto under `proto/helloworld/`, a module here and a service
+//! in `ServerBuilder`.
+
+use std::sync::Arc;
+
+use tonic::{Request, Response, Status};
+
+use crate::db::Db;
+use crate::greeter::{hello_world, hello_world::greeter_server::Greeter, MyGreeter};
+use crate::messages::{Broadcaster, Fanout};
+use crate::store::MessageStore;
+
+pub mod v1 {
+    tonic::include_proto!("helloworld.v1");
+}
+
+pub mod v2 {
+    tonic::include_proto!("helloworld.v2");
+}
+
+/// `helloworld.v1.Greeter` on top of a shared `MyGreeter`.
+pub struct GreeterV1<S = Db, B = Broadcaster> {
+    greeter: Arc<MyGreeter<S, B>>,
+}
+
+impl<S, B> GreeterV1<S, B> {
+    pub fn new(greeter: Arc<MyGreeter<S, B>>) -> Self {
+        Self { greeter }
+    }
+}
+
+#[tonic::async_trait]
+impl<S: MessageStore, B: Fanout> v1::greeter_server::Greeter for GreeterV1<S, B> {
+    async fn say_hello(
+        &self,
+        request: Request<v1::HelloRequest>,
+    ) -> Result<Response<v1::HelloReply>, Status> {
+        let request = request.map(|request| hello_world::HelloRequest {
+            name: request.name,
+            ..Default::default()
+        });
+        let reply = self.greeter.say_hello(request).await?;
+        Ok(reply.map(|reply| v1::HelloReply {
+            message: reply.message,
+        }))
+    }
+
+    async fn list_messages(
+        &self,
+        request: Request<v1::ListMessagesRequest>,
+    ) -> Result<Response<v1::ListMessagesReply>, Status> {
+        let request = request.map(|_| hello_world::ListMessagesRequest::default());
+        let reply = self.greeter.list_messages(request).await?;
+        Ok(reply.map(|reply| v1::ListMessagesReply {
+            messages: reply.messages,
+        }))
+    }
+}
+
+/// `helloworld.v2.Greeter` on top of a shared `MyGreeter`.
+pub struct GreeterV2<S = Db, B = Broadcaster> {
+    greeter: Arc<MyGreeter<S, B>>,
+}
+
+impl<S, B> GreeterV2<S, B> {
+    pub fn new(greeter: Arc<MyGreeter<S, B>>) -> Self {
+        Self { greeter }
+    }
+}
+
+impl From<v2::Salutation> for hello_world::Salutation {
+    fn from(salutation: v2::Salutation) -> Self {
+        match salutation {
+            v2::Salutation::Unspecified => Self::Unspecified,
+            v2::Salutation::Hello => Self::Hello,
+            v2::Salutation::Hi => Self::Hi,
+            v2::Salutation::Greetings => Self::Greetings,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl<S: MessageStore, B: Fanout> v2::greeter_server::Greeter for GreeterV2<S, B> {
+    async fn say_hello(
+        &self,
+        request: Request<v2::HelloRequest>,
+    ) -> Result<Response<v2::HelloReply>, Status> {
+        let request = request.map(|request| hello_world::HelloRequest {
+            salutation: hello_world::Salutation::from(request.salutation()).into(),
+            name: request.name,
+            locale: request.locale,
+            client_version: request.client_version,
+            tags: request.tags,
+            ..Default::default()
+        });
+        let reply = self.greeter.say_hello(request).await?;
+        Ok(reply.map(|reply| v2::HelloReply {
+            message: reply.message,
+            id: reply.cursor,
+        }))
+    }
+
+    async fn list_messages(
+        &self,
+        request: Request<v2::ListMessagesRequest>,
+    ) -> Result<Response<v2::ListMessagesReply>, Status> {
+        let request = request.map(|request| hello_world::ListMessagesRequest {
+            tags: request.tags,
+            ..Default::default()
+        });
+        let reply = self.greeter.list_messages(request).await?;
+        Ok(reply.map(|reply| v2::ListMessagesReply {
+            messages: reply.messages,
+        }))
+    }
+}
@@ -0,0 +1,313 @@
+//! Stores the names `SayHelloStream` calls greet, off their reply path. The
+//! calls queue their exchanges with a writer task of the greeter, which
+//! stores the names it finds waiting in a batch per tenant, so a slow
+//! database costs a round trip per batch rather than one per name, and
+//! takes one connection of the pool instead of one per call.
+//!
+//! `stream_write_queue` bounds the names waiting across calls, the
+//! `stream_write_overflow` policy says what calls do past that. Errors that
+//! end a call, the tenant out of quota or the server turning read-only, go
+//! to its response stream.
+
+use std::collections::HashSet;
+
+use tokio::{
+    runtime::Handle,
+    sync::mpsc::{self, error::TrySendError},
+};
+use tonic::Status;
+
+use crate::{
+    config::{Config, WriteOverflowPolicy},
+    db::Exchange,
+    greeter::hello_world::HelloReply,
+    messages::Fanout,
+    service::{GreetingService, ServiceError},
+    store::MessageStore,
+};
+
+type Replies = mpsc::Sender<Result<HelloReply, Status>>;
+
+/// The queue of the writer task, which runs until every clone is dropped.
+#[derive(Clone)]
+pub struct ExchangeWriter {
+    queue: mpsc::Sender<Write>,
+    overflow: WriteOverflowPolicy,
+}
+
+enum Write {
+    Exchange(Pending),
+    /// The session is over, it ends once what it queued before is stored.
+    End(i64),
+}
+
+struct Pending {
+    tenant: String,
+    session_id: i64,
+    exchange: Exchange,
+    replies: Replies,
+}
+
+impl ExchangeWriter {
+    /// Spawns the writer task, storing through `service`.
+    pub fn spawn<S: MessageStore, B: Fanout>(
+        service: GreetingService<S, B>,
+        config: &Config,
+    ) -> Self {
+        let (queue, rx) = mpsc::channel(config.stream_write_queue);
+        tokio::spawn(run(service, rx, config.stream_write_batch.max(1)));
+        Self {
+            queue,
+            overflow: config.stream_write_overflow,
+        }
+    }
+
+    /// The writes of session `session_id` of `tenant`, whose errors go to
+    /// `replies`. The session ends once they are dropped.
+    pub fn session(&self, tenant: String, session_id: i64, replies: Replies) -> SessionWrites {
+        SessionWrites {
+            writer: self.clone(),
+            tenant,
+            session_id,
+            replies,
+        }
+    }
+}
+
+/// The writes of a session, ending it once dropped.
+pub struct SessionWrites {
+    writer: ExchangeWriter,
+    tenant: String,
+    session_id: i64,
+    replies: Replies,
+}
+
+impl SessionWrites {
+    /// Queues `exchange`, the status to end the call with when the queue is
+    /// full under the `reject` policy or the writer stopped.
+    pub async fn write(&self, exchange: Exchange) -> Result<(), Status> {
+        let write = Write::Exchange(Pending {
+            tenant: self.tenant.clone(),
+            session_id: self.session_id,
+            exchange,
+            replies: self.replies.clone(),
+        });
+        let queue = &self.writer.queue;
+        match self.writer.overflow {
+            WriteOverflowPolicy::Block => queue.send(write).await.map_err(|_| stopped()),
+            WriteOverflowPolicy::Reject => match queue.try_send(write) {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Full(_)) => Err(Status::unavailable(
+                    "greetings come in faster than they are stored, try again later",
+                )),
+                Err(TrySendError::Closed(_)) => Err(stopped()),
+            },
+        }
+    }
+}
+
+impl Drop for SessionWrites {
+    fn drop(&mut self) {
+        // a full queue is waited on by a task, when there is a runtime left
+        // to run it
+        if let Err(TrySendError::Full(end)) =
+            self.writer.queue.try_send(Write::End(self.session_id))
+        {
+            if let Ok(runtime) = Handle::try_current() {
+                let queue = self.writer.queue.clone();
+                runtime.spawn(async move {
+                    let _ = queue.send(end).await;
+                });
+            }
+        }
+    }
+}
+
+fn stopped() -> Status {
+    Status::unavailable("greetings can't be stored")
+}
+
+async fn run<S: MessageStore, B: Fanout>(
+    service: GreetingService<S, B>,
+    mut queue: mpsc::Receiver<Write>,
+    batch_size: usize,
+) {
+    // sessions whose call was ended, what they queued since is dropped
+    let mut failed = HashSet::new();
+    let mut pending = Vec::with_capacity(batch_size);
+    while let Some(write) = queue.recv().await {
+        let mut next = Some(write);
+        while let Some(write) = next.take() {
+            match write {
+                Write::Exchange(write) if failed.contains(&write.session_id) => (),
+                Write::Exchange(write) => pending.push(write),
+                Write::End(session_id) => {
+                    store(&service, &mut pending, &mut failed).await;
+                    failed.remove(&session_id);
+                    if let Err(err) = service.end_session(session_id).await {
+                        eprintln!("failed to end session {}: {}", session_id, err);
+                    }
+                }
+            }
+            if pending.len() < batch_size {
+                next = queue.try_recv().ok();
+            }
+        }
+        store(&service, &mut pending, &mut failed).await;
+    }
+}
+
+/// Stores `pending` in a batch per tenant, in the order they came.
+async fn store<S: MessageStore, B: Fanout>(
+    service: &GreetingService<S, B>,
+    pending: &mut Vec<Pending>,
+    failed: &mut HashSet<i64>,
+) {
+    let exchanges = pending
+        .iter()
+        .map(|write| (write.session_id, write.exchange.clone()))
+        .collect::<Vec<_>>();
+    if let Err(err) = service.record_exchanges(&exchanges).await {
+        eprintln!("failed to record exchanges: {}", err);
+    }
+
+    let mut tenants = Vec::<&str>::new();
+    for write in pending.iter() {
+        if !tenants.contains(&write.tenant.as_str()) {
+            tenants.push(&write.tenant);
+        }
+    }
+    for tenant in tenants {
+        let writes = pending
+            .iter()
+            .filter(|write| write.tenant == tenant)
+            .collect::<Vec<_>>();
+        let names = writes
+            .iter()
+            .map(|write| write.exchange.name.clone())
+            .collect::<Vec<_>>();
+        match service.store_messages(tenant, &names, true).await {
+            Ok(_) => (),
+            // one at a time, to store what the quota allows and end the
+            // calls of the others
+            Err(ServiceError::QuotaExceeded(_) | ServiceError::ReadOnly) => {
+                for write in writes {
+                    if failed.contains(&write.session_id) {
+                        continue;
+                    }
+                    match service.store_message(tenant, &write.exchange.name).await {
+                        Ok(_) => (),
+                        Err(err @ (ServiceError::QuotaExceeded(_) | ServiceError::ReadOnly)) => {
+                            failed.insert(write.session_id);
+                            // the stream may be full, the writer doesn't wait for it
+                            let replies = write.replies.clone();
+                            tokio::spawn(async move {
+                                let _ = replies.send(Err(err.into())).await;
+                            });
+                        }
+                        Err(err) => eprintln!("failed to insert message: {}", err),
+                    }
+                }
+            }
+            Err(err) => eprintln!("failed to insert messages: {}", err),
+        }
+    }
+    pending.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tonic::Code;
+
+    use super::*;
+    use crate::mock::MockMessageStore;
+
+    fn exchange(name: &str) -> Exchange {
+        Exchange {
+            name: name.to_string(),
+            reply: format!("Hello {}!", name),
+            at_ms: 0,
+        }
+    }
+
+    /// Waits for the writer to end session `id`, returns what it recorded.
+    async fn ended(store: &MockMessageStore, id: i64) -> Vec<Exchange> {
+        for _ in 0..100 {
+            let session = store.get_session(id).await.unwrap().unwrap();
+            if session.ended_at.is_some() {
+                return session.exchanges;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("session {} was not ended", id);
+    }
+
+    #[tokio::test]
+    async fn writes_of_different_calls_are_stored_in_one_batch() {
+        let store = MockMessageStore::new();
+        let config = Config::default();
+        let service = GreetingService::new(store.clone(), &config);
+        let first = service.start_session("acme").await.unwrap();
+        let second = service.start_session("acme").await.unwrap();
+        let writer = ExchangeWriter::spawn(service, &config);
+
+        // the writer task doesn't run before the test yields
+        let (replies, _rx) = mpsc::channel(1);
+        let a = writer.session("acme".to_string(), first, replies.clone());
+        let b = writer.session("acme".to_string(), second, replies);
+        a.write(exchange("Ana")).await.unwrap();
+        b.write(exchange("Ben")).await.unwrap();
+        drop((a, b));
+
+        assert_eq!(ended(&store, first).await, vec![exchange("Ana")]);
+        assert_eq!(ended(&store, second).await, vec![exchange("Ben")]);
+        assert_eq!(store.batches(), vec![2]);
+    }
+
+    #[tokio::test]
+    async fn writes_past_a_full_queue_are_rejected() {
+        let store = MockMessageStore::new();
+        let config = Config {
+            stream_write_queue: 1,
+            stream_write_overflow: WriteOverflowPolicy::Reject,
+            ..Config::default()
+        };
+        let service = GreetingService::new(store.clone(), &config);
+        let id = service.start_session("acme").await.unwrap();
+        let writer = ExchangeWriter::spawn(service, &config);
+
+        let (replies, _rx) = mpsc::channel(1);
+        let session = writer.session("acme".to_string(), id, replies);
+        session.write(exchange("Ana")).await.unwrap();
+        let status = session.write(exchange("Ben")).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unavailable);
+        drop(session);
+
+        assert_eq!(ended(&store, id).await, vec![exchange("Ana")]);
+    }
+
+    #[tokio::test]
+    async fn sessions_end_once_their_writes_are_stored() {
+        let store = MockMessageStore::new();
+        let config = Config {
+            stream_write_batch: 1,
+            ..Config::default()
+        };
+        let service = GreetingService::new(store.clone(), &config);
+        let id = service.start_session("acme").await.unwrap();
+        let writer = ExchangeWriter::spawn(service, &config);
+
+        let (replies, _rx) = mpsc::channel(1);
+        let session = writer.session("acme".to_string(), id, replies);
+        for name in ["Ana", "Ben", "Cy"] {
+            session.write(exchange(name)).await.unwrap();
+        }
+        drop(session);
+
+        let names = ["Ana", "Ben", "Cy"].map(exchange).to_vec();
+        assert_eq!(ended(&store, id).await, names);
+        assert_eq!(store.batches(), vec![1, 1, 1]);
+    }
+}
@@ -0,0 +1,62 @@
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{
+        ws::{Message as WsMessage, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    response::Response,
+    routing::get,
+    Router,
+};
+use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::messages::{Broadcaster, Subscription};
+
+/// Routes bridging the broadcast feed to WebSocket clients, every stored
+/// greeting is sent as a `{"id": .., "message": ..}` text frame.
+pub fn router<S>(broadcaster: Broadcaster) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    Router::new()
+        .route("/ws", get(feed))
+        .with_state(broadcaster)
+}
+
+pub async fn serve(addr: SocketAddr, broadcaster: Broadcaster) -> Result<(), hyper::Error> {
+    println!("WebSocket feed listening on {}", addr);
+    axum::Server::bind(&addr)
+        .serve(router::<()>(broadcaster).into_make_service())
+        .await
+}
+
+async fn feed(ws: WebSocketUpgrade, State(broadcaster): State<Broadcaster>) -> Response {
+    let rx = broadcaster.subscribe("WebSocket feed");
+    ws.on_upgrade(move |socket| forward(socket, rx))
+}
+
+async fn forward(mut socket: WebSocket, mut rx: Subscription) {
+    loop {
+        tokio::select! {
+            msg = rx.recv() => match msg {
+                Ok(msg) => {
+                    let payload = json!({ "id": msg.id, "message": msg.message });
+                    if socket.send(WsMessage::Text(payload.to_string())).await.is_err() {
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    eprintln!("\twebsocket client lagged, skipped {} messages", skipped);
+                }
+                Err(RecvError::Closed) => break,
+            },
+            // the feed is one way, reading only notices the client going away
+            incoming = socket.recv() => match incoming {
+                Some(Ok(_)) => (),
+                _ => break,
+            },
+        }
+    }
+}
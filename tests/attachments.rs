@@ -0,0 +1,74 @@
+#![cfg(feature = "test-util")]
+
+mod common;
+
+use tonic::Code;
+
+use common::{hello, serve};
+use tonic_hello_tls::{
+    config::Config,
+    greeter::{
+        hello_world::{AttachmentChunk, GetAttachmentRequest},
+        MyGreeter,
+    },
+    mock::{MockGreeter, MockMessageStore},
+};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn attachments_are_kept_in_memory() {
+    let greeter = MockGreeter::new();
+    let mut client = serve(greeter.clone()).await;
+    let reply = client.say_hello(hello("Dan")).await.unwrap().into_inner();
+
+    let chunks = [b"hello ".to_vec(), b"attachment".to_vec()].map(|data| AttachmentChunk {
+        id: reply.cursor,
+        data,
+    });
+    let uploaded = client
+        .upload_attachment(tokio_stream::iter(chunks))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(uploaded.size, 16);
+
+    let get = |presigned| GetAttachmentRequest {
+        id: reply.cursor,
+        presigned,
+    };
+    let mut stream = client
+        .get_attachment(get(false))
+        .await
+        .unwrap()
+        .into_inner();
+    let reply = stream.message().await.unwrap().unwrap();
+    assert_eq!(reply.data, b"hello attachment");
+    let mut stream = client.get_attachment(get(true)).await.unwrap().into_inner();
+    let reply = stream.message().await.unwrap().unwrap();
+    assert!(reply.url.starts_with("mock://"));
+
+    let missing = GetAttachmentRequest {
+        id: 42,
+        presigned: false,
+    };
+    let status = client.get_attachment(missing).await.unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn attachments_are_refused_without_a_bucket() {
+    let mut client = serve(MyGreeter::new(MockMessageStore::new(), Config::default())).await;
+    let reply = client.say_hello(hello("Eve")).await.unwrap().into_inner();
+    let chunk = AttachmentChunk {
+        id: reply.cursor,
+        data: b"data".to_vec(),
+    };
+    let status = client
+        .upload_attachment(tokio_stream::iter([chunk]))
+        .await
+        .unwrap_err();
+    let expected = match cfg!(feature = "attachments") {
+        true => Code::FailedPrecondition,
+        false => Code::Unimplemented,
+    };
+    assert_eq!(status.code(), expected);
+}
@@ -0,0 +1,120 @@
+#![cfg(feature = "test-util")]
+
+mod common;
+
+use std::{error::Error, sync::Arc};
+
+use tonic::{transport::Server, Code};
+
+use common::{connect, hello, listen};
+use tonic_hello_tls::{
+    authn::{Authenticator, Credentials, ExternalIdentity, IdentityProvider},
+    authz::{AuthzLayer, Policy},
+    greeter::{hello_world::ListMessagesRequest, GreeterServer},
+    mock::{Call, MockGreeter},
+};
+
+/// Lets tenant `acme` say hello, and nothing else.
+struct AcmeGreetsOnly;
+
+#[tonic::async_trait]
+impl Policy for AcmeGreetsOnly {
+    async fn allows(
+        &self,
+        input: &serde_json::Value,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        Ok(input["rpc"] == "SayHello" && input["identity"]["tenant"] == "acme")
+    }
+}
+
+#[tokio::test]
+async fn calls_are_authorized_by_policy() {
+    let (incoming, addr) = listen();
+    let greeter = MockGreeter::new();
+    tokio::spawn(
+        Server::builder()
+            .layer(AuthzLayer::new(Arc::new(AcmeGreetsOnly), false))
+            .add_service(GreeterServer::new(greeter.clone()))
+            .serve_with_incoming(incoming),
+    );
+    let mut client = connect(addr).await;
+
+    let mut request = tonic::Request::new(hello("Alice"));
+    request
+        .metadata_mut()
+        .insert("x-tenant-id", "acme".parse().unwrap());
+    client.say_hello(request).await.unwrap();
+
+    let status = client.say_hello(hello("Bob")).await.unwrap_err();
+    assert_eq!(status.code(), Code::PermissionDenied);
+    let status = client
+        .list_messages(ListMessagesRequest::default())
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::PermissionDenied);
+    assert_eq!(greeter.calls(), [Call::SayHello(hello("Alice"))]);
+}
+
+/// Knows the bearer token `alice-token`, of a member of `staff`.
+struct StaffDirectory;
+
+#[tonic::async_trait]
+impl IdentityProvider for StaffDirectory {
+    async fn authenticate(
+        &self,
+        credentials: &Credentials,
+    ) -> Result<Option<ExternalIdentity>, Box<dyn Error + Send + Sync>> {
+        Ok(match credentials {
+            Credentials::Bearer(token) if token == "alice-token" => Some(ExternalIdentity {
+                user: "alice".to_string(),
+                groups: vec!["staff".to_string()],
+                issued_at: None,
+            }),
+            _ => None,
+        })
+    }
+}
+
+/// Lets the `greeter` role in.
+struct GreetersOnly;
+
+#[tonic::async_trait]
+impl Policy for GreetersOnly {
+    async fn allows(
+        &self,
+        input: &serde_json::Value,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let roles = input["identity"]["roles"].as_array();
+        Ok(roles.is_some_and(|roles| roles.iter().any(|role| role == "greeter")))
+    }
+}
+
+#[tokio::test]
+async fn callers_are_authenticated_and_their_groups_mapped_to_roles() {
+    let (incoming, addr) = listen();
+    let authenticator = Authenticator::new(
+        Arc::new(StaffDirectory),
+        [("staff".to_string(), vec!["greeter".to_string()])].into(),
+        Vec::new(),
+        std::time::Duration::from_secs(60),
+    );
+    tokio::spawn(
+        Server::builder()
+            .layer(AuthzLayer::new(Arc::new(GreetersOnly), false).with_authenticator(authenticator))
+            .add_service(GreeterServer::new(MockGreeter::new()))
+            .serve_with_incoming(incoming),
+    );
+    let mut client = connect(addr).await;
+    let with_token = |token: &str| {
+        let mut request = tonic::Request::new(hello("Alice"));
+        let value = format!("Bearer {}", token).parse().unwrap();
+        request.metadata_mut().insert("authorization", value);
+        request
+    };
+
+    client.say_hello(with_token("alice-token")).await.unwrap();
+    let status = client.say_hello(with_token("stolen")).await.unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated);
+    let status = client.say_hello(hello("Alice")).await.unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated);
+}
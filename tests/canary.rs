@@ -0,0 +1,43 @@
+#![cfg(feature = "test-util")]
+
+mod common;
+
+use tonic::{transport::Server, Code, Status};
+
+use common::{connect, hello, listen};
+use tonic_hello_tls::{
+    canary::{self, CanaryRouter},
+    greeter::GreeterServer,
+    mock::{Call, MockGreeter},
+};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn canary_calls_go_to_the_canary() {
+    let (incoming, addr) = listen();
+    let canary = MockGreeter::new().fail("SayHello", Status::unavailable("canary down"));
+    let router = CanaryRouter::new(GreeterServer::new(MockGreeter::new()), None)
+        .with_canary(GreeterServer::new(canary.clone()));
+    tokio::spawn(
+        Server::builder()
+            .add_service(router)
+            .serve_with_incoming(incoming),
+    );
+    let mut client = connect(addr).await;
+    let before = canary::stats();
+
+    let reply = client.say_hello(hello("Alice")).await.unwrap().into_inner();
+    assert_eq!(reply.message, "Hello Alice!");
+    let mut request = tonic::Request::new(hello("Bob"));
+    request
+        .metadata_mut()
+        .insert(canary::CANARY_METADATA, "true".parse().unwrap());
+    let err = client.say_hello(request).await.unwrap_err();
+    assert_eq!(err.code(), Code::Unavailable);
+    assert_eq!(canary.calls(), [Call::SayHello(hello("Bob"))]);
+
+    let after = canary::stats();
+    assert_eq!(after.stable.calls - before.stable.calls, 1);
+    assert_eq!(after.stable.failures - before.stable.failures, 0);
+    assert_eq!(after.canary.calls - before.canary.calls, 1);
+    assert_eq!(after.canary.failures - before.canary.failures, 1);
+}
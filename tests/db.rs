@@ -0,0 +1,525 @@
+mod common;
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use diesel_async::{AsyncConnection, AsyncPgConnection, SimpleAsyncConnection};
+use serde_json::json;
+
+use common::TestDatabase;
+use tonic_hello_tls::compat::{self, Mismatch, MIGRATIONS};
+use tonic_hello_tls::crypt::{CryptError, EncryptionKey, LocalKey, MessageCipher};
+use tonic_hello_tls::db::{Db, DbError, EventKind, NameCount, PoolOptions};
+use tonic_hello_tls::leader;
+use tonic_hello_tls::startup::{self, Retry};
+
+fn texts(messages: &[tonic_hello_tls::db::Message]) -> Vec<&str> {
+    messages
+        .iter()
+        .map(|msg| msg.message.as_deref().unwrap_or_default())
+        .collect()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn inserted_messages_are_listed() {
+    let database = TestDatabase::create().await;
+    let db = Db::new(&database.url).await.unwrap();
+
+    let first = db.insert_message("first").await.unwrap();
+    let rest = db
+        .insert_messages(&["second".to_string(), "third".to_string()])
+        .await
+        .unwrap();
+    assert_eq!(first.message.as_deref(), Some("first"));
+    assert!(rest.iter().all(|msg| msg.id > first.id));
+
+    assert_eq!(db.count_messages().await.unwrap(), 3);
+    assert_eq!(
+        texts(&db.get_messages().await.unwrap()),
+        ["first", "second", "third"]
+    );
+    assert_eq!(
+        texts(&db.get_messages_after(first.id).await.unwrap()),
+        ["second", "third"]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pages_cover_every_message_once() {
+    let database = TestDatabase::create().await;
+    let db = Db::new(&database.url).await.unwrap();
+
+    let names = (0..7).map(|i| format!("m{}", i)).collect::<Vec<_>>();
+    db.insert_messages(&names).await.unwrap();
+
+    let mut seen = Vec::new();
+    let mut after_id = 0;
+    loop {
+        let page = db.get_messages_page(after_id, 3).await.unwrap();
+        assert!(page.len() <= 3);
+        match page.last() {
+            Some(last) => after_id = last.id,
+            None => break,
+        }
+        seen.extend(page.into_iter().filter_map(|msg| msg.message));
+    }
+    assert_eq!(seen, names);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tagged_messages_are_found_by_tags() {
+    let database = TestDatabase::create().await;
+    let db = Db::new(&database.url).await.unwrap();
+
+    let tags = json!({ "locale": "en", "vip": true });
+    let metadata = json!({ "client": { "os": "linux", "langs": ["en", "de"] } });
+    db.insert_tagged_message("en", &tags, &metadata, 0, None, None)
+        .await
+        .unwrap();
+    db.insert_tagged_message("fr", &json!({ "locale": "fr" }), &json!({}), 0, None, None)
+        .await
+        .unwrap();
+    db.insert_message("untagged").await.unwrap();
+
+    let any = json!({});
+    let found = db
+        .get_messages_tagged(&json!({ "locale": "en" }), &any)
+        .await
+        .unwrap();
+    assert_eq!(texts(&found), ["en"]);
+    assert_eq!(found[0].metadata, metadata);
+    let found = db
+        .get_messages_tagged(&json!({ "locale": "fr", "vip": true }), &any)
+        .await
+        .unwrap();
+    assert!(found.is_empty());
+    assert_eq!(db.get_messages_tagged(&any, &any).await.unwrap().len(), 3);
+
+    let found = db
+        .get_messages_tagged(&any, &json!({ "client": { "langs": ["de"] } }))
+        .await
+        .unwrap();
+    assert_eq!(texts(&found), ["en"]);
+    let found = db
+        .get_messages_tagged(&any, &json!({ "client": { "os": "mac" } }))
+        .await
+        .unwrap();
+    assert!(found.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn changes_are_recorded_as_events() {
+    let database = TestDatabase::create().await;
+    let db = Db::new(&database.url).await.unwrap();
+
+    let msg = db.insert_message("before").await.unwrap();
+    let updated = db.update_message(msg.id, "after").await.unwrap().unwrap();
+    assert_eq!(updated.message.as_deref(), Some("after"));
+    assert!(db.delete_message(msg.id).await.unwrap());
+
+    assert!(db.update_message(msg.id, "gone").await.unwrap().is_none());
+    assert!(!db.delete_message(msg.id).await.unwrap());
+
+    let kinds = db
+        .get_events_page(0, 10)
+        .await
+        .unwrap()
+        .iter()
+        .map(|event| EventKind::parse(&event.kind))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        kinds,
+        [
+            Some(EventKind::Hello),
+            Some(EventKind::Update),
+            Some(EventKind::Delete)
+        ]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn messages_are_encrypted_at_rest() {
+    let database = TestDatabase::create().await;
+    let plain = Db::new(&database.url).await.unwrap();
+    let cipher = |key: &str| {
+        let key = key.parse::<EncryptionKey>().unwrap();
+        MessageCipher::new(Arc::new(LocalKey::new(&key)))
+    };
+    let key = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+    let encrypted = plain.clone().with_encryption(cipher(key));
+
+    plain.insert_message("Hello before!").await.unwrap();
+    let msg = encrypted.insert_message("Hello Ada!").await.unwrap();
+    assert_eq!(msg.message.as_deref(), Some("Hello Ada!"));
+    encrypted
+        .update_message(msg.id, "Hello Bob!")
+        .await
+        .unwrap();
+
+    // unencrypted messages stay readable
+    assert_eq!(
+        texts(&encrypted.get_messages().await.unwrap()),
+        ["Hello before!", "Hello Bob!"]
+    );
+    let stored = plain.get_messages().await.unwrap();
+    assert!(stored[1].message.as_deref().unwrap().starts_with("enc1:"));
+    let events = encrypted.get_events_page(0, 10).await.unwrap();
+    assert_eq!(
+        events[2].payload.as_deref(),
+        Some(r#"{"message":"Hello Bob!"}"#)
+    );
+
+    let wrong_key = "ZmVkY2JhOTg3NjU0MzIxMGZlZGNiYTk4NzY1NDMyMTA=";
+    let wrong = plain.clone().with_encryption(cipher(wrong_key));
+    assert!(matches!(
+        wrong.get_messages().await,
+        Err(DbError::Crypt(CryptError::Wrap(_)))
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn expired_partitions_are_dropped() {
+    let database = TestDatabase::create().await;
+    let db = Db::new(&database.url).await.unwrap();
+
+    let current = db.insert_message("current").await.unwrap();
+    let mut conn = AsyncPgConnection::establish(&database.url).await.unwrap();
+    conn.batch_execute(
+        "INSERT INTO messages (message, created_at) \
+         VALUES ('expired', LOCALTIMESTAMP - INTERVAL '3 months'); \
+         SELECT create_messages_partition(LOCALTIMESTAMP - INTERVAL '3 months')",
+    )
+    .await
+    .unwrap();
+
+    // this month and the next, a second round finds them there
+    assert_eq!(db.create_messages_partitions(1).await.unwrap(), 2);
+    assert_eq!(db.create_messages_partitions(1).await.unwrap(), 0);
+    assert_eq!(
+        texts(&db.get_messages().await.unwrap()),
+        ["current", "expired"]
+    );
+
+    assert_eq!(db.drop_messages_partitions(3).await.unwrap(), 0);
+    assert_eq!(db.drop_messages_partitions(1).await.unwrap(), 1);
+    assert_eq!(texts(&db.get_messages().await.unwrap()), ["current"]);
+    let last = db.get_events_page(0, 10).await.unwrap().pop().unwrap();
+    assert_eq!(EventKind::parse(&last.kind), Some(EventKind::Delete));
+    assert_ne!(last.message_id, current.id);
+
+    // messages still go where they belong
+    db.insert_message("later").await.unwrap();
+    assert_eq!(db.count_messages().await.unwrap(), 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stats_count_greetings_per_day_and_name() {
+    let database = TestDatabase::create().await;
+    let db = Db::new(&database.url).await.unwrap();
+
+    let greetings = ["Hello Alice!", "Hola Alice!", "Hi Bob!", "not a greeting"];
+    db.insert_messages(&greetings.map(String::from))
+        .await
+        .unwrap();
+    // counted by the refresh only
+    assert_eq!(db.get_stats(30, 10).await.unwrap().total, 0);
+
+    db.refresh_stats().await.unwrap();
+    let stats = db.get_stats(30, 10).await.unwrap();
+    assert_eq!(stats.total, 4);
+    assert_eq!(stats.per_day.len(), 1);
+    assert_eq!(stats.per_day[0].greetings, 4);
+    let names = stats
+        .top_names
+        .iter()
+        .map(|name| (name.name.as_str(), name.greetings))
+        .collect::<Vec<_>>();
+    assert_eq!(names, [("Alice", 2), ("Bob", 1)]);
+    assert_eq!(db.get_stats(30, 1).await.unwrap().top_names.len(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn name_counts_add_up() {
+    let database = TestDatabase::create().await;
+    let db = Db::new(&database.url).await.unwrap();
+    let count = |name: &str, greetings| NameCount {
+        name: name.to_string(),
+        greetings,
+    };
+
+    db.add_name_counts(&[count("Alice", 2), count("Bob", 1)])
+        .await
+        .unwrap();
+    db.add_name_counts(&[count("Alice", 3)]).await.unwrap();
+    let mut counts = db.get_name_counts().await.unwrap();
+    counts.sort_by(|a, b| a.name.cmp(&b.name));
+    assert_eq!(counts, [count("Alice", 5), count("Bob", 1)]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn an_exhausted_pool_times_out() {
+    let database = TestDatabase::create().await;
+    let options = PoolOptions {
+        max_size: 1,
+        connection_timeout: Duration::from_millis(200),
+        ..PoolOptions::default()
+    };
+    let db = Db::with_pool_options(&database.url, options).await.unwrap();
+
+    // hold the only pooled connection in a query blocked on a table lock
+    let mut locker = AsyncPgConnection::establish(&database.url).await.unwrap();
+    locker
+        .batch_execute("BEGIN; LOCK TABLE messages IN ACCESS EXCLUSIVE MODE")
+        .await
+        .unwrap();
+    let blocked = tokio::spawn({
+        let db = db.clone();
+        async move { db.count_messages().await }
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let err = db.count_messages().await.unwrap_err();
+    assert!(matches!(err, DbError::Pool(_)), "{}", err);
+
+    locker.batch_execute("COMMIT").await.unwrap();
+    assert_eq!(blocked.await.unwrap().unwrap(), 0);
+    assert_eq!(db.count_messages().await.unwrap(), 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dropped_queries_are_cancelled() {
+    let database = TestDatabase::create().await;
+    let options = PoolOptions {
+        max_size: 1,
+        connection_timeout: Duration::from_secs(2),
+        ..PoolOptions::default()
+    };
+    let db = Db::with_pool_options(&database.url, options).await.unwrap();
+
+    let mut locker = AsyncPgConnection::establish(&database.url).await.unwrap();
+    locker
+        .batch_execute("BEGIN; LOCK TABLE messages IN ACCESS EXCLUSIVE MODE")
+        .await
+        .unwrap();
+    let abandoned = tokio::time::timeout(Duration::from_millis(100), db.count_messages()).await;
+    assert!(abandoned.is_err());
+
+    // the only connection is free again while the lock is still held
+    db.ping().await.unwrap();
+    locker.batch_execute("COMMIT").await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn statements_time_out() {
+    let database = TestDatabase::create().await;
+    let options = PoolOptions {
+        statement_timeout: Duration::from_millis(100),
+        ..PoolOptions::default()
+    };
+    let db = Db::with_pool_options(&database.url, options).await.unwrap();
+
+    let mut locker = AsyncPgConnection::establish(&database.url).await.unwrap();
+    locker
+        .batch_execute("BEGIN; LOCK TABLE messages IN ACCESS EXCLUSIVE MODE")
+        .await
+        .unwrap();
+    let err = db.count_messages().await.unwrap_err();
+    assert!(err.to_string().contains("statement timeout"), "{}", err);
+
+    locker.batch_execute("COMMIT").await.unwrap();
+    assert_eq!(db.count_messages().await.unwrap(), 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn the_pool_reconnects_after_losing_its_connections() {
+    let database = TestDatabase::create().await;
+    let db = Db::new(&database.url).await.unwrap();
+    db.insert_message("kept").await.unwrap();
+
+    let mut admin = AsyncPgConnection::establish(&database.url).await.unwrap();
+    admin
+        .batch_execute(
+            "SELECT pg_terminate_backend(pid) FROM pg_stat_activity \
+             WHERE datname = current_database() AND pid <> pg_backend_pid()",
+        )
+        .await
+        .unwrap();
+
+    // broken connections are dropped on checkout and replaced
+    assert_eq!(db.count_messages().await.unwrap(), 1);
+    assert_eq!(texts(&db.get_messages().await.unwrap()), ["kept"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pings_record_the_database_health() {
+    let database = TestDatabase::create().await;
+    let db = Db::new(&database.url).await.unwrap();
+    assert_eq!(db.health().healthy, None);
+
+    db.insert_message("kept").await.unwrap();
+    let mut admin = AsyncPgConnection::establish(&database.url).await.unwrap();
+    admin
+        .batch_execute(
+            "SELECT pg_terminate_backend(pid) FROM pg_stat_activity \
+             WHERE datname = current_database() AND pid <> pg_backend_pid()",
+        )
+        .await
+        .unwrap();
+    assert!(db.recycle_idle().await.unwrap() > 0);
+    db.ping().await.unwrap();
+    let health = db.health();
+    assert_eq!(health.healthy, Some(true));
+    assert_eq!(health.consecutive_failures, 0);
+
+    let options = PoolOptions {
+        max_size: 1,
+        connection_timeout: Duration::from_millis(200),
+        ..PoolOptions::default()
+    };
+    let unreachable = Db::with_pool_options("postgres://postgres@127.0.0.1:1/none", options)
+        .await
+        .unwrap();
+    assert!(unreachable.ping().await.is_err());
+    assert!(unreachable.ping().await.is_err());
+    let health = unreachable.health();
+    assert_eq!(health.healthy, Some(false));
+    assert_eq!(health.consecutive_failures, 2);
+    assert!(health.last_error.is_some());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn one_replica_leads_until_it_is_gone() {
+    let database = TestDatabase::create().await;
+    let db = Db::new(&database.url).await.unwrap();
+
+    let lock = db.try_advisory_lock("task").await.unwrap().unwrap();
+    assert!(db.try_advisory_lock("task").await.unwrap().is_none());
+    assert!(db.try_advisory_lock("other").await.unwrap().is_some());
+    drop(lock);
+
+    let runs = [Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0))];
+    let replicas = runs
+        .iter()
+        .map(|count| {
+            let count = count.clone();
+            tokio::spawn(leader::lead(
+                db.clone(),
+                "task",
+                Duration::from_millis(50),
+                move || {
+                    let count = count.clone();
+                    async move {
+                        loop {
+                            count.fetch_add(1, Ordering::SeqCst);
+                            tokio::time::sleep(Duration::from_millis(10)).await;
+                        }
+                    }
+                },
+            ))
+        })
+        .collect::<Vec<_>>();
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let running = runs
+        .each_ref()
+        .map(|count| count.load(Ordering::SeqCst) > 0);
+    assert_eq!(running.iter().filter(|running| **running).count(), 1);
+
+    // the session holding the lock ends with the leader, the other takes over
+    let (leader, follower) = if running[0] { (0, 1) } else { (1, 0) };
+    replicas[leader].abort();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while runs[follower].load(Ordering::SeqCst) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("no replica took over");
+    replicas[follower].abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn schema_mismatches_are_found() {
+    let database = TestDatabase::create().await;
+    let db = Db::new(&database.url).await.unwrap();
+    assert_eq!(compat::check(&db).await.unwrap(), []);
+
+    let mut admin = AsyncPgConnection::establish(&database.url).await.unwrap();
+    // what the build doesn't know about is fine
+    admin
+        .batch_execute(
+            "ALTER TABLE messages ADD COLUMN language TEXT; \
+             INSERT INTO __diesel_schema_migrations (version) VALUES ('99990101000000')",
+        )
+        .await
+        .unwrap();
+    assert_eq!(compat::check(&db).await.unwrap(), []);
+
+    let latest = MIGRATIONS.last().unwrap();
+    admin
+        .batch_execute(&format!(
+            "ALTER TABLE name_counts DROP COLUMN greetings; \
+             ALTER TABLE audit_log ALTER COLUMN detail DROP NOT NULL; \
+             DELETE FROM __diesel_schema_migrations WHERE version = '{}'",
+            latest
+        ))
+        .await
+        .unwrap();
+    let mismatches = compat::check(&db).await.unwrap();
+    assert_eq!(
+        mismatches,
+        [
+            Mismatch::Nullability {
+                table: "audit_log".to_string(),
+                column: "detail".to_string(),
+                nullable: true,
+            },
+            Mismatch::MissingColumn {
+                table: "name_counts".to_string(),
+                column: "greetings".to_string(),
+            },
+            Mismatch::PendingMigration(latest.to_string()),
+        ]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn startup_waits_for_the_database() {
+    let database = TestDatabase::create().await;
+    let (base, _) = database.url.rsplit_once('/').unwrap();
+    let name = format!("created_late_{}", std::process::id());
+    let late_url = format!("{}/{}", base, name);
+    let db = Db::new(&late_url).await.unwrap();
+    let retry = Retry {
+        initial_backoff: Duration::from_millis(50),
+        max_backoff: Duration::from_millis(100),
+        max_wait: Duration::from_millis(300),
+    };
+
+    // not there within the wait
+    assert!(startup::retry("database", retry, || db.probe())
+        .await
+        .is_err());
+
+    let mut admin = AsyncPgConnection::establish(&database.url).await.unwrap();
+    let create = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        admin
+            .batch_execute(&format!("CREATE DATABASE {}", name))
+            .await
+            .unwrap();
+    });
+    let retry = Retry {
+        max_wait: Duration::from_secs(10),
+        ..retry
+    };
+    startup::retry("database", retry, || db.probe())
+        .await
+        .unwrap();
+    create.await.unwrap();
+}
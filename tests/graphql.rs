@@ -0,0 +1,35 @@
+#![cfg(feature = "graphql")]
+
+mod common;
+
+use serde_json::json;
+
+use common::TestServer;
+use tonic_hello_tls::{config::Config, graphql, service::GreetingService};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn say_hello_mutation_goes_through_the_service() {
+    let server = TestServer::start().await;
+    let service = GreetingService::new(server.db.clone(), &Config::default())
+        .with_broadcaster(server.broadcaster.clone());
+    let schema = graphql::schema(service);
+
+    let mutation = r#"mutation { sayHello(name: "Ada", tags: {team: "core"}) { message } }"#;
+    let response = schema.execute(mutation).await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+    assert_eq!(
+        response.data.into_json().unwrap(),
+        json!({ "sayHello": { "message": "Hello Ada!" } })
+    );
+
+    let query = r#"{ messages(tags: {team: "core"}) { message tags } messageCount }"#;
+    let response = schema.execute(query).await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+    assert_eq!(
+        response.data.into_json().unwrap(),
+        json!({
+            "messages": [{ "message": "Hello Ada!", "tags": { "team": "core" } }],
+            "messageCount": 1,
+        })
+    );
+}
@@ -0,0 +1,1882 @@
+mod common;
+
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tonic::{Code, Request, Streaming};
+
+use common::{eventually, hello, TestServer};
+use tonic_hello_tls::{
+    coalesce::InFlight,
+    config::{Config, DenyAction, ModerationConfig},
+    greeter::hello_world::{
+        admin_client::AdminClient, BackupChunk, CreateBackupRequest, DeleteAllForNameRequest,
+        DownloadPayloadRequest, EventKind, ExportFormat, ExportMessagesRequest,
+        GetCertificatesRequest, GetReadOnlyRequest, GetServerInfoRequest, GetSessionRequest,
+        GetStatsRequest, GetUsageRequest, HelloReply, HelloRequest, ImportMessagesRequest,
+        KillSessionRequest, ListMessagesRequest, ListSessionsRequest, PayloadChunk, Priority,
+        SayHelloManyRequest, ServerEventKind, SetReadOnlyRequest, StreamEventsRequest,
+        StreamLeaderboardRequest, StreamServerEventsRequest, UpdateMessageRequest,
+    },
+    greeter::{SESSION_METADATA, SUMMARY_MAX_DISTINCT_NAMES},
+    metadata, server_info,
+    tenant::{DEFAULT_TENANT, TENANT_METADATA},
+    versions::{v1, v2},
+};
+
+async fn next_reply(stream: &mut Streaming<HelloReply>) -> HelloReply {
+    tokio::time::timeout(Duration::from_secs(5), stream.message())
+        .await
+        .expect("reply")
+        .unwrap()
+        .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn say_hello_stores_the_greeting() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    let reply = client.say_hello(hello("Alice")).await.unwrap().into_inner();
+    assert_eq!(reply.message, "Hello Alice!");
+    assert!(reply.cursor > 0);
+
+    let request = HelloRequest {
+        locale: "fr-CA".to_string(),
+        ..hello("Bob")
+    };
+    let reply = client.say_hello(request).await.unwrap().into_inner();
+    assert_eq!(reply.message, "Bonjour Bob!");
+
+    let listed = client
+        .list_messages(ListMessagesRequest::default())
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(listed.messages, ["Hello Alice!", "Bonjour Bob!"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn say_hello_stream_replies_to_every_name() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    let names = tokio_stream::iter(["a", "b", "c"].map(hello));
+    let replies = client
+        .say_hello_stream(names)
+        .await
+        .unwrap()
+        .into_inner()
+        .map(|reply| reply.unwrap().message)
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(replies, ["Hello a!", "Hello b!", "Hello c!"]);
+
+    // stored by a writer task of its own
+    eventually("stored stream greetings", || async {
+        server.db.count_messages().await.unwrap() == 3
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn say_hello_stream_holds_names_until_replies_are_acked() {
+    let config = Config {
+        stream_ack_window: 2,
+        ..Config::default()
+    };
+    let server = TestServer::start_with(config).await;
+    let mut client = server.client().await;
+
+    let (tx, rx) = mpsc::channel(8);
+    for name in ["a", "b", "c"] {
+        tx.send(hello(name)).await.unwrap();
+    }
+    let mut replies = client
+        .say_hello_stream(ReceiverStream::new(rx))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(next_reply(&mut replies).await.message, "Hello a!");
+    let second = next_reply(&mut replies).await;
+    assert_eq!(second.message, "Hello b!");
+    // "c" waits for an ack instead of failing the call
+    let held = tokio::time::timeout(Duration::from_millis(200), replies.message()).await;
+    assert!(held.is_err());
+
+    let ack = HelloRequest {
+        ack: second.seq,
+        ..Default::default()
+    };
+    tx.send(ack).await.unwrap();
+    assert_eq!(next_reply(&mut replies).await.message, "Hello c!");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn say_hello_stream_sessions_are_replayed() {
+    let config = Config {
+        // the tenant is only taken from trusted frontends
+        tenant_frontends: vec!["127.0.0.1".parse().unwrap()],
+        ..Config::default()
+    };
+    let server = TestServer::start_with(config).await;
+    let mut client = server.client().await;
+
+    let names = tokio_stream::iter(["a", "b"].map(hello));
+    let response = client.say_hello_stream(names).await.unwrap();
+    let id: i64 = response
+        .metadata()
+        .get(SESSION_METADATA)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    let replies = response.into_inner().collect::<Vec<_>>().await;
+    assert_eq!(replies.len(), 2);
+
+    eventually("ended session", || {
+        let mut client = client.clone();
+        async move {
+            let session = client
+                .get_session(GetSessionRequest { id })
+                .await
+                .unwrap()
+                .into_inner();
+            session.ended_at_ms > 0
+        }
+    })
+    .await;
+    let session = client
+        .get_session(GetSessionRequest { id })
+        .await
+        .unwrap()
+        .into_inner();
+    let exchanges = session
+        .exchanges
+        .iter()
+        .map(|e| (e.name.as_str(), e.reply.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(exchanges, [("a", "Hello a!"), ("b", "Hello b!")]);
+    assert!(session.started_at_ms <= session.exchanges[0].at_ms);
+
+    // sessions are the tenant's own
+    let mut request = Request::new(GetSessionRequest { id });
+    request
+        .metadata_mut()
+        .insert(TENANT_METADATA, "other".parse().unwrap());
+    let err = client.get_session(request).await.unwrap_err();
+    assert_eq!(err.code(), Code::NotFound);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn admins_list_and_kill_open_streams() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+    let mut admin = AdminClient::new(server.channel().await);
+
+    let (names, rx) = mpsc::channel(4);
+    let response = client
+        .say_hello_stream(ReceiverStream::new(rx))
+        .await
+        .unwrap();
+    let id: i64 = response
+        .metadata()
+        .get(SESSION_METADATA)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    let mut replies = response.into_inner();
+    names.send(hello("a")).await.unwrap();
+    next_reply(&mut replies).await;
+
+    // the reply is counted once it is handed over, which can lag the client
+    eventually("listed session", || {
+        let mut admin = admin.clone();
+        async move {
+            let reply = admin.list_sessions(ListSessionsRequest {}).await.unwrap();
+            reply.into_inner().sessions.iter().any(|s| {
+                s.id == id
+                    && s.method == "SayHelloStream"
+                    && s.tenant == DEFAULT_TENANT
+                    && (s.received, s.sent) == (1, 1)
+            })
+        }
+    })
+    .await;
+
+    admin.kill_session(KillSessionRequest { id }).await.unwrap();
+    let status = tokio::time::timeout(Duration::from_secs(5), replies.message())
+        .await
+        .expect("stream end")
+        .unwrap_err();
+    assert_eq!(status.code(), Code::Aborted);
+
+    eventually("unlisted session", || {
+        let mut admin = admin.clone();
+        async move {
+            let reply = admin.list_sessions(ListSessionsRequest {}).await.unwrap();
+            reply.into_inner().sessions.iter().all(|s| s.id != id)
+        }
+    })
+    .await;
+    let err = admin
+        .kill_session(KillSessionRequest { id })
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), Code::NotFound);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn say_hello_many_cycles_through_names() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    let request = SayHelloManyRequest {
+        names: vec!["a".to_string(), "b".to_string()],
+        count: 2,
+        delay_ms: 0,
+    };
+    let replies = client
+        .say_hello_many(request)
+        .await
+        .unwrap()
+        .into_inner()
+        .map(|reply| reply.unwrap())
+        .collect::<Vec<_>>()
+        .await;
+    let messages = replies
+        .iter()
+        .map(|r| r.message.as_str())
+        .collect::<Vec<_>>();
+    let seqs = replies.iter().map(|r| r.seq).collect::<Vec<_>>();
+    assert_eq!(messages, ["Hello a!", "Hello b!", "Hello a!", "Hello b!"]);
+    assert_eq!(seqs, [1, 2, 3, 4]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn say_hello_summary_counts_names() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    let names = tokio_stream::iter(["a", "a", "b"].map(hello));
+    let summary = client.say_hello_summary(names).await.unwrap().into_inner();
+    assert_eq!(summary.count, 3);
+    assert_eq!(summary.inserted, 3);
+    let mut distinct = summary.distinct_names;
+    distinct.sort();
+    assert_eq!(distinct, ["a", "b"]);
+    assert!(!summary.distinct_names_truncated);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn say_hello_summary_caps_the_names_it_lists() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    let total = SUMMARY_MAX_DISTINCT_NAMES + 5;
+    let names = tokio_stream::iter((0..total).map(|i| hello(&format!("n{i}"))));
+    let summary = client.say_hello_summary(names).await.unwrap().into_inner();
+    assert_eq!(summary.count, total as u64);
+    assert_eq!(summary.distinct_names.len(), SUMMARY_MAX_DISTINCT_NAMES);
+    assert_eq!(summary.distinct_names[0], "n0");
+    assert!(summary.distinct_names_truncated);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn greetings_fan_out_to_every_subscriber() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    let mut first = client
+        .list_messages_stream(tokio_stream::iter([ListMessagesRequest::default()]))
+        .await
+        .unwrap()
+        .into_inner();
+    let mut second = client
+        .list_messages_stream(tokio_stream::iter([ListMessagesRequest::default()]))
+        .await
+        .unwrap()
+        .into_inner();
+    eventually("two subscribers", || async {
+        server.broadcaster.subscriber_count() == 2
+    })
+    .await;
+
+    client.say_hello(hello("fan-out")).await.unwrap();
+
+    for stream in [&mut first, &mut second] {
+        let reply = tokio::time::timeout(Duration::from_secs(5), stream.message())
+            .await
+            .expect("broadcast reply")
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply.message, "Hello fan-out!");
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn durable_delivery_replays_to_the_next_subscriber() {
+    let config = Config {
+        durable_delivery: true,
+        ..Config::default()
+    };
+    let server = TestServer::start_with(config).await;
+    let mut client = server.client().await;
+
+    // nobody is listening yet
+    client.say_hello(hello("early")).await.unwrap();
+
+    let mut stream = client
+        .list_messages_stream(tokio_stream::iter([ListMessagesRequest::default()]))
+        .await
+        .unwrap()
+        .into_inner();
+    let reply = tokio::time::timeout(Duration::from_secs(5), stream.message())
+        .await
+        .expect("replayed reply")
+        .unwrap()
+        .unwrap();
+    assert_eq!(reply.message, "Hello early!");
+
+    eventually("the queue to drain", || async {
+        server
+            .db
+            .get_pending_deliveries(10)
+            .await
+            .unwrap()
+            .is_empty()
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stream_filters_apply_to_replays_and_can_change_live() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+    let admin = AdminClient::new(server.channel().await);
+
+    let team = |team: &str| [("team".to_string(), team.to_string())].into();
+    let tagged = |name: &str, tag: &str| HelloRequest {
+        tags: team(tag),
+        ..hello(name)
+    };
+    for (name, tag) in [("a", "core"), ("b", "web"), ("c", "core"), ("d", "core")] {
+        client.say_hello(tagged(name, tag)).await.unwrap();
+    }
+
+    let (requests, rx) = mpsc::channel(4);
+    let first = ListMessagesRequest {
+        tags: team("core"),
+        replay_count: 2,
+        ..Default::default()
+    };
+    requests.send(first).await.unwrap();
+    let response = client
+        .list_messages_stream(ReceiverStream::new(rx))
+        .await
+        .unwrap();
+    let id: i64 = response
+        .metadata()
+        .get(SESSION_METADATA)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    let mut stream = response.into_inner();
+    assert_eq!(next_reply(&mut stream).await.message, "Hello c!");
+    assert_eq!(next_reply(&mut stream).await.message, "Hello d!");
+
+    client.say_hello(tagged("e", "web")).await.unwrap();
+    client.say_hello(tagged("f", "core")).await.unwrap();
+    assert_eq!(next_reply(&mut stream).await.message, "Hello f!");
+
+    let update = ListMessagesRequest {
+        tags: team("web"),
+        update_filters: true,
+        ..Default::default()
+    };
+    requests.send(update).await.unwrap();
+    eventually("the update to be read", || {
+        let mut admin = admin.clone();
+        async move {
+            let reply = admin.list_sessions(ListSessionsRequest {}).await.unwrap();
+            reply
+                .into_inner()
+                .sessions
+                .iter()
+                .any(|s| s.id == id && s.received == 2)
+        }
+    })
+    .await;
+    client.say_hello(tagged("g", "core")).await.unwrap();
+    client.say_hello(tagged("h", "web")).await.unwrap();
+    assert_eq!(next_reply(&mut stream).await.message, "Hello h!");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unacked_replies_are_redelivered() {
+    let config = Config {
+        ack_timeout_ms: 100,
+        ..Config::default()
+    };
+    let server = TestServer::start_with(config).await;
+    let mut client = server.client().await;
+
+    let subscribe = || ListMessagesRequest {
+        subscriber: "audit".to_string(),
+        ..Default::default()
+    };
+    let (acks, requests) = mpsc::channel(4);
+    acks.send(subscribe()).await.unwrap();
+    let mut stream = client
+        .list_messages_stream(ReceiverStream::new(requests))
+        .await
+        .unwrap()
+        .into_inner();
+    eventually("a subscriber", || async {
+        server.broadcaster.subscriber_count() == 1
+    })
+    .await;
+
+    client.say_hello(hello("unacked")).await.unwrap();
+    let reply = next_reply(&mut stream).await;
+    assert_eq!(reply.message, "Hello unacked!");
+    let redelivered = next_reply(&mut stream).await;
+    assert_eq!(redelivered.cursor, reply.cursor);
+
+    let ack = ListMessagesRequest {
+        ack: reply.cursor,
+        ..Default::default()
+    };
+    acks.send(ack).await.unwrap();
+    eventually("the ack to be stored", || async {
+        i64::from(server.db.get_acked_until("audit").await.unwrap()) == reply.cursor
+    })
+    .await;
+    drop(stream);
+
+    // a reconnecting subscriber picks up after what it acked
+    client.say_hello(hello("later")).await.unwrap();
+    let mut stream = client
+        .list_messages_stream(tokio_stream::iter([subscribe()]))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(next_reply(&mut stream).await.message, "Hello later!");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn subscribers_that_never_ack_are_dropped() {
+    let config = Config {
+        max_unacked_replies: 2,
+        ..Config::default()
+    };
+    let server = TestServer::start_with(config).await;
+    let mut client = server.client().await;
+
+    let (requests, rx) = mpsc::channel(4);
+    let subscribe = ListMessagesRequest {
+        subscriber: "lazy".to_string(),
+        ..Default::default()
+    };
+    requests.send(subscribe).await.unwrap();
+    let mut stream = client
+        .list_messages_stream(ReceiverStream::new(rx))
+        .await
+        .unwrap()
+        .into_inner();
+    eventually("a subscriber", || async {
+        server.broadcaster.subscriber_count() == 1
+    })
+    .await;
+
+    for name in ["a", "b", "c"] {
+        client.say_hello(hello(name)).await.unwrap();
+    }
+    assert_eq!(next_reply(&mut stream).await.message, "Hello a!");
+    assert_eq!(next_reply(&mut stream).await.message, "Hello b!");
+    let status = stream.message().await.unwrap_err();
+    assert_eq!(status.code(), Code::ResourceExhausted);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn subscription_members_split_its_messages() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    let join = || {
+        tokio_stream::iter([ListMessagesRequest {
+            subscription_name: "billing".to_string(),
+            ..Default::default()
+        }])
+    };
+    // a new subscription starts from the first message
+    client.say_hello(hello("before")).await.unwrap();
+    let mut first = client
+        .list_messages_stream(join())
+        .await
+        .unwrap()
+        .into_inner();
+    let mut second = client
+        .list_messages_stream(join())
+        .await
+        .unwrap()
+        .into_inner();
+    for name in ["a", "b", "c", "d"] {
+        client.say_hello(hello(name)).await.unwrap();
+    }
+
+    let mut received = Vec::new();
+    while received.len() < 5 {
+        let reply = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::select! {
+                reply = first.message() => reply,
+                reply = second.message() => reply,
+            }
+        })
+        .await
+        .expect("reply")
+        .unwrap()
+        .unwrap();
+        received.push(reply.cursor);
+    }
+    let mut distinct = received.clone();
+    distinct.sort();
+    distinct.dedup();
+    assert_eq!(distinct.len(), 5);
+
+    let last = *distinct.last().unwrap();
+    eventually("the position to be stored", || async {
+        i64::from(server.db.get_delivered_until("billing").await.unwrap()) == last
+    })
+    .await;
+    drop((first, second));
+
+    client.say_hello(hello("after")).await.unwrap();
+    let mut stream = client
+        .list_messages_stream(join())
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(next_reply(&mut stream).await.message, "Hello after!");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dropping_a_stream_cancels_its_subscription() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    let stream = client
+        .list_messages_stream(tokio_stream::iter([ListMessagesRequest::default()]))
+        .await
+        .unwrap()
+        .into_inner();
+    eventually("a subscriber", || async {
+        server.broadcaster.subscriber_count() == 1
+    })
+    .await;
+
+    drop(stream);
+    eventually("the subscription to end", || async {
+        server.broadcaster.subscriber_count() == 0
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn imported_messages_are_exported() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    let batches = tokio_stream::iter([ImportMessagesRequest {
+        messages: vec!["first".to_string(), " ".to_string(), "second".to_string()],
+        suppress_broadcast: true,
+    }]);
+    let reply = client.import_messages(batches).await.unwrap().into_inner();
+    assert_eq!(reply.total_inserted, 2);
+    assert_eq!(reply.batches[0].rejected, 1);
+
+    let request = ExportMessagesRequest {
+        format: ExportFormat::Jsonl.into(),
+        batch_size: 1,
+    };
+    let chunks = client
+        .export_messages(request)
+        .await
+        .unwrap()
+        .into_inner()
+        .map(|chunk| chunk.unwrap().data)
+        .collect::<Vec<_>>()
+        .await;
+    let export = String::from_utf8(chunks.concat()).unwrap();
+    let lines = export.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].contains("\"message\":\"first\""));
+    assert!(lines[1].contains("\"message\":\"second\""));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stream_events_replays_the_log() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    let reply = client
+        .say_hello(hello("events"))
+        .await
+        .unwrap()
+        .into_inner();
+
+    let request = StreamEventsRequest {
+        after_seq: 0,
+        follow: false,
+    };
+    let events = client
+        .stream_events(request)
+        .await
+        .unwrap()
+        .into_inner()
+        .map(|event| event.unwrap())
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].kind(), EventKind::Hello);
+    assert_eq!(i64::from(events[0].message_id), reply.cursor);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn updates_are_appended_to_the_event_log() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    let reply = client.say_hello(hello("Dan")).await.unwrap().into_inner();
+    let id = i32::try_from(reply.cursor).unwrap();
+    let request = UpdateMessageRequest {
+        id,
+        message: "Hi Dan!".to_string(),
+    };
+    let updated = client.update_message(request).await.unwrap().into_inner();
+    assert_eq!(updated.message, "Hi Dan!");
+    assert_eq!(updated.cursor, reply.cursor);
+
+    let request = UpdateMessageRequest {
+        id: id + 1,
+        message: "Hi nobody!".to_string(),
+    };
+    let status = client.update_message(request).await.unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+
+    let request = StreamEventsRequest {
+        after_seq: 0,
+        follow: false,
+    };
+    let events = client
+        .stream_events(request)
+        .await
+        .unwrap()
+        .into_inner()
+        .map(|event| event.unwrap())
+        .collect::<Vec<_>>()
+        .await;
+    let kinds = events.iter().map(|e| e.kind()).collect::<Vec<_>>();
+    assert_eq!(kinds, [EventKind::Hello, EventKind::Update]);
+    assert_eq!(events[1].message_id, id);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tenants_are_held_to_their_quota() {
+    let config = Config {
+        tenant_quotas: "acme=2:,*=:1000".parse().unwrap(),
+        // the tenant is only taken from trusted frontends
+        tenant_frontends: vec!["127.0.0.1".parse().unwrap()],
+        ..Config::default()
+    };
+    let server = TestServer::start_with(config).await;
+    let mut client = server.client().await;
+
+    let for_acme = |name: &str| {
+        let mut request = Request::new(hello(name));
+        request
+            .metadata_mut()
+            .insert(TENANT_METADATA, "acme".parse().unwrap());
+        request
+    };
+    client.say_hello(for_acme("a")).await.unwrap();
+    client.say_hello(for_acme("b")).await.unwrap();
+    let status = client.say_hello(for_acme("c")).await.unwrap_err();
+    assert_eq!(status.code(), Code::ResourceExhausted);
+
+    // other tenants have quotas of their own
+    client.say_hello(hello("d")).await.unwrap();
+
+    let mut request = Request::new(GetUsageRequest {});
+    request
+        .metadata_mut()
+        .insert(TENANT_METADATA, "acme".parse().unwrap());
+    let usage = client.get_usage(request).await.unwrap().into_inner();
+    assert_eq!(usage.tenant, "acme");
+    assert_eq!(usage.messages, 2);
+    assert_eq!(usage.bytes, ("Hello a!".len() * 2) as i64);
+    assert_eq!(usage.max_messages, Some(2));
+    assert_eq!(usage.max_bytes, None);
+
+    let usage = client
+        .get_usage(GetUsageRequest {})
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(usage.tenant, DEFAULT_TENANT);
+    assert_eq!(usage.messages, 1);
+    assert_eq!(usage.max_bytes, Some(1000));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn clients_cannot_name_their_tenant() {
+    let config = Config {
+        tenant_quotas: "acme=1:".parse().unwrap(),
+        tenant_frontends: vec!["10.0.0.0/8".parse().unwrap()],
+        ..Config::default()
+    };
+    let server = TestServer::start_with(config).await;
+    let mut client = server.client().await;
+
+    let mut request = Request::new(GetUsageRequest {});
+    request
+        .metadata_mut()
+        .insert(TENANT_METADATA, "acme".parse().unwrap());
+    let usage = client.get_usage(request).await.unwrap().into_inner();
+    assert_eq!(usage.tenant, DEFAULT_TENANT);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn calls_from_denied_addresses_are_refused() {
+    use tonic_hello_tls::greeter::hello_world::{GetIpRulesRequest, SetIpRulesRequest};
+
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+    let mut admin = AdminClient::new(server.channel().await);
+    client.say_hello(hello("a")).await.unwrap();
+
+    let set = |allow: &[&str], deny: &[&str]| SetIpRulesRequest {
+        allow: allow.iter().map(|block| block.to_string()).collect(),
+        deny: deny.iter().map(|block| block.to_string()).collect(),
+    };
+    let status = admin
+        .set_ip_rules(set(&["10.0.0.0/33"], &[]))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+
+    let reply = admin
+        .set_ip_rules(set(&["127.0.0.0/8", "::1"], &["127.0.0.2"]))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(reply.allow, ["127.0.0.0/8", "::1/128"]);
+    client.say_hello(hello("b")).await.unwrap();
+    let reply = admin
+        .get_ip_rules(GetIpRulesRequest {})
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(reply.deny, ["127.0.0.2/32"]);
+
+    // admin calls are filtered too, there is no way back for this server
+    admin.set_ip_rules(set(&[], &["127.0.0.1"])).await.unwrap();
+    let status = client.say_hello(hello("c")).await.unwrap_err();
+    assert_eq!(status.code(), Code::PermissionDenied);
+    assert_eq!(server.db.count_messages().await.unwrap(), 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn untrusted_peers_are_filtered_by_their_own_address() {
+    let config = Config {
+        proxy_protocol: true,
+        trusted_proxies: vec!["10.9.9.9".parse().unwrap()],
+        ip_allow: vec!["10.1.2.3".parse().unwrap()],
+        ..Config::default()
+    };
+    let server = TestServer::start_with(config).await;
+    let mut client = server.client().await;
+
+    // no PROXY header is expected from this peer, its address is not allowed
+    let status = client.say_hello(hello("a")).await.unwrap_err();
+    assert_eq!(status.code(), Code::PermissionDenied);
+}
+
+#[test]
+fn address_blocks_match_by_prefix() {
+    use tonic_hello_tls::ip_filter::Cidr;
+
+    let block: Cidr = "10.1.0.0/17".parse().unwrap();
+    assert!(block.contains("10.1.127.255".parse().unwrap()));
+    assert!(!block.contains("10.1.128.0".parse().unwrap()));
+    // IPv4 clients of a dual-stack listener
+    assert!(block.contains("::ffff:10.1.2.3".parse().unwrap()));
+    assert!(!"2001:db8::/32"
+        .parse::<Cidr>()
+        .unwrap()
+        .contains("10.1.2.3".parse().unwrap()));
+    assert!("0.0.0.0/0"
+        .parse::<Cidr>()
+        .unwrap()
+        .contains("192.0.2.1".parse().unwrap()));
+    assert!("10.0.0.1/".parse::<Cidr>().is_err());
+}
+
+#[test]
+fn tokens_are_only_accepted_as_issued() {
+    use tonic_hello_tls::{config::TokensConfig, tokens::TokenIssuer};
+
+    let config = TokensConfig {
+        secret: Some("shared by the replicas".to_string()),
+        ..TokensConfig::default()
+    };
+    let issuer = TokenIssuer::new(&config);
+    let (token, _) = issuer.issue("CN=client", &["staff".to_string()]);
+    let claims = issuer.verify(&token).unwrap();
+    assert_eq!(claims.sub, "CN=client");
+    assert_eq!(claims.groups, ["staff"]);
+    // another replica derives the same keys
+    assert!(TokenIssuer::new(&config).verify(&token).is_some());
+
+    let (signed, _) = token.rsplit_once('.').unwrap();
+    assert!(issuer.verify(&format!("{}.AAAA", signed)).is_none());
+    let other = TokenIssuer::new(&TokensConfig::default());
+    assert!(other.verify(&token).is_none());
+    let expired = TokenIssuer::new(&TokensConfig {
+        ttl_secs: 0,
+        ..config
+    });
+    let (token, _) = expired.issue("CN=client", &[]);
+    assert!(expired.verify(&token).is_none());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn revoked_sessions_are_refused() {
+    use std::collections::HashMap;
+
+    use sha2::{Digest, Sha256};
+    use tonic_hello_tls::{
+        config::{ApiKey, TokensConfig},
+        greeter::hello_world::{
+            tokens_client::TokensClient, IssueTokenRequest, RefreshTokenRequest,
+            RevokeSessionsRequest,
+        },
+    };
+
+    let api_key = ApiKey {
+        sha256: format!("{:x}", Sha256::digest("s3cret")),
+        groups: vec!["staff".to_string()],
+    };
+    let config = Config {
+        tokens: TokensConfig {
+            enabled: true,
+            api_keys: HashMap::from([("deploy".to_string(), api_key)]),
+            ..TokensConfig::default()
+        },
+        ..Config::default()
+    };
+    let server = TestServer::start_with(config).await;
+    let mut tokens = TokensClient::new(server.channel().await);
+    let mut client = server.client().await;
+    let mut admin = AdminClient::new(server.channel().await);
+    fn bearer<T>(token: &str, message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        let value = format!("Bearer {}", token).parse().unwrap();
+        request.metadata_mut().insert("authorization", value);
+        request
+    }
+
+    let issued = tokens
+        .issue_token(IssueTokenRequest {
+            api_key: "s3cret".to_string(),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(issued.subject, "deploy");
+    client
+        .say_hello(bearer(&issued.token, hello("a")))
+        .await
+        .unwrap();
+
+    let refresh = |refresh_token: &str| RefreshTokenRequest {
+        refresh_token: refresh_token.to_string(),
+    };
+    let refreshed = tokens
+        .refresh_token(refresh(&issued.refresh_token))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(refreshed.subject, "deploy");
+    // refresh tokens are good for one exchange
+    let status = tokens
+        .refresh_token(refresh(&issued.refresh_token))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated);
+
+    let reply = admin
+        .revoke_sessions(bearer(
+            &refreshed.token,
+            RevokeSessionsRequest {
+                subject: "deploy".to_string(),
+            },
+        ))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(reply.refresh_tokens_revoked, 1);
+    let status = client
+        .say_hello(bearer(&refreshed.token, hello("b")))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated);
+    let status = tokens
+        .refresh_token(refresh(&refreshed.refresh_token))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn read_only_mode_refuses_greetings() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+    let mut admin = AdminClient::new(server.channel().await);
+    client.say_hello(hello("a")).await.unwrap();
+
+    let set = |read_only| SetReadOnlyRequest { read_only };
+    admin.set_read_only(set(true)).await.unwrap();
+    let status = client.say_hello(hello("b")).await.unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition);
+    let reply = admin
+        .get_read_only(GetReadOnlyRequest {})
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(reply.read_only);
+
+    // reads go on
+    let reply = client
+        .list_messages(ListMessagesRequest::default())
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(reply.messages.len(), 1);
+
+    admin.set_read_only(set(false)).await.unwrap();
+    client.say_hello(hello("c")).await.unwrap();
+    assert_eq!(server.db.count_messages().await.unwrap(), 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stats_report_the_counted_greetings() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+    for name in ["Alice", "Bob", "Alice"] {
+        client.say_hello(hello(name)).await.unwrap();
+    }
+    server.db.refresh_stats().await.unwrap();
+
+    let reply = client
+        .get_stats(GetStatsRequest::default())
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(reply.total_greetings, 3);
+    assert_eq!(reply.per_day.len(), 1);
+    let names = reply
+        .top_names
+        .iter()
+        .map(|name| (name.name.as_str(), name.greetings))
+        .collect::<Vec<_>>();
+    assert_eq!(names, [("Alice", 2), ("Bob", 1)]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn leaderboard_streams_the_ranking_as_it_changes() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+    let mut leaderboard = client
+        .stream_leaderboard(StreamLeaderboardRequest { top: 2 })
+        .await
+        .unwrap()
+        .into_inner();
+
+    for name in ["Alice", "Bob", "Carol", "Alice"] {
+        client.say_hello(hello(name)).await.unwrap();
+    }
+    let ranked = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let reply = leaderboard.message().await.unwrap().unwrap();
+            let names = reply
+                .names
+                .into_iter()
+                .map(|name| (name.name, name.greetings))
+                .collect::<Vec<_>>();
+            if names.first().map(|(_, greetings)| *greetings) == Some(2) {
+                return names;
+            }
+        }
+    })
+    .await
+    .expect("ranking");
+    assert_eq!(ranked, [("Alice".to_string(), 2), ("Bob".to_string(), 1)]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn admin_reports_certificate_expiry() {
+    let server = TestServer::start().await;
+    let mut admin = AdminClient::new(server.channel().await);
+
+    let reply = admin
+        .get_certificates(GetCertificatesRequest {})
+        .await
+        .unwrap()
+        .into_inner();
+    if cfg!(feature = "tls") {
+        assert_eq!(reply.certificates.len(), 1);
+        let cert = &reply.certificates[0];
+        assert_eq!(cert.name, "server");
+        assert!(cert.days_left > 30);
+    } else {
+        assert!(reply.certificates.is_empty());
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn server_info_reports_the_build() {
+    let server = TestServer::start().await;
+    let mut admin = AdminClient::new(server.channel().await);
+
+    let info = admin
+        .get_server_info(GetServerInfoRequest {})
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert!(info.built_at > 0);
+    assert_eq!(
+        info.features.contains(&"tls".to_string()),
+        cfg!(feature = "tls")
+    );
+    assert_eq!(info.descriptor_sha256.len(), 64);
+
+    let reply = server.client().await.say_hello(hello("a")).await.unwrap();
+    let version = reply.metadata().get(server_info::VERSION_METADATA).unwrap();
+    assert!(version
+        .to_str()
+        .unwrap()
+        .starts_with(env!("CARGO_PKG_VERSION")));
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn expired_certificates_have_no_days_left() {
+    let mut params = rcgen::CertificateParams::new(vec!["localhost".to_string()]);
+    params.not_before = rcgen::date_time_ymd(2020, 1, 1);
+    params.not_after = rcgen::date_time_ymd(2021, 1, 1);
+    let pem = rcgen::Certificate::from_params(params)
+        .unwrap()
+        .serialize_pem()
+        .unwrap();
+
+    let certs = tonic_hello_tls::certs::parse("server", pem.as_bytes()).unwrap();
+    assert_eq!(certs.len(), 1);
+    assert!(certs[0].days_left() < -365);
+    assert!(tonic_hello_tls::certs::parse("server", b"").is_err());
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn identities_are_read_from_der_and_pkcs12() {
+    use tonic_hello_tls::{config::IdentityFormat, identity};
+
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let cert_der = cert.serialize_der().unwrap();
+    let key_der = cert.serialize_private_key_der();
+    let dir = std::env::temp_dir().join(format!("identity-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    std::fs::write(dir.join("server.pem"), &cert_der).unwrap();
+    std::fs::write(dir.join("server.key"), &key_der).unwrap();
+    let loaded = identity::load(&dir, IdentityFormat::Auto, "", None).unwrap();
+    assert_eq!(loaded.cert, identity::pem_block("CERTIFICATE", &cert_der));
+    assert!(tonic_hello_tls::certs::parse("server", loaded.cert.as_bytes()).is_ok());
+    assert!(matches!(
+        identity::load(&dir, IdentityFormat::Pem, "", None),
+        Err(identity::IdentityError::NotPem(_))
+    ));
+
+    // a bundle is only picked up on its own without a format
+    let bundle = p12::PFX::new(&cert_der, &key_der, None, "secret", "server").unwrap();
+    std::fs::write(dir.join("server.p12"), bundle.to_der()).unwrap();
+    std::fs::remove_file(dir.join("server.pem")).unwrap();
+    let loaded = identity::load(&dir, IdentityFormat::Auto, "secret", None).unwrap();
+    assert_eq!(loaded.cert, identity::pem_block("CERTIFICATE", &cert_der));
+    assert_eq!(loaded.key, identity::pem_block("PRIVATE KEY", &key_der));
+    assert!(identity::load(&dir, IdentityFormat::Pkcs12, "wrong", None).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn encrypted_keys_are_decrypted_with_the_passphrase() {
+    use pkcs8::{der::pem::LineEnding, pkcs5::pbes2, PrivateKeyInfo};
+    use tonic_hello_tls::{config::IdentityFormat, identity};
+
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let key_der = cert.serialize_private_key_der();
+    let params = pbes2::Parameters::pbkdf2_sha256_aes256cbc(2048, b"saltsalt", &[7; 16]).unwrap();
+    let encrypted = PrivateKeyInfo::try_from(key_der.as_slice())
+        .unwrap()
+        .encrypt_with_params(params, "hunter2")
+        .unwrap();
+    let dir = std::env::temp_dir().join(format!("encrypted-key-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("server.pem"), cert.serialize_pem().unwrap()).unwrap();
+
+    for key in [
+        encrypted
+            .to_pem("ENCRYPTED PRIVATE KEY", LineEnding::LF)
+            .unwrap()
+            .as_bytes()
+            .to_vec(),
+        encrypted.as_bytes().to_vec(),
+    ] {
+        std::fs::write(dir.join("server.key"), key).unwrap();
+        let loaded = identity::load(&dir, IdentityFormat::Auto, "", Some("hunter2")).unwrap();
+        assert_eq!(loaded.key, identity::pem_block("PRIVATE KEY", &key_der));
+        assert!(matches!(
+            identity::load(&dir, IdentityFormat::Auto, "", None),
+            Err(identity::IdentityError::NoPassphrase)
+        ));
+        assert!(matches!(
+            identity::load(&dir, IdentityFormat::Auto, "", Some("hunter3")),
+            Err(identity::IdentityError::Decrypt(_))
+        ));
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn clients_failing_handshakes_are_banned() {
+    use tonic_hello_tls::handshake::{HandshakeLimits, Handshakes};
+
+    let handshakes = Handshakes::new(HandshakeLimits {
+        max_concurrent: 1,
+        timeout: Duration::from_millis(50),
+        ban_failures: 2,
+        ..HandshakeLimits::default()
+    });
+    let ip = "192.0.2.1".parse().unwrap();
+
+    // completed, or dropped before the client sent anything: no failure
+    let handshake = handshakes.start(Some(ip)).await.unwrap();
+    handshake.complete();
+    drop(handshake);
+    drop(handshakes.start(Some(ip)).await.unwrap());
+
+    let mut first = handshakes.start(Some(ip)).await.unwrap();
+    first.received();
+    // the only room is taken until the first one ends
+    assert!(handshakes.start(Some(ip)).await.is_none());
+    drop(first);
+    assert!(!handshakes.is_banned(ip));
+
+    let mut second = handshakes.start(Some(ip)).await.unwrap();
+    second.received();
+    drop(second);
+    assert!(handshakes.is_banned(ip));
+    assert!(!handshakes.is_banned("192.0.2.2".parse().unwrap()));
+}
+
+#[test]
+fn client_auth_is_looked_up_by_method_then_service() {
+    use tonic_hello_tls::config::{ClientAuth, ClientAuthPolicy};
+
+    let policy: ClientAuthPolicy = concat!(
+        "grpc.health.v1.Health=optional,",
+        " helloworld.Greeter/SayHello=optional,",
+        "helloworld.Greeter=required",
+    )
+    .parse()
+    .unwrap();
+    assert_eq!(
+        policy.for_path("/grpc.health.v1.Health/Check"),
+        ClientAuth::Optional
+    );
+    assert_eq!(
+        policy.for_path("/helloworld.Greeter/SayHello"),
+        ClientAuth::Optional
+    );
+    assert_eq!(
+        policy.for_path("/helloworld.Greeter/ListMessages"),
+        ClientAuth::Required
+    );
+    assert_eq!(
+        policy.for_path("/helloworld.Admin/GetStats"),
+        ClientAuth::Required
+    );
+
+    let open: ClientAuthPolicy = "*=optional,helloworld.Admin=required".parse().unwrap();
+    assert_eq!(
+        open.for_path("/helloworld.Greeter/SayHello"),
+        ClientAuth::Optional
+    );
+    assert_eq!(
+        open.for_path("/helloworld.Admin/GetStats"),
+        ClientAuth::Required
+    );
+    assert!("helloworld.Greeter=maybe"
+        .parse::<ClientAuthPolicy>()
+        .is_err());
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn revoked_client_certificates_are_listed_by_issuer_and_serial() {
+    use rcgen::{
+        date_time_ymd, BasicConstraints, Certificate, CertificateParams, CertificateRevocationList,
+        CertificateRevocationListParams, IsCa, KeyIdMethod, KeyUsagePurpose, RevokedCertParams,
+        SerialNumber,
+    };
+    use tonic_hello_tls::revocation::RevocationList;
+
+    let ca = |name: &str| {
+        let mut params = CertificateParams::new(Vec::new());
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, name);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+        Certificate::from_params(params).unwrap()
+    };
+    let client = |serial: u64, issuer: &Certificate| {
+        let mut params = CertificateParams::new(vec!["client".to_string()]);
+        params.serial_number = Some(SerialNumber::from(serial));
+        let cert = Certificate::from_params(params).unwrap();
+        cert.serialize_der_with_signer(issuer).unwrap()
+    };
+    let (client_ca, other_ca) = (ca("client CA"), ca("other CA"));
+    let crl = CertificateRevocationList::from_params(CertificateRevocationListParams {
+        this_update: date_time_ymd(2024, 1, 1),
+        next_update: date_time_ymd(2100, 1, 1),
+        crl_number: SerialNumber::from(1),
+        issuing_distribution_point: None,
+        revoked_certs: vec![RevokedCertParams {
+            serial_number: SerialNumber::from(42),
+            revocation_time: date_time_ymd(2024, 1, 1),
+            reason_code: None,
+            invalidity_date: None,
+        }],
+        alg: &rcgen::PKCS_ECDSA_P256_SHA256,
+        key_identifier_method: KeyIdMethod::Sha256,
+    })
+    .unwrap();
+
+    for data in [
+        crl.serialize_pem_with_signer(&client_ca)
+            .unwrap()
+            .into_bytes(),
+        crl.serialize_der_with_signer(&client_ca).unwrap(),
+    ] {
+        let list = RevocationList::parse(&data).unwrap();
+        assert_eq!(list.len(), 1);
+        assert!(list.is_revoked(&client(42, &client_ca)));
+        assert!(!list.is_revoked(&client(43, &client_ca)));
+        // serials are only unique per issuer
+        assert!(!list.is_revoked(&client(42, &other_ca)));
+    }
+    assert!(RevocationList::parse(b"not a CRL").is_err());
+}
+
+#[cfg(feature = "dev-tls")]
+#[test]
+fn a_certificate_is_generated_only_when_missing() {
+    use tonic_hello_tls::dev_tls;
+
+    let dir = std::env::temp_dir().join(format!("dev-tls-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let fingerprint = dev_tls::ensure_identity(&dir).unwrap().expect("generated");
+    assert_eq!(fingerprint.len(), 32 * 3 - 1);
+
+    let pem = std::fs::read(dir.join("server.pem")).unwrap();
+    let certs = tonic_hello_tls::certs::parse("server", &pem).unwrap();
+    assert!(certs[0].days_left() > 0);
+    assert!(dir.join("server.key").exists());
+
+    assert_eq!(dev_tls::ensure_identity(&dir).unwrap(), None);
+    assert_eq!(std::fs::read(dir.join("server.pem")).unwrap(), pem);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn streaming_is_turned_off_by_feature_flag() {
+    let config = Config {
+        feature_flag_ttl_ms: 0,
+        // the tenant is only taken from trusted frontends
+        tenant_frontends: vec!["127.0.0.1".parse().unwrap()],
+        ..Config::default()
+    };
+    let server = TestServer::start_with(config).await;
+    let mut client = server.client().await;
+    let many = |tenant: &str| {
+        let mut request = Request::new(SayHelloManyRequest {
+            names: vec!["Ada".to_string()],
+            ..Default::default()
+        });
+        request
+            .metadata_mut()
+            .insert(TENANT_METADATA, tenant.parse().unwrap());
+        request
+    };
+
+    server
+        .db
+        .set_feature_flag("streaming", "*", false)
+        .await
+        .unwrap();
+    server
+        .db
+        .set_feature_flag("streaming", "acme", true)
+        .await
+        .unwrap();
+    client.say_hello_many(many("acme")).await.unwrap();
+    let status = client.say_hello_many(many("umbrella")).await.unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition);
+
+    // unary calls aren't affected
+    client.say_hello(hello("Ada")).await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn versioned_packages_share_the_greeter() {
+    let server = TestServer::start().await;
+    let mut v1 = v1::greeter_client::GreeterClient::new(server.channel().await);
+    let mut v2 = v2::greeter_client::GreeterClient::new(server.channel().await);
+
+    let request = v1::HelloRequest {
+        name: "Ada".to_string(),
+    };
+    let reply = v1.say_hello(request).await.unwrap().into_inner();
+    assert_eq!(reply.message, "Hello Ada!");
+
+    let request = v2::HelloRequest {
+        name: "Bob".to_string(),
+        locale: "fr".to_string(),
+        tags: [("team".to_string(), "core".to_string())].into(),
+        ..Default::default()
+    };
+    let reply = v2.say_hello(request).await.unwrap().into_inner();
+    assert_eq!(reply.message, "Bonjour Bob!");
+    assert!(reply.id > 0);
+
+    let request = v2::ListMessagesRequest {
+        tags: [("team".to_string(), "core".to_string())].into(),
+    };
+    let reply = v2.list_messages(request).await.unwrap().into_inner();
+    assert_eq!(reply.messages, ["Bonjour Bob!"]);
+
+    let reply = v1
+        .list_messages(v1::ListMessagesRequest {})
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(reply.messages, ["Hello Ada!", "Bonjour Bob!"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn priorities_are_stored_and_listed() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    client.say_hello(hello("normal")).await.unwrap();
+    let request = HelloRequest {
+        priority: Priority::High.into(),
+        ..hello("urgent")
+    };
+    let reply = client.say_hello(request).await.unwrap().into_inner();
+    assert_eq!(reply.priority(), Priority::High);
+
+    let listed = client
+        .list_messages(ListMessagesRequest::default())
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(listed.messages, ["Hello normal!", "Hello urgent!"]);
+    assert_eq!(
+        listed.priorities().collect::<Vec<_>>(),
+        [Priority::Normal, Priority::High]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn admins_tail_connection_events() {
+    let server = TestServer::start().await;
+    let mut admin = AdminClient::new(server.channel().await);
+
+    let request = StreamServerEventsRequest {
+        kinds: vec![
+            ServerEventKind::Connected.into(),
+            ServerEventKind::Disconnected.into(),
+        ],
+    };
+    let mut events = admin
+        .stream_server_events(request)
+        .await
+        .unwrap()
+        .into_inner();
+    drop(server.channel().await);
+
+    // other tests connect too, only the kinds asked for come through
+    let mut seen = Vec::new();
+    while !seen.contains(&ServerEventKind::Disconnected) {
+        let event = tokio::time::timeout(Duration::from_secs(5), events.message())
+            .await
+            .expect("server event")
+            .unwrap()
+            .unwrap();
+        assert!(event.at_ms > 0);
+        assert!(event.fields.contains_key("peer"));
+        seen.push(event.kind());
+    }
+    assert!(seen.contains(&ServerEventKind::Connected));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn payloads_are_uploaded_and_downloaded_in_chunks() {
+    let config = Config {
+        payload_max_bytes: 200_000,
+        ..Config::default()
+    };
+    let server = TestServer::start_with(config).await;
+    let mut client = server.client().await;
+
+    let request = HelloRequest {
+        payload: vec![1, 2, 3],
+        ..hello("inline")
+    };
+    let inline = client.say_hello(request).await.unwrap().into_inner();
+    let reply = client
+        .say_hello(hello("uploaded"))
+        .await
+        .unwrap()
+        .into_inner();
+
+    let payload = (0..150_000).map(|i| i as u8).collect::<Vec<_>>();
+    let chunks = payload
+        .chunks(16 * 1024)
+        .map(|data| PayloadChunk {
+            id: reply.cursor,
+            data: data.to_vec(),
+        })
+        .collect::<Vec<_>>();
+    let uploaded = client
+        .upload_payload(tokio_stream::iter(chunks))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(uploaded.size, payload.len() as u64);
+
+    let downloader = client.clone();
+    let download = |id| {
+        let mut client = downloader.clone();
+        async move {
+            let mut stream = client
+                .download_payload(DownloadPayloadRequest { id })
+                .await?
+                .into_inner();
+            let mut chunks = Vec::new();
+            while let Some(chunk) = stream.message().await? {
+                chunks.push(chunk.data);
+            }
+            Ok::<_, tonic::Status>(chunks)
+        }
+    };
+    let chunks = download(reply.cursor).await.unwrap();
+    assert!(chunks.len() > 1);
+    assert_eq!(chunks.concat(), payload);
+    assert_eq!(download(inline.cursor).await.unwrap().concat(), [1, 2, 3]);
+
+    let status = download(reply.cursor + 1).await.unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+    let oversized = PayloadChunk {
+        id: reply.cursor,
+        data: vec![0; 200_001],
+    };
+    let status = client
+        .upload_payload(tokio_stream::iter([oversized]))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+    assert_eq!(download(reply.cursor).await.unwrap().concat(), payload);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn list_messages_skips_unchanged_listings() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    client.say_hello(hello("first")).await.unwrap();
+    let listed = client
+        .list_messages(ListMessagesRequest::default())
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(listed.messages, ["Hello first!"]);
+    assert!(!listed.not_modified);
+
+    let known = || ListMessagesRequest {
+        known_version: listed.version.clone(),
+        ..Default::default()
+    };
+    let unchanged = client.list_messages(known()).await.unwrap().into_inner();
+    assert!(unchanged.not_modified);
+    assert!(unchanged.messages.is_empty());
+    assert_eq!(unchanged.version, listed.version);
+
+    client.say_hello(hello("second")).await.unwrap();
+    let changed = client.list_messages(known()).await.unwrap().into_inner();
+    assert!(!changed.not_modified);
+    assert_eq!(changed.messages.len(), 2);
+    assert_ne!(changed.version, listed.version);
+}
+
+#[tokio::test]
+async fn oversized_listings_are_chunked() {
+    let config = Config {
+        list_reply_max_bytes: 30,
+        ..Config::default()
+    };
+    let server = TestServer::start_with(config).await;
+    let mut client = server.client().await;
+
+    for name in ["one", "two", "three"] {
+        client.say_hello(hello(name)).await.unwrap();
+    }
+    let status = client
+        .list_messages(ListMessagesRequest::default())
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::ResourceExhausted);
+
+    let mut chunks = client
+        .list_messages_chunked(ListMessagesRequest::default())
+        .await
+        .unwrap()
+        .into_inner();
+    let mut listed = Vec::new();
+    let mut count = 0;
+    while let Some(chunk) = chunks.message().await.unwrap() {
+        assert!(!chunk.messages.is_empty());
+        listed.extend(chunk.messages);
+        count += 1;
+    }
+    assert_eq!(count, 2);
+    assert_eq!(listed, ["Hello one!", "Hello two!", "Hello three!"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn hedged_calls_share_one_greeting() {
+    let in_flight = InFlight::default();
+    let runs = AtomicUsize::new(0);
+    let call = |name: &'static str| {
+        let runs = &runs;
+        async move {
+            runs.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            name
+        }
+    };
+
+    let (first, second) = tokio::join!(
+        in_flight.run("key", call("first")),
+        in_flight.run("key", call("second")),
+    );
+    assert_eq!((first, second), ("first", "first"));
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+    // finished calls aren't remembered
+    assert_eq!(in_flight.run("key", call("third")).await, "third");
+    assert_eq!(in_flight.run("other", call("fourth")).await, "fourth");
+    assert_eq!(runs.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn names_in_cooldown_get_the_cached_reply() {
+    let config = Config {
+        greeting_cooldown_secs: 60,
+        ..Config::default()
+    };
+    let server = TestServer::start_with(config).await;
+    let mut client = server.client().await;
+
+    let first = client.say_hello(hello("ada")).await.unwrap().into_inner();
+    assert!(!first.cached);
+    let again = client.say_hello(hello("ada")).await.unwrap().into_inner();
+    assert!(again.cached);
+    assert_eq!((again.message, again.cursor), (first.message, first.cursor));
+
+    // other names aren't held up
+    let other = client.say_hello(hello("bob")).await.unwrap().into_inner();
+    assert!(!other.cached);
+    assert_eq!(server.db.count_messages().await.unwrap(), 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn names_are_moderated_before_they_are_stored() {
+    let config = Config {
+        moderation: ModerationConfig {
+            max_name_chars: 8,
+            deny: vec!["(?i)darn".to_string()],
+            deny_action: DenyAction::Mask,
+            ..ModerationConfig::default()
+        },
+        ..Config::default()
+    };
+    let server = TestServer::start_with(config).await;
+    let mut client = server.client().await;
+
+    let reply = client
+        .say_hello(hello("Darnell"))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(reply.message, "Hello ****ell!");
+    let err = client.say_hello(hello("Bartholomew")).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+
+    // rejected on the stream too, the names before it are greeted
+    let names = tokio_stream::iter(vec![hello("Ada"), hello("Maximilian")]);
+    let mut replies = client.say_hello_stream(names).await.unwrap().into_inner();
+    assert_eq!(next_reply(&mut replies).await.message, "Hello Ada!");
+    let err = replies.message().await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+
+    let audited = server.db.get_audit_log(10).await.unwrap();
+    let audited = audited
+        .iter()
+        .map(|entry| (entry.action.as_str(), entry.subject.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        audited,
+        [
+            ("mask_name", "Darnell"),
+            ("reject_name", "Bartholomew"),
+            ("reject_name", "Maximilian"),
+        ]
+    );
+    eventually("the streamed greeting stored", || async {
+        server.db.count_messages().await.unwrap() == 2
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn every_greeting_of_a_name_is_deleted() {
+    let config = Config {
+        message_encryption_key: Some(
+            "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY="
+                .parse()
+                .unwrap(),
+        ),
+        ..Config::default()
+    };
+    let server = TestServer::start_with(config).await;
+    let mut client = server.client().await;
+    for name in ["Ada", "Bob", "Ada"] {
+        client.say_hello(hello(name)).await.unwrap();
+    }
+
+    let request = DeleteAllForNameRequest {
+        name: "Ada".to_string(),
+    };
+    let mut progress = client
+        .delete_all_for_name(request)
+        .await
+        .unwrap()
+        .into_inner();
+    let mut last = None;
+    while let Some(next) = progress.message().await.unwrap() {
+        last = Some(next);
+    }
+    let last = last.unwrap();
+    assert!(last.done);
+    assert_eq!((last.scanned, last.deleted), (3, 2));
+
+    let listed = client
+        .list_messages(ListMessagesRequest::default())
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(listed.messages, ["Hello Bob!"]);
+    // no copy of the text is left in the event log
+    let events = server.db.get_events_page(0, 10).await.unwrap();
+    let kept = events
+        .iter()
+        .filter(|event| event.payload.is_some())
+        .count();
+    assert_eq!(kept, 1);
+
+    let audited = server.db.get_audit_log(1).await.unwrap();
+    assert_eq!(audited[0].action, "delete_name");
+    assert!(audited[0].subject.starts_with("sha256:"));
+    assert_eq!(audited[0].detail, "2 messages deleted");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn backups_restore_the_messages() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+    let mut admin = AdminClient::new(server.channel().await);
+    for name in ["Ada", "Bob"] {
+        client.say_hello(hello(name)).await.unwrap();
+    }
+
+    let archive = admin
+        .create_backup(CreateBackupRequest {})
+        .await
+        .unwrap()
+        .into_inner()
+        .map(|chunk| chunk.unwrap().data)
+        .collect::<Vec<_>>()
+        .await
+        .concat();
+
+    client.say_hello(hello("Carol")).await.unwrap();
+    let chunks = archive
+        .chunks(16)
+        .map(|data| BackupChunk {
+            data: data.to_vec(),
+        })
+        .collect::<Vec<_>>();
+    let reply = admin
+        .restore_backup(tokio_stream::iter(chunks))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!((reply.deleted, reply.restored), (3, 2));
+
+    let listed = client
+        .list_messages(ListMessagesRequest::default())
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(listed.messages, ["Hello Ada!", "Hello Bob!"]);
+    // new greetings don't reuse the restored ids
+    let reply = client.say_hello(hello("Dan")).await.unwrap().into_inner();
+    assert_eq!(server.db.get_messages_after(0).await.unwrap().len(), 3);
+    assert!(reply.cursor > 2);
+
+    // a broken archive leaves the messages as they were
+    let broken = BackupChunk {
+        data: archive[..archive.len() / 2].to_vec(),
+    };
+    let status = admin
+        .restore_backup(tokio_stream::iter([broken]))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+    assert_eq!(server.db.count_messages().await.unwrap(), 3);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn backups_are_streamed_page_by_page() {
+    let config = Config {
+        restore_max_bytes: 1 << 20,
+        ..Config::default()
+    };
+    let server = TestServer::start_with(config).await;
+    let mut admin = AdminClient::new(server.channel().await);
+    // several pages of messages, in archives of several chunks
+    let scramble = |i: u64, round: u64| (i * 4 + round).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    let names = (0..2500)
+        .map(|i| {
+            let noise = (0..4).map(|round| format!("{:016x}", scramble(i, round)));
+            format!("Hello {}!", noise.collect::<String>())
+        })
+        .collect::<Vec<_>>();
+    server.db.insert_messages(&names).await.unwrap();
+
+    let chunks = admin
+        .create_backup(CreateBackupRequest {})
+        .await
+        .unwrap()
+        .into_inner()
+        .map(|chunk| chunk.unwrap())
+        .collect::<Vec<_>>()
+        .await;
+    assert!(chunks.len() > 1);
+    let reply = admin
+        .restore_backup(tokio_stream::iter(chunks))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!((reply.deleted, reply.restored), (2500, 2500));
+    let restored = server.db.get_messages_after(0).await.unwrap();
+    assert_eq!(restored.len(), 2500);
+    assert_eq!(
+        restored[2499].message.as_deref(),
+        Some(names[2499].as_str())
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn metadata_is_stored_returned_and_filtered_on() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    let metadata = serde_json::json!({ "device": { "os": "linux", "cores": 8 }, "beta": true });
+    let reply = client
+        .say_hello(HelloRequest {
+            metadata: Some(metadata::from_json(&metadata)),
+            ..hello("tux")
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(
+        serde_json::Value::from(metadata::to_json(reply.metadata.unwrap())),
+        metadata
+    );
+    client.say_hello(hello("plain")).await.unwrap();
+
+    let list = |filter: serde_json::Value| ListMessagesRequest {
+        metadata: Some(metadata::from_json(&filter)),
+        ..Default::default()
+    };
+    let filter = serde_json::json!({ "device": { "os": "linux" } });
+    let listed = client.list_messages(list(filter)).await.unwrap();
+    assert_eq!(listed.into_inner().messages, ["Hello tux!"]);
+    let filter = serde_json::json!({ "device": { "os": "mac" } });
+    let listed = client.list_messages(list(filter)).await.unwrap();
+    assert!(listed.into_inner().messages.is_empty());
+    let listed = client.list_messages(list(serde_json::json!({}))).await;
+    assert_eq!(listed.unwrap().into_inner().messages.len(), 2);
+}
+
+#[test]
+fn pooled_greetings_read_like_composed_ones() {
+    use tonic_hello_tls::{greeter::hello_world::Salutation, greeting, pool};
+
+    for _ in 0..2 {
+        let mut message = pool::string();
+        greeting::compose_into(&mut message, "Ada", "FR-ca", Salutation::Unspecified);
+        assert_eq!(*message, "Bonjour Ada!");
+    }
+    assert_eq!(
+        greeting::compose("Ada", "xx", Salutation::Unspecified),
+        "Hello Ada!"
+    );
+    assert_eq!(&**pool::key(&["tenant", "Ada"]), "tenant/Ada");
+}
@@ -0,0 +1,113 @@
+#![cfg(feature = "test-util")]
+
+mod common;
+
+use tonic::{transport::Server, Code};
+use tonic_types::StatusExt;
+
+use common::{connect, hello, listen};
+use tonic_hello_tls::{
+    config::{CallerClass, CallerClassesConfig, MethodLimit},
+    greeter::{
+        hello_world::{ExportMessagesRequest, ListMessagesRequest},
+        GreeterServer,
+    },
+    limits::{self, MethodLimitLayer, CALLER_CLASS_METADATA},
+    mock::MockGreeter,
+};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn streams_are_held_to_their_method_limit() {
+    let (incoming, addr) = listen();
+    let route = "helloworld.Greeter/ListMessagesStream";
+    let config = [(
+        route.to_string(),
+        MethodLimit {
+            max_concurrent: 1,
+            max_queued: 1,
+            queue_timeout_ms: 100,
+            adaptive: false,
+            min_concurrent: 1,
+            latency_tolerance: 2.0,
+            backoff: 0.9,
+        },
+    )];
+    tokio::spawn(
+        Server::builder()
+            .layer(MethodLimitLayer::new(&config.into()))
+            .add_service(GreeterServer::new(MockGreeter::new()))
+            .serve_with_incoming(incoming),
+    );
+    let mut client = connect(addr).await;
+    let subscribe = || tokio_stream::iter([ListMessagesRequest::default()]);
+
+    let stream = client.list_messages_stream(subscribe()).await.unwrap();
+    // waits for the queue timeout, then tells to come back after it
+    let status = client.list_messages_stream(subscribe()).await.unwrap_err();
+    assert_eq!(status.code(), Code::Unavailable);
+    let retry_info = status.get_details_retry_info().unwrap();
+    assert_eq!(
+        retry_info.retry_delay,
+        Some(std::time::Duration::from_millis(100))
+    );
+    // other methods aren't limited
+    client.say_hello(hello("Alice")).await.unwrap();
+    let stats = limits::stats()[route];
+    assert_eq!((stats.in_flight, stats.timed_out), (1, 1));
+    assert!(stats.mean_wait_ms >= 100);
+
+    // the limit is freed once the stream ends
+    drop(stream);
+    let mut attempts = 0;
+    while let Err(status) = client.list_messages_stream(subscribe()).await {
+        assert_eq!(status.code(), Code::Unavailable);
+        attempts += 1;
+        assert!(attempts < 10, "the stream was never let go");
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn batch_calls_cannot_take_the_room_of_interactive_ones() {
+    let (incoming, addr) = listen();
+    let budget = MethodLimit {
+        max_concurrent: 1,
+        max_queued: 0,
+        queue_timeout_ms: 0,
+        adaptive: false,
+        min_concurrent: 1,
+        latency_tolerance: 2.0,
+        backoff: 0.9,
+    };
+    let layer = MethodLimitLayer::new(&Default::default()).with_classes(
+        &CallerClassesConfig::default(),
+        &[(CallerClass::Batch, budget)].into(),
+    );
+    tokio::spawn(
+        Server::builder()
+            .layer(layer)
+            .add_service(GreeterServer::new(MockGreeter::new()))
+            .serve_with_incoming(incoming),
+    );
+    let mut client = connect(addr).await;
+    fn batch<T>(message: T) -> tonic::Request<T> {
+        let mut request = tonic::Request::new(message);
+        request
+            .metadata_mut()
+            .insert(CALLER_CLASS_METADATA, "batch".parse().unwrap());
+        request
+    }
+
+    let subscribe = tokio_stream::iter([ListMessagesRequest::default()]);
+    let _stream = client.list_messages_stream(batch(subscribe)).await.unwrap();
+    let status = client.say_hello(batch(hello("Alice"))).await.unwrap_err();
+    assert_eq!(status.code(), Code::Unavailable);
+    // exports are batch whoever asks
+    let status = client
+        .export_messages(ExportMessagesRequest::default())
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::Unavailable);
+    client.say_hello(hello("Bob")).await.unwrap();
+    let stats = limits::stats()["batch callers"];
+    assert_eq!((stats.in_flight, stats.rejected), (1, 2));
+}
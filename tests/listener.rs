@@ -0,0 +1,59 @@
+use std::net::SocketAddr;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_stream::StreamExt;
+use tonic::transport::server::Connected;
+
+use tonic_hello_tls::listener::{self, Connection, ListenerOptions};
+
+const HEADER: &[u8] = b"PROXY TCP4 10.1.2.3 127.0.0.1 5000 50051\r\n";
+
+/// Connects to a listener trusting `trusted_proxies` and sends a PROXY header
+/// claiming 10.1.2.3 followed by `hi`.
+async fn accept_proxied(trusted_proxies: &[&str]) -> (TcpStream, Connection) {
+    let options = ListenerOptions {
+        proxy_protocol: true,
+        trusted_proxies: trusted_proxies
+            .iter()
+            .map(|block| block.parse().unwrap())
+            .collect(),
+        ..ListenerOptions::default()
+    };
+    let listener = listener::bind(SocketAddr::from(([127, 0, 0, 1], 0)), &options).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut incoming = Box::pin(listener::incoming(vec![listener], options));
+
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client.write_all(HEADER).await.unwrap();
+    client.write_all(b"hi").await.unwrap();
+    let conn = incoming.next().await.unwrap().unwrap();
+    (client, conn)
+}
+
+#[tokio::test]
+async fn proxy_headers_of_trusted_proxies_are_read() {
+    let (client, mut conn) = accept_proxied(&["127.0.0.0/8"]).await;
+    let info = conn.connect_info();
+    assert_eq!(info.remote_addr, Some("10.1.2.3:5000".parse().unwrap()));
+    assert_eq!(info.peer_addr, Some(client.local_addr().unwrap()));
+
+    let mut read = [0; 2];
+    conn.read_exact(&mut read).await.unwrap();
+    assert_eq!(&read, b"hi");
+}
+
+#[tokio::test]
+async fn proxy_headers_of_other_peers_are_not_trusted() {
+    let (client, mut conn) = accept_proxied(&["10.0.0.0/8"]).await;
+    let info = conn.connect_info();
+    assert_eq!(info.remote_addr, Some(client.local_addr().unwrap()));
+    assert_eq!(info.peer_addr, info.remote_addr);
+
+    // the header is left to the protocol, which will refuse it
+    let mut read = vec![0; HEADER.len()];
+    conn.read_exact(&mut read).await.unwrap();
+    assert_eq!(read, HEADER);
+}
@@ -0,0 +1,162 @@
+use std::time::Duration;
+
+use prost::Message as _;
+use tonic_hello_tls::{
+    codec::Frame,
+    config::OverflowPolicy,
+    db::Message,
+    greeter::hello_world::HelloReply,
+    messages::{Broadcaster, SharedMessage},
+};
+
+fn message(id: i32) -> Message {
+    Message {
+        id,
+        message: Some(format!("Hello {}!", id)),
+        updated: None,
+        tags: serde_json::json!({}),
+        metadata: serde_json::json!({}),
+        priority: 0,
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn drop_oldest_makes_lagging_subscribers_skip() {
+    let broadcaster = Broadcaster::with_capacity(2, OverflowPolicy::DropOldest);
+    let mut rx = broadcaster.subscribe("test");
+    for id in 1..=3 {
+        broadcaster.broadcast(message(id)).await;
+    }
+
+    assert!(rx.recv().await.is_err(), "lagged");
+    assert_eq!(rx.recv().await.unwrap().id, 2);
+    assert_eq!(rx.recv().await.unwrap().id, 3);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn drop_newest_keeps_what_subscribers_have_not_read() {
+    let broadcaster = Broadcaster::with_capacity(2, OverflowPolicy::DropNewest);
+    let mut rx = broadcaster.subscribe("test");
+    for id in 1..=4 {
+        broadcaster.broadcast(message(id)).await;
+    }
+
+    assert_eq!(rx.recv().await.unwrap().id, 1);
+    assert_eq!(rx.recv().await.unwrap().id, 2);
+    assert_eq!(broadcaster.stats().dropped, 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn block_waits_for_the_slowest_subscriber() {
+    let broadcaster = Broadcaster::with_capacity(2, OverflowPolicy::Block);
+    let mut rx = broadcaster.subscribe("test");
+    broadcaster.broadcast(message(1)).await;
+    broadcaster.broadcast(message(2)).await;
+
+    let blocked = tokio::spawn({
+        let broadcaster = broadcaster.clone();
+        async move { broadcaster.broadcast(message(3)).await }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!blocked.is_finished());
+
+    assert_eq!(rx.recv().await.unwrap().id, 1);
+    blocked.await.unwrap();
+    assert_eq!(rx.recv().await.unwrap().id, 2);
+    assert_eq!(rx.recv().await.unwrap().id, 3);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn high_priority_messages_overtake_queued_ones() {
+    let broadcaster = Broadcaster::with_capacity(8, OverflowPolicy::DropOldest);
+    let mut rx = broadcaster.subscribe("test");
+    for (id, priority) in [(1, 0), (2, 0), (3, 1), (4, 0), (5, 1)] {
+        let msg = Message {
+            priority,
+            ..message(id)
+        };
+        broadcaster.broadcast(msg).await;
+    }
+
+    let mut ids = Vec::new();
+    let mut overtook = Vec::new();
+    for _ in 0..5 {
+        ids.push(rx.recv().await.unwrap().id);
+        overtook.push(rx.overtook());
+    }
+    assert_eq!(ids, [3, 5, 1, 2, 4]);
+    // resuming after 3 or 5 would skip the others
+    assert_eq!(overtook, [true, true, false, false, false]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stats_track_every_subscriber() {
+    let broadcaster = Broadcaster::with_capacity(2, OverflowPolicy::DropOldest);
+    let mut reader = broadcaster.subscribe("reader");
+    let mut laggard = broadcaster.subscribe("laggard");
+    for id in 1..=3 {
+        broadcaster.broadcast(message(id)).await;
+        reader.recv().await.unwrap();
+    }
+
+    let stats = broadcaster.stats();
+    assert_eq!(stats.broadcast, 3);
+    let behind = stats
+        .subscribers
+        .iter()
+        .map(|s| (s.name.as_str(), s.received, s.behind))
+        .collect::<Vec<_>>();
+    assert_eq!(behind, [("reader", 3, 0), ("laggard", 0, 3)]);
+
+    assert!(laggard.recv().await.is_err(), "lagged");
+    let stats = broadcaster.stats();
+    assert_eq!(stats.missed, 1);
+    assert_eq!(stats.subscribers[1].missed, 1);
+    assert_eq!(stats.subscribers[1].behind, 2);
+
+    drop(laggard);
+    assert_eq!(broadcaster.stats().subscribers.len(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sharded_subscribers_get_every_message_in_order() {
+    let broadcaster = Broadcaster::with_shards(64, OverflowPolicy::DropOldest, 4);
+    let mut receivers = (0..10)
+        .map(|_| broadcaster.subscribe("test"))
+        .collect::<Vec<_>>();
+    assert_eq!(broadcaster.subscriber_count(), 10);
+
+    for id in 1..=40 {
+        assert!(broadcaster.broadcast(message(id)).await);
+    }
+    for rx in receivers.iter_mut() {
+        for id in 1..=40 {
+            assert_eq!(rx.recv().await.unwrap().id, id);
+        }
+    }
+    assert_eq!(broadcaster.stats().broadcast, 40);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn subscribers_share_the_reply_of_a_message() {
+    let broadcaster = Broadcaster::new();
+    let mut first = broadcaster.subscribe("first");
+    let mut second = broadcaster.subscribe("second");
+    broadcaster.broadcast(message(1)).await;
+
+    let expected = HelloReply::from(message(1));
+    assert_eq!(first.recv().await.unwrap().reply(), expected);
+    assert_eq!(second.recv().await.unwrap().reply(), expected);
+}
+
+#[test]
+fn encoded_replies_encode_as_the_reply() {
+    let shared = SharedMessage::from(message(1));
+    let frame = Frame::Encoded(shared.encoded());
+    assert_eq!(frame.encoded_len(), shared.reply().encoded_len());
+    assert_eq!(frame.encode_to_vec(), shared.reply().encode_to_vec());
+    assert_eq!(
+        HelloReply::decode(&*frame.encode_to_vec()).unwrap(),
+        shared.reply()
+    );
+}
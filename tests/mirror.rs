@@ -0,0 +1,56 @@
+#![cfg(feature = "test-util")]
+
+mod common;
+
+use tonic::{
+    transport::{Endpoint, Server},
+    Status,
+};
+
+use common::{connect, hello, listen};
+use tonic_hello_tls::{
+    greeter::GreeterServer,
+    mirror::{self, MirrorLayer},
+    mock::{Call, MockGreeter},
+};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unary_calls_are_mirrored() {
+    // the shadow's failures never reach the caller
+    let shadow = MockGreeter::new().fail("SayHello", Status::unavailable("shadow down"));
+    let (shadow_incoming, shadow_addr) = listen();
+    tokio::spawn(
+        Server::builder()
+            .add_service(GreeterServer::new(shadow.clone()))
+            .serve_with_incoming(shadow_incoming),
+    );
+
+    let endpoint = Endpoint::from_shared(format!("http://{}", shadow_addr)).unwrap();
+    let (incoming, addr) = listen();
+    tokio::spawn(
+        Server::builder()
+            .layer(MirrorLayer::new(endpoint, 50.0))
+            .add_service(GreeterServer::new(MockGreeter::new()))
+            .serve_with_incoming(incoming),
+    );
+    let mut client = connect(addr).await;
+    let before = mirror::stats();
+
+    for name in ["Alice", "Bob", "Carol", "Dave"] {
+        let reply = client.say_hello(hello(name)).await.unwrap().into_inner();
+        assert_eq!(reply.message, format!("Hello {}!", name));
+    }
+    // every other call, in no particular order
+    let mut mirrored = Vec::new();
+    for _ in 0..50 {
+        mirrored = shadow.calls();
+        if mirrored.len() == 2 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(mirrored.len(), 2);
+    assert!(mirrored.contains(&Call::SayHello(hello("Bob"))));
+    assert!(mirrored.contains(&Call::SayHello(hello("Dave"))));
+    assert_eq!(mirror::stats().mirrored - before.mirrored, 2);
+}
@@ -0,0 +1,91 @@
+#![cfg(feature = "test-util")]
+
+mod common;
+
+use tonic::{Code, Status};
+
+use common::{hello, serve};
+use tonic_hello_tls::{
+    config::Config,
+    greeter::{hello_world::ListMessagesRequest, MyGreeter},
+    mock::{Call, MockGreeter, MockMessageStore},
+    store::MessageStore,
+};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn greetings_are_stored_and_recorded() {
+    let greeter = MockGreeter::with_store(MockMessageStore::with_messages(["seeded"]));
+    let mut client = serve(greeter.clone()).await;
+
+    let reply = client.say_hello(hello("Alice")).await.unwrap().into_inner();
+    assert_eq!(reply.message, "Hello Alice!");
+    assert_eq!(reply.cursor, 2);
+
+    let listed = client
+        .list_messages(ListMessagesRequest::default())
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(listed.messages, ["seeded", "Hello Alice!"]);
+
+    assert_eq!(
+        greeter.calls(),
+        [
+            Call::SayHello(hello("Alice")),
+            Call::ListMessages(ListMessagesRequest::default())
+        ]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn failing_methods_return_their_status() {
+    let greeter = MockGreeter::new().fail("SayHello", Status::unavailable("down"));
+    let mut client = serve(greeter.clone()).await;
+
+    let status = client.say_hello(hello("Bob")).await.unwrap_err();
+    assert_eq!(status.code(), Code::Unavailable);
+    assert_eq!(greeter.store().count_messages().await.unwrap(), 0);
+
+    greeter.recover("SayHello");
+    client.say_hello(hello("Bob")).await.unwrap();
+    assert_eq!(greeter.calls().len(), 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn greeter_runs_on_any_message_store() {
+    let store = MockMessageStore::new();
+    let mut client = serve(MyGreeter::new(store.clone(), Config::default())).await;
+
+    let reply = client.say_hello(hello("Carol")).await.unwrap().into_inner();
+    assert_eq!(reply.cursor, 1);
+
+    let stored = store.get_messages().await.unwrap();
+    assert_eq!(stored[0].message.as_deref(), Some("Hello Carol!"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn streamed_names_are_stored_until_the_quota_runs_out() {
+    let config = Config {
+        tenant_quotas: "*=1:".parse().unwrap(),
+        ..Config::default()
+    };
+    let store = MockMessageStore::new();
+    let mut client = serve(MyGreeter::new(store.clone(), config)).await;
+
+    let names = tokio_stream::iter([hello("Erin"), hello("Finn")]);
+    let mut replies = client.say_hello_stream(names).await.unwrap().into_inner();
+    assert_eq!(
+        replies.message().await.unwrap().unwrap().message,
+        "Hello Erin!"
+    );
+    assert_eq!(
+        replies.message().await.unwrap().unwrap().message,
+        "Hello Finn!"
+    );
+    let status = replies.message().await.unwrap_err();
+    assert_eq!(status.code(), Code::ResourceExhausted);
+
+    let stored = store.get_messages().await.unwrap();
+    assert_eq!(stored.len(), 1);
+    assert_eq!(stored[0].message.as_deref(), Some("Erin"));
+}
@@ -0,0 +1,90 @@
+#![cfg(feature = "test-util")]
+
+mod common;
+
+use std::sync::Arc;
+
+use tonic::{transport::Server, Status};
+
+use common::listen;
+use tonic_hello_tls::{
+    clients::Upstream,
+    config::{Config, UpstreamConfig},
+    mock::MockMessageStore,
+    service::{Greeting, GreetingService},
+    translate::{
+        proto::{
+            translator_server::{Translator as TranslatorService, TranslatorServer},
+            TranslateReply, TranslateRequest,
+        },
+        Translator,
+    },
+};
+
+/// Translates into Swedish only, counting the calls.
+#[derive(Clone, Default)]
+struct Swedish {
+    calls: Arc<std::sync::atomic::AtomicUsize>,
+}
+
+#[tonic::async_trait]
+impl TranslatorService for Swedish {
+    async fn translate(
+        &self,
+        request: tonic::Request<TranslateRequest>,
+    ) -> Result<tonic::Response<TranslateReply>, Status> {
+        self.calls
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let request = request.into_inner();
+        match (request.text.as_str(), request.target_language.as_str()) {
+            ("Hello", "sv" | "sv-SE") => Ok(tonic::Response::new(TranslateReply {
+                text: "Hej".to_string(),
+            })),
+            _ => Err(Status::not_found("no translation")),
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn greetings_are_translated() {
+    let (incoming, addr) = listen();
+    let swedish = Swedish::default();
+    tokio::spawn(
+        Server::builder()
+            .add_service(TranslatorServer::new(swedish.clone()))
+            .serve_with_incoming(incoming),
+    );
+    let config = UpstreamConfig {
+        url: format!("http://{}", addr),
+        connections: 1,
+        timeout_ms: 1000,
+        connect_timeout_ms: 1000,
+        retries: 0,
+        retry_backoff_ms: 1,
+        ca_cert: None,
+        tls_domain: None,
+    };
+    let upstream = Upstream::new("translation-test", &config).unwrap();
+    let translator = Translator::new(upstream, std::time::Duration::from_secs(60));
+    let service = GreetingService::new(MockMessageStore::new(), &Config::default())
+        .with_translator(translator);
+    let greet = |name: &str, locale: &str| {
+        let greeting = Greeting {
+            name: name.to_string(),
+            locale: locale.to_string(),
+            ..Default::default()
+        };
+        let service = service.clone();
+        async move { service.greet("acme", &greeting).await.unwrap().message }
+    };
+
+    assert_eq!(greet("Ann", "sv-SE").await.as_deref(), Some("Hej Ann!"));
+    // cached
+    assert_eq!(greet("Bo", "sv-SE").await.as_deref(), Some("Hej Bo!"));
+    // failed translations fall back to what `greeting` has, English at worst
+    assert_eq!(greet("Cy", "fr").await.as_deref(), Some("Bonjour Cy!"));
+    assert_eq!(greet("Di", "fi").await.as_deref(), Some("Hello Di!"));
+    // English isn't translated
+    assert_eq!(greet("Ed", "en-GB").await.as_deref(), Some("Hello Ed!"));
+    assert_eq!(swedish.calls.load(std::sync::atomic::Ordering::Relaxed), 3);
+}
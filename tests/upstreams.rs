@@ -0,0 +1,60 @@
+#![cfg(feature = "test-util")]
+
+mod common;
+
+use tonic::{transport::Server, Code, Status};
+
+use common::{hello, listen};
+use tonic_hello_tls::{
+    clients::{self, Upstream},
+    config::UpstreamConfig,
+    greeter::{
+        hello_world::{greeter_client::GreeterClient, ListMessagesRequest},
+        GreeterServer,
+    },
+    mock::MockGreeter,
+};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn upstream_calls_are_retried_and_counted() {
+    let (incoming, addr) = listen();
+    let greeter = MockGreeter::new().fail("ListMessages", Status::unavailable("overloaded"));
+    tokio::spawn(
+        Server::builder()
+            .add_service(GreeterServer::new(greeter.clone()))
+            .serve_with_incoming(incoming),
+    );
+    let config = UpstreamConfig {
+        url: format!("http://{}", addr),
+        connections: 2,
+        timeout_ms: 1000,
+        connect_timeout_ms: 1000,
+        retries: 2,
+        retry_backoff_ms: 1,
+        ca_cert: None,
+        tls_domain: None,
+    };
+    let upstream = Upstream::new("upstream-test", &config).unwrap();
+
+    let reply = upstream
+        .call(|channel| async move { GreeterClient::new(channel).say_hello(hello("Ann")).await })
+        .await
+        .unwrap();
+    assert_eq!(reply.message, "Hello Ann!");
+    let err = upstream
+        .call(|channel| async move {
+            GreeterClient::new(channel)
+                .list_messages(ListMessagesRequest::default())
+                .await
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), Code::Unavailable);
+
+    let stats = clients::stats()["upstream-test"];
+    assert_eq!(stats.calls, 2);
+    assert_eq!(stats.failures, 1);
+    assert_eq!(stats.retries, 2);
+    // the first attempt and both retries
+    assert_eq!(greeter.calls().len(), 4);
+}